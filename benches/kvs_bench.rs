@@ -1,10 +1,13 @@
+// Written against a rand where `Alphanumeric` samples bytes rather than `char`s
+#![allow(clippy::useless_conversion)]
+
 use criterion::{criterion_group, criterion_main, Criterion};
 use rand::Rng;
 use rand::distributions::{Alphanumeric, Distribution, Uniform};
 use rand::seq::SliceRandom;
 use tempfile::TempDir;
 
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use kvs::{KvStore, KvsEngine, LsmKvsEngine, SledKvsEngine};
 
 fn kvs_bench(c: &mut Criterion) {
     let mut group = c.benchmark_group("kvs");
@@ -14,7 +17,7 @@ fn kvs_bench(c: &mut Criterion) {
     let dir = TempDir::new().unwrap();
     let path = dir.path();
 
-    let mut kvs = KvStore::open(path).unwrap();
+    let kvs = KvStore::open(path).unwrap();
 
     // Generate 100 keys and values of random length in [1, 100000] bytes.
     let mut rng = rand::thread_rng();
@@ -65,7 +68,7 @@ fn sled_bench(c: &mut Criterion) {
     let dir = TempDir::new().unwrap();
     let path = dir.path();
 
-    let mut sled = SledKvsEngine::open(path).unwrap();
+    let sled = SledKvsEngine::open(path).unwrap();
 
     // Generate 100 keys and values of random length in [1, 100000] bytes.
     let mut rng = rand::thread_rng();
@@ -111,5 +114,56 @@ fn sled_bench(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, kvs_bench, sled_bench);
+fn lsm_bench(c: &mut Criterion) {
+    // Create a temporary directory for the bench
+    let dir = TempDir::new().unwrap();
+    let path = dir.path();
+
+    let lsm = LsmKvsEngine::open(path).unwrap();
+
+    // Generate 100 keys and values of random length in [1, 100000] bytes.
+    let mut rng = rand::thread_rng();
+    let between = Uniform::from(1..100000);
+    let key_lengths = (0..100)
+        .map(|_| between.sample(&mut rng))
+        .collect::<Vec<_>>();
+    let value_lengths = (0..100)
+        .map(|_| between.sample(&mut rng))
+        .collect::<Vec<_>>();
+    let mut pairs = Vec::with_capacity(100);
+
+    for (key_length, value_length) in key_lengths.iter().zip(value_lengths.iter()) {
+        let key: String = (0..*key_length)
+            .map(|_| char::from(rng.sample(Alphanumeric)))
+            .collect();
+        let value: String = (0..*value_length)
+            .map(|_| char::from(rng.sample(Alphanumeric)))
+            .collect();
+        pairs.push((key, value));
+    }
+
+    c.bench_function("lsm_write 100", |b| {
+        b.iter(|| {
+            for (key, value) in pairs.iter() {
+                lsm.set(key.clone(), value.clone()).unwrap();
+            }
+        });
+    });
+
+    // Pick 1000 random keys from the set of 100 generated keys
+    let random_keys = (0..1000).map(|_| {
+        let random_key = pairs.choose(&mut rng).unwrap().0.clone();
+        random_key
+    }).collect::<Vec<_>>();
+
+    c.bench_function("lsm_read 1000", |b| {
+        b.iter(|| {
+            for key in random_keys.iter() {
+                lsm.get(key.clone()).unwrap();
+            }
+        });
+    });
+}
+
+criterion_group!(benches, kvs_bench, sled_bench, lsm_bench);
 criterion_main!(benches);
@@ -1,6 +1,31 @@
 /// KVS CLI
-use clap::{App, AppSettings, Arg, SubCommand};
-use kvs::{KvStore, KvsEngine, Result};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use kvs::engine::{migrate, restore, sync_dir, StoreLock};
+use kvs::{
+    Durability, ExportFormat, KvStore, KvStoreOptions, KvsEngine, KvsIterator, LsmKvsEngine,
+    Result, SledKvsEngine,
+};
+
+// Exit code for a missing key, which is kept apart from the exit code of 1 for
+// errors so that scripts can tell the two apart
+const EXIT_NOT_FOUND: i32 = 2;
+
+// Engines that keep a store on disk, and can be migrated between
+const ENGINES: &[&str] = &["kvs", "sled", "lsm"];
+
+// Formats understood by `export` and `import`
+const FORMATS: &[&str] = &["jsonl", "csv"];
+
+// Where `migrate` builds the new store, and keeps the old one once they are
+// swapped
+const MIGRATE_NEW_DIR: &str = "migrate.new";
+const MIGRATE_OLD_DIR: &str = "migrate.old";
 
 fn main() -> Result<()> {
     let matches = App::new("KVS")
@@ -13,7 +38,13 @@ fn main() -> Result<()> {
             SubCommand::with_name("set")
                 .about("Set a key and value")
                 .arg(Arg::with_name("key"))
-                .arg(Arg::with_name("value")),
+                .arg(Arg::with_name("value"))
+                .arg(
+                    Arg::with_name("ttl")
+                        .long("ttl")
+                        .value_name("SECONDS")
+                        .help("Expire the key after this many seconds"),
+                ),
         )
         .subcommand(
             SubCommand::with_name("get")
@@ -25,6 +56,114 @@ fn main() -> Result<()> {
                 .about("Remove the the specified key")
                 .arg(Arg::with_name("key")),
         )
+        .subcommand(
+            SubCommand::with_name("scan")
+                .about("List keys and values in key order")
+                .arg(
+                    Arg::with_name("start")
+                        .long("start")
+                        .value_name("KEY")
+                        .help("First key to list"),
+                )
+                .arg(
+                    Arg::with_name("end")
+                        .long("end")
+                        .value_name("KEY")
+                        .help("Stop before this key"),
+                )
+                .arg(
+                    Arg::with_name("prefix")
+                        .long("prefix")
+                        .value_name("PREFIX")
+                        .conflicts_with_all(&["start", "end"])
+                        .help("Only list keys with this prefix"),
+                )
+                .arg(
+                    Arg::with_name("reverse")
+                        .long("reverse")
+                        .help("List keys in reverse order"),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .value_name("N")
+                        .help("Maximum number of keys to list"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Write every key and value out as text")
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .possible_values(FORMATS)
+                        .default_value("jsonl")
+                        .help("Format to write"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .value_name("FILE")
+                        .help("File to write to, instead of stdout"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Set every key and value in a file written by export")
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .possible_values(FORMATS)
+                        .default_value("jsonl")
+                        .help("Format to read"),
+                )
+                .arg(Arg::with_name("file").help("File to read from, instead of stdin")),
+        )
+        .subcommand(
+            SubCommand::with_name("upgrade")
+                .about("Upgrade a log written by an older version to the current format"),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("Move a store over to another engine")
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .value_name("ENGINE-NAME")
+                        .possible_values(ENGINES)
+                        .required(true)
+                        .help("Engine the store is in"),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .value_name("ENGINE-NAME")
+                        .possible_values(ENGINES)
+                        .required(true)
+                        .help("Engine to move the store to"),
+                )
+                .arg(
+                    Arg::with_name("dir")
+                        .required(true)
+                        .help("Directory of the store"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Check a backup and turn it back into a store")
+                .arg(
+                    Arg::with_name("backup")
+                        .required(true)
+                        .help("Directory of the backup"),
+                )
+                .arg(
+                    Arg::with_name("dir")
+                        .required(true)
+                        .help("Directory to restore the store to"),
+                ),
+        )
         .get_matches();
 
     // If version was requested, print it and return
@@ -33,35 +172,139 @@ fn main() -> Result<()> {
         return Ok(());
     }
 
-    let mut store = KvStore::open(std::env::current_dir()?)?;
+    let current_dir = std::env::current_dir()?;
+
+    if matches.subcommand_name() == Some("upgrade") {
+        let upgraded = KvStore::upgrade(&current_dir)?;
+
+        // Make sure the upgraded log opens cleanly
+        KvStoreOptions::new().read_only(true).open(&current_dir)?;
+
+        if upgraded == 0 {
+            println!("Already up to date");
+        } else {
+            println!("Upgraded {} segments", upgraded);
+        }
+        return Ok(());
+    }
+
+    if let ("migrate", Some(sub_match)) = matches.subcommand() {
+        let from = sub_match.value_of("from").unwrap();
+        let to = sub_match.value_of("to").unwrap();
+        let dir = Path::new(sub_match.value_of("dir").unwrap());
+
+        match migrate_store(dir, from, to) {
+            Ok(count) => {
+                println!("Migrated {} keys from {} to {}", count, from, to);
+                println!(
+                    "The old store was kept in {}",
+                    dir.join(MIGRATE_OLD_DIR).display()
+                );
+            }
+            Err(e) => {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
+    if let ("restore", Some(sub_match)) = matches.subcommand() {
+        let backup = sub_match.value_of("backup").unwrap();
+        let dir = sub_match.value_of("dir").unwrap();
+
+        match restore(backup, dir) {
+            Ok(manifest) => println!(
+                "Restored {} store from {} ({} files)",
+                manifest.engine,
+                backup,
+                manifest.files.len()
+            ),
+            Err(e) => {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
+    // Reads share the store with other readers, writes need it to themselves
+    let read_only = matches!(
+        matches.subcommand_name(),
+        Some("get") | Some("scan") | Some("export")
+    ) && KvStore::is_log_present(&current_dir);
+    let store = match KvStoreOptions::new().read_only(read_only).open(current_dir) {
+        Err(e @ kvs::Error::Locked { .. }) | Err(e @ kvs::Error::UnsupportedVersion { .. }) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+        res => res?,
+    };
 
     match matches.subcommand() {
         ("set", sub_match) => {
             let key = sub_match.unwrap().value_of("key").unwrap().to_owned();
             let value = sub_match.unwrap().value_of("value").unwrap().to_owned();
-            store.set(key, value)?;
+            match parse_ttl(sub_match.unwrap())? {
+                Some(ttl) => store.set_with_ttl(key, value, ttl)?,
+                None => store.set(key, value)?,
+            }
         }
         ("get", sub_match) => {
             let key = sub_match.unwrap().value_of("key").unwrap().to_owned();
-            match store.get(key)? {
-                None => println!("Key not found"),
-                Some(value) => println!("{}", value),
+            match store.get_bytes(key.into_bytes())? {
+                Some(value) => write_value(&value)?,
+                None => not_found(),
             }
         }
         ("rm", sub_match) => {
             let key = sub_match.unwrap().value_of("key").unwrap().to_owned();
             match store.remove(key) {
-                Err(kvs::Error::KeyNotFound) => {
-                    println!("Key not found");
-                    std::process::exit(1);
-                }
-                Err(e) => {
-                    // Abort on any other error
-                    return Err(e);
-                }
-                Ok(_) => (),
+                Ok(()) => (),
+                Err(kvs::Error::KeyNotFound) => not_found(),
+                Err(e) => return Err(e),
             }
         }
+        ("scan", sub_match) => {
+            let sub_match = sub_match.unwrap();
+            let iter = scan(&store, sub_match)?;
+            let iter: Box<dyn Iterator<Item = _>> = if sub_match.is_present("reverse") {
+                Box::new(iter.rev())
+            } else {
+                iter
+            };
+
+            let limit = match sub_match.value_of("limit") {
+                Some(limit) => limit
+                    .parse()
+                    .map_err(|_| format!("Invalid limit: {}", limit))?,
+                None => usize::MAX,
+            };
+
+            for entry in iter.take(limit) {
+                let (key, value) = entry?;
+                write_entry(&key, &value)?;
+            }
+        }
+        ("export", sub_match) => {
+            let sub_match = sub_match.unwrap();
+            let format: ExportFormat = sub_match.value_of("format").unwrap().parse()?;
+
+            match sub_match.value_of("output") {
+                Some(path) => store.export(BufWriter::new(File::create(path)?), format)?,
+                None => store.export(BufWriter::new(io::stdout().lock()), format)?,
+            };
+        }
+        ("import", sub_match) => {
+            let sub_match = sub_match.unwrap();
+            let format: ExportFormat = sub_match.value_of("format").unwrap().parse()?;
+
+            let imported = match sub_match.value_of("file") {
+                Some(path) => store.import(BufReader::new(File::open(path)?), format)?,
+                None => store.import(io::stdin().lock(), format)?,
+            };
+            println!("Imported {} keys", imported);
+        }
         (_, _) => {
             panic!("Unexpected subcommand");
         }
@@ -69,3 +312,181 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+// Copy the store in `dir` over to another engine, then swap the copy in
+//
+// The copy is built in a subdirectory and verified before the original store
+// is touched. The store stays locked throughout, and the original store is
+// kept in `migrate.old` afterwards, for the user to remove once they are happy
+// with the copy. If the swap itself is interrupted, the copy is left in
+// `migrate.new` as well.
+fn migrate_store(dir: &Path, from: &str, to: &str) -> Result<u64> {
+    if from == to {
+        return Err(format!("The store is already in engine {}", to).into());
+    }
+
+    // Keep everyone else out until the copy is in place, and the engine from
+    // changing under us in the meantime
+    let _lock = StoreLock::acquire(dir)?;
+
+    match current_engine(dir) {
+        Some(current) if current == from => {}
+        Some(current) => {
+            return Err(format!(
+                "Current engine {} does not match selected engine {}",
+                current, from
+            )
+            .into())
+        }
+        None => return Err(format!("No store found in {}", dir.display()).into()),
+    }
+
+    // A store kept by an earlier migration, or one whose swap was interrupted,
+    // is left for the user to deal with
+    let old_dir = dir.join(MIGRATE_OLD_DIR);
+    if old_dir.exists() {
+        return Err(format!(
+            "A previous store was kept in {}; remove it before migrating again",
+            old_dir.display()
+        )
+        .into());
+    }
+
+    // Start over from anything left by an earlier attempt that did not get to
+    // the swap
+    let new_dir = dir.join(MIGRATE_NEW_DIR);
+    if new_dir.exists() {
+        fs::remove_dir_all(&new_dir)?;
+    }
+    fs::create_dir(&new_dir)?;
+
+    // Both stores are closed again before the swap
+    let count = match from {
+        "kvs" => migrate_to(
+            &KvStoreOptions::new().read_only(true).open(dir)?,
+            &new_dir,
+            to,
+        )?,
+        "sled" => migrate_to(&SledKvsEngine::open(dir)?, &new_dir, to)?,
+        _ => migrate_to(&LsmKvsEngine::open(dir)?, &new_dir, to)?,
+    };
+    sync_dir(&new_dir)?;
+
+    fs::create_dir(&old_dir)?;
+    for path in store_files(dir, from)? {
+        fs::rename(&path, old_dir.join(path.file_name().unwrap()))?;
+    }
+    sync_dir(&old_dir)?;
+    for entry in fs::read_dir(&new_dir)? {
+        let path = entry?.path();
+        if path.file_name().is_some_and(|name| name != "LOCK") {
+            fs::rename(&path, dir.join(path.file_name().unwrap()))?;
+        }
+    }
+    sync_dir(dir)?;
+
+    // Only the lock file of the copy is left behind
+    fs::remove_dir_all(&new_dir)?;
+    sync_dir(dir)?;
+
+    Ok(count)
+}
+
+fn migrate_to<S: KvsEngine>(source: &S, dir: &Path, to: &str) -> Result<u64> {
+    // Every batch is synced, so the copy is safely on disk before the original
+    // store goes away
+    match to {
+        "kvs" => migrate(
+            source,
+            &KvStore::open_with_durability(dir, Durability::Sync)?,
+        ),
+        "sled" => migrate(source, &SledKvsEngine::open(dir)?),
+        _ => migrate(
+            source,
+            &LsmKvsEngine::open_with_durability(dir, Durability::Sync)?,
+        ),
+    }
+}
+
+// Figure out which engine the store in `dir` is in, the same way `kvs-server` does
+fn current_engine(dir: &Path) -> Option<&'static str> {
+    if KvStore::is_log_present(dir) {
+        Some("kvs")
+    } else if SledKvsEngine::is_log_present(dir) {
+        Some("sled")
+    } else if LsmKvsEngine::is_log_present(dir) {
+        Some("lsm")
+    } else {
+        None
+    }
+}
+
+// Files and directories in `dir` that belong to a store in the given engine
+fn store_files(dir: &Path, engine: &str) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy();
+
+        let owned = match engine {
+            "kvs" => name.starts_with("kvs-") || name == "kvs.log",
+            "sled" => name == "sled",
+            _ => name == "lsm",
+        };
+        if owned {
+            files.push(path);
+        }
+    }
+
+    Ok(files)
+}
+
+fn scan(store: &KvStore, matches: &ArgMatches) -> Result<KvsIterator> {
+    if let Some(prefix) = matches.value_of("prefix") {
+        return store.scan_prefix_bytes(prefix.as_bytes().to_vec());
+    }
+
+    let start = match matches.value_of("start") {
+        Some(start) => Bound::Included(start.as_bytes().to_vec()),
+        None => Bound::Unbounded,
+    };
+    let end = match matches.value_of("end") {
+        Some(end) => Bound::Excluded(end.as_bytes().to_vec()),
+        None => Bound::Unbounded,
+    };
+
+    store.scan_bytes((start, end))
+}
+
+// Only values go to stdout, so a missing key is reported on stderr
+fn not_found() -> ! {
+    eprintln!("Key not found");
+    std::process::exit(EXIT_NOT_FOUND);
+}
+
+// Values are written out as-is, as they are not necessarily UTF-8
+fn write_value(value: &[u8]) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(value)?;
+    stdout.write_all(b"\n")?;
+    Ok(())
+}
+
+fn write_entry(key: &[u8], value: &[u8]) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(key)?;
+    stdout.write_all(b"\t")?;
+    stdout.write_all(value)?;
+    stdout.write_all(b"\n")?;
+    Ok(())
+}
+
+fn parse_ttl(matches: &ArgMatches) -> Result<Option<Duration>> {
+    match matches.value_of("ttl") {
+        Some(ttl) => match ttl.parse() {
+            Ok(secs) => Ok(Some(Duration::from_secs(secs))),
+            Err(_) => Err(format!("Invalid TTL: {}", ttl).into()),
+        },
+        None => Ok(None),
+    }
+}
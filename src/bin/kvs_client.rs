@@ -1,10 +1,22 @@
 /// KVS Client
-use clap::{App, AppSettings, Arg, SubCommand};
+use std::io::{self, Write};
+use std::ops::Bound;
+use std::time::Duration;
 
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+use kvs::protocol::KeyRange;
 use kvs::{client::KvsClient, Error, Result};
 
 const DEFAULT_SERVER_ADDR: &str = "127.0.0.1:4000";
 
+// Exit code for a missing key, which is kept apart from the exit code of 1 for
+// errors so that scripts can tell the two apart
+const EXIT_NOT_FOUND: i32 = 2;
+
+// Number of entries requested per page by `scan`
+const PAGE_SIZE: u32 = 1000;
+
 fn main() -> Result<()> {
     env_logger::init();
 
@@ -20,6 +32,12 @@ fn main() -> Result<()> {
                 .about("Set a key and value")
                 .arg(Arg::with_name("key"))
                 .arg(Arg::with_name("value"))
+                .arg(
+                    Arg::with_name("ttl")
+                        .long("ttl")
+                        .value_name("SECONDS")
+                        .help("Expire the key after this many seconds"),
+                )
                 .arg(
                     Arg::with_name("addr")
                         .long("addr")
@@ -49,6 +67,61 @@ fn main() -> Result<()> {
                         .help("Server address"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("scan")
+                .about("List keys and values in key order")
+                .arg(
+                    Arg::with_name("start")
+                        .long("start")
+                        .value_name("KEY")
+                        .help("First key to list"),
+                )
+                .arg(
+                    Arg::with_name("end")
+                        .long("end")
+                        .value_name("KEY")
+                        .help("Stop before this key"),
+                )
+                .arg(
+                    Arg::with_name("prefix")
+                        .long("prefix")
+                        .value_name("PREFIX")
+                        .conflicts_with_all(&["start", "end"])
+                        .help("Only list keys with this prefix"),
+                )
+                .arg(
+                    Arg::with_name("reverse")
+                        .long("reverse")
+                        .help("List keys in reverse order"),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .value_name("N")
+                        .help("Maximum number of keys to list"),
+                )
+                .arg(
+                    Arg::with_name("addr")
+                        .long("addr")
+                        .value_name("IP-PORT")
+                        .help("Server address"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("backup")
+                .about("Have the server back up its store")
+                .arg(
+                    Arg::with_name("path")
+                        .required(true)
+                        .help("Directory to write the backup to, below the server's --backup-dir"),
+                )
+                .arg(
+                    Arg::with_name("addr")
+                        .long("addr")
+                        .value_name("IP-PORT")
+                        .help("Server address"),
+                ),
+        )
         .get_matches();
 
     // If version was requested, print it and return
@@ -66,7 +139,10 @@ fn main() -> Result<()> {
             let mut client = KvsClient::connect(addr)?;
             let key = sub_match.unwrap().value_of("key").unwrap().to_owned();
             let value = sub_match.unwrap().value_of("value").unwrap().to_owned();
-            client.set(key, value)?;
+            match parse_ttl(sub_match.unwrap())? {
+                Some(ttl) => client.set_with_ttl(key, value, ttl)?,
+                None => client.set(key, value)?,
+            }
         }
         ("get", sub_match) => {
             let addr = sub_match
@@ -75,21 +151,9 @@ fn main() -> Result<()> {
                 .unwrap_or(DEFAULT_SERVER_ADDR);
             let mut client = KvsClient::connect(addr)?;
             let key = sub_match.unwrap().value_of("key").unwrap().to_owned();
-            let value = client.get(key);
-
-            match value {
-                Ok(value) => {
-                    if let Some(value) = value {
-                        println!("{}", value);
-                    } else {
-                        println!("Key not found");
-                    }
-                }
-                Err(Error::KeyNotFound) => {
-                    println!("Key not found");
-                    std::process::exit(1);
-                }
-                Err(e) => return Err(e),
+            match client.get_bytes(key.into_bytes())? {
+                Some(value) => write_value(&value)?,
+                None => not_found(),
             }
         }
         ("rm", sub_match) => {
@@ -100,15 +164,48 @@ fn main() -> Result<()> {
             let mut client = KvsClient::connect(addr)?;
             let key = sub_match.unwrap().value_of("key").unwrap().to_owned();
             match client.remove(key) {
-                Ok(_) => (),
-                Err(Error::KeyNotFound) => {
-                    // NOTE: tests check for stderr
-                    eprintln!("Key not found");
-                    std::process::exit(1);
-                }
+                Ok(()) => (),
+                Err(Error::KeyNotFound) => not_found(),
                 Err(e) => return Err(e),
             }
         }
+        ("scan", sub_match) => {
+            let sub_match = sub_match.unwrap();
+            let addr = sub_match.value_of("addr").unwrap_or(DEFAULT_SERVER_ADDR);
+            let mut client = KvsClient::connect(addr)?;
+
+            let range = key_range(sub_match);
+            let reverse = sub_match.is_present("reverse");
+            let mut remaining = match sub_match.value_of("limit") {
+                Some(limit) => limit
+                    .parse()
+                    .map_err(|_| format!("Invalid limit: {}", limit))?,
+                None => usize::MAX,
+            };
+
+            // Fetch one page at a time, so large scans are streamed to stdout
+            let mut cursor = None;
+            while remaining > 0 {
+                let limit = remaining.min(PAGE_SIZE as usize) as u32;
+                let (entries, next) = client.scan_page(range.clone(), reverse, limit, cursor)?;
+
+                for (key, value) in entries.iter().take(remaining) {
+                    write_entry(key, value)?;
+                }
+                remaining = remaining.saturating_sub(entries.len());
+
+                match next {
+                    Some(next) => cursor = Some(next),
+                    None => break,
+                }
+            }
+        }
+        ("backup", sub_match) => {
+            let sub_match = sub_match.unwrap();
+            let addr = sub_match.value_of("addr").unwrap_or(DEFAULT_SERVER_ADDR);
+            let mut client = KvsClient::connect(addr)?;
+            client.backup(sub_match.value_of("path").unwrap().to_owned())?;
+        }
         (s, _) => {
             panic!("Unexpected subcommand: \"{}\"", s);
         }
@@ -116,3 +213,53 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+fn key_range(matches: &ArgMatches) -> KeyRange {
+    if let Some(prefix) = matches.value_of("prefix") {
+        return KeyRange::Prefix(prefix.as_bytes().to_vec());
+    }
+
+    let start = match matches.value_of("start") {
+        Some(start) => Bound::Included(start.as_bytes().to_vec()),
+        None => Bound::Unbounded,
+    };
+    let end = match matches.value_of("end") {
+        Some(end) => Bound::Excluded(end.as_bytes().to_vec()),
+        None => Bound::Unbounded,
+    };
+
+    KeyRange::Range(start, end)
+}
+
+// Only values go to stdout, so a missing key is reported on stderr
+fn not_found() -> ! {
+    eprintln!("Key not found");
+    std::process::exit(EXIT_NOT_FOUND);
+}
+
+// Values are written out as-is, as they are not necessarily UTF-8
+fn write_value(value: &[u8]) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(value)?;
+    stdout.write_all(b"\n")?;
+    Ok(())
+}
+
+fn write_entry(key: &[u8], value: &[u8]) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(key)?;
+    stdout.write_all(b"\t")?;
+    stdout.write_all(value)?;
+    stdout.write_all(b"\n")?;
+    Ok(())
+}
+
+fn parse_ttl(matches: &ArgMatches) -> Result<Option<Duration>> {
+    match matches.value_of("ttl") {
+        Some(ttl) => match ttl.parse() {
+            Ok(secs) => Ok(Some(Duration::from_secs(secs))),
+            Err(_) => Err(format!("Invalid TTL: {}", ttl).into()),
+        },
+        None => Ok(None),
+    }
+}
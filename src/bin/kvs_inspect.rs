@@ -0,0 +1,267 @@
+/// Tool to look inside, verify and repair the log of a kvs store
+use std::path::Path;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use kvs::engine::kvs::{LogCommand, SegmentReader, SegmentRepair};
+use kvs::{KvStore, Result};
+
+// Number of most written keys listed by `verify`
+const TOP_KEYS: usize = 10;
+
+fn main() {
+    let dir_arg = || {
+        Arg::with_name("dir")
+            .default_value(".")
+            .help("Directory of the store")
+    };
+
+    let matches = App::new("kvs-inspect")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about("Look inside, verify and repair the log of a kvs store")
+        .subcommand(
+            SubCommand::with_name("dump")
+                .about("Print every record of the log")
+                .arg(dir_arg())
+                .arg(
+                    Arg::with_name("segment")
+                        .long("segment")
+                        .value_name("GEN")
+                        .help("Only print the segment with this generation"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Check that every record can be read, and count live and dead bytes")
+                .arg(dir_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("truncate")
+                .about("Cut every damaged segment short at its first damaged record")
+                .arg(dir_arg())
+                .arg(
+                    Arg::with_name("allow-stale")
+                        .long("allow-stale")
+                        .help("Cut off damage even if keys may get their old values back"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("salvage")
+                .about("Copy every intact record to a new store, skipping damaged ones")
+                .arg(
+                    Arg::with_name("dir")
+                        .required(true)
+                        .help("Directory of the store"),
+                )
+                .arg(
+                    Arg::with_name("dest")
+                        .required(true)
+                        .help("Directory to write the new store to"),
+                )
+                .arg(
+                    Arg::with_name("allow-stale")
+                        .long("allow-stale")
+                        .help("Skip damage even if keys it wrote may get their old values back"),
+                ),
+        )
+        .get_matches();
+
+    let res = match matches.subcommand() {
+        ("dump", Some(sub_match)) => dump(sub_match),
+        ("verify", Some(sub_match)) => verify(sub_match),
+        ("truncate", Some(sub_match)) => truncate(sub_match),
+        ("salvage", Some(sub_match)) => salvage(sub_match),
+        _ => unreachable!(),
+    };
+
+    match res {
+        Ok(true) => {}
+        // Damage was found
+        Ok(false) => std::process::exit(1),
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Each command returns whether the log is intact, or was made so
+
+fn dump(matches: &ArgMatches) -> Result<bool> {
+    let dir = Path::new(matches.value_of("dir").unwrap());
+    let only = match matches.value_of("segment") {
+        Some(gen) => Some(
+            gen.parse::<u64>()
+                .map_err(|_| format!("Invalid generation: {}", gen))?,
+        ),
+        None => None,
+    };
+
+    let mut intact = true;
+    for (gen, path) in KvStore::segments(dir)? {
+        if only.is_some_and(|only| only != gen) {
+            continue;
+        }
+
+        let mut reader = SegmentReader::open(&path)?;
+        print!("{}: version {}", path.display(), reader.version());
+        if let (Some(created_at), Some(created_by)) = (reader.created_at(), reader.created_by()) {
+            print!(", created at {} by kvs {}", created_at, created_by);
+        }
+        println!(", {} bytes", reader.size());
+        println!("{:>10}  {:>8}  command", "offset", "size");
+
+        while let Some(res) = reader.next() {
+            match res {
+                Ok(record) => {
+                    print!("{:>10}  {:>8}  ", record.offset, record.size);
+                    print_command(&record.command, 0);
+                }
+                Err(damage) => {
+                    intact = false;
+                    println!("{:>10}  {}", damage.offset, damage);
+
+                    let end = reader.skip_damage().unwrap_or_else(|| reader.size());
+                    println!(
+                        "{:>10}  skipped {} bytes",
+                        damage.offset,
+                        end - damage.offset
+                    );
+                }
+            }
+        }
+        println!();
+    }
+
+    Ok(intact)
+}
+
+fn print_command(command: &LogCommand, depth: usize) {
+    let indent = "  ".repeat(depth);
+    match command {
+        LogCommand::Get { key } => println!("{}get {}", indent, key.escape_ascii()),
+        LogCommand::Set {
+            key,
+            value_size,
+            expires_at,
+        } => {
+            print!(
+                "{}set {} ({} bytes)",
+                indent,
+                key.escape_ascii(),
+                value_size
+            );
+            match expires_at {
+                Some(expires_at) => println!(", expires at {}", expires_at),
+                None => println!(),
+            }
+        }
+        LogCommand::Remove { key } => println!("{}rm {}", indent, key.escape_ascii()),
+        LogCommand::Batch(commands) => {
+            println!("{}batch of {}", indent, commands.len());
+            for command in commands {
+                print!("{:>22}", "");
+                print_command(command, depth + 1);
+            }
+        }
+    }
+}
+
+fn verify(matches: &ArgMatches) -> Result<bool> {
+    let dir = Path::new(matches.value_of("dir").unwrap());
+    let report = KvStore::inspect(dir)?;
+
+    let (mut live, mut dead) = (0, 0);
+    for segment in &report.segments {
+        println!(
+            "{}: {} records, {} live bytes, {} dead bytes",
+            segment.path.display(),
+            segment.records,
+            segment.live_bytes,
+            segment.dead_bytes
+        );
+        if let Some(damage) = &segment.damage {
+            if damage.torn {
+                println!("  {} (truncated when the store is next opened)", damage);
+            } else {
+                println!("  {}", damage);
+            }
+        }
+
+        live += segment.live_bytes;
+        dead += segment.dead_bytes;
+    }
+
+    println!(
+        "{} segments, {} live keys, {} live bytes, {} dead bytes",
+        report.segments.len(),
+        report.live_keys,
+        live,
+        dead
+    );
+    println!(
+        "{} keys written more than once",
+        report.duplicate_keys.len()
+    );
+
+    let mut duplicates: Vec<_> = report.duplicate_keys.iter().collect();
+    duplicates.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+    for (key, writes) in duplicates.into_iter().take(TOP_KEYS) {
+        println!("  {} ({} writes)", key.escape_ascii(), writes);
+    }
+
+    if report.is_damaged() {
+        println!("Log is damaged");
+        return Ok(false);
+    }
+    println!("Log is intact");
+
+    Ok(true)
+}
+
+fn truncate(matches: &ArgMatches) -> Result<bool> {
+    let dir = matches.value_of("dir").unwrap();
+    let allow_stale = matches.is_present("allow-stale");
+
+    let repairs = KvStore::truncate_damaged(dir, allow_stale)?;
+    if repairs.is_empty() {
+        println!("Nothing to truncate");
+    }
+    for repair in &repairs {
+        println!(
+            "Truncated {} at offset {}, dropping {} bytes",
+            repair.path.display(),
+            repair.damage[0].offset,
+            repair.dropped
+        );
+    }
+
+    Ok(true)
+}
+
+fn salvage(matches: &ArgMatches) -> Result<bool> {
+    let dir = matches.value_of("dir").unwrap();
+    let dest = matches.value_of("dest").unwrap();
+
+    let allow_stale = matches.is_present("allow-stale");
+
+    let repairs = KvStore::salvage(dir, dest, allow_stale)?;
+    for SegmentRepair {
+        path,
+        damage,
+        dropped,
+        ..
+    } in &repairs
+    {
+        println!(
+            "Skipped {} damaged regions ({} bytes) in {}",
+            damage.len(),
+            dropped,
+            path.display()
+        );
+    }
+    println!("Salvaged log to {}", dest);
+
+    Ok(true)
+}
@@ -1,8 +1,28 @@
 /// KVS Server
-use clap::{App, AppSettings, Arg};
+use std::str::FromStr;
 
-use kvs::engine::{KvsEngine, SledKvsEngine};
-use kvs::{server::KvsServer, KvStore, Result};
+use clap::{App, AppSettings, Arg, ArgMatches};
+
+use kvs::engine::{Durability, KvsEngine, LsmKvsEngine, MemoryKvsEngine, SledKvsEngine, StoreLock};
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{server::KvsServer, KvStore, KvStoreOptions, Result};
+
+// Options that only apply to the kvs engine
+const KVS_OPTIONS: &[&str] = &[
+    "compaction-threshold",
+    "compaction-ratio",
+    "max-segment-size",
+    "write-buffer-size",
+    "read-buffer-size",
+    "max-key-size",
+    "max-value-size",
+    "read-only",
+    "no-create",
+    "error-if-exists",
+];
+
+// Options that only apply to the memory engine
+const MEMORY_OPTIONS: &[&str] = &["snapshot"];
 
 fn main() -> Result<()> {
     env_logger::builder()
@@ -27,9 +47,102 @@ fn main() -> Result<()> {
             Arg::with_name("engine")
                 .long("engine")
                 .value_name("ENGINE-NAME")
-                .possible_values(&["kvs", "sled"])
+                .possible_values(&["kvs", "sled", "lsm", "memory"])
                 .help("KV engine name"),
         )
+        .arg(
+            Arg::with_name("durability")
+                .long("durability")
+                .value_name("POLICY")
+                .help(
+                    "When writes are synced to disk: none, sync, group-commit or periodic:<ms> \
+                     (defaults to none for kvs and lsm, and sync for sled)",
+                ),
+        )
+        .arg(
+            Arg::with_name("compaction-threshold")
+                .long("compaction-threshold")
+                .value_name("BYTES")
+                .help("Stale bytes in the log before it is compacted (kvs only)"),
+        )
+        .arg(
+            Arg::with_name("compaction-ratio")
+                .long("compaction-ratio")
+                .value_name("RATIO")
+                .help("Smallest stale share of the log, from 0.0 to 1.0, before it is compacted (kvs only)"),
+        )
+        .arg(
+            Arg::with_name("max-segment-size")
+                .long("max-segment-size")
+                .value_name("BYTES")
+                .help("Size at which the log rolls over to a new segment (kvs only)"),
+        )
+        .arg(
+            Arg::with_name("write-buffer-size")
+                .long("write-buffer-size")
+                .value_name("BYTES")
+                .help("Size of the buffer used to append to the log (kvs only)"),
+        )
+        .arg(
+            Arg::with_name("read-buffer-size")
+                .long("read-buffer-size")
+                .value_name("BYTES")
+                .help("Size of the buffer used to read through the log (kvs only)"),
+        )
+        .arg(
+            Arg::with_name("max-key-size")
+                .long("max-key-size")
+                .value_name("BYTES")
+                .help("Largest key that can be written (kvs only)"),
+        )
+        .arg(
+            Arg::with_name("max-value-size")
+                .long("max-value-size")
+                .value_name("BYTES")
+                .help("Largest value that can be written (kvs only)"),
+        )
+        .arg(
+            Arg::with_name("read-only")
+                .long("read-only")
+                .help("Serve an existing store without writing to it (kvs only)"),
+        )
+        .arg(
+            Arg::with_name("no-create")
+                .long("no-create")
+                .help("Fail if there is no store yet (kvs only)"),
+        )
+        .arg(
+            Arg::with_name("error-if-exists")
+                .long("error-if-exists")
+                .conflicts_with("no-create")
+                .help("Fail if there already is a store (kvs only)"),
+        )
+        .arg(
+            Arg::with_name("snapshot")
+                .long("snapshot")
+                .value_name("FILE")
+                .help("Load the store from this file, and save it there on shutdown (memory only)"),
+        )
+        .arg(
+            Arg::with_name("backup-dir")
+                .long("backup-dir")
+                .value_name("DIR")
+                .help("Directory that clients can have backups written to; backups are refused without it"),
+        )
+        .arg(
+            Arg::with_name("thread-pool")
+                .long("thread-pool")
+                .value_name("POOL-NAME")
+                .possible_values(&["naive", "shared-queue", "rayon"])
+                .default_value("shared-queue")
+                .help("Thread pool used to handle connections"),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .value_name("N")
+                .help("Number of threads in the pool (defaults to the number of CPUs)"),
+        )
         .get_matches();
 
     // If version was requested, print it and return
@@ -42,12 +155,30 @@ fn main() -> Result<()> {
 
     let current_dir = std::env::current_dir()?;
 
+    // Keep other processes out of the directory from before the engine is
+    // detected, so that it cannot change under us, e.g. by a migration. A
+    // read-only store is shared, and the memory engine does not use it.
+    let shared = matches.value_of("engine") == Some("memory") || matches.is_present("read-only");
+    let _lock = if shared {
+        None
+    } else {
+        match StoreLock::acquire(&current_dir) {
+            Err(e @ kvs::Error::Locked { .. }) => {
+                println!("{}", e);
+                std::process::exit(1)
+            }
+            res => Some(res?),
+        }
+    };
+
     // Figure out which engine is currently in used based on presence of relevant
     // log in the current directory
     let current_engine = if KvStore::is_log_present(&current_dir) {
         Some("kvs")
     } else if SledKvsEngine::is_log_present(&current_dir) {
         Some("sled")
+    } else if LsmKvsEngine::is_log_present(&current_dir) {
+        Some("lsm")
     } else {
         None
     };
@@ -60,9 +191,10 @@ fn main() -> Result<()> {
         Some(engine) => engine,
     };
 
-    // If the user provided an engine that does not match current engine, error out
+    // If the user provided an engine that does not match current engine, error
+    // out. The memory engine does not use the directory, so it never conflicts.
     if let Some(curr) = current_engine {
-        if curr != engine {
+        if curr != engine && engine != "memory" {
             println!(
                 "Current engine {} does not match selected engine {}",
                 curr, engine
@@ -73,25 +205,174 @@ fn main() -> Result<()> {
 
     log::info!("Engine: {}", engine);
 
-    // Setup the appropriate engine
-    let engine: Box<dyn KvsEngine> = match engine {
-        "kvs" => {
-            let engine = KvStore::open(current_dir)?;
-            Box::new(engine)
-        }
-        "sled" => {
-            let engine = SledKvsEngine::open(current_dir)?;
-            Box::new(engine)
-        }
-        _ => panic!("Unexpected engine!"),
+    // Nothing is synced to disk by the memory engine, so durability does not apply
+    let unsupported = match engine {
+        "kvs" => MEMORY_OPTIONS.to_vec(),
+        "sled" | "lsm" => [KVS_OPTIONS, MEMORY_OPTIONS].concat(),
+        _ => [KVS_OPTIONS, &["durability"]].concat(),
     };
+    if let Some(name) = unsupported.iter().find(|name| matches.is_present(name)) {
+        println!("--{} is not supported by engine {}", name, engine);
+        std::process::exit(1)
+    }
 
     let addr = matches.value_of("addr").unwrap();
 
     log::info!("Address: {}", addr);
 
-    let mut server = KvsServer::new(engine, addr.to_string())?;
-    server.start()?;
+    let pool = matches.value_of("thread-pool").unwrap();
+    let threads = match matches.value_of("threads") {
+        Some(threads) => threads
+            .parse::<u32>()
+            .ok()
+            .filter(|&n| n > 0)
+            .ok_or_else(|| format!("Invalid number of threads: {}", threads))?,
+        None => std::thread::available_parallelism()
+            .map(|n| n.get() as u32)
+            .unwrap_or(1),
+    };
+
+    log::info!("Thread pool: {} ({} threads)", pool, threads);
+
+    let backup_dir = matches.value_of("backup-dir");
+    if let Some(dir) = backup_dir {
+        log::info!("Backup directory: {}", dir);
+    }
+
+    let durability = match matches.value_of("durability") {
+        Some(durability) => durability.parse()?,
+        None if engine == "sled" => Durability::Sync,
+        None => Durability::None,
+    };
+
+    if engine != "memory" {
+        log::info!("Durability: {}", durability);
+    }
+
+    // Setup the appropriate engine
+    let res = match engine {
+        "kvs" => kvs_options(&matches, durability)?
+            .open(current_dir)
+            .and_then(|store| run_with_pool(store, pool, threads, addr, backup_dir)),
+        "sled" => SledKvsEngine::open_with_durability(current_dir, durability)
+            .and_then(|engine| run_with_pool(engine, pool, threads, addr, backup_dir)),
+        "lsm" => LsmKvsEngine::open_with_durability(current_dir, durability)
+            .and_then(|engine| run_with_pool(engine, pool, threads, addr, backup_dir)),
+        "memory" => memory_engine(&matches)
+            .and_then(|engine| run_with_pool(engine, pool, threads, addr, backup_dir)),
+        _ => panic!("Unexpected engine!"),
+    };
+
+    // Another process is using the store, most likely another server, or the
+    // store needs to be upgraded first
+    if let Err(e @ kvs::Error::Locked { .. }) | Err(e @ kvs::Error::UnsupportedVersion { .. }) = res
+    {
+        println!("{}", e);
+        std::process::exit(1)
+    }
+
+    res
+}
+
+fn kvs_options(matches: &ArgMatches, durability: Durability) -> Result<KvStoreOptions> {
+    let mut options = KvStoreOptions::new();
+    options
+        .durability(durability)
+        .read_only(matches.is_present("read-only"))
+        .create_if_missing(!matches.is_present("no-create"))
+        .error_if_exists(matches.is_present("error-if-exists"));
+
+    if let Some(bytes) = parse_arg(matches, "compaction-threshold")? {
+        options.compaction_threshold(bytes);
+    }
+    if let Some(ratio) = parse_arg(matches, "compaction-ratio")? {
+        options.compaction_ratio(ratio);
+    }
+    if let Some(bytes) = parse_arg(matches, "max-segment-size")? {
+        options.max_segment_size(bytes);
+    }
+    if let Some(bytes) = parse_arg(matches, "write-buffer-size")? {
+        options.write_buffer_size(bytes);
+    }
+    if let Some(bytes) = parse_arg(matches, "read-buffer-size")? {
+        options.read_buffer_size(bytes);
+    }
+    if let Some(bytes) = parse_arg(matches, "max-key-size")? {
+        options.max_key_size(bytes);
+    }
+    if let Some(bytes) = parse_arg(matches, "max-value-size")? {
+        options.max_value_size(bytes);
+    }
+
+    Ok(options)
+}
+
+fn memory_engine(matches: &ArgMatches) -> Result<MemoryKvsEngine> {
+    let path = match matches.value_of("snapshot") {
+        Some(path) => path,
+        None => return Ok(MemoryKvsEngine::new()),
+    };
+
+    log::info!("Snapshot: {}", path);
+
+    let engine = MemoryKvsEngine::with_snapshot(path)?;
 
-    Ok(())
+    // The server never returns, so the snapshot is saved when it is told to stop
+    let handle = engine.clone();
+    ctrlc::set_handler(move || {
+        let code = match handle.snapshot() {
+            Ok(()) => 0,
+            Err(e) => {
+                log::error!("Saving the snapshot failed: {}", e);
+                1
+            }
+        };
+        std::process::exit(code);
+    })
+    .map_err(|e| format!("Failed to set up the shutdown handler: {}", e))?;
+
+    Ok(engine)
+}
+
+fn parse_arg<T: FromStr>(matches: &ArgMatches, name: &str) -> Result<Option<T>> {
+    match matches.value_of(name) {
+        Some(value) => match value.parse() {
+            Ok(value) => Ok(Some(value)),
+            Err(_) => Err(format!("Invalid value for --{}: {}", name, value).into()),
+        },
+        None => Ok(None),
+    }
+}
+
+fn run_with_pool<E: KvsEngine>(
+    engine: E,
+    pool: &str,
+    threads: u32,
+    addr: &str,
+    backup_dir: Option<&str>,
+) -> Result<()> {
+    match pool {
+        "naive" => run(engine, NaiveThreadPool::new(threads)?, addr, backup_dir),
+        "shared-queue" => run(
+            engine,
+            SharedQueueThreadPool::new(threads)?,
+            addr,
+            backup_dir,
+        ),
+        "rayon" => run(engine, RayonThreadPool::new(threads)?, addr, backup_dir),
+        _ => panic!("Unexpected thread pool!"),
+    }
+}
+
+fn run<E: KvsEngine, P: ThreadPool>(
+    engine: E,
+    pool: P,
+    addr: &str,
+    backup_dir: Option<&str>,
+) -> Result<()> {
+    let mut server = KvsServer::new(engine, pool, addr.to_string())?;
+    if let Some(dir) = backup_dir {
+        server.backup_dir(dir);
+    }
+    server.start()
 }
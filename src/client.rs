@@ -1,68 +1,336 @@
-use std::io::Write;
-use std::net::TcpStream;
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{Shutdown, TcpStream};
+use std::thread;
+use std::time::Duration;
 
-use crate::{
-    server::{Request, Response},
-    Result,
+use crate::engine::into_string_result;
+use crate::protocol::{
+    read_message, write_message, Hello, HelloResponse, KeyRange, Request, Response,
 };
+use crate::{ConditionFailed, ConditionalResult, Error, Result, WriteBatch};
 
+/// Entries in a page of a scan, along with the cursor for the next page
+pub type ScanPage = (Vec<(Vec<u8>, Vec<u8>)>, Option<Vec<u8>>);
+
+/// Client for a `KvsServer`
+///
+/// The connection is kept open, so a single client can issue any number of
+/// requests.
 pub struct KvsClient {
-    socket: TcpStream,
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+
+    // Protocol version agreed on with the server
+    version: u32,
+
+    // Features supported by the server
+    capabilities: Vec<String>,
 }
 
 impl KvsClient {
     pub fn connect(addr: &str) -> Result<Self> {
         let socket = TcpStream::connect(addr)?;
-        Ok(Self { socket })
+        let mut reader = BufReader::new(socket.try_clone()?);
+        let mut writer = BufWriter::new(socket);
+
+        write_message(&mut writer, &Hello::new())?;
+        writer.flush()?;
+
+        match read_message(&mut reader)? {
+            HelloResponse::Accepted {
+                version,
+                capabilities,
+                ..
+            } => {
+                log::info!("Connected to {} with protocol version {}", addr, version);
+
+                Ok(Self {
+                    reader,
+                    writer,
+                    version,
+                    capabilities,
+                })
+            }
+            HelloResponse::Rejected(msg) => Err(Error::Protocol(msg)),
+        }
     }
 
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        log::info!("Sending get: {}", key);
+    /// Protocol version agreed on with the server
+    pub fn protocol_version(&self) -> u32 {
+        self.version
+    }
 
-        let req = Request::Get(key.clone());
-        let buf = rmp_serde::to_vec(&req)?;
-        self.socket.write_all(&buf)?;
+    /// Returns `true` if the server advertised the given capability
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
 
-        log::info!("Waiting for value: {}", key);
+    // Fail before sending a request the server did not say it supports
+    fn require(&self, capability: &str) -> Result<()> {
+        if !self.has_capability(capability) {
+            return Err(Error::Unsupported {
+                capability: capability.to_owned(),
+            });
+        }
+        Ok(())
+    }
 
-        let resp: Response = rmp_serde::from_read(&self.socket)?;
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        log::info!("Sending get: {}", String::from_utf8_lossy(&key));
 
-        match resp {
-            Response::Value(v) => Ok(Some(v)),
-            Response::Ok => Ok(None),
+        match self.request(&Request::Get(key))? {
+            Response::Found(v) => Ok(Some(v)),
+            Response::NotFound => Ok(None),
             Response::Error(e) => Err(e),
+            r => Err(unexpected(r)),
         }
     }
 
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        log::info!("Sending set: {}, {}", key, value);
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        log::info!("Sending set: {}", String::from_utf8_lossy(&key));
 
-        let req = Request::Set(key, value);
-        let buf = rmp_serde::to_vec(&req)?;
-        self.socket.write_all(&buf)?;
+        match self.request(&Request::Set(key, value))? {
+            Response::Stored => Ok(()),
+            Response::Error(e) => Err(e),
+            r => Err(unexpected(r)),
+        }
+    }
 
-        let resp: Response = rmp_serde::from_read(&self.socket)?;
+    /// Set a key that expires once `ttl` has passed
+    pub fn set_bytes_with_ttl(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.require("ttl")?;
+        log::info!(
+            "Sending set: {} (ttl = {:?})",
+            String::from_utf8_lossy(&key),
+            ttl
+        );
 
-        match resp {
-            Response::Ok => Ok(()),
+        let ttl = ttl.as_millis() as u64;
+        match self.request(&Request::SetWithTtl(key, value, ttl))? {
+            Response::Stored => Ok(()),
             Response::Error(e) => Err(e),
-            _ => panic!("not expected"),
+            r => Err(unexpected(r)),
         }
     }
 
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        log::info!("Sending remove: {}", String::from_utf8_lossy(&key));
+
+        match self.request(&Request::Remove(key))? {
+            Response::Deleted => Ok(()),
+            Response::NotFound => Err(Error::KeyNotFound),
+            Response::Error(e) => Err(e),
+            r => Err(unexpected(r)),
+        }
+    }
+
+    /// Apply every write in the batch atomically
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.require("batch")?;
+        log::info!("Sending batch of {} writes", batch.len());
+
+        match self.request(&Request::Batch(batch))? {
+            Response::Stored => Ok(()),
+            Response::Error(e) => Err(e),
+            r => Err(unexpected(r)),
+        }
+    }
+
+    /// Replace the value of a key if it currently has the expected one
+    ///
+    /// `None` stands for a missing key, on either side.
+    pub fn compare_and_swap_bytes(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<ConditionalResult> {
+        self.require("cas")?;
+        log::info!(
+            "Sending compare and swap: {}",
+            String::from_utf8_lossy(&key)
+        );
+
+        match self.request(&Request::CompareAndSwap { key, expected, new })? {
+            Response::Swapped => Ok(Ok(())),
+            Response::Conflict { current } => Ok(Err(ConditionFailed { current })),
+            Response::Error(e) => Err(e),
+            r => Err(unexpected(r)),
+        }
+    }
+
+    /// Set a key, unless it already exists
+    pub fn set_if_absent_bytes(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Result<ConditionalResult> {
+        self.compare_and_swap_bytes(key, None, Some(value))
+    }
+
+    /// Have the server write a backup of its store to `path`
+    ///
+    /// The path is relative to the server's backup directory, and must be
+    /// missing or empty.
+    pub fn backup(&mut self, path: String) -> Result<()> {
+        self.require("backup")?;
+        log::info!("Sending backup: {}", path);
+
+        match self.request(&Request::Backup(path))? {
+            Response::BackedUp => Ok(()),
+            Response::Error(e) => Err(e),
+            r => Err(unexpected(r)),
+        }
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+    }
+
     pub fn remove(&mut self, key: String) -> Result<()> {
-        log::info!("Sending remove: {}", key);
+        self.remove_bytes(key.into_bytes())
+    }
 
-        let req = Request::Remove(key);
-        let buf = rmp_serde::to_vec(&req)?;
-        self.socket.write_all(&buf)?;
+    pub fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<ConditionalResult<String>> {
+        let res = self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )?;
 
-        let resp: Response = rmp_serde::from_read(&self.socket)?;
+        into_string_result(res)
+    }
+
+    pub fn set_if_absent(
+        &mut self,
+        key: String,
+        value: String,
+    ) -> Result<ConditionalResult<String>> {
+        into_string_result(self.set_if_absent_bytes(key.into_bytes(), value.into_bytes())?)
+    }
 
-        match resp {
-            Response::Ok => Ok(()),
+    /// Fetch a single page of a scan
+    ///
+    /// Returns the entries along with the cursor for the next page, if there is one.
+    pub fn scan_page(
+        &mut self,
+        range: KeyRange,
+        reverse: bool,
+        limit: u32,
+        cursor: Option<Vec<u8>>,
+    ) -> Result<ScanPage> {
+        self.require("scan")?;
+        log::info!("Sending scan: {:?} (cursor = {:?})", range, cursor);
+
+        let request = Request::Scan {
+            range,
+            reverse,
+            limit,
+            cursor,
+        };
+
+        match self.request(&request)? {
+            Response::Page { entries, cursor } => Ok((entries, cursor)),
             Response::Error(e) => Err(e),
-            _ => panic!("not expected"),
+            r => Err(unexpected(r)),
+        }
+    }
+
+    /// Fetch every key-value pair in the range, one page at a time
+    pub fn scan(&mut self, range: KeyRange, reverse: bool) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        const PAGE_SIZE: u32 = 1000;
+
+        let mut entries = Vec::new();
+        let mut cursor = None;
+
+        loop {
+            let (page, next) = self.scan_page(range.clone(), reverse, PAGE_SIZE, cursor)?;
+            entries.extend(page);
+
+            match next {
+                Some(next) => cursor = Some(next),
+                None => return Ok(entries),
+            }
         }
     }
+
+    /// Send several requests without waiting for each response in turn
+    ///
+    /// Responses are returned in the same order as the requests. Nothing is sent
+    /// if the server does not support one of the requests.
+    pub fn pipeline(&mut self, requests: &[Request]) -> Result<Vec<Response>> {
+        log::info!("Sending {} pipelined requests", requests.len());
+
+        self.require("pipelining")?;
+        for request in requests {
+            if let Some(capability) = request.capability() {
+                self.require(capability)?;
+            }
+        }
+
+        // Shutting down the socket unblocks the sender if we stop reading
+        let socket = self.writer.get_ref().try_clone()?;
+        let Self { reader, writer, .. } = self;
+
+        // Requests are written from another thread, as the server could otherwise
+        // stop reading once we stop reading its responses
+        thread::scope(|s| {
+            let sender = s.spawn(move || -> Result<()> {
+                for request in requests {
+                    write_message(writer, request)?;
+                }
+                writer.flush()?;
+                Ok(())
+            });
+
+            let mut responses = Vec::with_capacity(requests.len());
+            for _ in 0..requests.len() {
+                match read_message(reader) {
+                    Ok(response) => responses.push(response),
+                    Err(e) => {
+                        let _ = socket.shutdown(Shutdown::Write);
+                        let _ = sender.join();
+                        return Err(e);
+                    }
+                }
+            }
+
+            sender.join().expect("Request sender panicked")?;
+
+            Ok(responses)
+        })
+    }
+
+    fn request(&mut self, request: &Request) -> Result<Response> {
+        write_message(&mut self.writer, request)?;
+        self.writer.flush()?;
+
+        read_message(&mut self.reader)
+    }
+}
+
+// A server that sends a response of the wrong kind is not following the protocol
+fn unexpected(response: Response) -> Error {
+    Error::Protocol(format!("Unexpected response: {:?}", response))
 }
@@ -0,0 +1,320 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::log::{encode_frame, read_frame, sync_dir};
+use super::memory::SNAPSHOT_NAME;
+use super::{KvStore, KvStoreOptions, LsmKvsEngine, MemoryKvsEngine, SledKvsEngine};
+use crate::error::{Error, Result};
+
+const MANIFEST_NAME: &str = "BACKUP";
+const MANIFEST_TMP_NAME: &str = "BACKUP.tmp";
+
+// Engines that can be backed up
+const ENGINES: &[&str] = &["kvs", "sled", "lsm", "memory"];
+
+// Version of the backup manifest written by this version of kvs
+const FORMAT_VERSION: u32 = 1;
+
+/// Describes a backup taken with `KvsEngine::backup`
+///
+/// It is stored as the `BACKUP` file of the backup directory, next to the
+/// files it lists.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BackupManifest {
+    pub version: u32,
+
+    /// Engine the backup was taken from: `kvs`, `sled`, `lsm` or `memory`
+    pub engine: String,
+
+    /// Point in time the backup reflects, in milliseconds since the Unix epoch
+    pub created_at: u64,
+
+    pub files: Vec<BackupFile>,
+}
+
+/// A file of a backup, along with what it must contain
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BackupFile {
+    /// Path relative to the backup directory, with `/` as separator
+    pub path: String,
+    pub size: u64,
+    pub crc: u32,
+}
+
+impl BackupManifest {
+    fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join(MANIFEST_NAME);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(Error::Generic(format!(
+                    "No backup found in {}",
+                    dir.display()
+                )))
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let buf = read_frame(&mut BufReader::new(file), 0).map_err(|e| e.in_file(&path))?;
+        let manifest: Self = rmp_serde::from_slice(&buf).map_err(|e| Error::Corruption {
+            file: Some(path.clone()),
+            offset: 0,
+            reason: e.to_string(),
+        })?;
+
+        if manifest.version != FORMAT_VERSION {
+            return Err(Error::UnsupportedVersion {
+                found: manifest.version,
+                expected: FORMAT_VERSION,
+            });
+        }
+
+        Ok(manifest)
+    }
+
+    fn store(&self, dir: &Path) -> Result<()> {
+        let tmp_path = dir.join(MANIFEST_TMP_NAME);
+
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&encode_frame(&rmp_serde::to_vec(self)?))?;
+        file.sync_data()?;
+        drop(file);
+
+        fs::rename(&tmp_path, dir.join(MANIFEST_NAME))?;
+        sync_dir(dir)
+    }
+}
+
+// Make sure a backup can be written to `dest`, which must be missing or empty
+pub(crate) fn create_backup_dir(dest: &Path) -> Result<()> {
+    if dest.exists() && fs::read_dir(dest)?.next().is_some() {
+        return Err(Error::Generic(format!(
+            "Backup directory {} is not empty",
+            dest.display()
+        )));
+    }
+
+    fs::create_dir_all(dest)?;
+
+    Ok(())
+}
+
+// Sync every file an engine wrote to `dest` and record it in the manifest
+//
+// The manifest is written last, so a backup without one is incomplete.
+pub(crate) fn seal_backup(dest: &Path, engine: &str, created_at: u64) -> Result<()> {
+    let mut files = Vec::new();
+    list_files(dest, "", &mut files)?;
+
+    let files = files
+        .into_iter()
+        .map(|path| {
+            let file = File::open(dest.join(&path))?;
+            file.sync_all()?;
+            let (size, crc) = checksum(file)?;
+            Ok(BackupFile { path, size, crc })
+        })
+        .collect::<Result<_>>()?;
+
+    BackupManifest {
+        version: FORMAT_VERSION,
+        engine: engine.to_owned(),
+        created_at,
+        files,
+    }
+    .store(dest)
+}
+
+// Put a file that never changes into a backup
+//
+// The file is hard-linked, or copied if it cannot be, e.g. because the backup
+// is on another file system.
+pub(crate) fn link_or_copy(src: &Path, dst: &Path) -> io::Result<()> {
+    if fs::hard_link(src, dst).is_err() {
+        fs::copy(src, dst)?;
+    }
+    Ok(())
+}
+
+/// Check that every file of a backup is present and intact
+///
+/// Returns the manifest of the backup.
+pub fn verify_backup(path: impl Into<PathBuf>) -> Result<BackupManifest> {
+    let dir = path.into();
+    let manifest = BackupManifest::load(&dir)?;
+
+    for entry in &manifest.files {
+        let path = dir.join(&entry.path);
+
+        // Restoring must never write outside the target directory
+        let relative = Path::new(&entry.path)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)));
+        if !relative {
+            return Err(corruption(&path, "file is outside the backup".to_owned()));
+        }
+
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(corruption(
+                    &path,
+                    "file is missing from the backup".to_owned(),
+                ))
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        check_file(entry, &path, checksum(file)?)?;
+    }
+
+    Ok(manifest)
+}
+
+/// Turn a backup back into a store in `dir`
+///
+/// The backup is verified before anything is copied, and every file is checked
+/// again as it is copied. `dir` must not hold a store already. The restored
+/// store is opened once, to make sure it loads.
+///
+/// Returns the manifest of the backup.
+pub fn restore(backup: impl Into<PathBuf>, dir: impl Into<PathBuf>) -> Result<BackupManifest> {
+    let backup = backup.into();
+    let dir = dir.into();
+
+    let manifest = verify_backup(&backup)?;
+    if !ENGINES.contains(&manifest.engine.as_str()) {
+        return Err(Error::Generic(format!(
+            "Backup is of unknown engine {}",
+            manifest.engine
+        )));
+    }
+
+    if KvStore::is_log_present(&dir)
+        || SledKvsEngine::is_log_present(&dir)
+        || LsmKvsEngine::is_log_present(&dir)
+        || dir.join(SNAPSHOT_NAME).exists()
+    {
+        return Err(Error::Generic(format!(
+            "A store already exists in {}",
+            dir.display()
+        )));
+    }
+
+    // Files are copied rather than linked, as the restored store writes to some
+    // of them
+    fs::create_dir_all(&dir)?;
+    let mut dirs = vec![dir.clone()];
+    for entry in &manifest.files {
+        let src = backup.join(&entry.path);
+        let dst = dir.join(&entry.path);
+
+        let parent = dst.parent().unwrap().to_owned();
+        if !dirs.contains(&parent) {
+            fs::create_dir_all(&parent)?;
+            dirs.push(parent);
+        }
+
+        let mut writer = File::create(&dst)?;
+        let copied = checksum(TeeReader {
+            reader: File::open(&src)?,
+            writer: &mut writer,
+        })?;
+        writer.sync_all()?;
+
+        check_file(entry, &src, copied)?;
+    }
+    for dir in &dirs {
+        sync_dir(dir)?;
+    }
+
+    match manifest.engine.as_str() {
+        "kvs" => drop(KvStoreOptions::new().read_only(true).open(&dir)?),
+        "sled" => drop(SledKvsEngine::open(&dir)?),
+        "lsm" => drop(LsmKvsEngine::open(&dir)?),
+        _ => drop(MemoryKvsEngine::with_snapshot(dir.join(SNAPSHOT_NAME))?),
+    }
+
+    log::info!(
+        "Restored {} files of a {} backup to {}",
+        manifest.files.len(),
+        manifest.engine,
+        dir.display()
+    );
+
+    Ok(manifest)
+}
+
+// Every file below `dir`, relative to the backup directory, in a stable order
+fn list_files(dir: &Path, prefix: &str, files: &mut Vec<String>) -> Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let path = format!("{}{}", prefix, name);
+
+        if entry.file_type()?.is_dir() {
+            list_files(&entry.path(), &format!("{}/", path), files)?;
+        } else if path != MANIFEST_NAME {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+// Size and CRC of everything the reader returns
+fn checksum(mut reader: impl Read) -> Result<(u64, u32)> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut size = 0;
+
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            return Ok((size, hasher.finalize()));
+        }
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+}
+
+fn check_file(entry: &BackupFile, path: &Path, (size, crc): (u64, u32)) -> Result<()> {
+    if size != entry.size {
+        return Err(corruption(
+            path,
+            format!("expected {} bytes, found {}", entry.size, size),
+        ));
+    }
+    if crc != entry.crc {
+        return Err(corruption(path, "checksum mismatch".to_owned()));
+    }
+
+    Ok(())
+}
+
+fn corruption(path: &Path, reason: String) -> Error {
+    Error::Corruption {
+        file: Some(path.to_owned()),
+        offset: 0,
+        reason,
+    }
+}
+
+// Copies everything that is read to a writer along the way
+struct TeeReader<R, W> {
+    reader: R,
+    writer: W,
+}
+
+impl<R: Read, W: Write> Read for TeeReader<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.writer.write_all(&buf[..n])?;
+        Ok(n)
+    }
+}
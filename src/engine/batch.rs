@@ -0,0 +1,73 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// A group of writes that is applied atomically
+///
+/// Either every write in the batch is applied, or none are. Writes are applied
+/// in the order they were added, so a later write to the same key wins.
+/// Removing a key that does not exist is not an error.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+/// A single write in a `WriteBatch`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum BatchOp {
+    Set(
+        #[serde(with = "serde_bytes")] Vec<u8>,
+        #[serde(with = "serde_bytes")] Vec<u8>,
+        Option<Duration>,
+    ),
+    Remove(#[serde(with = "serde_bytes")] Vec<u8>),
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.ops.push(BatchOp::Set(key, value, None));
+    }
+
+    /// Set a key that expires once `ttl` has passed, counting from when the
+    /// batch is applied
+    pub fn set_bytes_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) {
+        self.ops.push(BatchOp::Set(key, value, Some(ttl)));
+    }
+
+    pub fn remove_bytes(&mut self, key: Vec<u8>) {
+        self.ops.push(BatchOp::Remove(key));
+    }
+
+    pub fn set(&mut self, key: String, value: String) {
+        self.set_bytes(key.into_bytes(), value.into_bytes());
+    }
+
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl);
+    }
+
+    pub fn remove(&mut self, key: String) {
+        self.remove_bytes(key.into_bytes());
+    }
+
+    /// Number of writes in the batch
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    pub fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
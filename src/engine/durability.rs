@@ -0,0 +1,71 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::error::Error;
+
+/// When writes are synced to stable storage
+///
+/// `KvStore` hands every write to the OS before it returns, so it survives the
+/// process crashing under any policy. Only synced writes survive power loss or
+/// an OS crash.
+///
+/// Sled buffers writes in memory until it flushes them. Under `None`, it keeps
+/// flushing in the background at its own pace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    /// Never sync; the OS writes data back whenever it sees fit
+    None,
+
+    /// Sync in the background at the given interval
+    ///
+    /// Up to one interval's worth of writes can be lost on power loss.
+    Periodic(Duration),
+
+    /// Sync every write before it returns, with concurrent writers sharing a
+    /// single sync
+    GroupCommit,
+
+    /// Sync every write on its own before it returns
+    Sync,
+}
+
+impl Durability {
+    /// Returns `true` if every write is synced before it returns
+    pub fn syncs_every_write(&self) -> bool {
+        matches!(self, Durability::GroupCommit | Durability::Sync)
+    }
+}
+
+// Parsed from `none`, `sync`, `group-commit` or `periodic:<ms>`
+impl FromStr for Durability {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Durability::None),
+            "sync" => Ok(Durability::Sync),
+            "group-commit" => Ok(Durability::GroupCommit),
+            _ => {
+                let interval = s
+                    .strip_prefix("periodic:")
+                    .and_then(|ms| ms.parse().ok())
+                    .filter(|ms| *ms > 0)
+                    .ok_or_else(|| Error::Generic(format!("Invalid durability policy: {}", s)))?;
+
+                Ok(Durability::Periodic(Duration::from_millis(interval)))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Durability::None => write!(f, "none"),
+            Durability::Periodic(interval) => write!(f, "periodic:{}", interval.as_millis()),
+            Durability::GroupCommit => write!(f, "group-commit"),
+            Durability::Sync => write!(f, "sync"),
+        }
+    }
+}
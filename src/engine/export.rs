@@ -0,0 +1,272 @@
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::ops::RangeFull;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use super::migration::{BATCH_BYTES, BATCH_KEYS};
+use super::{now_millis, KvsEngine, WriteBatch};
+use crate::error::{Error, Result};
+
+/// Text format of `KvsEngine::export` and `KvsEngine::import`
+///
+/// Both formats hold one entry per key, in key order. An entry whose key and
+/// value are both valid UTF-8 is written as text. Otherwise both are written in
+/// base64, and the entry is marked with an encoding of `base64`. A key with a
+/// TTL has an `expires_at` of the time it expires, in milliseconds since the
+/// Unix epoch.
+///
+/// - `JsonLines`: one object per line, such as `{"key":"a","value":"b"}` or
+///   `{"key":"/w==","value":"AA==","encoding":"base64","expires_at":1700000000000}`
+/// - `Csv`: a `key,value,encoding,expires_at` header, then one row per key with
+///   an encoding of `utf8` or `base64`, and an empty `expires_at` if the key has
+///   no TTL
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    JsonLines,
+    Csv,
+}
+
+// Parsed from `jsonl` or `csv`
+impl FromStr for ExportFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "jsonl" => Ok(ExportFormat::JsonLines),
+            "csv" => Ok(ExportFormat::Csv),
+            _ => Err(Error::Generic(format!("Invalid export format: {}", s))),
+        }
+    }
+}
+
+impl fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExportFormat::JsonLines => write!(f, "jsonl"),
+            ExportFormat::Csv => write!(f, "csv"),
+        }
+    }
+}
+
+const CSV_HEADER: [&str; 4] = ["key", "value", "encoding", "expires_at"];
+
+// A single key as it is exported
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+    key: String,
+    value: String,
+    #[serde(default, skip_serializing_if = "Encoding::is_utf8")]
+    encoding: Encoding,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Encoding {
+    #[default]
+    Utf8,
+    Base64,
+}
+
+impl Encoding {
+    fn is_utf8(&self) -> bool {
+        *self == Encoding::Utf8
+    }
+}
+
+impl Entry {
+    fn new(key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Self {
+        let (key, value, encoding) = match (String::from_utf8(key), String::from_utf8(value)) {
+            (Ok(key), Ok(value)) => (key, value, Encoding::Utf8),
+            (key, value) => {
+                let key = key.map_or_else(|e| e.into_bytes(), String::into_bytes);
+                let value = value.map_or_else(|e| e.into_bytes(), String::into_bytes);
+                (base64::encode(key), base64::encode(value), Encoding::Base64)
+            }
+        };
+
+        Entry {
+            key,
+            value,
+            encoding,
+            expires_at,
+        }
+    }
+
+    // Turn the entry back into its key and value
+    fn decode(self) -> std::result::Result<(Vec<u8>, Vec<u8>), String> {
+        match self.encoding {
+            Encoding::Utf8 => Ok((self.key.into_bytes(), self.value.into_bytes())),
+            Encoding::Base64 => match (base64::decode(&self.key), base64::decode(&self.value)) {
+                (Ok(key), Ok(value)) => Ok((key, value)),
+                (Err(e), _) | (_, Err(e)) => Err(format!("invalid base64: {}", e)),
+            },
+        }
+    }
+}
+
+pub(super) fn export<E: KvsEngine>(
+    store: &E,
+    mut writer: impl Write,
+    format: ExportFormat,
+) -> Result<u64> {
+    let entries = store.scan_bytes(RangeFull)?.map(|entry| -> Result<Entry> {
+        let (key, value) = entry?;
+        let expires_at = store.expiry_bytes(key.clone())?;
+        Ok(Entry::new(key, value, expires_at))
+    });
+
+    let mut exported = 0;
+    match format {
+        ExportFormat::JsonLines => {
+            for entry in entries {
+                serde_json::to_writer(&mut writer, &entry?)?;
+                writer.write_all(b"\n")?;
+                exported += 1;
+            }
+        }
+        ExportFormat::Csv => {
+            // The header is written by hand, and rows as tuples, so that every
+            // row has all the columns
+            let mut writer = csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(&mut writer);
+            writer.write_record(CSV_HEADER)?;
+
+            for entry in entries {
+                let entry = entry?;
+                writer.serialize((entry.key, entry.value, entry.encoding, entry.expires_at))?;
+                exported += 1;
+            }
+            writer.flush()?;
+        }
+    }
+    writer.flush()?;
+
+    Ok(exported)
+}
+
+pub(super) fn import<E: KvsEngine>(
+    store: &E,
+    reader: impl BufRead,
+    format: ExportFormat,
+) -> Result<u64> {
+    let mut committed = 0;
+
+    let res = match format {
+        ExportFormat::JsonLines => import_entries(store, json_entries(reader), &mut committed),
+        ExportFormat::Csv => import_entries(store, csv_entries(reader)?, &mut committed),
+    };
+
+    // Batches before the error stay in place, so say how many keys made it in
+    match res {
+        Err(e) if committed > 0 => Err(Error::Generic(format!(
+            "{} ({} keys were imported before it)",
+            e, committed
+        ))),
+        res => res.map(|_| committed),
+    }
+}
+
+// Write entries to the store in batches, counting the keys of every batch
+// that is written
+fn import_entries<E: KvsEngine>(
+    store: &E,
+    entries: impl Iterator<Item = Result<(u64, Entry)>>,
+    committed: &mut u64,
+) -> Result<()> {
+    let now = now_millis();
+    let mut batch = WriteBatch::new();
+    let mut batch_bytes = 0;
+
+    for entry in entries {
+        let (line_no, entry) = entry?;
+        let expires_at = entry.expires_at;
+        let (key, value) = entry.decode().map_err(|e| invalid_entry(line_no, e))?;
+
+        batch_bytes += key.len() + value.len();
+        match expires_at {
+            Some(ts) => {
+                let ttl = Duration::from_millis(ts.saturating_sub(now));
+                batch.set_bytes_with_ttl(key, value, ttl);
+            }
+            None => batch.set_bytes(key, value),
+        }
+
+        if batch.len() >= BATCH_KEYS || batch_bytes >= BATCH_BYTES {
+            let len = batch.len() as u64;
+            store.write_batch(std::mem::take(&mut batch))?;
+            *committed += len;
+            batch_bytes = 0;
+        }
+    }
+
+    let len = batch.len() as u64;
+    store.write_batch(batch)?;
+    *committed += len;
+
+    Ok(())
+}
+
+fn invalid_entry(line: u64, reason: impl fmt::Display) -> Error {
+    Error::Generic(format!("Invalid entry on line {}: {}", line, reason))
+}
+
+// Read one entry per line, skipping blank lines
+//
+// Entries come with the line they start on, to point out mistakes with.
+fn json_entries(reader: impl BufRead) -> impl Iterator<Item = Result<(u64, Entry)>> {
+    reader
+        .lines()
+        .zip(1..)
+        .filter(|(line, _)| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|(line, line_no)| {
+            let entry = serde_json::from_str(&line?).map_err(|e| invalid_entry(line_no, e))?;
+            Ok((line_no, entry))
+        })
+}
+
+// Read the header, then one entry per record
+fn csv_entries(reader: impl BufRead) -> Result<impl Iterator<Item = Result<(u64, Entry)>>> {
+    let mut reader = csv::Reader::from_reader(reader);
+
+    let headers = reader.headers()?.clone();
+    if !headers.iter().eq(CSV_HEADER.iter().copied()) {
+        return Err(Error::Generic(format!(
+            "Expected a CSV header of {}",
+            CSV_HEADER.join(",")
+        )));
+    }
+
+    Ok(reader.into_records().map(move |record| {
+        let record = record.map_err(csv_error)?;
+        let line_no = record.position().map_or(0, |pos| pos.line());
+        let entry = record
+            .deserialize(Some(&headers))
+            .map_err(|e| invalid_entry(line_no, e))?;
+        Ok((line_no, entry))
+    }))
+}
+
+// Point out the line a malformed record starts on
+fn csv_error(err: csv::Error) -> Error {
+    let line_no = err.position().map(|pos| pos.line());
+    match (err.kind(), line_no) {
+        (
+            csv::ErrorKind::UnequalLengths {
+                expected_len, len, ..
+            },
+            Some(line_no),
+        ) => invalid_entry(
+            line_no,
+            format!("expected {} fields, found {}", expected_len, len),
+        ),
+        (csv::ErrorKind::Utf8 { err, .. }, Some(line_no)) => invalid_entry(line_no, err),
+        _ => err.into(),
+    }
+}
@@ -0,0 +1,70 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use super::hint::hint_path;
+use super::segment::segment_path;
+use super::KvStoreInner;
+use crate::engine::backup::{create_backup_dir, link_or_copy, seal_backup};
+use crate::engine::log::ReadAt;
+use crate::engine::now_millis;
+use crate::error::Result;
+
+impl KvStoreInner {
+    // Copy the log as it is right now to `dest`
+    //
+    // Sealed segments never change, so they are hard-linked while the writer is
+    // held, before a compaction can delete them. The active segment is cut off
+    // at the current end of the log, and copied once writes are flowing again.
+    // Segments that cannot be linked, e.g. because `dest` is on another file
+    // system, are copied through the files already open for reads, which stay
+    // readable even if the segment is deleted in the meantime.
+    pub(super) fn backup(&self, dest: &Path) -> Result<()> {
+        create_backup_dir(dest)?;
+
+        let (created_at, copies) = {
+            let writer = self.writer.lock()?;
+            let readers = self.readers.read()?;
+
+            let mut copies = Vec::new();
+            for (&gen, file) in readers.iter() {
+                if gen == writer.current_gen {
+                    copies.push((gen, file.clone(), writer.log_pos as u64));
+                    continue;
+                }
+
+                if fs::hard_link(segment_path(&self.log_dir, gen), segment_path(dest, gen)).is_err()
+                {
+                    copies.push((gen, file.clone(), file.metadata()?.len()));
+                }
+                link_hint(&self.log_dir, dest, gen)?;
+            }
+
+            (now_millis(), copies)
+        };
+
+        for (gen, file, len) in copies {
+            copy_segment(&file, len, &segment_path(dest, gen))?;
+        }
+
+        seal_backup(dest, "kvs", created_at)
+    }
+}
+
+// Hints are only ever written whole, so they are as immutable as their segment
+fn link_hint(log_dir: &Path, dest: &Path, gen: u64) -> Result<()> {
+    match link_or_copy(&hint_path(log_dir, gen), &hint_path(dest, gen)) {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        res => Ok(res?),
+    }
+}
+
+// Copy the first `len` bytes of a segment
+fn copy_segment(file: &Arc<File>, len: u64, dst: &Path) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(dst)?);
+    io::copy(&mut ReadAt::new(file, 0).take(len), &mut writer)?;
+    writer.flush()?;
+
+    Ok(())
+}
@@ -0,0 +1,310 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use super::header::encode_header;
+use super::hint::{remove_hint, write_hint};
+use super::record::{encode_record, read_record};
+use super::segment::{new_segment_reader, open_segment_file, segment_path, tmp_segment_path};
+use super::{Command, CommandIndex, KvStoreInner, KvStoreOptions};
+use crate::engine::log::{read_record_header, sync_dir, RECORD_HEADER_SIZE};
+use crate::error::{Error, Result};
+
+// A compaction of a set of immutable segments
+//
+// Live entries from the input segments are copied over to new segments, after
+// which the index is switched over and the inputs are deleted.
+//
+// The new segments are given generations that sort after every input segment,
+// but before the active segment. They are written under a temporary name, and
+// only renamed into the log once they and their hints are complete, so a crash
+// never leaves a half-written segment in the log. Whatever made it in, replaying
+// all segments in order still produces the right index.
+//
+// The inputs are only deleted once the output segments, their hints and the
+// directory entries for both are synced, whatever the durability policy.
+pub(super) struct Compaction {
+    // Segments being compacted, in order
+    inputs: Vec<u64>,
+
+    // Number of uncompacted bytes in each input when the compaction started
+    // Restored if the compaction fails
+    uncompacted: Vec<(u64, usize)>,
+
+    // Range of generations reserved for the output
+    first_gen: u64,
+    last_gen: u64,
+
+    // Live entries in the input segments when the compaction started
+    entries: Vec<(Vec<u8>, CommandIndex)>,
+}
+
+// Where a live entry ended up in the output
+struct Moved {
+    key: Vec<u8>,
+    old: CommandIndex,
+    new: CommandIndex,
+}
+
+impl Compaction {
+    pub(super) fn new(
+        inputs: Vec<u64>,
+        uncompacted: Vec<(u64, usize)>,
+        first_gen: u64,
+        last_gen: u64,
+        mut entries: Vec<(Vec<u8>, CommandIndex)>,
+    ) -> Self {
+        // Sort the live entries by their position in the log
+        entries.sort_by_key(|(_, index)| (index.gen, index.pos));
+
+        Self {
+            inputs,
+            uncompacted,
+            first_gen,
+            last_gen,
+            entries,
+        }
+    }
+
+    // Run the compaction to completion
+    //
+    // Writers and readers are only blocked while the index is switched over.
+    pub(super) fn run(self, inner: &KvStoreInner) -> Result<()> {
+        match self.merge(inner) {
+            Ok((moved, new_gen)) => self.install(inner, moved, new_gen),
+            Err(e) => {
+                self.abort(inner);
+                Err(e)
+            }
+        }
+    }
+
+    // Copy all live entries to the output segments
+    //
+    // Returns the new location of each entry and the last output generation.
+    fn merge(&self, inner: &KvStoreInner) -> Result<(Vec<Moved>, u64)> {
+        let mut moved = Vec::with_capacity(self.entries.len());
+
+        let mut new_gen = self.first_gen;
+        let (mut new_log_writer, mut new_log_pos) =
+            create_output(&inner.log_dir, new_gen, &inner.options)?;
+
+        // Readers are opened separately from the ones used for `get`
+        let mut reader_gen = None;
+        let mut reader = None;
+
+        for (key, index) in self.entries.iter() {
+            // Move on to the next output segment once this one is full
+            if new_log_pos >= inner.options.max_segment_size && new_gen < self.last_gen {
+                new_log_writer.flush()?;
+                new_log_writer.get_ref().sync_data()?;
+                new_gen += 1;
+                (new_log_writer, new_log_pos) =
+                    create_output(&inner.log_dir, new_gen, &inner.options)?;
+            }
+
+            // Entries are sorted, so each input is only opened once
+            if reader_gen != Some(index.gen) {
+                reader = Some(new_segment_reader(
+                    &inner.log_dir,
+                    index.gen,
+                    &inner.options,
+                )?);
+                reader_gen = Some(index.gen);
+            }
+            let reader = reader.as_mut().unwrap();
+
+            // Seek to the position in the old segment
+            reader.seek(SeekFrom::Start(index.pos as u64))?;
+            let in_file = |e: Error| e.in_file(&segment_path(&inner.log_dir, index.gen));
+            let header = read_record_header(reader, index.pos).map_err(in_file)?;
+
+            let size = if RECORD_HEADER_SIZE + header.size as usize == index.size {
+                reader.seek(SeekFrom::Start(index.pos as u64))?;
+
+                // Create a wrapped `BufReader` that will only return the bytes of
+                // this record (header and command)
+                let mut wrapped_reader = reader.by_ref().take(index.size as u64);
+
+                // Copy the record as-is from the old segment to the new one. The
+                // checksums are computed over the record contents, so they remain valid.
+                std::io::copy(&mut wrapped_reader, &mut new_log_writer)?;
+
+                index.size
+            } else {
+                // The key only owns a share of a batch record. The batch was
+                // applied in full long ago, so its write to this key is copied
+                // out as a record of its own.
+                reader.seek(SeekFrom::Start(index.pos as u64))?;
+                let command = read_record(reader, index.pos)
+                    .and_then(|command| extract_from_batch(command, key, index.pos))
+                    .map_err(in_file)?;
+                let buf = encode_record(&command)?;
+                new_log_writer.write_all(&buf)?;
+
+                buf.len()
+            };
+
+            moved.push(Moved {
+                key: key.clone(),
+                old: index.clone(),
+                new: CommandIndex {
+                    gen: new_gen,
+                    pos: new_log_pos,
+                    size,
+                    expires_at: index.expires_at,
+                },
+            });
+
+            new_log_pos += size;
+        }
+
+        // Ensure all data is on disk before deleting anything
+        new_log_writer.flush()?;
+        new_log_writer.get_ref().sync_data()?;
+
+        // Nothing was live, so there is no point in keeping an empty segment
+        if moved.is_empty() {
+            fs::remove_file(tmp_segment_path(&inner.log_dir, new_gen))?;
+            new_gen -= 1;
+        }
+
+        // Write a hint for every output segment, now that they are complete
+        for gen in self.first_gen..=new_gen {
+            let segment_size = fs::metadata(tmp_segment_path(&inner.log_dir, gen))?.len();
+            let entries = moved
+                .iter()
+                .filter(|entry| entry.new.gen == gen)
+                .map(|entry| (&entry.key, &entry.new));
+            write_hint(&inner.log_dir, gen, segment_size, entries)?;
+        }
+
+        // Only now do the outputs become part of the log
+        for gen in self.first_gen..=new_gen {
+            fs::rename(
+                tmp_segment_path(&inner.log_dir, gen),
+                segment_path(&inner.log_dir, gen),
+            )?;
+        }
+
+        // The new segments and hints must survive power loss before the old
+        // segments can go
+        sync_dir(&inner.log_dir)?;
+
+        Ok((moved, new_gen))
+    }
+
+    // Switch the index over to the output segments and delete the inputs
+    fn install(self, inner: &KvStoreInner, moved: Vec<Moved>, new_gen: u64) -> Result<()> {
+        let mut readers = Vec::new();
+        for gen in self.first_gen..=new_gen {
+            readers.push((gen, Arc::new(open_segment_file(&inner.log_dir, gen)?)));
+        }
+
+        // Sizes of the log on either side of the switch
+        let mut old_size = 0;
+        for gen in self.inputs.iter() {
+            old_size += fs::metadata(segment_path(&inner.log_dir, *gen))?.len() as usize;
+        }
+        let mut new_size = 0;
+        for gen in self.first_gen..=new_gen {
+            new_size += fs::metadata(segment_path(&inner.log_dir, gen))?.len() as usize;
+        }
+
+        {
+            let mut writer = inner.writer.lock()?;
+            let mut store = inner.store.write()?;
+
+            for entry in moved {
+                match store.get_mut(&entry.key) {
+                    // The key was not touched while we were busy
+                    Some(index) if index.gen == entry.old.gen && index.pos == entry.old.pos => {
+                        *index = entry.new;
+                    }
+                    // The key was overwritten or removed, so the copy is already stale
+                    _ => writer.uncompacted.mark(entry.new.gen, entry.new.size),
+                }
+            }
+
+            for gen in self.inputs.iter() {
+                writer.uncompacted.take(*gen);
+            }
+
+            let mut segment_readers = inner.readers.write()?;
+            segment_readers.extend(readers);
+            for gen in self.inputs.iter() {
+                segment_readers.remove(gen);
+            }
+
+            writer.log_size = writer.log_size.saturating_sub(old_size) + new_size;
+        }
+
+        // The old segments are no longer referenced by the index
+        for gen in self.inputs {
+            fs::remove_file(segment_path(&inner.log_dir, gen))?;
+            remove_hint(&inner.log_dir, gen)?;
+        }
+
+        Ok(())
+    }
+
+    // Clean up after a failed compaction
+    //
+    // The input segments are still in use, so we only need to drop any output
+    // and start tracking the inputs again.
+    fn abort(self, inner: &KvStoreInner) {
+        for gen in self.first_gen..=self.last_gen {
+            for path in [
+                tmp_segment_path(&inner.log_dir, gen),
+                segment_path(&inner.log_dir, gen),
+            ] {
+                if path.exists() {
+                    let _ = fs::remove_file(path);
+                }
+            }
+            let _ = remove_hint(&inner.log_dir, gen);
+        }
+
+        // A poisoned store refuses every write, so there is nothing to track
+        if let Ok(mut writer) = inner.writer.lock() {
+            for (gen, size) in self.uncompacted {
+                writer.uncompacted.mark(gen, size);
+            }
+        }
+    }
+}
+
+// Create an output segment under its temporary name, containing only its header
+//
+// Returns a writer for the segment along with the size of the header.
+fn create_output(
+    log_dir: &Path,
+    gen: u64,
+    options: &KvStoreOptions,
+) -> Result<(BufWriter<File>, usize)> {
+    let header = encode_header(gen)?;
+
+    let file = File::create(tmp_segment_path(log_dir, gen))?;
+    let mut writer = BufWriter::with_capacity(options.write_buffer_size, file);
+    writer.write_all(&header)?;
+
+    Ok((writer, header.len()))
+}
+
+// Find the last write to `key` in a batch
+fn extract_from_batch(command: Command, key: &[u8], pos: usize) -> Result<Command> {
+    let found = match command {
+        Command::Batch(commands) => commands
+            .into_iter()
+            .rev()
+            .find(|command| matches!(command, Command::Set(k, _, _) if k == key)),
+        _ => None,
+    };
+
+    found.ok_or_else(|| Error::Corruption {
+        file: None,
+        offset: pos as u64,
+        reason: "key missing from batch".to_owned(),
+    })
+}
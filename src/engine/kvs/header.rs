@@ -0,0 +1,91 @@
+use std::io::{ErrorKind, Read};
+
+use serde::{Deserialize, Serialize};
+
+use crate::engine::log::{encode_frame, read_frame, RECORD_HEADER_SIZE};
+use crate::engine::now_millis;
+use crate::error::{Error, Result};
+
+// Every segment starts with a header:
+//
+// - magic (8 bytes): `KVSLOG\r\n`
+// - format version (u32 LE)
+// - a framed record holding the `SegmentInfo`
+//
+// Nothing past the version is read unless the version is known, so a future
+// version is free to change everything that follows it. Segments written before
+// the header existed have neither magic nor version, and count as version 0.
+pub(crate) const MAGIC: &[u8; 8] = b"KVSLOG\r\n";
+
+// Version of the format written by this version of kvs
+pub(crate) const FORMAT_VERSION: u32 = 1;
+
+// Size of the magic and version
+const PREFIX_SIZE: usize = 12;
+
+// Metadata recorded when a segment is created
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct SegmentInfo {
+    pub(crate) gen: u64,
+
+    // Milliseconds since the Unix epoch
+    pub(crate) created_at: u64,
+
+    // Version of kvs that created the segment
+    pub(crate) created_by: String,
+}
+
+// Build the header for a new segment
+pub(crate) fn encode_header(gen: u64) -> Result<Vec<u8>> {
+    let info = SegmentInfo {
+        gen,
+        created_at: now_millis(),
+        created_by: env!("CARGO_PKG_VERSION").to_owned(),
+    };
+
+    let mut buf = MAGIC.to_vec();
+    buf.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    buf.extend_from_slice(&encode_frame(&rmp_serde::to_vec(&info)?));
+
+    Ok(buf)
+}
+
+// Read the format version at the start of a segment
+pub(crate) fn read_version(reader: &mut impl Read) -> Result<u32> {
+    let mut magic = [0u8; 8];
+    match reader.read_exact(&mut magic) {
+        Ok(()) if &magic == MAGIC => {}
+        Ok(()) => return Ok(0),
+        // Too short to have a header at all
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(0),
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut version = [0u8; 4];
+    reader.read_exact(&mut version)?;
+
+    Ok(u32::from_le_bytes(version))
+}
+
+// Read and validate the header at the start of a segment
+//
+// Returns the segment info along with the size of the header, which is where
+// the first record starts.
+pub(crate) fn read_header(reader: &mut impl Read) -> Result<(SegmentInfo, usize)> {
+    let version = read_version(reader)?;
+    if version != FORMAT_VERSION {
+        return Err(Error::UnsupportedVersion {
+            found: version,
+            expected: FORMAT_VERSION,
+        });
+    }
+
+    let payload = read_frame(reader, PREFIX_SIZE)?;
+    let info = rmp_serde::from_slice(&payload).map_err(|e| Error::Corruption {
+        file: None,
+        offset: PREFIX_SIZE as u64,
+        reason: e.to_string(),
+    })?;
+
+    Ok((info, PREFIX_SIZE + RECORD_HEADER_SIZE + payload.len()))
+}
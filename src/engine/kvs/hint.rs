@@ -0,0 +1,152 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::segment::{segment_path, Uncompacted};
+use super::{CommandIndex, KvStore};
+use crate::engine::log::{encode_frame, read_frame};
+use crate::engine::now_millis;
+use crate::error::Result;
+
+// Hint files sit next to compacted segments (`kvs-<gen>.hint`) and list the
+// location of every record in the segment. Opening the store can then rebuild
+// the index from the hint without reading (and deserializing) any values.
+//
+// The hint is stored as a single framed record, so a damaged hint is detected
+// and the segment is simply replayed instead.
+#[derive(Debug, Deserialize, Serialize)]
+struct Hint {
+    // Size of the segment the hint was written for
+    segment_size: u64,
+    entries: Vec<HintEntry>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct HintEntry {
+    #[serde(with = "serde_bytes")]
+    key: Vec<u8>,
+    pos: u64,
+    size: u64,
+    #[serde(default)]
+    expires_at: Option<u64>,
+}
+
+pub(super) fn hint_path(log_dir: &Path, gen: u64) -> PathBuf {
+    log_dir.join(format!("{}-{}.hint", KvStore::LOG_PREFIX, gen))
+}
+
+// Write the hint for a compacted segment of `segment_size` bytes
+//
+// The hint is written to a temporary file first and then renamed, so a hint
+// file that exists is always complete.
+pub(super) fn write_hint<'a>(
+    log_dir: &Path,
+    gen: u64,
+    segment_size: u64,
+    entries: impl Iterator<Item = (&'a Vec<u8>, &'a CommandIndex)>,
+) -> Result<()> {
+    let hint = Hint {
+        segment_size,
+        entries: entries
+            .map(|(key, index)| HintEntry {
+                key: key.clone(),
+                pos: index.pos as u64,
+                size: index.size as u64,
+                expires_at: index.expires_at,
+            })
+            .collect(),
+    };
+
+    let payload = rmp_serde::to_vec(&hint)?;
+
+    let path = hint_path(log_dir, gen);
+    let tmp_path = path.with_extension("hint.tmp");
+
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    writer.write_all(&encode_frame(&payload))?;
+    writer.flush()?;
+    writer.get_ref().sync_data()?;
+    drop(writer);
+
+    // The directory is synced by the compaction once all hints are written
+    fs::rename(tmp_path, path)?;
+
+    Ok(())
+}
+
+// Load the hint for a segment into the index
+//
+// Returns `false` if there is no usable hint, in which case the segment needs
+// to be replayed.
+pub(super) fn load_hint(
+    log_dir: &Path,
+    gen: u64,
+    store: &mut BTreeMap<Vec<u8>, CommandIndex>,
+    uncompacted: &mut Uncompacted,
+) -> Result<bool> {
+    let path = hint_path(log_dir, gen);
+    if !path.exists() {
+        return Ok(false);
+    }
+
+    let hint = match read_hint(&path) {
+        Ok(hint) => hint,
+        Err(e) => {
+            log::warn!("Ignoring hint {}: {}", path.display(), e);
+            return Ok(false);
+        }
+    };
+
+    // The segment must be exactly the one the hint was written for
+    let segment_size = fs::metadata(segment_path(log_dir, gen))?.len();
+    if segment_size != hint.segment_size {
+        log::warn!(
+            "Ignoring hint {}: expected a segment of {} bytes, found {}",
+            path.display(),
+            hint.segment_size,
+            segment_size
+        );
+        return Ok(false);
+    }
+
+    let now = now_millis();
+
+    for entry in hint.entries {
+        let index = CommandIndex {
+            gen,
+            pos: entry.pos as usize,
+            size: entry.size as usize,
+            expires_at: entry.expires_at,
+        };
+
+        if index.is_expired(now) {
+            if let Some(old) = store.remove(&entry.key) {
+                uncompacted.mark(old.gen, old.size);
+            }
+            uncompacted.mark(index.gen, index.size);
+        } else if let Some(old) = store.insert(entry.key, index) {
+            uncompacted.mark(old.gen, old.size);
+        }
+    }
+
+    Ok(true)
+}
+
+fn read_hint(path: &Path) -> Result<Hint> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let payload = read_frame(&mut reader, 0)?;
+    Ok(rmp_serde::from_slice(&payload)?)
+}
+
+// Delete the hint for a segment, if there is one
+pub(super) fn remove_hint(log_dir: &Path, gen: u64) -> Result<()> {
+    let path = hint_path(log_dir, gen);
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+
+    Ok(())
+}
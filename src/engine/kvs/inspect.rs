@@ -0,0 +1,684 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use super::header::{encode_header, read_header, read_version, SegmentInfo, FORMAT_VERSION};
+use super::hint::remove_hint;
+use super::record::{decode_command, encode_record, read_command_size, LEGACY_RECORD_HEADER_SIZE};
+use super::segment::{process_command, segment_gens, segment_path, Uncompacted};
+use super::{Command, CommandIndex, KvStore, KvStoreOptions};
+use crate::engine::lock::DirLock;
+use crate::engine::log::{read_record_header, sync_dir, RECORD_HEADER_SIZE};
+use crate::error::{Error, Result};
+
+/// A record read back from a segment
+#[derive(Debug)]
+pub struct LogRecord {
+    /// Offset of the record in its segment
+    pub offset: u64,
+
+    /// Size of the record, header included
+    pub size: u64,
+
+    pub command: LogCommand,
+}
+
+/// The command held by a record
+///
+/// Values are left out, only their size is kept.
+#[derive(Debug)]
+pub enum LogCommand {
+    Get {
+        key: Vec<u8>,
+    },
+    Set {
+        key: Vec<u8>,
+        value_size: u64,
+        expires_at: Option<u64>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    Batch(Vec<LogCommand>),
+}
+
+impl From<Command> for LogCommand {
+    fn from(command: Command) -> Self {
+        match command {
+            Command::Get(key) => Self::Get { key },
+            Command::Set(key, value, expires_at) => Self::Set {
+                key,
+                value_size: value.len() as u64,
+                expires_at,
+            },
+            Command::Remove(key) => Self::Remove { key },
+            Command::Batch(commands) => Self::Batch(commands.into_iter().map(Self::from).collect()),
+        }
+    }
+}
+
+/// A record that could not be read
+#[derive(Clone, Debug)]
+pub struct Damage {
+    /// Offset of the record in its segment
+    pub offset: u64,
+
+    pub reason: String,
+
+    /// Whether this looks like a record that was cut short by a crash at the
+    /// end of the segment, which opening the store truncates away by itself
+    pub torn: bool,
+}
+
+impl fmt::Display for Damage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = if self.torn { "Torn" } else { "Damaged" };
+        write!(
+            f,
+            "{} record at offset {}: {}",
+            kind, self.offset, self.reason
+        )
+    }
+}
+
+/// Walks the records of a single segment
+///
+/// The segment is read through a buffer, record by record. Iteration stops at
+/// the first damaged record, which is returned as an error; `skip_damage` looks
+/// for the next intact record past it.
+pub struct SegmentReader {
+    reader: BufReader<File>,
+    size: usize,
+    version: u32,
+    info: Option<SegmentInfo>,
+    header_size: usize,
+
+    // Whether records are framed the way the original `kvs.log` had them, with
+    // just the size of the command in front and no checksums
+    legacy: bool,
+
+    // Where the run of zeros at the end of the segment starts, if it has one
+    zeroed_tail: usize,
+
+    // Offset of the next record
+    pos: usize,
+
+    // Offset `reader` is at
+    reader_pos: usize,
+
+    // Set when a damaged record was returned, until `skip_damage` moves past it
+    damaged: bool,
+}
+
+impl SegmentReader {
+    /// Open a segment, or a log written before segments existed
+    ///
+    /// A `kvs.log` from before segments is read the way it was written, with no
+    /// checksums to tell damaged records apart. A segment in a format version
+    /// this version cannot read is refused.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len() as usize;
+        let legacy = path.file_name() == Some(KvStore::LEGACY_LOG_NAME.as_ref());
+
+        // Older segments have no header, and start with their first record
+        let (info, header_size) = match read_version(&mut file)? {
+            _ if legacy => (None, 0),
+            0 => (None, 0),
+            FORMAT_VERSION => {
+                file.seek(SeekFrom::Start(0))?;
+                let (info, size) = read_header(&mut file).map_err(|e| e.in_file(path))?;
+                (Some(info), size)
+            }
+            found => {
+                return Err(Error::UnsupportedVersion {
+                    found,
+                    expected: FORMAT_VERSION,
+                })
+            }
+        };
+
+        let zeroed_tail = zeroed_tail(&mut file, size)?;
+        file.seek(SeekFrom::Start(header_size as u64))?;
+
+        Ok(Self {
+            version: if info.is_some() { FORMAT_VERSION } else { 0 },
+            reader: BufReader::with_capacity(KvStore::BUFFER_SIZE, file),
+            size,
+            info,
+            header_size,
+            legacy,
+            zeroed_tail,
+            pos: header_size,
+            reader_pos: header_size,
+            damaged: false,
+        })
+    }
+
+    /// Format version of the segment, 0 if it has no header
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Generation recorded in the segment header
+    pub fn gen(&self) -> Option<u64> {
+        self.info.as_ref().map(|info| info.gen)
+    }
+
+    /// Time the segment was created, in milliseconds since the Unix epoch
+    pub fn created_at(&self) -> Option<u64> {
+        self.info.as_ref().map(|info| info.created_at)
+    }
+
+    /// Version of kvs that created the segment
+    pub fn created_by(&self) -> Option<&str> {
+        self.info.as_ref().map(|info| info.created_by.as_str())
+    }
+
+    /// Size of the segment header, which is where the first record starts
+    pub fn header_size(&self) -> u64 {
+        self.header_size as u64
+    }
+
+    /// Size of the whole segment
+    pub fn size(&self) -> u64 {
+        self.size as u64
+    }
+
+    /// Move past a damaged record to the next intact one
+    ///
+    /// Every offset past the damage is tried in turn, so this finds records
+    /// whose header and command both check out. Returns the offset of the
+    /// record found, or `None` if nothing past the damage can be read.
+    pub fn skip_damage(&mut self) -> Option<u64> {
+        if !self.damaged {
+            return Some(self.pos as u64);
+        }
+        self.damaged = false;
+
+        for pos in self.pos + 1..self.size {
+            if self.read_at(pos).is_ok() {
+                self.pos = pos;
+                return Some(pos as u64);
+            }
+        }
+
+        self.pos = self.size;
+        None
+    }
+
+    // Read the next record, returning it along with its offset and size
+    fn next_command(&mut self) -> Option<std::result::Result<(usize, usize, Command), Damage>> {
+        if self.damaged || self.pos >= self.size {
+            return None;
+        }
+
+        match self.read_at(self.pos) {
+            Ok((command, size)) => {
+                let pos = self.pos;
+                self.pos += size;
+                Some(Ok((pos, size, command)))
+            }
+            Err(damage) => {
+                self.damaged = true;
+                Some(Err(damage))
+            }
+        }
+    }
+
+    // Read the record at `pos` the same way replaying the segment does, telling
+    // a torn tail apart from damage in the middle of the segment
+    fn read_at(&mut self, pos: usize) -> std::result::Result<(Command, usize), Damage> {
+        let rest = self.size - pos;
+        let damage = |reason: &str, torn: bool| Damage {
+            offset: pos as u64,
+            reason: reason.to_owned(),
+            torn,
+        };
+
+        // No record is all zeros, not even its header
+        if pos >= self.zeroed_tail {
+            return Err(damage("zero-filled tail", true));
+        }
+
+        let record_header_size = if self.legacy {
+            LEGACY_RECORD_HEADER_SIZE
+        } else {
+            RECORD_HEADER_SIZE
+        };
+        if rest < record_header_size {
+            return Err(damage("incomplete record header", true));
+        }
+
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        let header = &mut header[..record_header_size];
+        self.read_exact_at(pos, header)
+            .map_err(|e| damage(&e.to_string(), false))?;
+
+        let (command_size, crc) = if self.legacy {
+            let size =
+                read_command_size(&mut &header[..]).map_err(|e| damage(&reason(e), false))?;
+            (size, None)
+        } else {
+            let header =
+                read_record_header(&mut &header[..], pos).map_err(|e| damage(&reason(e), false))?;
+            (header.size, Some(header.crc))
+        };
+
+        let size = record_header_size as u64 + command_size;
+        if size > rest as u64 {
+            return Err(damage("record extends past the end of the segment", true));
+        }
+        let size = size as usize;
+
+        let mut payload = vec![0u8; size - record_header_size];
+        self.read_exact_at(pos + record_header_size, &mut payload)
+            .map_err(|e| damage(&e.to_string(), false))?;
+
+        if crc.is_some_and(|crc| crc32fast::hash(&payload) != crc) {
+            return Err(damage("command checksum mismatch", size == rest));
+        }
+
+        let command = decode_command(&payload, pos).map_err(|e| damage(&reason(e), false))?;
+
+        Ok((command, size))
+    }
+
+    // Fill `buf` from `pos` on, staying within the buffer when `pos` is close
+    // to where the last read ended
+    fn read_exact_at(&mut self, pos: usize, buf: &mut [u8]) -> io::Result<()> {
+        self.reader
+            .seek_relative(pos as i64 - self.reader_pos as i64)?;
+        self.reader_pos = pos;
+
+        let res = self.reader.read_exact(buf);
+        self.reader_pos = match res {
+            Ok(()) => pos + buf.len(),
+            // Where the reader ended up is unknown, so find out next time
+            Err(_) => self.reader.stream_position()? as usize,
+        };
+        res
+    }
+}
+
+// Find where the run of zeros at the end of a file starts, reading it backwards
+fn zeroed_tail(file: &mut File, size: usize) -> Result<usize> {
+    let mut buf = vec![0u8; KvStore::BUFFER_SIZE];
+    let mut end = size;
+
+    while end > 0 {
+        let start = end.saturating_sub(buf.len());
+        let chunk = &mut buf[..end - start];
+        file.seek(SeekFrom::Start(start as u64))?;
+        file.read_exact(chunk)?;
+
+        if let Some(pos) = chunk.iter().rposition(|b| *b != 0) {
+            return Ok(start + pos + 1);
+        }
+        end = start;
+    }
+
+    Ok(0)
+}
+
+impl Iterator for SegmentReader {
+    type Item = std::result::Result<LogRecord, Damage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_command().map(|res| {
+            res.map(|(pos, size, command)| LogRecord {
+                offset: pos as u64,
+                size: size as u64,
+                command: command.into(),
+            })
+        })
+    }
+}
+
+/// What `KvStore::inspect` found in a log
+#[derive(Debug)]
+pub struct LogReport {
+    pub segments: Vec<SegmentReport>,
+
+    /// Number of keys that are set
+    pub live_keys: u64,
+
+    /// Keys written by more than one command, with the number of commands
+    pub duplicate_keys: BTreeMap<Vec<u8>, u64>,
+}
+
+impl LogReport {
+    /// Returns `true` if any segment has a damaged record
+    pub fn is_damaged(&self) -> bool {
+        self.segments.iter().any(|segment| segment.damage.is_some())
+    }
+}
+
+/// What `KvStore::inspect` found in a single segment
+#[derive(Debug)]
+pub struct SegmentReport {
+    pub gen: u64,
+    pub path: PathBuf,
+    pub version: u32,
+    pub size: u64,
+
+    /// Number of records read before any damage
+    pub records: u64,
+
+    /// Bytes of records that still hold a live key
+    pub live_bytes: u64,
+
+    /// Bytes of records that compaction would drop
+    pub dead_bytes: u64,
+
+    /// The first damaged record, if any
+    pub damage: Option<Damage>,
+}
+
+/// What a repair did to a single segment
+#[derive(Debug)]
+pub struct SegmentRepair {
+    pub gen: u64,
+    pub path: PathBuf,
+
+    /// Damaged records that were dropped, along with everything up to the next
+    /// intact record
+    pub damage: Vec<Damage>,
+
+    /// Number of records that were kept
+    pub records: u64,
+
+    /// Number of bytes that were dropped
+    pub dropped: u64,
+}
+
+impl KvStore {
+    /// Every segment of a log along with its generation, oldest first
+    ///
+    /// A log written before segments existed counts as the first segment.
+    pub fn segments(path: impl Into<PathBuf>) -> Result<Vec<(u64, PathBuf)>> {
+        let log_dir = path.into();
+        let gens = segment_gens(&log_dir)?;
+
+        let legacy_log = log_dir.join(Self::LEGACY_LOG_NAME);
+        if gens.is_empty() && legacy_log.exists() {
+            return Ok(vec![(1, legacy_log)]);
+        }
+        if gens.is_empty() {
+            return Err(Error::Generic(format!(
+                "No store found in {}",
+                log_dir.display()
+            )));
+        }
+
+        Ok(gens
+            .into_iter()
+            .map(|gen| (gen, segment_path(&log_dir, gen)))
+            .collect())
+    }
+
+    /// Walk every record of a log, without opening it as a store
+    ///
+    /// Hints are ignored, so every segment is read in full. No lock is taken,
+    /// so the log can be inspected while a store has it open, in which case the
+    /// end of the active segment may show up as torn.
+    pub fn inspect(path: impl Into<PathBuf>) -> Result<LogReport> {
+        let log_dir = path.into();
+
+        let mut store = BTreeMap::new();
+        let mut uncompacted = Uncompacted::default();
+        let mut writes: HashMap<Vec<u8>, u64> = HashMap::new();
+        let mut segments = Vec::new();
+
+        let all_segments = Self::segments(&log_dir)?;
+        let active_gen = all_segments.last().map(|(gen, _)| *gen);
+        for (gen, path) in all_segments {
+            let mut reader = SegmentReader::open(&path)?;
+            let mut report = SegmentReport {
+                gen,
+                path,
+                version: reader.version(),
+                size: reader.size(),
+                records: 0,
+                live_bytes: 0,
+                dead_bytes: 0,
+                damage: None,
+            };
+
+            while let Some(res) = reader.next_command() {
+                let (pos, size, command) = match res {
+                    Ok(record) => record,
+                    // Only the active segment is cut short when it is opened
+                    Err(mut damage) => {
+                        damage.torn &= Some(gen) == active_gen;
+                        report.damage = Some(damage);
+                        break;
+                    }
+                };
+
+                count_writes(&command, &mut writes);
+                let index = CommandIndex {
+                    gen,
+                    pos,
+                    size,
+                    expires_at: command.expires_at(),
+                };
+                process_command(command, index, &mut store, &mut uncompacted);
+
+                report.records += 1;
+                report.live_bytes += size as u64;
+            }
+
+            segments.push(report);
+        }
+
+        // Only now is it known which records were superseded later on
+        for report in &mut segments {
+            report.dead_bytes = uncompacted.get(report.gen) as u64;
+            report.live_bytes -= report.dead_bytes;
+        }
+
+        Ok(LogReport {
+            segments,
+            live_keys: store.len() as u64,
+            duplicate_keys: writes.into_iter().filter(|(_, n)| *n > 1).collect(),
+        })
+    }
+
+    /// Cut every damaged segment short at its first damaged record
+    ///
+    /// Everything from the damaged record on is lost, including intact records
+    /// past it; `salvage` keeps those. A key whose latest write is cut off
+    /// comes back with the value it had before, so truncating anything but the
+    /// torn tail of the active segment is refused unless `allow_stale` is set.
+    /// The store must not be open.
+    ///
+    /// Returns the segments that were truncated.
+    pub fn truncate_damaged(
+        path: impl Into<PathBuf>,
+        allow_stale: bool,
+    ) -> Result<Vec<SegmentRepair>> {
+        let log_dir = path.into();
+        let _lock = DirLock::exclusive(&log_dir)?;
+
+        let report = Self::inspect(&log_dir)?;
+        let damaged = report
+            .segments
+            .iter()
+            .find(|segment| segment.damage.as_ref().is_some_and(|damage| !damage.torn));
+        if let (Some(segment), false) = (damaged, allow_stale) {
+            return Err(Error::Generic(format!(
+                "Damage in {} would be cut off along with the records past it, which may bring back old values of their keys; stale values have to be allowed to truncate it",
+                segment.path.display()
+            )));
+        }
+
+        let mut repairs = Vec::new();
+        for (gen, path) in Self::segments(&log_dir)? {
+            let mut reader = SegmentReader::open(&path)?;
+            let mut records = 0;
+            let mut damage = None;
+            while let Some(res) = reader.next_command() {
+                match res {
+                    Ok(_) => records += 1,
+                    Err(e) => damage = Some(e),
+                }
+            }
+            let damage = match damage {
+                Some(damage) => damage,
+                None => continue,
+            };
+
+            // The hint may point at records that are about to go away
+            remove_hint(&log_dir, gen)?;
+
+            // Segments may be hard-linked into backups, so the intact part is
+            // copied to a new file that replaces the segment, rather than
+            // truncated in place
+            let tmp_path = path.with_extension("log.tmp");
+            let mut file = File::create(&tmp_path)?;
+            io::copy(&mut File::open(&path)?.take(damage.offset), &mut file)?;
+            file.sync_all()?;
+            drop(file);
+            fs::rename(&tmp_path, &path)?;
+            sync_dir(&log_dir)?;
+
+            log::warn!(
+                "Truncated {} at offset {}: {}",
+                path.display(),
+                damage.offset,
+                damage.reason
+            );
+
+            repairs.push(SegmentRepair {
+                gen,
+                path,
+                dropped: reader.size() - damage.offset,
+                damage: vec![damage],
+                records,
+            });
+        }
+
+        Ok(repairs)
+    }
+
+    /// Copy every intact record of a log to a new store in `dest`
+    ///
+    /// Damaged regions are skipped over, and copying carries on with the next
+    /// intact record past them. A key whose latest write was lost, such as a
+    /// removal, comes back with the value it had before, so skipping damage
+    /// anywhere but in the torn tail of the active segment is refused unless
+    /// `allow_stale` is set. Segments get a header in the current format on the
+    /// way. `dest` must not hold a store already; the original log is left as
+    /// it is.
+    ///
+    /// Returns the segments that had damage skipped over.
+    pub fn salvage(
+        path: impl Into<PathBuf>,
+        dest: impl Into<PathBuf>,
+        allow_stale: bool,
+    ) -> Result<Vec<SegmentRepair>> {
+        let log_dir = path.into();
+        let dest = dest.into();
+
+        if Self::is_log_present(&dest) {
+            return Err(Error::Generic(format!(
+                "A store already exists in {}",
+                dest.display()
+            )));
+        }
+
+        let _lock = DirLock::shared(&log_dir)?;
+
+        // A torn tail is dropped when the store is opened anyway
+        let report = Self::inspect(&log_dir)?;
+        let damaged = report
+            .segments
+            .iter()
+            .find(|segment| segment.damage.as_ref().is_some_and(|damage| !damage.torn));
+        if let (Some(segment), false) = (damaged, allow_stale) {
+            return Err(Error::Generic(format!(
+                "Damage in {} would be skipped, which may bring back old values of the keys it held; stale values have to be allowed to salvage it",
+                segment.path.display()
+            )));
+        }
+
+        fs::create_dir_all(&dest)?;
+        let dest_lock = DirLock::exclusive(&dest)?;
+
+        let mut repairs = Vec::new();
+        for (gen, path) in Self::segments(&log_dir)? {
+            let mut reader = SegmentReader::open(&path)?;
+            let mut repair = SegmentRepair {
+                gen,
+                path,
+                damage: Vec::new(),
+                records: 0,
+                dropped: 0,
+            };
+
+            let mut writer = BufWriter::new(File::create(segment_path(&dest, gen))?);
+            writer.write_all(&encode_header(gen)?)?;
+
+            loop {
+                match reader.next_command() {
+                    // Records are framed anew, as a `kvs.log` has no checksums
+                    Some(Ok((_, _, command))) => {
+                        writer.write_all(&encode_record(&command)?)?;
+                        repair.records += 1;
+                    }
+                    Some(Err(damage)) => {
+                        let end = reader.skip_damage().unwrap_or_else(|| reader.size());
+                        repair.dropped += end - damage.offset;
+                        repair.damage.push(damage);
+                    }
+                    None => break,
+                }
+            }
+
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+
+            if !repair.damage.is_empty() {
+                log::warn!(
+                    "Skipped {} damaged bytes in {}",
+                    repair.dropped,
+                    repair.path.display()
+                );
+                repairs.push(repair);
+            }
+        }
+        sync_dir(&dest)?;
+        drop(dest_lock);
+
+        // Make sure the salvaged log opens cleanly
+        drop(KvStoreOptions::new().read_only(true).open(&dest)?);
+
+        Ok(repairs)
+    }
+}
+
+// Count the commands that write to each key
+fn count_writes(command: &Command, writes: &mut HashMap<Vec<u8>, u64>) {
+    match command {
+        Command::Set(key, _, _) | Command::Remove(key) => {
+            *writes.entry(key.clone()).or_insert(0) += 1;
+        }
+        Command::Batch(commands) => {
+            for command in commands {
+                count_writes(command, writes);
+            }
+        }
+        Command::Get(_) => (),
+    }
+}
+
+fn reason(err: Error) -> String {
+    match err {
+        Error::Corruption { reason, .. } => reason,
+        err => err.to_string(),
+    }
+}
@@ -0,0 +1,832 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::RangeBounds;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::engine::lock::DirLock;
+use crate::engine::log::{sync_dir, GroupCommit, PeriodicSync, ReadAt};
+use crate::engine::{
+    expires_at, now_millis, BatchOp, ConditionFailed, ConditionalResult, Durability, KvsEngine,
+    KvsIterator, WriteBatch,
+};
+use crate::error::{Error, Result};
+
+mod backup;
+mod compaction;
+mod header;
+mod hint;
+mod inspect;
+mod options;
+mod record;
+mod scan;
+mod segment;
+mod upgrade;
+
+use compaction::Compaction;
+use header::{read_header, FORMAT_VERSION};
+use hint::load_hint;
+pub use inspect::{
+    Damage, LogCommand, LogRecord, LogReport, SegmentReader, SegmentRepair, SegmentReport,
+};
+pub use options::KvStoreOptions;
+use record::{encode_record, read_record};
+use scan::ScanIter;
+use segment::{
+    create_segment, new_segment_writer, open_segment_file, process_command, remove_leftovers,
+    replay_segment, segment_gens, segment_path, Uncompacted,
+};
+
+// A single entry in the log
+//
+// Keys and values are stored as msgpack binaries. Logs written when they were
+// still strings decode just the same.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) enum Command {
+    Get(#[serde(with = "serde_bytes")] Vec<u8>),
+    Set(
+        #[serde(with = "serde_bytes")] Vec<u8>,
+        #[serde(with = "serde_bytes")] Vec<u8>,
+        // Expiry time in milliseconds since the Unix epoch, missing from older logs
+        #[serde(default)] Option<u64>,
+    ),
+    Remove(#[serde(with = "serde_bytes")] Vec<u8>),
+    // A write batch, which only ever contains `Set` and `Remove` commands
+    Batch(Vec<Command>),
+}
+
+impl Command {
+    fn expires_at(&self) -> Option<u64> {
+        match self {
+            Command::Set(_, _, expires_at) => *expires_at,
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct CommandIndex {
+    // Generation of the segment containing the command
+    gen: u64,
+    pos: usize,
+
+    // Size of the record
+    // For a command in a batch, this is its share of the batch record instead,
+    // so that the whole record is uncompacted once every command in it is.
+    size: usize,
+
+    // Expiry time of the key, if it has one
+    expires_at: Option<u64>,
+}
+
+impl CommandIndex {
+    #[inline]
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// Log-structured store
+///
+/// The log is split into numbered segments (`kvs-<gen>.log`), each starting with
+/// a header that records the version of the format it is in. Writes are only
+/// ever appended to the segment with the highest generation; once it reaches
+/// the maximum segment size, writes roll over to a new segment and the old one
+/// becomes immutable.
+///
+/// Logs in an older format have to be brought up to date with `upgrade` before
+/// they can be opened.
+///
+/// Compaction of the immutable segments runs on a background thread.
+///
+/// Handles are cheap to clone and can be shared across threads. Reads run
+/// concurrently with each other and with a single writer.
+///
+/// A store can only be open for writing in one process at a time, which holds
+/// a lock on the `LOCK` file in its directory. Read-only opens share the lock
+/// with each other, and never create the file.
+///
+/// How much of the log survives power loss depends on the `Durability` policy.
+/// Compaction always syncs its output before deleting anything, whatever the
+/// policy.
+#[derive(Clone)]
+pub struct KvStore {
+    inner: Arc<KvStoreInner>,
+
+    // Shared by all handles; dropped along with the last one
+    _closer: Arc<Closer>,
+}
+
+// State shared with the background compaction thread
+//
+// Locks are always taken in the order: `writer`, `store`, `readers`.
+struct KvStoreInner {
+    // Directory containing the log
+    log_dir: PathBuf,
+
+    // In-memory store index
+    // Each entry contains a segment, position and length
+    store: RwLock<BTreeMap<Vec<u8>, CommandIndex>>,
+
+    // Every segment, keyed by generation
+    // Reads use positional I/O, so a single file can be shared by all readers
+    readers: RwLock<BTreeMap<u64, Arc<File>>>,
+
+    writer: Mutex<LogWriter>,
+
+    options: KvStoreOptions,
+
+    // Only used with `Durability::GroupCommit`
+    group_commit: GroupCommit,
+}
+
+struct LogWriter {
+    // Writer for the active segment
+    // Never written to if the store is read-only
+    writer: BufWriter<File>,
+
+    // Generation of the active segment
+    current_gen: u64,
+
+    // Current position in the active segment
+    // Used for the index
+    log_pos: usize,
+
+    // Number of bytes written to the log through this handle, across segments
+    // Used to tell which writes a sync covered
+    written: u64,
+
+    // Total size of all segments
+    log_size: usize,
+
+    uncompacted: Uncompacted,
+
+    // Keys with a TTL, by expiry time
+    // Entries for keys that were overwritten or removed since are skipped
+    expiring: BTreeSet<(u64, Vec<u8>)>,
+
+    // Background compaction, if one was started
+    compaction: Option<JoinHandle<()>>,
+}
+
+impl KvStore {
+    const LOG_PREFIX: &'static str = "kvs";
+    const LEGACY_LOG_NAME: &'static str = "kvs.log";
+    const MAX_UNCOMPACTED: usize = 1024 * 1024; // 1 MB
+    const MAX_SEGMENT_SIZE: usize = 4 * 1024 * 1024; // 4 MB
+    const BUFFER_SIZE: usize = 8 * 1024; // 8 KB
+
+    /// Returns `true` if a log already exists
+    pub fn is_log_present(path: impl Into<PathBuf>) -> bool {
+        let log_dir = path.into();
+
+        if log_dir.join(Self::LEGACY_LOG_NAME).exists() {
+            return true;
+        }
+
+        match segment_gens(&log_dir) {
+            Ok(gens) => !gens.is_empty(),
+            Err(_) => false,
+        }
+    }
+
+    /// Open an existing log or create a new one.
+    ///
+    /// Writes are never synced; use `KvStoreOptions` for anything else.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        KvStoreOptions::new().open(path)
+    }
+
+    /// Open an existing log or create a new one, syncing writes according to
+    /// the given policy
+    pub fn open_with_durability(path: impl Into<PathBuf>, durability: Durability) -> Result<Self> {
+        KvStoreOptions::new().durability(durability).open(path)
+    }
+
+    fn open_with_options(path: impl Into<PathBuf>, mut options: KvStoreOptions) -> Result<Self> {
+        let log_dir = path.into();
+
+        if !(0.0..=1.0).contains(&options.compaction_ratio) {
+            return Err(Error::Generic(format!(
+                "Invalid compaction ratio: {}",
+                options.compaction_ratio
+            )));
+        }
+
+        let exists = Self::is_log_present(&log_dir);
+        if !exists && (options.read_only || !options.create_if_missing) {
+            return Err(Error::Generic(format!(
+                "No store found in {}",
+                log_dir.display()
+            )));
+        }
+        if exists && options.error_if_exists {
+            return Err(Error::Generic(format!(
+                "A store already exists in {}",
+                log_dir.display()
+            )));
+        }
+
+        // Only one process may write to the store at a time
+        let lock = if options.read_only {
+            DirLock::shared(&log_dir)?
+        } else {
+            Some(DirLock::exclusive(&log_dir)?)
+        };
+
+        // There is nothing to sync if nothing is ever written
+        if options.read_only {
+            options.durability = Durability::None;
+        } else if exists {
+            remove_leftovers(&log_dir)?;
+        }
+
+        let mut gens = segment_gens(&log_dir)?;
+
+        // A log written before segments existed has no header either
+        if gens.is_empty() && log_dir.join(Self::LEGACY_LOG_NAME).exists() {
+            return Err(Error::UnsupportedVersion {
+                found: 0,
+                expected: FORMAT_VERSION,
+            });
+        }
+
+        // Keep appending to the most recent segment
+        let current_gen = gens.last().cloned().unwrap_or(1);
+        let writer = if gens.is_empty() {
+            let (writer, _) = create_segment(&log_dir, current_gen, &options)?;
+            if options.durability != Durability::None {
+                sync_dir(&log_dir)?;
+            }
+            gens.push(current_gen);
+            writer
+        } else if options.read_only {
+            BufWriter::new(open_segment_file(&log_dir, current_gen)?)
+        } else {
+            new_segment_writer(&log_dir, current_gen, &options)?
+        };
+
+        // Load existing log entries into memory, oldest segment first. Compacted
+        // segments come with a hint, so only the rest of the log is replayed.
+        let mut store = BTreeMap::new();
+        let mut uncompacted = Uncompacted::default();
+        let mut readers = BTreeMap::new();
+        let mut log_pos = 0;
+        let mut log_size = 0;
+
+        for gen in gens {
+            // Every segment has to be in a format we know, hinted or not
+            let file = open_segment_file(&log_dir, gen)?;
+            let (_, header_size) = read_header(&mut ReadAt::new(&file, 0))
+                .map_err(|e| e.in_file(&segment_path(&log_dir, gen)))?;
+
+            let hinted =
+                gen != current_gen && load_hint(&log_dir, gen, &mut store, &mut uncompacted)?;
+            if !hinted {
+                log_pos = replay_segment(
+                    &log_dir,
+                    gen,
+                    header_size,
+                    gen == current_gen,
+                    &options,
+                    &mut store,
+                    &mut uncompacted,
+                )?;
+            }
+
+            log_size += file.metadata()?.len() as usize;
+            readers.insert(gen, Arc::new(file));
+        }
+
+        let expiring = store
+            .iter()
+            .filter_map(|(key, index)| index.expires_at.map(|ts| (ts, key.clone())))
+            .collect();
+
+        // We are now at the end of the log - pos = len(active segment)
+        let writer = LogWriter {
+            writer,
+            current_gen,
+            log_pos,
+            written: 0,
+            log_size,
+            uncompacted,
+            expiring,
+            compaction: None,
+        };
+
+        let durability = options.durability;
+        let inner = Arc::new(KvStoreInner {
+            log_dir,
+            store: RwLock::new(store),
+            readers: RwLock::new(readers),
+            writer: Mutex::new(writer),
+            options,
+            group_commit: GroupCommit::default(),
+        });
+
+        let periodic_sync = match durability {
+            Durability::Periodic(interval) => Some(PeriodicSync::start(
+                Arc::downgrade(&inner),
+                interval,
+                KvStoreInner::sync_active,
+            )),
+            _ => None,
+        };
+
+        Ok(Self {
+            inner: inner.clone(),
+            _closer: Arc::new(Closer {
+                inner,
+                _periodic_sync: periodic_sync,
+                _lock: lock,
+            }),
+        })
+    }
+}
+
+impl KvStoreInner {
+    // Reject writes the store does not accept
+    fn check_write(&self, key: &[u8], value: Option<&[u8]>) -> Result<()> {
+        if self.options.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        check_size("Key", key, self.options.max_key_size)?;
+        if let Some(value) = value {
+            check_size("Value", value, self.options.max_value_size)?;
+        }
+
+        Ok(())
+    }
+
+    // Append a single command to the active segment
+    //
+    // Returns the location of the record that was written. Under
+    // `Durability::GroupCommit`, the caller still has to `commit` once it lets
+    // go of the writer.
+    fn write_command(&self, writer: &mut LogWriter, command: &Command) -> Result<CommandIndex> {
+        let buf = encode_record(command)?;
+        writer.writer.write_all(&buf)?;
+        writer.writer.flush()?;
+
+        if self.options.durability == Durability::Sync {
+            writer.writer.get_ref().sync_data()?;
+        }
+        writer.written += buf.len() as u64;
+        writer.log_size += buf.len();
+
+        let index = CommandIndex {
+            gen: writer.current_gen,
+            pos: writer.log_pos,
+            size: buf.len(),
+            expires_at: command.expires_at(),
+        };
+
+        // We wrote the record header and the command
+        writer.log_pos += index.size;
+
+        // Roll over to a new segment once the active one is full
+        if writer.log_pos >= self.options.max_segment_size {
+            self.open_segment(writer, writer.current_gen + 1)?;
+        }
+
+        Ok(index)
+    }
+
+    // Write a `Set` to the log and point the index at it
+    fn set(
+        &self,
+        writer: &mut LogWriter,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        // Insert this command into the log
+        let command = Command::Set(key.clone(), value, expires_at);
+        let index = self.write_command(writer, &command)?;
+
+        if let Some(ts) = expires_at {
+            writer.expiring.insert((ts, key.clone()));
+        }
+
+        // Store the key in the in-memory index
+        if let Some(old) = self.store.write()?.insert(key, index) {
+            // Mark the old bytes as being compactable
+            writer.uncompacted.mark(old.gen, old.size);
+        }
+
+        Ok(())
+    }
+
+    // Write a `Remove` to the log and drop the key from the index
+    fn remove(&self, writer: &mut LogWriter, key: Vec<u8>) -> Result<()> {
+        // Write this remove command to the log
+        let command = Command::Remove(key.clone());
+        let index = self.write_command(writer, &command)?;
+
+        // Both the old command and this removal can be cleaned up during
+        // compaction
+        if let Some(old) = self.store.write()?.remove(&key) {
+            writer.uncompacted.mark(old.gen, old.size);
+        }
+        writer.uncompacted.mark(index.gen, index.size);
+
+        Ok(())
+    }
+
+    // Make a new, empty segment the active one
+    //
+    // Only the active segment is ever synced after this, so the old one is
+    // synced on the way out.
+    fn open_segment(&self, writer: &mut LogWriter, gen: u64) -> Result<()> {
+        writer.writer.flush()?;
+        if self.options.durability != Durability::None {
+            writer.writer.get_ref().sync_data()?;
+        }
+
+        let (new_writer, header_size) = create_segment(&self.log_dir, gen, &self.options)?;
+        writer.writer = new_writer;
+        if self.options.durability != Durability::None {
+            sync_dir(&self.log_dir)?;
+        }
+
+        let file = open_segment_file(&self.log_dir, gen)?;
+        self.readers.write()?.insert(gen, Arc::new(file));
+
+        writer.current_gen = gen;
+        writer.log_pos = header_size;
+        writer.log_size += header_size;
+
+        Ok(())
+    }
+
+    // Sync the active segment
+    //
+    // Every other segment was synced when it was sealed, so this covers the
+    // whole log. Returns how many bytes were written when the sync started.
+    fn sync_active(&self) -> Result<u64> {
+        let (written, file) = {
+            let writer = self.writer.lock()?;
+            (writer.written, writer.writer.get_ref().try_clone()?)
+        };
+
+        file.sync_data()?;
+
+        Ok(written)
+    }
+
+    // Wait for writes up to `written` to be synced, under group commit
+    //
+    // Must be called without holding the writer, so that other writers can
+    // join the next sync.
+    fn commit(&self, written: u64) -> Result<()> {
+        if self.options.durability != Durability::GroupCommit {
+            return Ok(());
+        }
+
+        self.group_commit.wait(written, || self.sync_active())
+    }
+
+    // Drop every key that has expired from the index
+    //
+    // Their records become uncompacted bytes, so the next compaction reclaims them.
+    fn purge_expired(&self, writer: &mut LogWriter) -> Result<()> {
+        let now = now_millis();
+        if writer.expiring.first().is_none_or(|(ts, _)| *ts > now) {
+            return Ok(());
+        }
+
+        let mut store = self.store.write()?;
+
+        while writer.expiring.first().is_some_and(|(ts, _)| *ts <= now) {
+            let (ts, key) = writer.expiring.pop_first().unwrap();
+
+            // The key may have been overwritten or removed in the meantime
+            if store.get(&key).is_some_and(|i| i.expires_at == Some(ts)) {
+                let old = store.remove(&key).unwrap();
+                writer.uncompacted.mark(old.gen, old.size);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Once we hit a certain number of uncompacted bytes, start compacting the log
+    // in the background.
+    //
+    // The active segment is sealed first, so that every segment being compacted
+    // is immutable. Only segments that contain uncompacted bytes are compacted;
+    // segments that only contain live entries are left untouched.
+    fn maybe_compact(self: &Arc<Self>, writer: &mut LogWriter) -> Result<()> {
+        // Expired keys must not point into the segments being compacted
+        self.purge_expired(writer)?;
+
+        // Compact once there is enough stale data, both in bytes and as a share
+        // of the log
+        let uncompacted = writer.uncompacted.total();
+        if uncompacted <= self.options.compaction_threshold
+            || (uncompacted as f64) < self.options.compaction_ratio * writer.log_size as f64
+        {
+            return Ok(());
+        }
+
+        // Only a single compaction runs at a time
+        if let Some(handle) = writer.compaction.take() {
+            if !handle.is_finished() {
+                writer.compaction = Some(handle);
+                return Ok(());
+            }
+
+            let _ = handle.join();
+        }
+
+        let inputs = writer.uncompacted.gens();
+
+        // Reserve one generation per input segment for the compacted output; it
+        // can never be larger than the input
+        let first_gen = writer.current_gen + 1;
+        let last_gen = writer.current_gen + inputs.len() as u64;
+
+        // All new writes go to a fresh segment
+        self.open_segment(writer, last_gen + 1)?;
+
+        // Writes to the input segments from now on are tracked separately
+        let uncompacted = inputs
+            .iter()
+            .map(|gen| (*gen, writer.uncompacted.take(*gen)))
+            .collect();
+
+        let entries = self
+            .store
+            .read()?
+            .iter()
+            .filter(|(_, index)| inputs.binary_search(&index.gen).is_ok())
+            .map(|(key, index)| (key.clone(), index.clone()))
+            .collect();
+
+        let compaction = Compaction::new(inputs, uncompacted, first_gen, last_gen, entries);
+
+        let inner = self.clone();
+        let handle = thread::spawn(move || {
+            if let Err(e) = compaction.run(&inner) {
+                log::error!("Compaction failed: {}", e);
+            }
+        });
+
+        writer.compaction = Some(handle);
+
+        Ok(())
+    }
+}
+
+fn check_size(what: &str, buf: &[u8], max: usize) -> Result<()> {
+    if buf.len() > max {
+        return Err(Error::TooLarge {
+            what: what.to_owned(),
+            size: buf.len() as u64,
+            max: max as u64,
+        });
+    }
+    Ok(())
+}
+
+struct Closer {
+    inner: Arc<KvStoreInner>,
+
+    // Stopped before anything else
+    _periodic_sync: Option<PeriodicSync>,
+
+    // Released after everything else; a read-only store may have no lock to
+    // share
+    _lock: Option<DirLock>,
+}
+
+impl Drop for Closer {
+    // Wait for any running compaction before the log can be reopened
+    fn drop(&mut self) {
+        drop(self._periodic_sync.take());
+
+        // Even if the store is poisoned, the thread has to finish first
+        let handle = self
+            .inner
+            .writer
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .compaction
+            .take();
+        if let Some(handle) = handle {
+            let _ = handle.join();
+        }
+
+        // Whatever the periodic sync did not get to yet
+        if let Durability::Periodic(_) = self.inner.options.durability {
+            if let Err(e) = self.inner.sync_active() {
+                log::error!("Syncing the log failed: {}", e);
+            }
+        }
+    }
+}
+
+impl KvStore {
+    fn set_with_expiry(&self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        self.inner.check_write(&key, Some(&value))?;
+
+        let written = {
+            let mut writer = self.inner.writer.lock()?;
+            self.inner.set(&mut writer, key, value, expires_at)?;
+            self.inner.maybe_compact(&mut writer)?;
+            writer.written
+        };
+
+        self.inner.commit(written)
+    }
+}
+
+impl KvsEngine for KvStore {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.set_with_expiry(key, value, None)
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.set_with_expiry(key, value, Some(expires_at(ttl)))
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        // Figure out the position of the value in the log. The segment is looked
+        // up while the index is still locked, as a compaction could otherwise
+        // switch the key over to another segment in between.
+        let (index, file) = {
+            let store = self.inner.store.read()?;
+
+            let index = match store.get(&key) {
+                Some(p) if !p.is_expired(now_millis()) => p.clone(),
+                _ => return Ok(None),
+            };
+
+            let file = self
+                .inner
+                .readers
+                .read()?
+                .get(&index.gen)
+                .cloned()
+                .ok_or_else(|| format!("Missing reader for segment {}", index.gen))?;
+
+            (index, file)
+        };
+
+        // Now read the command and extract the value. The index should only ever
+        // point at a write to this key, so anything else means the log is damaged.
+        let path = || segment_path(&self.inner.log_dir, index.gen);
+        let mut reader = ReadAt::new(&file, index.pos as u64);
+        let value = match read_record(&mut reader, index.pos).map_err(|e| e.in_file(&path()))? {
+            Command::Set(k, v, _) if k == key => Some(v),
+            // The index points at the last write to the key in the batch
+            Command::Batch(commands) => {
+                commands
+                    .into_iter()
+                    .rev()
+                    .find_map(|command| match command {
+                        Command::Set(k, v, _) if k == key => Some(v),
+                        _ => None,
+                    })
+            }
+            _ => None,
+        };
+
+        match value {
+            Some(value) => Ok(Some(value)),
+            None => Err(Error::Corruption {
+                file: Some(path()),
+                offset: index.pos as u64,
+                reason: "expected a set of the key".to_owned(),
+            }),
+        }
+    }
+
+    fn expiry_bytes(&self, key: Vec<u8>) -> Result<Option<u64>> {
+        let store = self.inner.store.read()?;
+        Ok(store
+            .get(&key)
+            .filter(|index| !index.is_expired(now_millis()))
+            .and_then(|index| index.expires_at))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.inner.check_write(&key, None)?;
+
+        let written = {
+            let mut writer = self.inner.writer.lock()?;
+
+            let now = now_millis();
+            let store = self.inner.store.read()?;
+            if store.get(&key).is_none_or(|index| index.is_expired(now)) {
+                return Err(Error::KeyNotFound);
+            }
+            drop(store);
+
+            self.inner.remove(&mut writer, key)?;
+            self.inner.maybe_compact(&mut writer)?;
+            writer.written
+        };
+
+        self.inner.commit(written)
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<ConditionalResult> {
+        self.inner.check_write(&key, new.as_deref())?;
+
+        let written = {
+            // Holding the writer keeps the key from changing under us
+            let mut writer = self.inner.writer.lock()?;
+
+            let current = self.get_bytes(key.clone())?;
+            if current != expected {
+                return Ok(Err(ConditionFailed { current }));
+            }
+
+            match new {
+                Some(value) => self.inner.set(&mut writer, key, value, None)?,
+                None if current.is_some() => self.inner.remove(&mut writer, key)?,
+                None => return Ok(Ok(())),
+            }
+
+            self.inner.maybe_compact(&mut writer)?;
+            writer.written
+        };
+
+        self.inner.commit(written)?;
+
+        Ok(Ok(()))
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        for op in batch.ops() {
+            match op {
+                BatchOp::Set(key, value, _) => self.inner.check_write(key, Some(value))?,
+                BatchOp::Remove(key) => self.inner.check_write(key, None)?,
+            }
+        }
+
+        let commands = batch
+            .into_ops()
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set(key, value, ttl) => Command::Set(key, value, ttl.map(expires_at)),
+                BatchOp::Remove(key) => Command::Remove(key),
+            })
+            .collect();
+
+        let written = {
+            let mut writer = self.inner.writer.lock()?;
+
+            // The whole batch goes into a single record, so it is either
+            // replayed in full or not at all
+            let command = Command::Batch(commands);
+            let index = self.inner.write_command(&mut writer, &command)?;
+
+            if let Command::Batch(commands) = &command {
+                for command in commands {
+                    if let Command::Set(key, _, Some(ts)) = command {
+                        writer.expiring.insert((*ts, key.clone()));
+                    }
+                }
+            }
+
+            {
+                let mut store = self.inner.store.write()?;
+                process_command(command, index, &mut store, &mut writer.uncompacted);
+            }
+
+            self.inner.maybe_compact(&mut writer)?;
+            writer.written
+        };
+
+        self.inner.commit(written)
+    }
+
+    fn scan_bytes(&self, range: impl RangeBounds<Vec<u8>>) -> Result<KvsIterator> {
+        let start = range.start_bound().cloned();
+        let end = range.end_bound().cloned();
+
+        Ok(Box::new(ScanIter::new(self.clone(), start, end)))
+    }
+
+    fn backup(&self, dest: impl Into<PathBuf>) -> Result<()> {
+        self.inner.backup(&dest.into())
+    }
+}
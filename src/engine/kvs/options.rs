@@ -0,0 +1,132 @@
+use std::path::PathBuf;
+
+use super::KvStore;
+use crate::engine::Durability;
+use crate::error::Result;
+
+/// Options for opening a `KvStore`
+///
+/// Setters can be chained, after which `open` opens the store:
+/// `KvStoreOptions::new().read_only(true).open(path)`.
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    pub(super) compaction_threshold: usize,
+    pub(super) compaction_ratio: f64,
+    pub(super) max_segment_size: usize,
+    pub(super) create_if_missing: bool,
+    pub(super) error_if_exists: bool,
+    pub(super) read_only: bool,
+    pub(super) durability: Durability,
+    pub(super) write_buffer_size: usize,
+    pub(super) read_buffer_size: usize,
+    pub(super) max_key_size: usize,
+    pub(super) max_value_size: usize,
+}
+
+impl KvStoreOptions {
+    pub fn new() -> Self {
+        Self {
+            compaction_threshold: KvStore::MAX_UNCOMPACTED,
+            compaction_ratio: 0.0,
+            max_segment_size: KvStore::MAX_SEGMENT_SIZE,
+            create_if_missing: true,
+            error_if_exists: false,
+            read_only: false,
+            durability: Durability::None,
+            write_buffer_size: KvStore::BUFFER_SIZE,
+            read_buffer_size: KvStore::BUFFER_SIZE,
+            max_key_size: usize::MAX,
+            max_value_size: usize::MAX,
+        }
+    }
+
+    /// Number of bytes taken up by stale entries before the log is compacted
+    ///
+    /// Defaults to 1 MB.
+    pub fn compaction_threshold(&mut self, bytes: usize) -> &mut Self {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// Smallest share of the log that has to be stale before it is compacted,
+    /// from 0.0 to 1.0
+    ///
+    /// Applies on top of the threshold. Defaults to 0.0, so that only the
+    /// threshold counts.
+    pub fn compaction_ratio(&mut self, ratio: f64) -> &mut Self {
+        self.compaction_ratio = ratio;
+        self
+    }
+
+    /// Size at which writes roll over to a new segment
+    ///
+    /// Defaults to 4 MB.
+    pub fn max_segment_size(&mut self, bytes: usize) -> &mut Self {
+        self.max_segment_size = bytes;
+        self
+    }
+
+    /// Create a new store if there is none yet; defaults to `true`
+    pub fn create_if_missing(&mut self, create_if_missing: bool) -> &mut Self {
+        self.create_if_missing = create_if_missing;
+        self
+    }
+
+    /// Fail to open if there already is a store; defaults to `false`
+    pub fn error_if_exists(&mut self, error_if_exists: bool) -> &mut Self {
+        self.error_if_exists = error_if_exists;
+        self
+    }
+
+    /// Open an existing store without ever writing to it; defaults to `false`
+    ///
+    /// Writes return `Error::ReadOnly`, and nothing on disk is touched, not even
+    /// to drop a torn write at the end of the log. Any number of processes can
+    /// open a store read-only at once, as long as none has it open for writing.
+    pub fn read_only(&mut self, read_only: bool) -> &mut Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// When writes are synced to disk; defaults to `Durability::None`
+    pub fn durability(&mut self, durability: Durability) -> &mut Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Size of the buffer used to append to the log; defaults to 8 KB
+    pub fn write_buffer_size(&mut self, bytes: usize) -> &mut Self {
+        self.write_buffer_size = bytes;
+        self
+    }
+
+    /// Size of the buffer used to read through the log when opening and
+    /// compacting it; defaults to 8 KB
+    pub fn read_buffer_size(&mut self, bytes: usize) -> &mut Self {
+        self.read_buffer_size = bytes;
+        self
+    }
+
+    /// Largest key that can be written; unlimited by default
+    pub fn max_key_size(&mut self, bytes: usize) -> &mut Self {
+        self.max_key_size = bytes;
+        self
+    }
+
+    /// Largest value that can be written; unlimited by default
+    pub fn max_value_size(&mut self, bytes: usize) -> &mut Self {
+        self.max_value_size = bytes;
+        self
+    }
+
+    /// Open the store in the given directory with these options
+    pub fn open(&self, path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, self.clone())
+    }
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
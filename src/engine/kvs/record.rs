@@ -0,0 +1,38 @@
+use std::io::Read;
+
+use super::Command;
+use crate::engine::log::{encode_frame, read_frame};
+use crate::error::{Error, Result};
+
+// Serialize a command into a framed log record
+pub(super) fn encode_record(command: &Command) -> Result<Vec<u8>> {
+    let payload = rmp_serde::to_vec(command)?;
+    Ok(encode_frame(&payload))
+}
+
+// Read the full record starting at `pos` and verify its checksum
+pub(super) fn read_record(reader: &mut impl Read, pos: usize) -> Result<Command> {
+    let buf = read_frame(reader, pos)?;
+    decode_command(&buf, pos)
+}
+
+// Size of the header of a record in the original `kvs.log` format: just the
+// command size (u64), with no checksums
+pub(super) const LEGACY_RECORD_HEADER_SIZE: usize = 8;
+
+// Read the size of the command in a record of the original `kvs.log` format
+pub(super) fn read_command_size(reader: &mut impl Read) -> Result<u64> {
+    let mut size = [0u8; LEGACY_RECORD_HEADER_SIZE];
+    reader.read_exact(&mut size)?;
+
+    Ok(u64::from_le_bytes(size))
+}
+
+// A record whose checksum matches but does not decode is still corrupt
+pub(super) fn decode_command(buf: &[u8], pos: usize) -> Result<Command> {
+    rmp_serde::from_slice(buf).map_err(|e| Error::Corruption {
+        file: None,
+        offset: pos as u64,
+        reason: e.to_string(),
+    })
+}
@@ -0,0 +1,116 @@
+use std::collections::VecDeque;
+use std::ops::Bound;
+
+use super::KvStore;
+use crate::engine::{is_empty_range, KvsEngine};
+use crate::error::Result;
+
+// Lazy iterator over a range of keys in a `KvStore`
+//
+// Keys are pulled from the index in batches, so the index is only locked for
+// short periods of time. Values are read from the log as the iterator advances;
+// keys removed in the meantime are skipped.
+pub(super) struct ScanIter {
+    store: KvStore,
+
+    // Part of the range that has not been pulled from the index yet
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+
+    // Keys pulled from either end of the range
+    front: VecDeque<Vec<u8>>,
+    back: VecDeque<Vec<u8>>,
+}
+
+impl ScanIter {
+    const BATCH_SIZE: usize = 256;
+
+    pub(super) fn new(store: KvStore, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> Self {
+        Self {
+            store,
+            start,
+            end,
+            front: VecDeque::new(),
+            back: VecDeque::new(),
+        }
+    }
+
+    // Pull the next batch of keys from the index
+    fn fill(&mut self, reverse: bool) -> Result<()> {
+        if is_empty_range(&self.start, &self.end) {
+            return Ok(());
+        }
+
+        let store = self.store.inner.store.read()?;
+        let range = store.range::<Vec<u8>, _>((self.start.clone(), self.end.clone()));
+
+        if reverse {
+            self.back = range
+                .rev()
+                .take(Self::BATCH_SIZE)
+                .map(|(k, _)| k.clone())
+                .collect();
+            if let Some(first) = self.back.back() {
+                self.end = Bound::Excluded(first.clone());
+            }
+        } else {
+            self.front = range
+                .take(Self::BATCH_SIZE)
+                .map(|(k, _)| k.clone())
+                .collect();
+            if let Some(last) = self.front.back() {
+                self.start = Bound::Excluded(last.clone());
+            }
+        }
+
+        Ok(())
+    }
+
+    // Read the value for a key, if it still exists
+    fn read(&self, key: Vec<u8>) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        match self.store.get_bytes(key.clone()) {
+            Ok(Some(value)) => Some(Ok((key, value))),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+impl Iterator for ScanIter {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.front.is_empty() {
+                if let Err(e) = self.fill(false) {
+                    return Some(Err(e));
+                }
+            }
+
+            // Once the range is used up, whatever was pulled from the back is next
+            let key = self.front.pop_front().or_else(|| self.back.pop_back())?;
+
+            if let Some(item) = self.read(key) {
+                return Some(item);
+            }
+        }
+    }
+}
+
+impl DoubleEndedIterator for ScanIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.back.is_empty() {
+                if let Err(e) = self.fill(true) {
+                    return Some(Err(e));
+                }
+            }
+
+            let key = self.back.pop_front().or_else(|| self.front.pop_back())?;
+
+            if let Some(item) = self.read(key) {
+                return Some(item);
+            }
+        }
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use super::header::encode_header;
+use super::record::decode_command;
+use super::{Command, CommandIndex, KvStore, KvStoreOptions};
+use crate::engine::log::{replay_frames, truncate_tail};
+use crate::engine::now_millis;
+use crate::error::{Error, Result};
+
+// Number of uncompacted bytes in each segment
+#[derive(Default)]
+pub(super) struct Uncompacted {
+    segments: HashMap<u64, usize>,
+
+    // Number of uncompacted bytes across all segments
+    total: usize,
+}
+
+impl Uncompacted {
+    // Record that `size` bytes in the given segment can be compacted away
+    #[inline]
+    pub(super) fn mark(&mut self, gen: u64, size: usize) {
+        *self.segments.entry(gen).or_insert(0) += size;
+        self.total += size;
+    }
+
+    // Stop tracking a segment, returning its number of uncompacted bytes
+    pub(super) fn take(&mut self, gen: u64) -> usize {
+        let size = self.segments.remove(&gen).unwrap_or(0);
+        self.total -= size;
+        size
+    }
+
+    // Generations of every segment that contains uncompacted bytes, in order
+    pub(super) fn gens(&self) -> Vec<u64> {
+        let mut gens: Vec<u64> = self
+            .segments
+            .iter()
+            .filter(|(_, size)| **size > 0)
+            .map(|(gen, _)| *gen)
+            .collect();
+        gens.sort_unstable();
+        gens
+    }
+
+    // Number of uncompacted bytes in a segment
+    pub(super) fn get(&self, gen: u64) -> usize {
+        self.segments.get(&gen).cloned().unwrap_or(0)
+    }
+
+    #[inline]
+    pub(super) fn total(&self) -> usize {
+        self.total
+    }
+}
+
+pub(super) fn segment_path(log_dir: &Path, gen: u64) -> PathBuf {
+    log_dir.join(format!("{}-{}.log", KvStore::LOG_PREFIX, gen))
+}
+
+// Returns the generations of all segments in the directory, in order
+pub(super) fn segment_gens(log_dir: &Path) -> Result<Vec<u64>> {
+    let prefix = format!("{}-", KvStore::LOG_PREFIX);
+    let mut gens = Vec::new();
+
+    if !log_dir.is_dir() {
+        return Ok(gens);
+    }
+
+    for entry in fs::read_dir(log_dir)? {
+        let name = entry?.file_name();
+        let gen = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(&prefix))
+            .and_then(|name| name.strip_suffix(".log"))
+            .and_then(|gen| gen.parse::<u64>().ok());
+
+        if let Some(gen) = gen {
+            gens.push(gen);
+        }
+    }
+
+    gens.sort_unstable();
+
+    Ok(gens)
+}
+
+// Where a segment is written before it becomes part of the log
+pub(super) fn tmp_segment_path(log_dir: &Path, gen: u64) -> PathBuf {
+    segment_path(log_dir, gen).with_extension("log.tmp")
+}
+
+// Remove what a crash may have left behind: segments that were still being
+// written, and hints whose segment never made it into the log
+pub(super) fn remove_leftovers(log_dir: &Path) -> Result<()> {
+    let prefix = format!("{}-", KvStore::LOG_PREFIX);
+
+    for entry in fs::read_dir(log_dir)? {
+        let path = entry?.path();
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) if name.starts_with(&prefix) => name,
+            _ => continue,
+        };
+
+        let leftover = if name.ends_with(".log.tmp") || name.ends_with(".hint.tmp") {
+            true
+        } else if name.ends_with(".hint") {
+            !path.with_extension("log").exists()
+        } else {
+            false
+        };
+
+        if leftover {
+            log::warn!("Removing leftover {}", path.display());
+            fs::remove_file(&path)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Create a new segment, containing only its header
+//
+// The header is written to a temporary file that is then renamed, so a segment
+// that exists always has a complete header. Returns a writer for the segment
+// along with the size of the header.
+pub(super) fn create_segment(
+    log_dir: &Path,
+    gen: u64,
+    options: &KvStoreOptions,
+) -> Result<(BufWriter<File>, usize)> {
+    let path = segment_path(log_dir, gen);
+    let tmp_path = tmp_segment_path(log_dir, gen);
+
+    let header = encode_header(gen)?;
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&header)?;
+    file.sync_data()?;
+    drop(file);
+
+    fs::rename(tmp_path, path)?;
+
+    Ok((new_segment_writer(log_dir, gen, options)?, header.len()))
+}
+
+// Open an existing segment in append mode
+pub(super) fn new_segment_writer(
+    log_dir: &Path,
+    gen: u64,
+    options: &KvStoreOptions,
+) -> Result<BufWriter<File>> {
+    let file = OpenOptions::new()
+        .append(true)
+        .open(segment_path(log_dir, gen))?;
+
+    Ok(BufWriter::with_capacity(options.write_buffer_size, file))
+}
+
+pub(super) fn new_segment_reader(
+    log_dir: &Path,
+    gen: u64,
+    options: &KvStoreOptions,
+) -> Result<BufReader<File>> {
+    let file = open_segment_file(log_dir, gen)?;
+    Ok(BufReader::with_capacity(options.read_buffer_size, file))
+}
+
+pub(super) fn open_segment_file(log_dir: &Path, gen: u64) -> Result<File> {
+    Ok(File::open(segment_path(log_dir, gen))?)
+}
+
+// Replays every record in a segment into the index, starting at the end of
+// the segment header (`start`)
+//
+// A record at the tail of the active segment that was only partially written
+// (e.g., the process crashed in the middle of a `set`) is truncated away. Any
+// other damaged record is reported as corruption, including one at the tail of
+// a sealed segment, which was complete when it was sealed. A read-only store
+// leaves the torn record in place and stops short of it instead.
+//
+// Returns the position of the end of the segment.
+pub(super) fn replay_segment(
+    log_dir: &Path,
+    gen: u64,
+    start: usize,
+    active: bool,
+    options: &KvStoreOptions,
+    store: &mut BTreeMap<Vec<u8>, CommandIndex>,
+    uncompacted: &mut Uncompacted,
+) -> Result<usize> {
+    let path = segment_path(log_dir, gen);
+    let mut reader = new_segment_reader(log_dir, gen, options)?;
+    let size = reader.get_ref().metadata()?.len() as usize;
+    reader.seek(SeekFrom::Start(start as u64))?;
+
+    let res = replay_frames(
+        &mut reader,
+        start,
+        size,
+        |pos, reason| drop_torn_tail(&path, pos, size, active, options, reason),
+        |payload, pos, record_size| {
+            let command = decode_command(payload, pos)?;
+            let index = CommandIndex {
+                gen,
+                pos,
+                size: record_size,
+                expires_at: command.expires_at(),
+            };
+            process_command(command, index, store, uncompacted);
+            Ok(())
+        },
+    )
+    .map_err(|e| e.in_file(&path));
+    if let Err(e @ Error::Corruption { .. }) = &res {
+        log::error!("{}", e);
+    }
+
+    res
+}
+
+// Deal with a record that looks torn, at `pos` in a segment of `size` bytes
+//
+// Only the active segment can have been cut short by a crash. Sealed segments
+// are never written to again, so a torn record there is reported as corruption.
+// A read-only store leaves the torn record in place.
+fn drop_torn_tail(
+    path: &Path,
+    pos: usize,
+    size: usize,
+    active: bool,
+    options: &KvStoreOptions,
+    reason: &str,
+) -> Result<()> {
+    if !active {
+        return Err(Error::Corruption {
+            file: Some(path.to_owned()),
+            offset: pos as u64,
+            reason: reason.to_owned(),
+        });
+    }
+
+    if options.read_only {
+        log::warn!(
+            "Ignoring torn record in {} at offset {} ({} bytes)",
+            path.display(),
+            pos,
+            size - pos
+        );
+        return Ok(());
+    }
+
+    truncate_tail(path, pos, size)
+}
+
+// Process a single command into the in-memory index
+#[inline]
+pub(super) fn process_command(
+    command: Command,
+    index: CommandIndex,
+    store: &mut BTreeMap<Vec<u8>, CommandIndex>,
+    uncompacted: &mut Uncompacted,
+) {
+    match command {
+        // A key that has expired since is as good as removed
+        Command::Set(key, _, _) if index.is_expired(now_millis()) => {
+            if let Some(old) = store.remove(&key) {
+                uncompacted.mark(old.gen, old.size);
+            }
+            uncompacted.mark(index.gen, index.size);
+        }
+        Command::Set(key, _, _) => {
+            if let Some(old) = store.insert(key, index) {
+                uncompacted.mark(old.gen, old.size);
+            }
+        }
+        Command::Remove(key) => {
+            if let Some(old) = store.remove(&key) {
+                uncompacted.mark(old.gen, old.size);
+            }
+
+            // The removal itself is never needed after compaction
+            uncompacted.mark(index.gen, index.size);
+        }
+        Command::Batch(commands) => {
+            // Split the record between its commands, giving the first one any
+            // remainder
+            let n = commands.len().max(1);
+            let share = index.size / n;
+            let mut remainder = index.size % n;
+
+            for command in commands {
+                let index = CommandIndex {
+                    size: share + std::mem::take(&mut remainder),
+                    expires_at: command.expires_at(),
+                    ..index
+                };
+                process_command(command, index, store, uncompacted);
+            }
+        }
+        _ => (),
+    }
+}
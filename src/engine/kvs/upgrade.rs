@@ -0,0 +1,152 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::header::{encode_header, read_version, FORMAT_VERSION};
+use super::hint::remove_hint;
+use super::record::{decode_command, encode_record, read_command_size, LEGACY_RECORD_HEADER_SIZE};
+use super::segment::{segment_gens, segment_path};
+use super::KvStore;
+use crate::engine::lock::DirLock;
+use crate::engine::log::sync_dir;
+use crate::error::{Error, Result};
+
+impl KvStore {
+    /// Upgrade a log written in an older format to the current one, in place
+    ///
+    /// Every outdated segment is copied with a header in front into a temporary
+    /// file, which then replaces it. A `kvs.log` of the original format has its
+    /// records reframed with checksums on the way. An interrupted upgrade can
+    /// simply be run again.
+    ///
+    /// Returns the number of segments that were upgraded.
+    pub fn upgrade(path: impl Into<PathBuf>) -> Result<usize> {
+        let log_dir = path.into();
+        let _lock = DirLock::exclusive(&log_dir)?;
+
+        let gens = segment_gens(&log_dir)?;
+        let mut upgraded = 0;
+
+        // A log written before segments existed becomes the first segment
+        let legacy_log = log_dir.join(Self::LEGACY_LOG_NAME);
+        if gens.is_empty() && legacy_log.exists() {
+            upgrade_legacy_log(&legacy_log, &segment_path(&log_dir, 1), 1)?;
+            sync_dir(&log_dir)?;
+            fs::remove_file(&legacy_log)?;
+            upgraded += 1;
+        } else if gens.contains(&1) && legacy_log.exists() {
+            // An earlier upgrade got as far as the first segment, but died
+            // before removing the original log
+            let first = segment_path(&log_dir, 1);
+            if read_version(&mut BufReader::new(File::open(first)?))? == FORMAT_VERSION {
+                log::info!("Removing leftover {}", legacy_log.display());
+                fs::remove_file(&legacy_log)?;
+            }
+        }
+
+        for gen in gens {
+            let path = segment_path(&log_dir, gen);
+
+            match read_version(&mut BufReader::new(File::open(&path)?))? {
+                FORMAT_VERSION => {}
+                0 => {
+                    // The hint points at positions that are about to move
+                    remove_hint(&log_dir, gen)?;
+                    upgrade_segment(&path, &path, gen)?;
+                    upgraded += 1;
+                }
+                found => {
+                    return Err(Error::UnsupportedVersion {
+                        found,
+                        expected: FORMAT_VERSION,
+                    })
+                }
+            }
+        }
+
+        sync_dir(&log_dir)?;
+
+        if upgraded > 0 {
+            log::info!("Upgraded {} segments in {}", upgraded, log_dir.display());
+        }
+
+        Ok(upgraded)
+    }
+}
+
+// Copy a headerless segment to `dst`, with a header in front
+//
+// Its records are already framed the way they are now.
+fn upgrade_segment(src: &Path, dst: &Path, gen: u64) -> Result<()> {
+    write_segment(dst, gen, |writer| {
+        io::copy(&mut BufReader::new(File::open(src)?), writer)?;
+        Ok(())
+    })
+}
+
+// Rewrite a log of the original format into a segment at `dst`
+//
+// The original log held each command's size as a u64 LE, followed by the
+// command, with no checksums. Every command is framed the way records are now.
+// A record that was cut short at the end of the log is dropped, as it would be
+// from the active segment.
+fn upgrade_legacy_log(src: &Path, dst: &Path, gen: u64) -> Result<()> {
+    let size = fs::metadata(src)?.len() as usize;
+    let mut reader = BufReader::new(File::open(src)?);
+
+    write_segment(dst, gen, |writer| {
+        let mut pos = 0;
+        let mut buf = Vec::new();
+
+        while pos < size {
+            let command_size = if size - pos < LEGACY_RECORD_HEADER_SIZE {
+                None
+            } else {
+                Some(read_command_size(&mut reader)? as usize)
+            };
+
+            let command_size = match command_size {
+                Some(n) if n <= size - pos - LEGACY_RECORD_HEADER_SIZE => n,
+                _ => {
+                    log::warn!(
+                        "Dropping torn record in {} at offset {} ({} bytes)",
+                        src.display(),
+                        pos,
+                        size - pos
+                    );
+                    break;
+                }
+            };
+
+            buf.resize(command_size, 0);
+            reader.read_exact(&mut buf)?;
+            let command = decode_command(&buf, pos).map_err(|e| e.in_file(src))?;
+            writer.write_all(&encode_record(&command)?)?;
+
+            pos += LEGACY_RECORD_HEADER_SIZE + command_size;
+        }
+
+        Ok(())
+    })
+}
+
+// Write a segment to a temporary file that then replaces `dst`, with a header
+// in front of whatever `write_records` writes
+fn write_segment(
+    dst: &Path,
+    gen: u64,
+    write_records: impl FnOnce(&mut BufWriter<File>) -> Result<()>,
+) -> Result<()> {
+    let tmp_path = dst.with_extension("log.tmp");
+
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    writer.write_all(&encode_header(gen)?)?;
+    write_records(&mut writer)?;
+    writer.flush()?;
+    writer.get_ref().sync_data()?;
+    drop(writer);
+
+    fs::rename(tmp_path, dst)?;
+
+    Ok(())
+}
@@ -0,0 +1,219 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use crate::error::{Error, Result};
+
+const LOCK_NAME: &str = "LOCK";
+
+// Directories locked by a `StoreLock` of this process
+//
+// Locks taken through separate opens of a file conflict even within a
+// process, so engines opened in one of these claim the `StoreLock`'s lock
+// instead of taking one of their own.
+static HELD: Mutex<Vec<Held>> = Mutex::new(Vec::new());
+
+struct Held {
+    dir: PathBuf,
+
+    // Whether an engine opened for writing claimed the lock, and how many
+    // read-only ones did
+    writer: bool,
+    readers: usize,
+}
+
+/// Exclusive lock on a store directory, held until dropped
+///
+/// While the lock is held, a single engine opened in the directory by this
+/// process can go ahead for writing, or any number for reading, without
+/// locking it themselves. Other processes are kept out until it is dropped.
+/// This keeps a store to ourselves across several steps, e.g. to find out
+/// which engine wrote it before opening it.
+pub struct StoreLock {
+    dir: PathBuf,
+    _lock: DirLock,
+}
+
+impl StoreLock {
+    pub fn acquire(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = held_path(&dir.into());
+        if held().iter().any(|held| held.dir == dir) {
+            return Err(Error::Locked {
+                pid: Some(std::process::id()),
+            });
+        }
+
+        let lock = DirLock::exclusive(&dir)?;
+        held().push(Held {
+            dir: dir.clone(),
+            writer: false,
+            readers: 0,
+        });
+
+        Ok(Self { dir, _lock: lock })
+    }
+}
+
+impl Drop for StoreLock {
+    fn drop(&mut self) {
+        let mut held = held();
+        if let Some(i) = held.iter().position(|held| held.dir == self.dir) {
+            held.remove(i);
+        }
+    }
+}
+
+// Every change to the list leaves it whole, so it can still be used after a
+// panic while it was locked
+fn held() -> MutexGuard<'static, Vec<Held>> {
+    HELD.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// The same directory can be reached through different paths
+fn held_path(dir: &Path) -> PathBuf {
+    dir.canonicalize().unwrap_or_else(|_| dir.to_owned())
+}
+
+// Claim the lock of a `StoreLock` on the directory, if there is one
+//
+// Claims conflict with each other the same way locks on the file do. Returns
+// the directory to release the claim on later.
+fn claim(dir: &Path, exclusive: bool) -> Result<Option<PathBuf>> {
+    let dir = held_path(dir);
+    let mut held = held();
+    let held = match held.iter_mut().find(|held| held.dir == dir) {
+        Some(held) => held,
+        None => return Ok(None),
+    };
+
+    if held.writer || (exclusive && held.readers > 0) {
+        return Err(Error::Locked {
+            pid: held.writer.then(std::process::id),
+        });
+    }
+
+    if exclusive {
+        held.writer = true;
+    } else {
+        held.readers += 1;
+    }
+
+    Ok(Some(dir))
+}
+
+fn release(dir: &Path, exclusive: bool) {
+    if let Some(held) = held().iter_mut().find(|held| held.dir == dir) {
+        if exclusive {
+            held.writer = false;
+        } else {
+            held.readers -= 1;
+        }
+    }
+}
+
+// Advisory lock on a store directory, held until dropped
+//
+// A process that opens a store for writing holds an exclusive lock on the
+// directory's `LOCK` file and writes its pid there. Read-only openers share
+// the lock, so they can run alongside each other but not alongside a writer.
+//
+// The file is `None` if the lock was claimed from a `StoreLock`, in which case
+// `claimed` is the directory it was claimed on.
+pub(crate) struct DirLock {
+    file: Option<File>,
+    exclusive: bool,
+    claimed: Option<PathBuf>,
+}
+
+impl DirLock {
+    pub(crate) fn exclusive(dir: &Path) -> Result<Self> {
+        if let Some(claimed) = claim(dir, true)? {
+            return Ok(Self {
+                file: None,
+                exclusive: true,
+                claimed: Some(claimed),
+            });
+        }
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join(LOCK_NAME))?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Err(locked(&mut file)),
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+        file.sync_data()?;
+
+        Ok(Self {
+            file: Some(file),
+            exclusive: true,
+            claimed: None,
+        })
+    }
+
+    // A read-only open never writes, so the lock file is not created if it is
+    // missing: there is no lock to share then, e.g. for a store that was never
+    // opened for writing by a version that locks it
+    pub(crate) fn shared(dir: &Path) -> Result<Option<Self>> {
+        if let Some(claimed) = claim(dir, false)? {
+            return Ok(Some(Self {
+                file: None,
+                exclusive: false,
+                claimed: Some(claimed),
+            }));
+        }
+
+        let mut file = match File::open(dir.join(LOCK_NAME)) {
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            res => res?,
+        };
+
+        match file.try_lock_shared() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Err(locked(&mut file)),
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+
+        Ok(Some(Self {
+            file: Some(file),
+            exclusive: false,
+            claimed: None,
+        }))
+    }
+}
+
+impl Drop for DirLock {
+    // Forget our pid before letting go, so that it does not outlive us
+    fn drop(&mut self) {
+        if let Some(file) = &self.file {
+            if self.exclusive {
+                let _ = file.set_len(0);
+            }
+            let _ = file.unlock();
+        }
+        if let Some(dir) = &self.claimed {
+            release(dir, self.exclusive);
+        }
+    }
+}
+
+// Error for a lock held by someone else, naming the writer if there is one
+fn locked(file: &mut File) -> Error {
+    let mut contents = String::new();
+    let pid = file
+        .seek(SeekFrom::Start(0))
+        .and_then(|_| file.read_to_string(&mut contents))
+        .ok()
+        .and_then(|_| contents.trim().parse().ok());
+
+    Error::Locked { pid }
+}
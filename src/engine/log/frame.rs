@@ -0,0 +1,99 @@
+use std::fs::File;
+use std::io::{self, Read};
+
+use crate::error::{Error, Result};
+
+// Size of a record header: payload size (u64), payload CRC (u32) and header CRC (u32)
+pub(crate) const RECORD_HEADER_SIZE: usize = 16;
+
+// Header that precedes every payload in a log
+//
+// The header has its own checksum so that a damaged size can be told apart
+// from a record that was cut short at the end of the log.
+pub(crate) struct RecordHeader {
+    pub(crate) size: u64,
+    pub(crate) crc: u32,
+}
+
+// Prefix a payload with a record header
+pub(crate) fn encode_frame(payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+    buf.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    buf.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    let header_crc = crc32fast::hash(&buf);
+    buf.extend_from_slice(&header_crc.to_le_bytes());
+    buf.extend_from_slice(payload);
+
+    buf
+}
+
+// Read and validate the header of the record starting at `pos`
+pub(crate) fn read_record_header(reader: &mut impl Read, pos: usize) -> Result<RecordHeader> {
+    // NOTE(aksiksi): The default buffer size for `BufRead` is 8KB. If you
+    // only use the `read` API, you could get partial reads into the buffer
+    // once you reach the buffer size.
+    let mut buf = [0u8; RECORD_HEADER_SIZE];
+    reader.read_exact(&mut buf)?;
+
+    let mut field = [0u8; 4];
+    field.copy_from_slice(&buf[12..16]);
+    if crc32fast::hash(&buf[..12]) != u32::from_le_bytes(field) {
+        return Err(Error::Corruption {
+            file: None,
+            offset: pos as u64,
+            reason: "header checksum mismatch".to_owned(),
+        });
+    }
+
+    let mut size = [0u8; 8];
+    size.copy_from_slice(&buf[..8]);
+    field.copy_from_slice(&buf[8..12]);
+
+    Ok(RecordHeader {
+        size: u64::from_le_bytes(size),
+        crc: u32::from_le_bytes(field),
+    })
+}
+
+// Read the payload of the record starting at `pos` and verify its checksum
+pub(crate) fn read_frame(reader: &mut impl Read, pos: usize) -> Result<Vec<u8>> {
+    let header = read_record_header(reader, pos)?;
+
+    let mut buf = vec![0u8; header.size as usize];
+    reader.read_exact(&mut buf)?;
+
+    if crc32fast::hash(&buf) != header.crc {
+        return Err(Error::Corruption {
+            file: None,
+            offset: pos as u64,
+            reason: "command checksum mismatch".to_owned(),
+        });
+    }
+
+    Ok(buf)
+}
+
+// Reads a file starting at a given offset without using the file cursor, so
+// that a single file can be read from several threads at once
+pub(crate) struct ReadAt<'a> {
+    file: &'a File,
+    pos: u64,
+}
+
+impl<'a> ReadAt<'a> {
+    pub(crate) fn new(file: &'a File, pos: u64) -> Self {
+        Self { file, pos }
+    }
+}
+
+impl Read for ReadAt<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(unix)]
+        let n = std::os::unix::fs::FileExt::read_at(self.file, buf, self.pos)?;
+        #[cfg(windows)]
+        let n = std::os::windows::fs::FileExt::seek_read(self.file, buf, self.pos)?;
+
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
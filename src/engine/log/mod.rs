@@ -0,0 +1,16 @@
+// Building blocks shared by the engines that keep their data in logs
+//
+// - `frame`: the record framing, a header with checksums in front of every
+//   payload
+// - `recovery`: replaying framed records, and dealing with a record torn by a
+//   crash at the end of a log
+// - `sync`: syncing logs, on every write or in the background, and directories
+
+mod frame;
+mod recovery;
+mod sync;
+
+pub(crate) use frame::{encode_frame, read_frame, read_record_header, ReadAt, RECORD_HEADER_SIZE};
+pub(crate) use recovery::{replay_frames, truncate_tail};
+pub use sync::sync_dir;
+pub(crate) use sync::{GroupCommit, PeriodicSync};
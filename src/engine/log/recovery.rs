@@ -0,0 +1,102 @@
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use super::frame::{read_record_header, RECORD_HEADER_SIZE};
+use crate::error::{Error, Result};
+
+// Replay the framed records of a log of `size` bytes, from `start` on
+//
+// Every payload is handed to `apply` along with the offset and size of its
+// record. A record that looks torn by a crash is handed to `torn` along with
+// the reason, and ends the replay: one that is cut short, a zero-filled tail,
+// or a bad checksum on the very last record. Damage anywhere else is reported
+// as corruption, without a file for the caller to fill in.
+//
+// Returns the position of the end of the last record that was applied.
+pub(crate) fn replay_frames<R: Read + Seek>(
+    reader: &mut R,
+    start: usize,
+    size: usize,
+    mut torn: impl FnMut(usize, &str) -> Result<()>,
+    mut apply: impl FnMut(&[u8], usize, usize) -> Result<()>,
+) -> Result<usize> {
+    let mut pos = start;
+    let mut buf = Vec::new();
+
+    while pos < size {
+        // Not even enough room left for a header
+        if size - pos < RECORD_HEADER_SIZE {
+            torn(pos, "incomplete record header")?;
+            break;
+        }
+
+        let header = match read_record_header(reader, pos) {
+            Ok(header) => header,
+            // Some filesystems leave a zero-filled tail behind after a crash
+            Err(Error::Corruption { .. }) if is_zeroed_tail(reader, pos)? => {
+                torn(pos, "zero-filled tail")?;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        let record_size = RECORD_HEADER_SIZE + header.size as usize;
+
+        // The record claims to extend past the end of the log
+        if record_size > size - pos {
+            torn(pos, "record extends past the end of the log")?;
+            break;
+        }
+
+        buf.resize(header.size as usize, 0);
+        reader.read_exact(&mut buf)?;
+
+        if crc32fast::hash(&buf) != header.crc {
+            // A bad checksum on the very last record means that it was torn
+            if pos + record_size == size {
+                torn(pos, "command checksum mismatch")?;
+                break;
+            }
+
+            return Err(Error::Corruption {
+                file: None,
+                offset: pos as u64,
+                reason: "command checksum mismatch".to_owned(),
+            });
+        }
+
+        apply(&buf, pos, record_size)?;
+
+        pos += record_size;
+    }
+
+    Ok(pos)
+}
+
+// Returns `true` if every byte from `pos` to the end of the log is zero
+fn is_zeroed_tail<R: Read + Seek>(reader: &mut R, pos: usize) -> Result<bool> {
+    reader.seek(SeekFrom::Start(pos as u64))?;
+
+    let mut tail = Vec::new();
+    reader.read_to_end(&mut tail)?;
+
+    Ok(tail.iter().all(|b| *b == 0))
+}
+
+// Drop a torn record (and everything after it) from the end of a log of `size`
+// bytes
+pub(crate) fn truncate_tail(path: &Path, pos: usize, size: usize) -> Result<()> {
+    log::warn!(
+        "Truncating torn record in {} at offset {} ({} bytes)",
+        path.display(),
+        pos,
+        size - pos
+    );
+
+    OpenOptions::new()
+        .write(true)
+        .open(path)?
+        .set_len(pos as u64)?;
+
+    Ok(())
+}
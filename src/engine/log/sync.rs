@@ -0,0 +1,122 @@
+use std::path::Path;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Condvar, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::error::Result;
+
+// Batches concurrent writers into a single sync
+//
+// Writers only wait for the sync after releasing the writer lock, so while one
+// sync is running, more writes pile up behind it. Whoever comes next syncs all
+// of them at once.
+#[derive(Default)]
+pub(crate) struct GroupCommit {
+    state: Mutex<GroupState>,
+    synced: Condvar,
+}
+
+#[derive(Default)]
+struct GroupState {
+    // Number of bytes written to the log that are known to be synced
+    synced: u64,
+
+    // Whether a writer is currently running a sync
+    syncing: bool,
+}
+
+impl GroupCommit {
+    // Wait until the first `written` bytes of the log are synced
+    //
+    // If nobody is syncing yet, we run `sync` ourselves. It returns how many
+    // bytes of the log it synced, which may cover other writers too.
+    pub(crate) fn wait(&self, written: u64, sync: impl FnOnce() -> Result<u64>) -> Result<()> {
+        let mut state = self.state.lock()?;
+        loop {
+            if state.synced >= written {
+                return Ok(());
+            }
+            if !state.syncing {
+                break;
+            }
+            state = self.synced.wait(state)?;
+        }
+
+        state.syncing = true;
+        drop(state);
+
+        let res = sync();
+
+        let mut state = self.state.lock()?;
+        state.syncing = false;
+        if let Ok(synced) = res {
+            state.synced = state.synced.max(synced);
+        }
+        self.synced.notify_all();
+
+        res.map(|_| ())
+    }
+}
+
+// Background thread that syncs the log at a fixed interval
+//
+// `sync` is run on the store for as long as it is alive.
+pub(crate) struct PeriodicSync {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl PeriodicSync {
+    pub(crate) fn start<T: Send + Sync + 'static>(
+        inner: Weak<T>,
+        interval: Duration,
+        sync: fn(&T) -> Result<u64>,
+    ) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                let inner = match inner.upgrade() {
+                    Some(inner) => inner,
+                    None => return,
+                };
+                if let Err(e) = sync(&inner) {
+                    log::error!("Syncing the log failed: {}", e);
+                }
+            }
+        });
+
+        Self {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for PeriodicSync {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Sync a directory, so that files created, renamed or deleted in it survive
+/// power loss
+#[cfg(unix)]
+pub fn sync_dir(dir: &Path) -> Result<()> {
+    std::fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Sync a directory, so that files created, renamed or deleted in it survive
+/// power loss
+///
+/// Directories cannot be opened as files here; their entries are made durable
+/// along with the files themselves.
+#[cfg(not(unix))]
+pub fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
@@ -0,0 +1,69 @@
+use std::fs;
+use std::path::Path;
+
+use super::manifest::{Manifest, FORMAT_VERSION};
+use super::memtable::Entry;
+use super::sstable::table_path;
+use super::wal::Wal;
+use super::{LsmInner, LsmKvsEngine};
+use crate::engine::backup::{create_backup_dir, link_or_copy, seal_backup};
+use crate::engine::now_millis;
+use crate::error::Result;
+
+impl LsmInner {
+    // Copy the store as it is right now to `dest`
+    //
+    // Tables never change, so the ones in the current version are hard-linked.
+    // Holding on to the version keeps a compaction from deleting them in the
+    // meantime. Writes that are only in the memtables go to a single WAL, which
+    // the restored store flushes when it is opened.
+    pub(super) fn backup(&self, dest: &Path) -> Result<()> {
+        create_backup_dir(dest)?;
+        let dir = dest.join(LsmKvsEngine::DIR_NAME);
+        fs::create_dir(&dir)?;
+
+        // Oldest writes first, so that replaying the WAL keeps the newest ones
+        let (created_at, version, entries) = {
+            let state = self.state.read()?;
+            let imm = state.imm.iter().flat_map(|(imm, _)| imm.iter());
+            let entries: Vec<_> = imm
+                .chain(state.memtable.iter())
+                .map(|(key, value)| Entry {
+                    key: key.clone(),
+                    value: value.clone(),
+                })
+                .collect();
+
+            (now_millis(), state.version.clone(), entries)
+        };
+
+        let tables = version.levels.iter().flatten();
+        for table in tables.clone() {
+            link_or_copy(
+                &table_path(&self.dir, table.meta.id),
+                &table_path(&dir, table.meta.id),
+            )?;
+        }
+
+        let wal_id = tables.map(|table| table.meta.id + 1).max().unwrap_or(1);
+        let mut wal = Wal::create(&dir, wal_id)?;
+        if !entries.is_empty() {
+            wal.append(&entries)?;
+        }
+        drop(wal);
+
+        Manifest {
+            version: FORMAT_VERSION,
+            next_file: wal_id + 1,
+            log_number: wal_id,
+            levels: version
+                .levels
+                .iter()
+                .map(|tables| tables.iter().map(|table| table.meta.clone()).collect())
+                .collect(),
+        }
+        .store(&dir)?;
+
+        seal_backup(dest, "lsm", created_at)
+    }
+}
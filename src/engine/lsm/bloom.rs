@@ -0,0 +1,59 @@
+use serde::{Deserialize, Serialize};
+
+// Bloom filter over the keys of a table, so that looking up a key that is not
+// in the table can mostly skip reading it
+//
+// Keys are hashed once with FNV-1a, and the two halves of the hash are combined
+// to get each probe (double hashing). The hash is part of the file format, so
+// it must never change.
+#[derive(Deserialize, Serialize)]
+pub(super) struct BloomFilter {
+    #[serde(with = "serde_bytes")]
+    bits: Vec<u8>,
+    probes: u32,
+}
+
+impl BloomFilter {
+    // Build a filter from the hashes of every key, using about `bits_per_key`
+    // bits for each
+    pub(super) fn new(hashes: &[u64], bits_per_key: usize) -> Self {
+        let nbits = (hashes.len() * bits_per_key).max(64);
+
+        // ln(2) * bits per key gives the lowest false positive rate
+        let probes = ((bits_per_key as f64) * 0.69).round().clamp(1.0, 30.0) as u32;
+
+        let mut filter = Self {
+            bits: vec![0; nbits.div_ceil(8)],
+            probes,
+        };
+        for hash in hashes {
+            for bit in filter.probes(*hash) {
+                filter.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+
+        filter
+    }
+
+    // Returns `false` if the key is definitely not in the table
+    pub(super) fn may_contain(&self, key: &[u8]) -> bool {
+        self.probes(hash(key))
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    fn probes(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let nbits = (self.bits.len() * 8) as u64;
+        let h1 = hash & 0xffff_ffff;
+        let h2 = (hash >> 32) | 1;
+
+        (0..self.probes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % nbits) as usize)
+    }
+}
+
+pub(super) fn hash(key: &[u8]) -> u64 {
+    const OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    key.iter()
+        .fold(OFFSET, |h, b| (h ^ *b as u64).wrapping_mul(PRIME))
+}
@@ -0,0 +1,202 @@
+use std::ops::Bound;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, PoisonError};
+
+use super::scan::{level_source, table_source, MergeIter};
+use super::sstable::{Table, TableBuilder};
+use super::{LsmInner, Version, MAX_LEVELS};
+use crate::engine::now_millis;
+use crate::error::Result;
+
+// Merges tables of one level into the next one
+//
+// The output replaces the inputs in the next level. Inputs are only deleted
+// once nothing is reading them anymore.
+struct Compaction {
+    level: usize,
+
+    // Tables taken from `level`
+    inputs: Vec<Arc<Table>>,
+
+    // Tables of the next level that overlap the inputs
+    overlaps: Vec<Arc<Table>>,
+}
+
+impl LsmInner {
+    // Compact levels until none of them is over its limit
+    //
+    // Must only be called from the background thread, or while it is not
+    // running.
+    pub(super) fn compact(&self) -> Result<()> {
+        loop {
+            let version = self.state.read()?.version.clone();
+            match self.pick_compaction(&version) {
+                Some(compaction) => self.run_compaction(&version, compaction)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    fn pick_compaction(&self, version: &Version) -> Option<Compaction> {
+        // L0 tables all overlap each other, so they are compacted together
+        let level0 = &version.levels[0];
+        if !level0.is_empty() && level0.len() >= self.options.level0_limit {
+            let smallest = level0.iter().map(|t| &t.meta.smallest).min().unwrap();
+            let largest = level0.iter().map(|t| &t.meta.largest).max().unwrap();
+
+            return Some(Compaction {
+                level: 0,
+                inputs: level0.clone(),
+                overlaps: overlapping(&version.levels[1], smallest, largest),
+            });
+        }
+
+        // The last level has nowhere to go
+        let mut max_size = self.options.base_level_size;
+        for level in 1..MAX_LEVELS - 1 {
+            let tables = &version.levels[level];
+            let size: u64 = tables.iter().map(|t| t.meta.size).sum();
+
+            if size > max_size {
+                // Pick up after the last table compacted out of this level. The
+                // pointers are only a place to start, so a panic cannot leave
+                // them in a state that matters.
+                let mut pointers = self
+                    .compact_pointers
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
+                let table = tables
+                    .iter()
+                    .find(|t| t.meta.smallest > pointers[level])
+                    .unwrap_or(&tables[0])
+                    .clone();
+                pointers[level] = table.meta.largest.clone();
+
+                let overlaps = overlapping(
+                    &version.levels[level + 1],
+                    &table.meta.smallest,
+                    &table.meta.largest,
+                );
+
+                return Some(Compaction {
+                    level,
+                    inputs: vec![table],
+                    overlaps,
+                });
+            }
+
+            max_size = max_size.saturating_mul(10);
+        }
+
+        None
+    }
+
+    fn run_compaction(&self, version: &Version, compaction: Compaction) -> Result<()> {
+        let level = compaction.level;
+
+        // A table that overlaps nothing in the next level can just move down
+        let outputs = if level > 0 && compaction.overlaps.is_empty() {
+            compaction.inputs.clone()
+        } else {
+            self.merge(version, &compaction)?
+        };
+
+        let mut new_version = version.clone();
+        let is_input = |table: &Arc<Table>| {
+            compaction
+                .inputs
+                .iter()
+                .chain(&compaction.overlaps)
+                .any(|input| input.meta.id == table.meta.id)
+        };
+        new_version.levels[level].retain(|table| !is_input(table));
+        new_version.levels[level + 1].retain(|table| !is_input(table));
+        new_version.levels[level + 1].extend(outputs.iter().cloned());
+        new_version.levels[level + 1].sort_by(|a, b| a.meta.smallest.cmp(&b.meta.smallest));
+
+        let log_number = self.state.read()?.log_number;
+        self.store_manifest(&new_version, log_number)?;
+        self.state.write()?.version = Arc::new(new_version);
+
+        log::debug!(
+            "Compacted {} tables from L{} and {} from L{} into {} tables",
+            compaction.inputs.len(),
+            level,
+            compaction.overlaps.len(),
+            level + 1,
+            outputs.len()
+        );
+
+        // Moved tables are still part of the store
+        for table in compaction.inputs.iter().chain(&compaction.overlaps) {
+            if !outputs.iter().any(|output| output.meta.id == table.meta.id) {
+                table.mark_obsolete();
+            }
+        }
+
+        Ok(())
+    }
+
+    // Write the latest write to every key in the inputs to new tables
+    fn merge(&self, version: &Version, compaction: &Compaction) -> Result<Vec<Arc<Table>>> {
+        let level = compaction.level;
+
+        // Newest first: L0 tables from newest to oldest, or the input table,
+        // then the next level
+        let mut sources: Vec<_> = compaction
+            .inputs
+            .iter()
+            .rev()
+            .map(|table| table_source(table.clone(), Bound::Unbounded, Bound::Unbounded, false))
+            .collect();
+        sources.push(level_source(
+            compaction.overlaps.clone(),
+            Bound::Unbounded,
+            Bound::Unbounded,
+            false,
+        ));
+
+        // Removed and expired keys can only be dropped if there is nothing
+        // older further down that they hide
+        let bottom = version.levels[level + 2..]
+            .iter()
+            .all(|tables| tables.is_empty());
+        let now = now_millis();
+
+        let mut outputs = Vec::new();
+        let mut builder: Option<TableBuilder> = None;
+
+        for entry in MergeIter::new(sources, false) {
+            let entry = entry?;
+            if bottom && entry.value.is_dead(now) {
+                continue;
+            }
+
+            if builder.is_none() {
+                let id = self.next_file.fetch_add(1, Ordering::SeqCst);
+                builder = Some(TableBuilder::new(&self.dir, id, self.options.block_size)?);
+            }
+
+            let current = builder.as_mut().unwrap();
+            current.add(entry.key, entry.value)?;
+            if current.size() >= self.options.table_size {
+                outputs.push(Arc::new(builder.take().unwrap().finish()?));
+            }
+        }
+
+        if let Some(builder) = builder {
+            outputs.push(Arc::new(builder.finish()?));
+        }
+
+        Ok(outputs)
+    }
+}
+
+// Tables of a level that may hold keys from `smallest` to `largest`
+fn overlapping(tables: &[Arc<Table>], smallest: &[u8], largest: &[u8]) -> Vec<Arc<Table>> {
+    tables
+        .iter()
+        .filter(|t| t.meta.largest.as_slice() >= smallest && t.meta.smallest.as_slice() <= largest)
+        .cloned()
+        .collect()
+}
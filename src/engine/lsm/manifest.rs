@@ -0,0 +1,86 @@
+use std::fs::{self, File};
+use std::io::{BufReader, ErrorKind, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::engine::log::{encode_frame, read_frame, sync_dir};
+use crate::error::{Error, Result};
+
+const MANIFEST_NAME: &str = "MANIFEST";
+const MANIFEST_TMP_NAME: &str = "MANIFEST.tmp";
+
+// Version of the manifest, table and WAL formats written by this version of kvs
+pub(super) const FORMAT_VERSION: u32 = 1;
+
+// A table as recorded in the manifest
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(super) struct TableMeta {
+    pub(super) id: u64,
+    #[serde(with = "serde_bytes")]
+    pub(super) smallest: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub(super) largest: Vec<u8>,
+    pub(super) size: u64,
+}
+
+// Which tables make up the store
+//
+// The manifest is rewritten in full on every change, to a temporary file that
+// then replaces it, so it always describes a consistent set of tables. Tables
+// and WALs that it does not mention are left over from a crash, and deleted on
+// open.
+#[derive(Debug, Deserialize, Serialize)]
+pub(super) struct Manifest {
+    pub(super) version: u32,
+
+    // Id for the next table or WAL
+    pub(super) next_file: u64,
+
+    // Oldest WAL that still holds writes missing from the tables
+    pub(super) log_number: u64,
+
+    // Tables of each level. L0 tables are ordered from oldest to newest and may
+    // overlap; tables of the other levels are ordered by key and never overlap.
+    pub(super) levels: Vec<Vec<TableMeta>>,
+}
+
+impl Manifest {
+    // Load the manifest, if the store has one yet
+    pub(super) fn load(dir: &Path) -> Result<Option<Self>> {
+        let path = dir.join(MANIFEST_NAME);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let buf = read_frame(&mut BufReader::new(file), 0).map_err(|e| e.in_file(&path))?;
+        let manifest: Self = rmp_serde::from_slice(&buf).map_err(|e| Error::Corruption {
+            file: Some(path.clone()),
+            offset: 0,
+            reason: e.to_string(),
+        })?;
+
+        if manifest.version != FORMAT_VERSION {
+            return Err(Error::UnsupportedVersion {
+                found: manifest.version,
+                expected: FORMAT_VERSION,
+            });
+        }
+
+        Ok(Some(manifest))
+    }
+
+    pub(super) fn store(&self, dir: &Path) -> Result<()> {
+        let tmp_path = dir.join(MANIFEST_TMP_NAME);
+
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&encode_frame(&rmp_serde::to_vec(self)?))?;
+        file.sync_data()?;
+        drop(file);
+
+        fs::rename(&tmp_path, dir.join(MANIFEST_NAME))?;
+        sync_dir(dir)
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use serde::{Deserialize, Serialize};
+
+// The latest write to a key
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(super) enum Value {
+    // Value along with its expiry time, if it has a TTL
+    Put(#[serde(with = "serde_bytes")] Vec<u8>, Option<u64>),
+    // Tombstone that hides older values of the key
+    Delete,
+}
+
+impl Value {
+    // The value, unless the key was removed or has expired by `now`
+    pub(super) fn live(self, now: u64) -> Option<Vec<u8>> {
+        match self {
+            Value::Put(value, expires_at) if expires_at.is_none_or(|ts| ts > now) => Some(value),
+            _ => None,
+        }
+    }
+
+    // Returns `true` for a tombstone or an expired value, neither of which is
+    // needed once nothing older is left for it to hide
+    pub(super) fn is_dead(&self, now: u64) -> bool {
+        match self {
+            Value::Put(_, expires_at) => expires_at.is_some_and(|ts| ts <= now),
+            Value::Delete => true,
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Value::Put(value, _) => value.len() + 9,
+            Value::Delete => 1,
+        }
+    }
+}
+
+// A key along with its latest write, as stored in the WAL and in tables
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(super) struct Entry {
+    #[serde(with = "serde_bytes")]
+    pub(super) key: Vec<u8>,
+    pub(super) value: Value,
+}
+
+// Sorted in-memory buffer of the latest writes, which is flushed to a table
+// once it grows large enough
+#[derive(Default)]
+pub(super) struct Memtable {
+    entries: BTreeMap<Vec<u8>, Value>,
+
+    // Approximate number of bytes taken up by the entries
+    size: usize,
+}
+
+impl Memtable {
+    // Rough per-entry overhead of the map
+    const ENTRY_OVERHEAD: usize = 32;
+
+    pub(super) fn get(&self, key: &[u8]) -> Option<&Value> {
+        self.entries.get(key)
+    }
+
+    pub(super) fn insert(&mut self, key: Vec<u8>, value: Value) {
+        match self.entries.get_mut(&key) {
+            Some(old) => {
+                self.size = self.size - old.size() + value.size();
+                *old = value;
+            }
+            None => {
+                self.size += key.len() + value.size() + Self::ENTRY_OVERHEAD;
+                self.entries.insert(key, value);
+            }
+        }
+    }
+
+    pub(super) fn size(&self) -> usize {
+        self.size
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Entries in the range, in key order or in reverse
+    //
+    // The range must not be empty, see `is_empty_range`.
+    pub(super) fn range(
+        &self,
+        start: &Bound<Vec<u8>>,
+        end: &Bound<Vec<u8>>,
+        reverse: bool,
+    ) -> Box<dyn Iterator<Item = (&Vec<u8>, &Value)> + '_> {
+        let range = self
+            .entries
+            .range::<Vec<u8>, _>((start.clone(), end.clone()));
+        if reverse {
+            Box::new(range.rev())
+        } else {
+            Box::new(range)
+        }
+    }
+
+    pub(super) fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &Value)> {
+        self.entries.iter()
+    }
+}
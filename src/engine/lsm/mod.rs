@@ -0,0 +1,632 @@
+use std::collections::HashSet;
+use std::fs;
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::engine::lock::DirLock;
+use crate::engine::log::{sync_dir, GroupCommit, PeriodicSync};
+use crate::engine::{
+    expires_at, now_millis, BatchOp, ConditionFailed, ConditionalResult, Durability, KvsEngine,
+    KvsIterator, WriteBatch,
+};
+use crate::error::{Error, Result};
+
+mod backup;
+mod bloom;
+mod compaction;
+mod manifest;
+mod memtable;
+mod options;
+mod scan;
+mod sstable;
+mod wal;
+
+use manifest::{Manifest, FORMAT_VERSION};
+use memtable::{Entry, Memtable, Value};
+pub use options::LsmOptions;
+use scan::LsmScanIter;
+use sstable::{Table, TableBuilder};
+use wal::{replay_wal, Wal};
+
+// Number of levels, L0 included
+const MAX_LEVELS: usize = 7;
+
+/// Log-structured merge-tree store
+///
+/// Writes go to a write-ahead log (WAL) and to an in-memory, sorted memtable.
+/// Once the memtable is full, it is flushed on a background thread to an
+/// immutable, sorted table (`<id>.sst`) in level 0, and writes move on to a
+/// fresh memtable and WAL.
+///
+/// Every table has a block index and a bloom filter, so a lookup reads at most
+/// one block from each table that may hold the key. Level 0 tables may overlap
+/// each other; once there are too many of them, they are merged into level 1.
+/// Every level after that holds tables that do not overlap, and is merged into
+/// the next one once it grows too large. Only the index and filter of each
+/// table are kept in memory, so memory use does not grow with the number of
+/// keys, only with the number of tables.
+///
+/// The store lives in the `lsm` directory of the given path. The `MANIFEST`
+/// there records which tables make up the store.
+///
+/// Handles are cheap to clone and can be shared across threads. Reads run
+/// concurrently with each other and with a single writer. A store can only be
+/// open in one process at a time.
+///
+/// How much of the WAL survives power loss depends on the `Durability` policy.
+/// Tables and the manifest are always synced before they are used.
+#[derive(Clone)]
+pub struct LsmKvsEngine {
+    inner: Arc<LsmInner>,
+
+    // Shared by all handles; dropped along with the last one
+    _closer: Arc<Closer>,
+}
+
+// State shared with the background flush thread
+//
+// Locks are always taken in the order: `writer`, `state`.
+struct LsmInner {
+    // Directory containing the tables, WALs and manifest
+    dir: PathBuf,
+
+    writer: Mutex<Writer>,
+    state: RwLock<State>,
+
+    // Id for the next table or WAL
+    next_file: AtomicU64,
+
+    // Largest key of the last table compacted out of each level, so that
+    // compactions work their way around the key space
+    // Only used by the background thread
+    compact_pointers: Mutex<Vec<Vec<u8>>>,
+
+    options: LsmOptions,
+
+    // Only used with `Durability::GroupCommit`
+    group_commit: GroupCommit,
+}
+
+struct Writer {
+    wal: Wal,
+
+    // Number of bytes written to the WALs through this handle
+    // Used to tell which writes a sync covered
+    written: u64,
+
+    // Background flush (and the compactions after it), if one was started
+    background: Option<JoinHandle<()>>,
+}
+
+struct State {
+    memtable: Memtable,
+
+    // Full memtable that is being flushed, along with the id of its WAL
+    imm: Option<(Arc<Memtable>, u64)>,
+
+    version: Arc<Version>,
+
+    // Oldest WAL that is still needed
+    log_number: u64,
+}
+
+// The tables that make up the store at some point in time
+//
+// Versions are never modified, only replaced, so reads and scans can keep
+// using one while flushes and compactions move on.
+#[derive(Clone)]
+struct Version {
+    // L0 is ordered from oldest to newest, other levels by key
+    levels: Vec<Vec<Arc<Table>>>,
+}
+
+impl LsmKvsEngine {
+    const DIR_NAME: &'static str = "lsm";
+    const MEMTABLE_SIZE: usize = 4 * 1024 * 1024; // 4 MB
+    const BLOCK_SIZE: usize = 4 * 1024; // 4 KB
+    const TABLE_SIZE: u64 = 2 * 1024 * 1024; // 2 MB
+    const LEVEL0_LIMIT: usize = 4;
+    const BASE_LEVEL_SIZE: u64 = 10 * 1024 * 1024; // 10 MB
+
+    /// Returns `true` if a store already exists
+    pub fn is_log_present(path: impl Into<PathBuf>) -> bool {
+        path.into().join(Self::DIR_NAME).join("MANIFEST").exists()
+    }
+
+    /// Open an existing store or create a new one.
+    ///
+    /// Writes are never synced; use `LsmOptions` for anything else.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        LsmOptions::new().open(path)
+    }
+
+    /// Open an existing store or create a new one, syncing writes according to
+    /// the given policy
+    pub fn open_with_durability(path: impl Into<PathBuf>, durability: Durability) -> Result<Self> {
+        LsmOptions::new().durability(durability).open(path)
+    }
+
+    fn open_with_options(path: impl Into<PathBuf>, options: LsmOptions) -> Result<Self> {
+        let path = path.into();
+
+        // Only one process may use the store at a time
+        let lock = DirLock::exclusive(&path)?;
+
+        let dir = path.join(Self::DIR_NAME);
+        fs::create_dir_all(&dir)?;
+
+        let manifest = Manifest::load(&dir)?.unwrap_or(Manifest {
+            version: FORMAT_VERSION,
+            next_file: 1,
+            log_number: 0,
+            levels: Vec::new(),
+        });
+
+        let mut levels = vec![Vec::new(); MAX_LEVELS];
+        let mut live = HashSet::new();
+        for (level, metas) in manifest.levels.iter().enumerate() {
+            for meta in metas {
+                levels[level].push(Arc::new(Table::open(&dir, meta)?));
+                live.insert(meta.id);
+            }
+        }
+
+        // Clean up after a flush or compaction that did not make it into the
+        // manifest, and find the WALs that still have to be replayed
+        let mut next_file = manifest.next_file;
+        let mut wals = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let name = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) => name,
+                None => continue,
+            };
+
+            if name.ends_with(".tmp") {
+                fs::remove_file(&path)?;
+                continue;
+            }
+
+            let (id, ext) = match name.split_once('.') {
+                Some((id, ext)) => match id.parse::<u64>() {
+                    Ok(id) => (id, ext),
+                    Err(_) => continue,
+                },
+                None => continue,
+            };
+            next_file = next_file.max(id + 1);
+
+            match ext {
+                "sst" if !live.contains(&id) => fs::remove_file(&path)?,
+                "wal" if id >= manifest.log_number => wals.push(id),
+                _ => {}
+            }
+        }
+        wals.sort_unstable();
+
+        let mut memtable = Memtable::default();
+        for id in &wals {
+            replay_wal(&dir, *id, &mut memtable)?;
+        }
+
+        let wal_id = next_file;
+        let writer = Writer {
+            wal: Wal::create(&dir, wal_id)?,
+            written: 0,
+            background: None,
+        };
+
+        let durability = options.durability;
+        let inner = Arc::new(LsmInner {
+            dir,
+            writer: Mutex::new(writer),
+            state: RwLock::new(State {
+                memtable: Memtable::default(),
+                imm: None,
+                version: Arc::new(Version { levels }),
+                log_number: manifest.log_number,
+            }),
+            next_file: AtomicU64::new(wal_id + 1),
+            compact_pointers: Mutex::new(vec![Vec::new(); MAX_LEVELS]),
+            options,
+            group_commit: GroupCommit::default(),
+        });
+
+        // Whatever was replayed goes straight to L0, so that the old WALs can go
+        inner.flush(Arc::new(memtable), wal_id)?;
+
+        let periodic_sync = match durability {
+            Durability::Periodic(interval) => Some(PeriodicSync::start(
+                Arc::downgrade(&inner),
+                interval,
+                LsmInner::sync_active,
+            )),
+            _ => None,
+        };
+
+        Ok(Self {
+            inner: inner.clone(),
+            _closer: Arc::new(Closer {
+                inner,
+                _periodic_sync: periodic_sync,
+                _lock: lock,
+            }),
+        })
+    }
+
+    // Write a single request's entries
+    fn write(&self, entries: Vec<Entry>) -> Result<()> {
+        let written = {
+            let mut writer = self.inner.writer.lock()?;
+            self.inner.apply(&mut writer, entries)?;
+            writer.written
+        };
+
+        self.inner.commit(written)
+    }
+}
+
+impl LsmInner {
+    // Append the entries to the WAL, then add them to the memtable
+    fn apply(self: &Arc<Self>, writer: &mut Writer, entries: Vec<Entry>) -> Result<()> {
+        writer.written += writer.wal.append(&entries)?;
+        if self.options.durability == Durability::Sync {
+            writer.wal.file().sync_data()?;
+        }
+
+        {
+            let mut state = self.state.write()?;
+            for entry in entries {
+                state.memtable.insert(entry.key, entry.value);
+            }
+        }
+
+        self.maybe_flush(writer)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.latest(key)?.and_then(|value| value.live(now_millis())))
+    }
+
+    // Find the latest write to a key, which may be a tombstone or have expired
+    fn latest(&self, key: &[u8]) -> Result<Option<Value>> {
+        // Newest writes first: the memtable, the one being flushed, then tables
+        let version = {
+            let state = self.state.read()?;
+            if let Some(value) = state.memtable.get(key) {
+                return Ok(Some(value.clone()));
+            }
+            if let Some(value) = state.imm.as_ref().and_then(|(imm, _)| imm.get(key)) {
+                return Ok(Some(value.clone()));
+            }
+            state.version.clone()
+        };
+
+        version.get(key)
+    }
+
+    // Once the memtable is full, switch to a new one and flush the old one in
+    // the background
+    //
+    // Only one memtable is flushed at a time, so writers wait here while a
+    // flush is still running.
+    fn maybe_flush(self: &Arc<Self>, writer: &mut Writer) -> Result<()> {
+        if self.state.read()?.memtable.size() < self.options.memtable_size {
+            return Ok(());
+        }
+
+        if let Some(handle) = writer.background.take() {
+            let _ = handle.join();
+        }
+
+        // The last flush failed, so try again before anything else
+        let imm = self.state.read()?.imm.clone();
+        if let Some((imm, _)) = imm {
+            self.flush(imm, writer.wal.id)?;
+        }
+
+        // Only the active WAL is ever synced after this, so the old one is
+        // synced on the way out
+        let id = self.next_file.fetch_add(1, Ordering::SeqCst);
+        let wal = Wal::create(&self.dir, id)?;
+        if self.options.durability != Durability::None {
+            writer.wal.file().sync_data()?;
+            sync_dir(&self.dir)?;
+        }
+        let old = std::mem::replace(&mut writer.wal, wal);
+
+        let imm = {
+            let mut state = self.state.write()?;
+            let imm = Arc::new(std::mem::take(&mut state.memtable));
+            state.imm = Some((imm.clone(), old.id));
+            imm
+        };
+
+        let inner = self.clone();
+        writer.background = Some(thread::spawn(move || {
+            if let Err(e) = inner.flush(imm, id) {
+                log::error!("Flushing the memtable failed: {}", e);
+                return;
+            }
+            if let Err(e) = inner.compact() {
+                log::error!("Compaction failed: {}", e);
+            }
+        }));
+
+        Ok(())
+    }
+
+    // Write a memtable out to a new L0 table
+    //
+    // Every WAL before `log_number` is deleted once the table is part of the
+    // store. Must only be called from the background thread, or while it is
+    // not running.
+    fn flush(&self, memtable: Arc<Memtable>, log_number: u64) -> Result<()> {
+        let mut version = (*self.state.read()?.version).clone();
+
+        if !memtable.is_empty() {
+            let id = self.next_file.fetch_add(1, Ordering::SeqCst);
+            let mut builder = TableBuilder::new(&self.dir, id, self.options.block_size)?;
+            for (key, value) in memtable.iter() {
+                builder.add(key.clone(), value.clone())?;
+            }
+            version.levels[0].push(Arc::new(builder.finish()?));
+        }
+
+        self.store_manifest(&version, log_number)?;
+
+        {
+            let mut state = self.state.write()?;
+            state.version = Arc::new(version);
+            state.imm = None;
+            state.log_number = log_number;
+        }
+
+        remove_old_wals(&self.dir, log_number)
+    }
+
+    fn store_manifest(&self, version: &Version, log_number: u64) -> Result<()> {
+        Manifest {
+            version: FORMAT_VERSION,
+            next_file: self.next_file.load(Ordering::SeqCst),
+            log_number,
+            levels: version
+                .levels
+                .iter()
+                .map(|tables| tables.iter().map(|table| table.meta.clone()).collect())
+                .collect(),
+        }
+        .store(&self.dir)
+    }
+
+    // Sync the active WAL
+    //
+    // Every other WAL was synced when it was sealed. Returns how many bytes were
+    // written when the sync started.
+    fn sync_active(&self) -> Result<u64> {
+        let (written, file) = {
+            let writer = self.writer.lock()?;
+            (writer.written, writer.wal.file().try_clone()?)
+        };
+
+        file.sync_data()?;
+
+        Ok(written)
+    }
+
+    // Wait for writes up to `written` to be synced, under group commit
+    //
+    // Must be called without holding the writer, so that other writers can
+    // join the next sync.
+    fn commit(&self, written: u64) -> Result<()> {
+        if self.options.durability != Durability::GroupCommit {
+            return Ok(());
+        }
+
+        self.group_commit.wait(written, || self.sync_active())
+    }
+}
+
+impl Version {
+    // Find the latest write to a key in the tables
+    fn get(&self, key: &[u8]) -> Result<Option<Value>> {
+        // L0 tables may overlap, so every one of them that covers the key has to
+        // be checked, newest first
+        for table in self.levels[0].iter().rev() {
+            if table.meta.smallest.as_slice() <= key && key <= table.meta.largest.as_slice() {
+                if let Some(value) = table.get(key)? {
+                    return Ok(Some(value));
+                }
+            }
+        }
+
+        // At most one table of every other level covers the key
+        for tables in &self.levels[1..] {
+            let i = tables.partition_point(|table| table.meta.largest.as_slice() < key);
+            if let Some(table) = tables.get(i) {
+                if table.meta.smallest.as_slice() <= key {
+                    if let Some(value) = table.get(key)? {
+                        return Ok(Some(value));
+                    }
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+// Delete every WAL before `log_number`, as their writes are all in tables now
+fn remove_old_wals(dir: &Path, log_number: u64) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let id = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".wal"))
+            .and_then(|id| id.parse::<u64>().ok());
+
+        if id.is_some_and(|id| id < log_number) {
+            fs::remove_file(&path)?;
+        }
+    }
+
+    Ok(())
+}
+
+struct Closer {
+    inner: Arc<LsmInner>,
+
+    // Stopped before anything else
+    _periodic_sync: Option<PeriodicSync>,
+
+    // Released after everything else
+    _lock: DirLock,
+}
+
+impl Drop for Closer {
+    // Wait for any running flush before the store can be reopened
+    //
+    // The memtable is not flushed; it is replayed from the WAL on the next open.
+    fn drop(&mut self) {
+        drop(self._periodic_sync.take());
+
+        // Even if the store is poisoned, the thread has to finish first
+        let handle = self
+            .inner
+            .writer
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .background
+            .take();
+        if let Some(handle) = handle {
+            let _ = handle.join();
+        }
+
+        // Whatever the periodic sync did not get to yet
+        if let Durability::Periodic(_) = self.inner.options.durability {
+            if let Err(e) = self.inner.sync_active() {
+                log::error!("Syncing the WAL failed: {}", e);
+            }
+        }
+    }
+}
+
+impl LsmKvsEngine {
+    fn set_with_expiry(&self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        self.write(vec![Entry {
+            key,
+            value: Value::Put(value, expires_at),
+        }])
+    }
+}
+
+impl KvsEngine for LsmKvsEngine {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.set_with_expiry(key, value, None)
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.set_with_expiry(key, value, Some(expires_at(ttl)))
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.inner.get(&key)
+    }
+
+    fn expiry_bytes(&self, key: Vec<u8>) -> Result<Option<u64>> {
+        match self.inner.latest(&key)? {
+            Some(Value::Put(_, Some(ts))) if ts > now_millis() => Ok(Some(ts)),
+            _ => Ok(None),
+        }
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let written = {
+            // Holding the writer keeps the key from changing under us
+            let mut writer = self.inner.writer.lock()?;
+            if self.inner.get(&key)?.is_none() {
+                return Err(Error::KeyNotFound);
+            }
+
+            let entry = Entry {
+                key,
+                value: Value::Delete,
+            };
+            self.inner.apply(&mut writer, vec![entry])?;
+            writer.written
+        };
+
+        self.inner.commit(written)
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        // The whole batch goes into a single WAL record, so it is either
+        // replayed in full or not at all
+        let entries = batch
+            .into_ops()
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set(key, value, ttl) => Entry {
+                    key,
+                    value: Value::Put(value, ttl.map(expires_at)),
+                },
+                BatchOp::Remove(key) => Entry {
+                    key,
+                    value: Value::Delete,
+                },
+            })
+            .collect();
+
+        self.write(entries)
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<ConditionalResult> {
+        let written = {
+            // Holding the writer keeps the key from changing under us
+            let mut writer = self.inner.writer.lock()?;
+
+            let current = self.inner.get(&key)?;
+            if current != expected {
+                return Ok(Err(ConditionFailed { current }));
+            }
+
+            let value = match new {
+                Some(value) => Value::Put(value, None),
+                None if current.is_some() => Value::Delete,
+                None => return Ok(Ok(())),
+            };
+
+            self.inner.apply(&mut writer, vec![Entry { key, value }])?;
+            writer.written
+        };
+
+        self.inner.commit(written)?;
+
+        Ok(Ok(()))
+    }
+
+    fn scan_bytes(&self, range: impl RangeBounds<Vec<u8>>) -> Result<KvsIterator> {
+        let start = range.start_bound().cloned();
+        let end = range.end_bound().cloned();
+
+        Ok(Box::new(LsmScanIter::new(self.clone(), start, end)))
+    }
+
+    fn backup(&self, dest: impl Into<PathBuf>) -> Result<()> {
+        self.inner.backup(&dest.into())
+    }
+}
@@ -0,0 +1,85 @@
+use std::path::PathBuf;
+
+use super::LsmKvsEngine;
+use crate::engine::Durability;
+use crate::error::Result;
+
+/// Options for opening an `LsmKvsEngine`
+///
+/// Setters can be chained, after which `open` opens the store:
+/// `LsmOptions::new().memtable_size(1 << 20).open(path)`.
+#[derive(Clone, Debug)]
+pub struct LsmOptions {
+    pub(super) memtable_size: usize,
+    pub(super) block_size: usize,
+    pub(super) table_size: u64,
+    pub(super) level0_limit: usize,
+    pub(super) base_level_size: u64,
+    pub(super) durability: Durability,
+}
+
+impl LsmOptions {
+    pub fn new() -> Self {
+        Self {
+            memtable_size: LsmKvsEngine::MEMTABLE_SIZE,
+            block_size: LsmKvsEngine::BLOCK_SIZE,
+            table_size: LsmKvsEngine::TABLE_SIZE,
+            level0_limit: LsmKvsEngine::LEVEL0_LIMIT,
+            base_level_size: LsmKvsEngine::BASE_LEVEL_SIZE,
+            durability: Durability::None,
+        }
+    }
+
+    /// Size the memtable grows to before it is flushed to a table
+    ///
+    /// Defaults to 4 MB.
+    pub fn memtable_size(&mut self, bytes: usize) -> &mut Self {
+        self.memtable_size = bytes;
+        self
+    }
+
+    /// Size of the blocks that tables are read in; defaults to 4 KB
+    pub fn block_size(&mut self, bytes: usize) -> &mut Self {
+        self.block_size = bytes;
+        self
+    }
+
+    /// Size at which compaction starts a new output table; defaults to 2 MB
+    pub fn table_size(&mut self, bytes: u64) -> &mut Self {
+        self.table_size = bytes;
+        self
+    }
+
+    /// Number of tables flushed to level 0 before they are compacted into
+    /// level 1; defaults to 4
+    pub fn level0_limit(&mut self, tables: usize) -> &mut Self {
+        self.level0_limit = tables;
+        self
+    }
+
+    /// Size of level 1 before it is compacted into level 2
+    ///
+    /// Each level after that may grow ten times larger than the one before it.
+    /// Defaults to 10 MB.
+    pub fn base_level_size(&mut self, bytes: u64) -> &mut Self {
+        self.base_level_size = bytes;
+        self
+    }
+
+    /// When writes are synced to disk; defaults to `Durability::None`
+    pub fn durability(&mut self, durability: Durability) -> &mut Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Open the store in the given directory with these options
+    pub fn open(&self, path: impl Into<PathBuf>) -> Result<LsmKvsEngine> {
+        LsmKvsEngine::open_with_options(path, self.clone())
+    }
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,328 @@
+use std::collections::VecDeque;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use super::memtable::{Entry, Memtable};
+use super::sstable::{Table, TableIter};
+use super::LsmKvsEngine;
+use crate::engine::{is_empty_range, now_millis};
+use crate::error::Result;
+
+// Entries of a memtable, table or level, in key order or in reverse
+pub(super) type Source = Box<dyn Iterator<Item = Result<Entry>> + Send>;
+
+pub(super) fn in_range(key: &[u8], start: &Bound<Vec<u8>>, end: &Bound<Vec<u8>>) -> bool {
+    let after_start = match start {
+        Bound::Included(start) => key >= start.as_slice(),
+        Bound::Excluded(start) => key > start.as_slice(),
+        Bound::Unbounded => true,
+    };
+    let before_end = match end {
+        Bound::Included(end) => key <= end.as_slice(),
+        Bound::Excluded(end) => key < end.as_slice(),
+        Bound::Unbounded => true,
+    };
+
+    after_start && before_end
+}
+
+pub(super) fn table_source(
+    table: Arc<Table>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    reverse: bool,
+) -> Source {
+    Box::new(TableIter::new(table, start, end, reverse))
+}
+
+// The tables of a level other than L0 never overlap, so they can be read one
+// after the other
+pub(super) fn level_source(
+    mut tables: Vec<Arc<Table>>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    reverse: bool,
+) -> Source {
+    if reverse {
+        tables.reverse();
+    }
+
+    Box::new(
+        tables
+            .into_iter()
+            .flat_map(move |table| TableIter::new(table, start.clone(), end.clone(), reverse)),
+    )
+}
+
+// Merges sources into a single one, keeping only the newest entry for each key
+//
+// Sources are ordered from newest to oldest, so when several of them hold the
+// same key, the first one wins.
+pub(super) struct MergeIter {
+    sources: Vec<Source>,
+
+    // Next entry of each source
+    heads: Vec<Option<Entry>>,
+    started: bool,
+    reverse: bool,
+}
+
+impl MergeIter {
+    pub(super) fn new(sources: Vec<Source>, reverse: bool) -> Self {
+        Self {
+            heads: sources.iter().map(|_| None).collect(),
+            sources,
+            started: false,
+            reverse,
+        }
+    }
+
+    fn advance(&mut self, i: usize) -> Result<()> {
+        self.heads[i] = self.sources[i].next().transpose()?;
+        Ok(())
+    }
+}
+
+impl Iterator for MergeIter {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.started {
+            self.started = true;
+            for i in 0..self.sources.len() {
+                if let Err(e) = self.advance(i) {
+                    return Some(Err(e));
+                }
+            }
+        }
+
+        // Smallest key (or largest, in reverse), from the newest source
+        let mut best: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            let key = match head {
+                Some(entry) => &entry.key,
+                None => continue,
+            };
+            let better = match best.and_then(|b| self.heads[b].as_ref()) {
+                Some(current) if self.reverse => key > &current.key,
+                Some(current) => key < &current.key,
+                None => true,
+            };
+            if better {
+                best = Some(i);
+            }
+        }
+
+        let best = best?;
+        let entry = self.heads[best].take().unwrap();
+
+        // Older writes to the same key are hidden
+        for i in best..self.sources.len() {
+            let same = i == best
+                || self.heads[i]
+                    .as_ref()
+                    .is_some_and(|head| head.key == entry.key);
+            if same {
+                if let Err(e) = self.advance(i) {
+                    return Some(Err(e));
+                }
+            }
+        }
+
+        Some(Ok(entry))
+    }
+}
+
+// Lazy iterator over a range of keys in an `LsmKvsEngine`
+//
+// Entries are pulled in batches. For each batch, the memtables are copied while
+// the store is locked, up to the batch size; the tables are then read without
+// holding any lock. Writes made after a batch was pulled only show up in later
+// batches.
+pub(super) struct LsmScanIter {
+    engine: LsmKvsEngine,
+
+    // Part of the range that has not been pulled yet
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    done: bool,
+
+    // Entries pulled from either end of the range
+    front: VecDeque<(Vec<u8>, Vec<u8>)>,
+    back: VecDeque<(Vec<u8>, Vec<u8>)>,
+}
+
+impl LsmScanIter {
+    const BATCH_SIZE: usize = 256;
+
+    pub(super) fn new(engine: LsmKvsEngine, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> Self {
+        Self {
+            engine,
+            start,
+            end,
+            done: false,
+            front: VecDeque::new(),
+            back: VecDeque::new(),
+        }
+    }
+
+    // Pull the next batch of entries
+    fn fill(&mut self, reverse: bool) -> Result<()> {
+        if self.done || is_empty_range(&self.start, &self.end) {
+            return Ok(());
+        }
+
+        // Only part of a memtable may be copied, in which case nothing past the
+        // last key copied (the frontier) can be returned in this batch
+        let mut frontier: Option<Vec<u8>> = None;
+        let mut sources: Vec<Source> = Vec::new();
+        let mut copy = |memtable: &Memtable| {
+            let entries: Vec<_> = memtable
+                .range(&self.start, &self.end, reverse)
+                .take(Self::BATCH_SIZE)
+                .map(|(key, value)| Entry {
+                    key: key.clone(),
+                    value: value.clone(),
+                })
+                .collect();
+
+            if entries.len() == Self::BATCH_SIZE {
+                let last = entries.last().unwrap().key.clone();
+                let nearer =
+                    frontier
+                        .as_ref()
+                        .is_none_or(|f| if reverse { &last > f } else { &last < f });
+                if nearer {
+                    frontier = Some(last);
+                }
+            }
+            sources.push(Box::new(entries.into_iter().map(Ok)));
+        };
+
+        let version = {
+            let state = self.engine.inner.state.read()?;
+            copy(&state.memtable);
+            if let Some((imm, _)) = &state.imm {
+                copy(imm);
+            }
+            state.version.clone()
+        };
+
+        // L0 tables, newest first, then one source per level
+        for table in version.levels[0].iter().rev() {
+            if table.overlaps(&self.start, &self.end) {
+                sources.push(table_source(
+                    table.clone(),
+                    self.start.clone(),
+                    self.end.clone(),
+                    reverse,
+                ));
+            }
+        }
+        for tables in &version.levels[1..] {
+            let tables = tables
+                .iter()
+                .filter(|table| table.overlaps(&self.start, &self.end))
+                .cloned()
+                .collect();
+            sources.push(level_source(
+                tables,
+                self.start.clone(),
+                self.end.clone(),
+                reverse,
+            ));
+        }
+
+        let now = now_millis();
+        let mut entries = VecDeque::new();
+        let mut last = None;
+        let mut merge = MergeIter::new(sources, reverse);
+
+        loop {
+            if entries.len() == Self::BATCH_SIZE {
+                break;
+            }
+
+            let entry = match merge.next() {
+                Some(entry) => entry?,
+                // Everything up to the frontier was pulled
+                None => {
+                    last = frontier.take();
+                    if last.is_none() {
+                        self.done = true;
+                    }
+                    break;
+                }
+            };
+
+            let past_frontier = frontier.as_ref().is_some_and(|f| {
+                if reverse {
+                    &entry.key < f
+                } else {
+                    &entry.key > f
+                }
+            });
+            if past_frontier {
+                last = frontier.take();
+                break;
+            }
+
+            last = Some(entry.key.clone());
+            if let Some(value) = entry.value.live(now) {
+                entries.push_back((entry.key, value));
+            }
+        }
+
+        if let Some(last) = last {
+            if reverse {
+                self.end = Bound::Excluded(last);
+            } else {
+                self.start = Bound::Excluded(last);
+            }
+        }
+
+        if reverse {
+            self.back = entries;
+        } else {
+            self.front = entries;
+        }
+
+        Ok(())
+    }
+}
+
+impl Iterator for LsmScanIter {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Batches can come back empty if every key in them was removed
+        while self.front.is_empty() && !self.done && !is_empty_range(&self.start, &self.end) {
+            if let Err(e) = self.fill(false) {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+
+        // Once the range is used up, whatever was pulled from the back is next
+        self.front
+            .pop_front()
+            .or_else(|| self.back.pop_back())
+            .map(Ok)
+    }
+}
+
+impl DoubleEndedIterator for LsmScanIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        while self.back.is_empty() && !self.done && !is_empty_range(&self.start, &self.end) {
+            if let Err(e) = self.fill(true) {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+
+        self.back
+            .pop_front()
+            .or_else(|| self.front.pop_back())
+            .map(Ok)
+    }
+}
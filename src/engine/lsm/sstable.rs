@@ -0,0 +1,394 @@
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use super::bloom::{self, BloomFilter};
+use super::manifest::TableMeta;
+use super::memtable::{Entry, Value};
+use super::scan::in_range;
+use crate::engine::log::{encode_frame, read_frame, ReadAt};
+use crate::error::{Error, Result};
+
+// A table is an immutable file of entries in key order:
+//
+// - data blocks, each a framed msgpack list of entries
+// - the index: a framed list with the last key, offset and size of each block
+// - the bloom filter over every key, framed
+// - the footer (40 bytes): the offset and size of the index and of the filter
+//   (u64 LE each), then the magic
+//
+// The index and filter are kept in memory while the table is open, so a lookup
+// reads at most one block.
+const MAGIC: &[u8; 8] = b"KVSSST\r\n";
+const FOOTER_SIZE: usize = 40;
+
+// Bits of the bloom filter for each key, for a false positive rate of about 1%
+const BITS_PER_KEY: usize = 10;
+
+pub(super) fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:06}.sst", id))
+}
+
+// Location of a data block
+#[derive(Clone, Deserialize, Serialize)]
+struct BlockHandle {
+    #[serde(with = "serde_bytes")]
+    last_key: Vec<u8>,
+    offset: u64,
+    size: u64,
+}
+
+// Writes a new table, one entry at a time and in key order
+//
+// The table is written to a temporary file, which only takes the place of the
+// table once it is complete and synced.
+pub(super) struct TableBuilder {
+    id: u64,
+    path: PathBuf,
+    tmp_path: PathBuf,
+    writer: BufWriter<File>,
+    pos: u64,
+
+    block_size: usize,
+    block: Vec<Entry>,
+    block_bytes: usize,
+
+    index: Vec<BlockHandle>,
+    hashes: Vec<u64>,
+    smallest: Option<Vec<u8>>,
+}
+
+impl TableBuilder {
+    pub(super) fn new(dir: &Path, id: u64, block_size: usize) -> Result<Self> {
+        let path = table_path(dir, id);
+        let tmp_path = path.with_extension("sst.tmp");
+
+        Ok(Self {
+            id,
+            writer: BufWriter::new(File::create(&tmp_path)?),
+            path,
+            tmp_path,
+            pos: 0,
+            block_size,
+            block: Vec::new(),
+            block_bytes: 0,
+            index: Vec::new(),
+            hashes: Vec::new(),
+            smallest: None,
+        })
+    }
+
+    pub(super) fn add(&mut self, key: Vec<u8>, value: Value) -> Result<()> {
+        if self.smallest.is_none() {
+            self.smallest = Some(key.clone());
+        }
+        self.hashes.push(bloom::hash(&key));
+
+        self.block_bytes += key.len()
+            + match &value {
+                Value::Put(value, _) => value.len(),
+                Value::Delete => 0,
+            };
+        self.block.push(Entry { key, value });
+
+        if self.block_bytes >= self.block_size {
+            self.finish_block()?;
+        }
+
+        Ok(())
+    }
+
+    // Approximate size of the table so far
+    pub(super) fn size(&self) -> u64 {
+        self.pos + self.block_bytes as u64
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        let last_key = match self.block.last() {
+            Some(entry) => entry.key.clone(),
+            None => return Ok(()),
+        };
+
+        let frame = encode_frame(&rmp_serde::to_vec(&self.block)?);
+        self.writer.write_all(&frame)?;
+
+        self.index.push(BlockHandle {
+            last_key,
+            offset: self.pos,
+            size: frame.len() as u64,
+        });
+        self.pos += frame.len() as u64;
+        self.block.clear();
+        self.block_bytes = 0;
+
+        Ok(())
+    }
+
+    // Write out the rest of the table and open it
+    pub(super) fn finish(mut self) -> Result<Table> {
+        self.finish_block()?;
+
+        let smallest = self.smallest.take().unwrap_or_default();
+        let largest = self
+            .index
+            .last()
+            .map(|handle| handle.last_key.clone())
+            .unwrap_or_default();
+
+        let index = encode_frame(&rmp_serde::to_vec(&self.index)?);
+        let filter = BloomFilter::new(&self.hashes, BITS_PER_KEY);
+        let filter = encode_frame(&rmp_serde::to_vec(&filter)?);
+
+        let index_offset = self.pos;
+        let filter_offset = index_offset + index.len() as u64;
+
+        self.writer.write_all(&index)?;
+        self.writer.write_all(&filter)?;
+        for field in [
+            index_offset,
+            index.len() as u64,
+            filter_offset,
+            filter.len() as u64,
+        ] {
+            self.writer.write_all(&field.to_le_bytes())?;
+        }
+        self.writer.write_all(MAGIC)?;
+
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        fs::rename(&self.tmp_path, &self.path)?;
+
+        let size = filter_offset + filter.len() as u64 + FOOTER_SIZE as u64;
+        Table::open(
+            self.path.parent().unwrap(),
+            &TableMeta {
+                id: self.id,
+                smallest,
+                largest,
+                size,
+            },
+        )
+    }
+}
+
+// An open table
+pub(super) struct Table {
+    pub(super) meta: TableMeta,
+    path: PathBuf,
+    file: File,
+    index: Vec<BlockHandle>,
+    filter: BloomFilter,
+
+    // Set once the table is no longer part of the store. Scans may still be
+    // reading it, so the file is only deleted along with the last reference.
+    obsolete: AtomicBool,
+}
+
+impl Table {
+    pub(super) fn open(dir: &Path, meta: &TableMeta) -> Result<Self> {
+        let path = table_path(dir, meta.id);
+        let file = File::open(&path)?;
+        let corrupt = |offset: u64, reason: &str| Error::Corruption {
+            file: Some(path.clone()),
+            offset,
+            reason: reason.to_owned(),
+        };
+
+        let size = file.metadata()?.len();
+        if size < FOOTER_SIZE as u64 {
+            return Err(corrupt(0, "table is too short"));
+        }
+
+        let footer_pos = size - FOOTER_SIZE as u64;
+        let mut footer = [0u8; FOOTER_SIZE];
+        std::io::Read::read_exact(&mut ReadAt::new(&file, footer_pos), &mut footer)?;
+        if &footer[32..] != MAGIC {
+            return Err(corrupt(footer_pos, "bad table magic"));
+        }
+
+        let field = |i: usize| {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(&footer[i * 8..(i + 1) * 8]);
+            u64::from_le_bytes(buf)
+        };
+        let (index_offset, filter_offset) = (field(0), field(2));
+
+        let read = |offset: u64| -> Result<Vec<u8>> {
+            read_frame(&mut ReadAt::new(&file, offset), offset as usize)
+                .map_err(|e| e.in_file(&path))
+        };
+        let index = rmp_serde::from_slice(&read(index_offset)?)
+            .map_err(|e| corrupt(index_offset, &e.to_string()))?;
+        let filter = rmp_serde::from_slice(&read(filter_offset)?)
+            .map_err(|e| corrupt(filter_offset, &e.to_string()))?;
+
+        Ok(Self {
+            meta: meta.clone(),
+            path,
+            file,
+            index,
+            filter,
+            obsolete: AtomicBool::new(false),
+        })
+    }
+
+    pub(super) fn get(&self, key: &[u8]) -> Result<Option<Value>> {
+        if !self.filter.may_contain(key) {
+            return Ok(None);
+        }
+
+        // The first block that ends at or after the key is the only one that
+        // can hold it
+        let i = self
+            .index
+            .partition_point(|handle| handle.last_key.as_slice() < key);
+        if i == self.index.len() {
+            return Ok(None);
+        }
+
+        let block = self.read_block(i)?;
+        Ok(block
+            .binary_search_by(|entry| entry.key.as_slice().cmp(key))
+            .ok()
+            .map(|j| block[j].value.clone()))
+    }
+
+    fn read_block(&self, i: usize) -> Result<Vec<Entry>> {
+        let offset = self.index[i].offset;
+        let buf = read_frame(&mut ReadAt::new(&self.file, offset), offset as usize)
+            .map_err(|e| e.in_file(&self.path))?;
+
+        rmp_serde::from_slice(&buf).map_err(|e| Error::Corruption {
+            file: Some(self.path.clone()),
+            offset,
+            reason: e.to_string(),
+        })
+    }
+
+    pub(super) fn mark_obsolete(&self) {
+        self.obsolete.store(true, Ordering::SeqCst);
+    }
+
+    // Returns `true` if the table may hold keys in the range
+    pub(super) fn overlaps(&self, start: &Bound<Vec<u8>>, end: &Bound<Vec<u8>>) -> bool {
+        let after_start = match start {
+            Bound::Included(start) => &self.meta.largest >= start,
+            Bound::Excluded(start) => &self.meta.largest > start,
+            Bound::Unbounded => true,
+        };
+        let before_end = match end {
+            Bound::Included(end) => &self.meta.smallest <= end,
+            Bound::Excluded(end) => &self.meta.smallest < end,
+            Bound::Unbounded => true,
+        };
+
+        after_start && before_end
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        if self.obsolete.load(Ordering::SeqCst) {
+            if let Err(e) = fs::remove_file(&self.path) {
+                log::error!("Failed to remove {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+// Iterates over the entries of a table in a range, in key order or in reverse
+//
+// Blocks are read one at a time as the iterator advances.
+pub(super) struct TableIter {
+    table: Arc<Table>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    reverse: bool,
+
+    // Blocks that have not been read yet
+    first_block: usize,
+    last_block: usize,
+
+    entries: VecDeque<Entry>,
+}
+
+impl TableIter {
+    pub(super) fn new(
+        table: Arc<Table>,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        reverse: bool,
+    ) -> Self {
+        // Blocks from the first one that ends at or after the start, up to the
+        // first one that ends at or after the end
+        let first_block = match &start {
+            Bound::Included(key) | Bound::Excluded(key) => {
+                table.index.partition_point(|h| &h.last_key < key)
+            }
+            Bound::Unbounded => 0,
+        };
+        let last_block = match &end {
+            Bound::Included(key) | Bound::Excluded(key) => {
+                (table.index.partition_point(|h| &h.last_key < key) + 1).min(table.index.len())
+            }
+            Bound::Unbounded => table.index.len(),
+        };
+
+        Self {
+            table,
+            start,
+            end,
+            reverse,
+            first_block,
+            last_block,
+            entries: VecDeque::new(),
+        }
+    }
+}
+
+impl Iterator for TableIter {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = if self.reverse {
+                self.entries.pop_back()
+            } else {
+                self.entries.pop_front()
+            };
+            if let Some(entry) = entry {
+                return Some(Ok(entry));
+            }
+
+            if self.first_block >= self.last_block {
+                return None;
+            }
+            let i = if self.reverse {
+                self.last_block -= 1;
+                self.last_block
+            } else {
+                self.first_block += 1;
+                self.first_block - 1
+            };
+
+            match self.table.read_block(i) {
+                Ok(block) => {
+                    self.entries = block
+                        .into_iter()
+                        .filter(|entry| in_range(&entry.key, &self.start, &self.end))
+                        .collect()
+                }
+                Err(e) => {
+                    self.first_block = self.last_block;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use super::memtable::{Entry, Memtable};
+use crate::engine::log::{encode_frame, replay_frames, truncate_tail};
+use crate::error::{Error, Result};
+
+pub(super) fn wal_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:06}.wal", id))
+}
+
+// Write-ahead log of the memtable
+//
+// Every write is appended to the WAL before it goes into the memtable, so the
+// memtable can be rebuilt after a crash. Each record holds the entries of a
+// single write, so a batch is replayed in full or not at all. Once the memtable
+// is flushed to a table, its WAL is deleted.
+pub(super) struct Wal {
+    pub(super) id: u64,
+    writer: BufWriter<File>,
+}
+
+impl Wal {
+    pub(super) fn create(dir: &Path, id: u64) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(wal_path(dir, id))?;
+
+        Ok(Self {
+            id,
+            writer: BufWriter::new(file),
+        })
+    }
+
+    // Append the entries of a single write, handing them to the OS
+    //
+    // Returns the size of the record.
+    pub(super) fn append(&mut self, entries: &[Entry]) -> Result<u64> {
+        let buf = encode_frame(&rmp_serde::to_vec(entries)?);
+        self.writer.write_all(&buf)?;
+        self.writer.flush()?;
+
+        Ok(buf.len() as u64)
+    }
+
+    pub(super) fn file(&self) -> &File {
+        self.writer.get_ref()
+    }
+}
+
+// Replay a WAL into the memtable
+//
+// A record torn by a crash at the end of the WAL is dropped, along with
+// anything after it. Corruption anywhere else is an error.
+pub(super) fn replay_wal(dir: &Path, id: u64, memtable: &mut Memtable) -> Result<()> {
+    let path = wal_path(dir, id);
+    let file = File::open(&path)?;
+    let size = file.metadata()?.len() as usize;
+
+    replay_frames(
+        &mut BufReader::new(file),
+        0,
+        size,
+        |pos, _| truncate_tail(&path, pos, size),
+        |payload, pos, _| {
+            let entries: Vec<Entry> =
+                rmp_serde::from_slice(payload).map_err(|e| Error::Corruption {
+                    file: None,
+                    offset: pos as u64,
+                    reason: e.to_string(),
+                })?;
+            for entry in entries {
+                memtable.insert(entry.key, entry.value);
+            }
+            Ok(())
+        },
+    )
+    .map_err(|e| e.in_file(&path))?;
+
+    Ok(())
+}
@@ -0,0 +1,346 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{BufReader, ErrorKind, Write};
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::{ByteBuf, Bytes};
+
+use super::backup::{create_backup_dir, seal_backup};
+use super::log::sync_dir;
+use super::{
+    expires_at, is_empty_range, now_millis, BatchOp, ConditionFailed, ConditionalResult, KvsEngine,
+    KvsIterator, WriteBatch,
+};
+use crate::error::{Error, Result};
+
+// Name of the snapshot file in a backup
+pub(crate) const SNAPSHOT_NAME: &str = "snapshot";
+
+/// Storage engine that keeps every key in memory
+///
+/// Nothing is written to disk, unless the engine is given a snapshot file with
+/// `with_snapshot`. The store is then loaded from that file when it is opened,
+/// and saved back to it by `snapshot` and when the last handle is dropped.
+/// Writes made since the last snapshot are lost if the process dies.
+#[derive(Clone)]
+pub struct MemoryKvsEngine {
+    inner: Arc<MemoryInner>,
+}
+
+struct MemoryInner {
+    state: RwLock<State>,
+    snapshot_path: Option<PathBuf>,
+}
+
+#[derive(Default)]
+struct State {
+    map: BTreeMap<Vec<u8>, Entry>,
+
+    // Keys with a TTL, ordered by their expiry time
+    expiring: BTreeSet<(u64, Vec<u8>)>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Entry {
+    #[serde(with = "serde_bytes")]
+    value: Vec<u8>,
+    expires_at: Option<u64>,
+}
+
+impl Entry {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|ts| ts <= now)
+    }
+}
+
+impl MemoryKvsEngine {
+    /// Create an empty store that only lives as long as its handles
+    pub fn new() -> Self {
+        Self::with_state(State::default(), None)
+    }
+
+    /// Open a store that is loaded from, and saved to, the given file
+    ///
+    /// The store starts out empty if the file does not exist yet.
+    pub fn with_snapshot(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+
+        let mut state = State::default();
+        match File::open(&path) {
+            Ok(file) => {
+                let entries: Vec<(ByteBuf, Entry)> = rmp_serde::from_read(BufReader::new(file))?;
+                for (key, entry) in entries {
+                    state.insert(key.into_vec(), entry);
+                }
+                log::info!("Loaded {} keys from {}", state.map.len(), path.display());
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(Self::with_state(state, Some(path)))
+    }
+
+    fn with_state(state: State, snapshot_path: Option<PathBuf>) -> Self {
+        Self {
+            inner: Arc::new(MemoryInner {
+                state: RwLock::new(state),
+                snapshot_path,
+            }),
+        }
+    }
+
+    /// Save every key to the snapshot file, replacing the previous snapshot
+    ///
+    /// Does nothing if the engine has no snapshot file.
+    pub fn snapshot(&self) -> Result<()> {
+        self.inner.snapshot()
+    }
+
+    fn set_with_expiry(&self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        let mut state = self.inner.write();
+        state.purge_expired();
+        state.insert(key, Entry { value, expires_at });
+
+        Ok(())
+    }
+}
+
+impl Default for MemoryKvsEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KvsEngine for MemoryKvsEngine {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.set_with_expiry(key, value, None)
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.set_with_expiry(key, value, Some(expires_at(ttl)))
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let state = self.inner.read();
+        Ok(state
+            .get(&key, now_millis())
+            .map(|entry| entry.value.clone()))
+    }
+
+    fn expiry_bytes(&self, key: Vec<u8>) -> Result<Option<u64>> {
+        let state = self.inner.read();
+        Ok(state
+            .get(&key, now_millis())
+            .and_then(|entry| entry.expires_at))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let mut state = self.inner.write();
+        state.purge_expired();
+
+        match state.remove(&key) {
+            Some(_) => Ok(()),
+            None => Err(Error::KeyNotFound),
+        }
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut state = self.inner.write();
+        state.purge_expired();
+
+        for op in batch.into_ops() {
+            match op {
+                BatchOp::Set(key, value, ttl) => {
+                    let expires_at = ttl.map(expires_at);
+                    state.insert(key, Entry { value, expires_at });
+                }
+                BatchOp::Remove(key) => {
+                    state.remove(&key);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<ConditionalResult> {
+        let mut state = self.inner.write();
+        state.purge_expired();
+
+        let current = state.get(&key, now_millis()).map(|entry| &entry.value);
+        if current != expected.as_ref() {
+            return Ok(Err(ConditionFailed {
+                current: current.cloned(),
+            }));
+        }
+
+        match new {
+            Some(value) => state.insert(
+                key,
+                Entry {
+                    value,
+                    expires_at: None,
+                },
+            ),
+            None => {
+                state.remove(&key);
+            }
+        }
+
+        Ok(Ok(()))
+    }
+
+    // The range is copied out, so the scan does not hold up writers
+    fn scan_bytes(&self, range: impl RangeBounds<Vec<u8>>) -> Result<KvsIterator> {
+        let start = range.start_bound().cloned();
+        let end = range.end_bound().cloned();
+
+        if is_empty_range(&start, &end) {
+            return Ok(Box::new(std::iter::empty()));
+        }
+
+        let now = now_millis();
+        let state = self.inner.read();
+        let entries: Vec<_> = state
+            .map
+            .range((start, end))
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .map(|(key, entry)| Ok((key.clone(), entry.value.clone())))
+            .collect();
+
+        Ok(Box::new(entries.into_iter()))
+    }
+
+    // A backup is just a snapshot
+    fn backup(&self, dest: impl Into<PathBuf>) -> Result<()> {
+        let dest = dest.into();
+        create_backup_dir(&dest)?;
+
+        let created_at = now_millis();
+        self.inner.write_snapshot(&dest.join(SNAPSHOT_NAME))?;
+
+        seal_backup(&dest, "memory", created_at)
+    }
+}
+
+impl MemoryInner {
+    // A panic while the state was locked leaves at worst part of a batch
+    // applied, which beats throwing away every key, so the lock is taken even
+    // if it was poisoned
+    fn read(&self) -> RwLockReadGuard<'_, State> {
+        self.state.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, State> {
+        self.state.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn snapshot(&self) -> Result<()> {
+        match &self.snapshot_path {
+            Some(path) => self.write_snapshot(path),
+            None => Ok(()),
+        }
+    }
+
+    fn write_snapshot(&self, path: &Path) -> Result<()> {
+        // Encode while the store is locked, so the snapshot is taken at a
+        // single point in time, but write it out after letting go
+        let (buf, count) = {
+            let state = self.read();
+            let now = now_millis();
+            let entries: Vec<_> = state
+                .map
+                .iter()
+                .filter(|(_, entry)| !entry.is_expired(now))
+                .map(|(key, entry)| (Bytes::new(key), entry))
+                .collect();
+
+            (rmp_serde::to_vec(&entries)?, entries.len())
+        };
+
+        // Replace the previous snapshot in one go, so a crash leaves one or
+        // the other behind
+        let mut tmp_path = OsString::from(path);
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&buf)?;
+        file.sync_data()?;
+        drop(file);
+
+        fs::rename(&tmp_path, path)?;
+        match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => sync_dir(dir)?,
+            _ => sync_dir(Path::new("."))?,
+        }
+
+        log::info!("Saved {} keys to {}", count, path.display());
+
+        Ok(())
+    }
+}
+
+impl Drop for MemoryInner {
+    fn drop(&mut self) {
+        if let Err(e) = self.snapshot() {
+            log::error!("Saving the snapshot failed: {}", e);
+        }
+    }
+}
+
+impl State {
+    // Look up a key, unless it has expired
+    fn get(&self, key: &[u8], now: u64) -> Option<&Entry> {
+        self.map.get(key).filter(|entry| !entry.is_expired(now))
+    }
+
+    fn insert(&mut self, key: Vec<u8>, entry: Entry) {
+        let expires_at = entry.expires_at;
+
+        if let Some(old) = self.map.insert(key.clone(), entry) {
+            if let Some(ts) = old.expires_at {
+                self.expiring.remove(&(ts, key.clone()));
+            }
+        }
+
+        if let Some(ts) = expires_at {
+            self.expiring.insert((ts, key));
+        }
+    }
+
+    // Remove a key, returning its entry if it had not expired yet
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        let old = self.map.remove(key)?;
+        if let Some(ts) = old.expires_at {
+            self.expiring.remove(&(ts, key.to_vec()));
+        }
+
+        if old.is_expired(now_millis()) {
+            None
+        } else {
+            Some(old)
+        }
+    }
+
+    // Drop every key that has expired, to free up its memory
+    fn purge_expired(&mut self) {
+        let now = now_millis();
+
+        while self.expiring.first().is_some_and(|(ts, _)| *ts <= now) {
+            let (_, key) = self.expiring.pop_first().unwrap();
+            self.map.remove(&key);
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use std::ops::RangeFull;
+use std::time::Duration;
+
+use super::{now_millis, KvsEngine, WriteBatch};
+use crate::error::{Error, Result};
+
+// Largest number of keys and of bytes written to the destination in one batch,
+// here and by `import`
+pub(super) const BATCH_KEYS: usize = 1000;
+pub(super) const BATCH_BYTES: usize = 4 * 1024 * 1024; // 4 MB
+
+/// Copy every live key from one store into another, then check that both hold
+/// exactly the same keys, values and TTLs with `verify_migration`
+///
+/// Keys are streamed from a scan of the source and written to the destination
+/// in batches, so neither store has to fit in memory. The destination must be
+/// empty, and nothing may write to the source until the migration is done.
+///
+/// Keys with a TTL expire in the destination when they would have in the
+/// source.
+///
+/// Returns the number of keys copied.
+pub fn migrate<S: KvsEngine, D: KvsEngine>(source: &S, dest: &D) -> Result<u64> {
+    if dest.scan_bytes(RangeFull)?.next().is_some() {
+        return Err(Error::Generic(
+            "The destination store is not empty".to_owned(),
+        ));
+    }
+
+    let mut batch = WriteBatch::new();
+    let mut batch_bytes = 0;
+
+    for entry in source.scan_bytes(RangeFull)? {
+        let (key, value) = entry?;
+        batch_bytes += key.len() + value.len();
+
+        match source.expiry_bytes(key.clone())? {
+            Some(ts) => {
+                let ttl = Duration::from_millis(ts.saturating_sub(now_millis()));
+                batch.set_bytes_with_ttl(key, value, ttl);
+            }
+            None => batch.set_bytes(key, value),
+        }
+
+        if batch.len() >= BATCH_KEYS || batch_bytes >= BATCH_BYTES {
+            dest.write_batch(std::mem::take(&mut batch))?;
+            batch_bytes = 0;
+        }
+    }
+    dest.write_batch(batch)?;
+
+    verify_migration(source, dest)
+}
+
+/// Check that two stores hold exactly the same keys and values, with a TTL on
+/// the same keys
+///
+/// Both stores are scanned side by side, in key order. Returns the number of
+/// keys, or an error naming the first key that differs.
+pub fn verify_migration<S: KvsEngine, D: KvsEngine>(source: &S, dest: &D) -> Result<u64> {
+    let mut source_iter = source.scan_bytes(RangeFull)?;
+    let mut dest_iter = dest.scan_bytes(RangeFull)?;
+    let mut verified = 0;
+
+    loop {
+        let key = match (
+            source_iter.next().transpose()?,
+            dest_iter.next().transpose()?,
+        ) {
+            (None, None) => return Ok(verified),
+            (Some(a), Some(b)) if a == b => {
+                let key = a.0;
+                if source.expiry_bytes(key.clone())?.is_some()
+                    == dest.expiry_bytes(key.clone())?.is_some()
+                {
+                    verified += 1;
+                    continue;
+                }
+                key
+            }
+            // The smaller key is missing from the other store, or both have
+            // the key with different values
+            (Some((a, _)), Some((b, _))) => a.min(b),
+            (Some((key, _)), None) | (None, Some((key, _))) => key,
+        };
+
+        return Err(Error::Generic(format!(
+            "Stores differ at key {:?} after {} matching keys",
+            String::from_utf8_lossy(&key),
+            verified
+        )));
+    }
+}
@@ -1,13 +1,244 @@
+use std::io::{BufRead, Write};
+use std::ops::{Bound, RangeBounds};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use crate::error::Result;
 
+pub mod backup;
+pub mod batch;
+pub mod durability;
+pub mod export;
 pub mod kvs;
+mod lock;
+mod log;
+pub mod lsm;
+pub mod memory;
+pub mod migration;
 pub mod sled;
 
 pub use self::sled::SledKvsEngine;
-pub use kvs::KvStore;
+pub use backup::{restore, verify_backup, BackupFile, BackupManifest};
+pub use batch::{BatchOp, WriteBatch};
+pub use durability::Durability;
+pub use export::ExportFormat;
+pub use kvs::{KvStore, KvStoreOptions};
+pub use lock::StoreLock;
+pub use log::sync_dir;
+pub use lsm::{LsmKvsEngine, LsmOptions};
+pub use memory::MemoryKvsEngine;
+pub use migration::{migrate, verify_migration};
+
+/// Lazy iterator over key-value pairs, in key order
+///
+/// Use `rev()` to iterate in reverse order.
+pub type KvsIterator<T = Vec<u8>> = Box<dyn DoubleEndedIterator<Item = Result<(T, T)>> + Send>;
+
+/// Returned by a conditional write whose condition did not hold
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConditionFailed<T = Vec<u8>> {
+    /// Value of the key at the time of the write
+    pub current: Option<T>,
+}
+
+/// Outcome of a conditional write
+pub type ConditionalResult<T = Vec<u8>> = std::result::Result<(), ConditionFailed<T>>;
+
+/// A key-value storage engine
+///
+/// Keys and values are arbitrary byte strings. The `String` methods are a
+/// convenience layer on top of the `_bytes` ones; reading a value that is not
+/// valid UTF-8 through them returns an error.
+///
+/// Engines are handles that can be cloned and sent to other threads; all clones
+/// operate on the same underlying store.
+pub trait KvsEngine: Clone + Send + 'static {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// Set a key that expires once `ttl` has passed
+    ///
+    /// Expired keys are invisible to reads, and their space is eventually reclaimed.
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// Time at which a key expires, in milliseconds since the Unix epoch
+    ///
+    /// Returns `None` for a key without a TTL, as well as for a missing one.
+    fn expiry_bytes(&self, key: Vec<u8>) -> Result<Option<u64>>;
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+
+    /// Apply every write in the batch atomically
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Atomically replace the value of a key, if it currently has the expected one
+    ///
+    /// `None` stands for a missing key: an `expected` of `None` only matches if
+    /// the key does not exist, and a `new` of `None` removes the key. A key
+    /// written this way has no TTL.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<ConditionalResult>;
+
+    /// Set a key, unless it already exists
+    fn set_if_absent_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<ConditionalResult> {
+        self.compare_and_swap_bytes(key, None, Some(value))
+    }
+
+    /// Iterate over all keys in the given range
+    fn scan_bytes(&self, range: impl RangeBounds<Vec<u8>>) -> Result<KvsIterator>;
+
+    /// Iterate over all keys that start with the given prefix
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<KvsIterator> {
+        let end = prefix_end(&prefix);
+        self.scan_bytes((Bound::Included(prefix), end))
+    }
+
+    /// Copy the store, as it was at a single point in time, to the directory
+    /// `dest`, which must be missing or empty
+    ///
+    /// Writes keep going while the backup is taken, except on sled, where they
+    /// wait for it. The copy comes with a manifest of checksums, and can be
+    /// turned back into a store with `restore`.
+    fn backup(&self, dest: impl Into<PathBuf>) -> Result<()>;
+
+    /// Write every live key and value to `writer` as text, in key order
+    ///
+    /// Keys with a TTL are written with the time they expire at. Returns the
+    /// number of keys written.
+    fn export(&self, writer: impl Write, format: ExportFormat) -> Result<u64> {
+        export::export(self, writer, format)
+    }
+
+    /// Set every key read from `reader`, in the format written by `export`
+    ///
+    /// Keys are written in batches, each of which is applied atomically, so an
+    /// error partway through leaves the batches before it in place, and its
+    /// message says how many keys were imported. Keys keep their TTLs, and
+    /// expire when they would have in the exported store. Returns the number of
+    /// keys imported.
+    fn import(&self, reader: impl BufRead, format: ExportFormat) -> Result<u64> {
+        export::import(self, reader, format)
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn expiry(&self, key: String) -> Result<Option<u64>> {
+        self.expiry_bytes(key.into_bytes())
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<ConditionalResult<String>> {
+        let res = self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )?;
+
+        into_string_result(res)
+    }
+
+    fn set_if_absent(&self, key: String, value: String) -> Result<ConditionalResult<String>> {
+        into_string_result(self.set_if_absent_bytes(key.into_bytes(), value.into_bytes())?)
+    }
+
+    /// Iterate over all keys in the given range
+    fn scan(&self, range: impl RangeBounds<String>) -> Result<KvsIterator<String>> {
+        // UTF-8 preserves the order of code points, so the byte range selects
+        // exactly the same keys
+        let start = range.start_bound().cloned().map(String::into_bytes);
+        let end = range.end_bound().cloned().map(String::into_bytes);
+
+        Ok(into_strings(self.scan_bytes((start, end))?))
+    }
+
+    /// Iterate over all keys that start with the given prefix
+    fn scan_prefix(&self, prefix: String) -> Result<KvsIterator<String>> {
+        Ok(into_strings(self.scan_prefix_bytes(prefix.into_bytes())?))
+    }
+}
+
+/// Returns the (exclusive) end of the range of keys that start with `prefix`
+pub fn prefix_end(prefix: &[u8]) -> Bound<Vec<u8>> {
+    let mut end = prefix.to_vec();
+
+    // Bump the last byte that can be incremented, dropping any after it
+    while let Some(b) = end.pop() {
+        if b < u8::MAX {
+            end.push(b + 1);
+            return Bound::Excluded(end);
+        }
+    }
+
+    Bound::Unbounded
+}
+
+// Current time, in milliseconds since the Unix epoch
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// Time at which a key set now with the given TTL expires
+pub(crate) fn expires_at(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+// Decode the current value carried by a failed conditional write
+pub(crate) fn into_string_result(res: ConditionalResult) -> Result<ConditionalResult<String>> {
+    match res {
+        Ok(()) => Ok(Ok(())),
+        Err(ConditionFailed { current }) => Ok(Err(ConditionFailed {
+            current: current.map(String::from_utf8).transpose()?,
+        })),
+    }
+}
+
+fn into_strings(iter: KvsIterator) -> KvsIterator<String> {
+    Box::new(iter.map(|entry| {
+        let (key, value) = entry?;
+        Ok((String::from_utf8(key)?, String::from_utf8(value)?))
+    }))
+}
+
+// Returns `true` if no key can fall in the range
+//
+// `BTreeMap::range` panics on such ranges, so they need to be caught first.
+pub(crate) fn is_empty_range<T: Ord>(start: &Bound<T>, end: &Bound<T>) -> bool {
+    use Bound::*;
 
-pub trait KvsEngine {
-    fn set(&mut self, key: String, value: String) -> Result<()>;
-    fn get(&mut self, key: String) -> Result<Option<String>>;
-    fn remove(&mut self, key: String) -> Result<()>;
+    match (start, end) {
+        (Included(s), Included(e)) => s > e,
+        (Included(s), Excluded(e)) | (Excluded(s), Included(e)) | (Excluded(s), Excluded(e)) => {
+            s >= e
+        }
+        _ => false,
+    }
 }
@@ -1,15 +1,57 @@
+use std::ops::RangeBounds;
 use std::path::PathBuf;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
-use super::KvsEngine;
+use sled::transaction::{ConflictableTransactionResult, TransactionError, TransactionalTree};
+use sled::{Transactional, Tree};
+
+use super::backup::{create_backup_dir, seal_backup};
+use super::lock::DirLock;
+use super::{
+    expires_at, is_empty_range, now_millis, BatchOp, ConditionFailed, ConditionalResult,
+    Durability, KvsEngine, KvsIterator, WriteBatch,
+};
 use crate::error::{Error, Result};
 
 /// Wrapper for Sled storage engine
+///
+/// `sled::Db` is itself a cheaply cloneable, thread-safe handle.
+///
+/// Sled has no notion of expiry, so the expiry time of keys with a TTL is kept
+/// in separate trees. A background thread sweeps expired keys away.
+///
+/// Under `Durability::GroupCommit`, concurrent writers share sled's flushes, as
+/// a flush covers every write made before it.
+///
+/// Sled has no snapshots, so writes wait while a backup is taken.
+#[derive(Clone)]
 pub struct SledKvsEngine {
     db: sled::Db,
+
+    // Expiry time of every key with a TTL
+    ttl: Tree,
+
+    // Keys with a TTL, keyed by their expiry time followed by the key
+    expiring: Tree,
+
+    durability: Durability,
+
+    // Writes, the sweeper's included, share this lock, and a backup takes it
+    // exclusively, so that it copies every tree as of a single point in time.
+    // It guards no data, so a panic while it is held does not matter.
+    writes: Arc<RwLock<()>>,
+
+    // Shared by all handles; dropped along with the last one
+    _sweeper: Arc<Sweeper>,
+    _lock: Arc<DirLock>,
 }
 
 impl SledKvsEngine {
     const LOG_NAME: &'static str = "sled";
+    const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
 
     /// Returns `true` if log already exists
     pub fn is_log_present(path: impl Into<PathBuf>) -> bool {
@@ -18,40 +60,393 @@ impl SledKvsEngine {
         log_file.exists()
     }
 
+    /// Open the database, flushing every write to disk before it returns
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with_durability(path, Durability::Sync)
+    }
+
+    /// Open the database, flushing writes according to the given policy
+    pub fn open_with_durability(path: impl Into<PathBuf>, durability: Durability) -> Result<Self> {
         let log_dir = path.into();
         let log_file = log_dir.join(Self::LOG_NAME);
-        let db = sled::open(log_file)?;
+
+        // Sled locks its own files too, but that does not tell who holds them
+        let lock = DirLock::exclusive(&log_dir)?;
+
+        // Sled buffers writes in memory until it flushes them, so it keeps
+        // flushing in the background unless every write is flushed anyway
+        let config = sled::Config::new().path(log_file);
+        let db = match durability {
+            Durability::None => config,
+            Durability::Periodic(interval) => {
+                config.flush_every_ms(Some(interval.as_millis().max(1) as u64))
+            }
+            Durability::GroupCommit | Durability::Sync => config.flush_every_ms(None),
+        }
+        .open()?;
 
         log::info!("Opened DB, recovered = {}", db.was_recovered());
 
-        Ok(Self { db })
+        let ttl = db.open_tree("ttl")?;
+        let expiring = db.open_tree("expiring")?;
+        let writes = Arc::new(RwLock::new(()));
+        let sweeper = Sweeper::start(db.clone(), ttl.clone(), expiring.clone(), writes.clone());
+
+        Ok(Self {
+            db,
+            ttl,
+            expiring,
+            durability,
+            writes,
+            _sweeper: Arc::new(sweeper),
+            _lock: Arc::new(lock),
+        })
+    }
+
+    // Flush a write to disk, if the policy asks for it
+    fn commit(&self) -> Result<()> {
+        if self.durability.syncs_every_write() {
+            self.db.flush()?;
+        }
+        Ok(())
+    }
+
+    fn set_with_expiry(&self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        let _writing = self.writes.read().unwrap_or_else(PoisonError::into_inner);
+        transaction(&self.db, &self.ttl, &self.expiring, |db, ttl, expiring| {
+            db.insert(key.as_slice(), value.as_slice())?;
+            set_expiry(ttl, expiring, &key, expires_at)
+        })?;
+        self.commit()?;
+
+        Ok(())
     }
 }
 
 impl KvsEngine for SledKvsEngine {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.db.insert(key.as_bytes(), value.as_bytes())?;
-        self.db.flush()?;
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.set_with_expiry(key, value, None)
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.set_with_expiry(key, value, Some(expires_at(ttl)))
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let value = self.db.get(&key)?.map(|v| v.to_vec());
+
+        if value.is_some() && is_expired(&self.ttl, &key, now_millis())? {
+            return Ok(None);
+        }
+
+        Ok(value)
+    }
+
+    // Only keys with a TTL have an entry in the ttl tree
+    fn expiry_bytes(&self, key: Vec<u8>) -> Result<Option<u64>> {
+        let ts = self.ttl.get(&key)?.map(|ts| decode_ts(&ts));
+        Ok(ts.filter(|ts| *ts > now_millis()))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let _writing = self.writes.read().unwrap_or_else(PoisonError::into_inner);
+        let now = now_millis();
+        let found = transaction(&self.db, &self.ttl, &self.expiring, |db, ttl, expiring| {
+            let expired = match ttl.get(key.as_slice())? {
+                Some(ts) => decode_ts(&ts) <= now,
+                None => false,
+            };
+
+            let res = db.remove(key.as_slice())?;
+            clear_expiry(ttl, expiring, &key)?;
+
+            Ok(res.is_some() && !expired)
+        })?;
+        self.commit()?;
+
+        if found {
+            Ok(())
+        } else {
+            Err(Error::KeyNotFound)
+        }
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut data = sled::Batch::default();
+        let mut expiries = Vec::with_capacity(batch.len());
+
+        for op in batch.into_ops() {
+            match op {
+                BatchOp::Set(key, value, ttl) => {
+                    expiries.push((key.clone(), ttl.map(expires_at)));
+                    data.insert(key, value);
+                }
+                BatchOp::Remove(key) => {
+                    expiries.push((key.clone(), None));
+                    data.remove(key);
+                }
+            }
+        }
+
+        let _writing = self.writes.read().unwrap_or_else(PoisonError::into_inner);
+        transaction(&self.db, &self.ttl, &self.expiring, |db, ttl, expiring| {
+            db.apply_batch(&data)?;
+            for (key, expires_at) in expiries.iter() {
+                set_expiry(ttl, expiring, key, *expires_at)?;
+            }
+            Ok(())
+        })?;
+        self.commit()?;
+
         Ok(())
     }
 
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        let value = self
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<ConditionalResult> {
+        let _writing = self.writes.read().unwrap_or_else(PoisonError::into_inner);
+        let now = now_millis();
+        let res = transaction(&self.db, &self.ttl, &self.expiring, |db, ttl, expiring| {
+            // A key that expired but was not swept yet must not take part in the
+            // swap
+            let expired = match ttl.get(key.as_slice())? {
+                Some(ts) => decode_ts(&ts) <= now,
+                None => false,
+            };
+            let current = match db.get(key.as_slice())? {
+                Some(value) if !expired => Some(value.to_vec()),
+                _ => None,
+            };
+            if current != expected {
+                return Ok(Err(ConditionFailed { current }));
+            }
+
+            match &new {
+                Some(value) => db.insert(key.as_slice(), value.as_slice())?,
+                None => db.remove(key.as_slice())?,
+            };
+
+            // Values written by a swap have no TTL
+            clear_expiry(ttl, expiring, &key)?;
+
+            Ok(Ok(()))
+        })?;
+
+        if res.is_ok() {
+            self.commit()?;
+        }
+
+        Ok(res)
+    }
+
+    fn scan_bytes(&self, range: impl RangeBounds<Vec<u8>>) -> Result<KvsIterator> {
+        let start = range.start_bound().cloned();
+        let end = range.end_bound().cloned();
+
+        if is_empty_range(&start, &end) {
+            return Ok(Box::new(std::iter::empty()));
+        }
+
+        let now = now_millis();
+        let ttl = self.ttl.clone();
+
+        let iter = self
             .db
-            .get(key.as_bytes())?
-            .map(|v| String::from_utf8(v.to_vec()).unwrap());
+            .range::<Vec<u8>, _>((start, end))
+            .map(|res| {
+                let (key, value) = res?;
+                Ok((key.to_vec(), value.to_vec()))
+            })
+            .filter(move |res: &Result<_>| match res {
+                Ok((key, _)) => !is_expired(&ttl, key, now).unwrap_or(false),
+                Err(_) => true,
+            });
 
-        Ok(value)
+        Ok(Box::new(iter))
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        let res = self.db.remove(key.as_bytes())?;
-        self.db.flush()?;
+    // Sled has no snapshots, so this is sled's export: every tree, including
+    // the expiry times, is copied to a new database one key at a time. Writes
+    // wait until the copy is done, so that it holds every tree as it was when
+    // the backup started.
+    fn backup(&self, dest: impl Into<PathBuf>) -> Result<()> {
+        let dest = dest.into();
+        create_backup_dir(&dest)?;
+
+        let writes = self.writes.write().unwrap_or_else(PoisonError::into_inner);
+        let created_at = now_millis();
 
-        match res {
-            None => Err(Error::KeyNotFound),
-            _ => Ok(()),
+        // `sled::Db::export` and `import` panic on errors, so their loop is
+        // done here instead
+        let db = sled::Config::new()
+            .path(dest.join(Self::LOG_NAME))
+            .flush_every_ms(None)
+            .open()?;
+        for name in self.db.tree_names() {
+            let src = self.db.open_tree(&name)?;
+            let dst = db.open_tree(&name)?;
+
+            // Keys added past the end of the tree would keep the copy going
+            // for as long as they keep coming
+            let last = match src.last()? {
+                Some((key, _)) => key,
+                None => continue,
+            };
+            for res in src.range(..=last) {
+                let (key, value) = res?;
+                dst.insert(key, value)?;
+            }
         }
+        drop(writes);
+        db.flush()?;
+        drop(db);
+
+        seal_backup(&dest, "sled", created_at)
     }
 }
+
+// Background thread that removes expired keys
+struct Sweeper {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Sweeper {
+    fn start(db: sled::Db, ttl: Tree, expiring: Tree, writes: Arc<RwLock<()>>) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+
+        // Wakes up every interval until the last handle is dropped
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) =
+                stopped.recv_timeout(SledKvsEngine::SWEEP_INTERVAL)
+            {
+                let _writing = writes.read().unwrap_or_else(PoisonError::into_inner);
+                if let Err(e) = sweep(&db, &ttl, &expiring) {
+                    log::error!("Sweeping expired keys failed: {}", e);
+                }
+            }
+        });
+
+        Self {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Sweeper {
+    // The sweeper holds on to the database, so it must be gone before the
+    // database can be reopened
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+// Remove every key that has expired by now
+fn sweep(db: &sled::Db, ttl: &Tree, expiring: &Tree) -> Result<()> {
+    let now = now_millis();
+    let mut swept = 0;
+
+    for entry in expiring.range(..expiring_key(now.saturating_add(1), &[])) {
+        let (entry, _) = entry?;
+        let ts = decode_ts(&entry[..8]);
+        let key = &entry[8..];
+
+        expire(db, ttl, expiring, key, ts)?;
+        swept += 1;
+    }
+
+    if swept > 0 {
+        log::debug!("Swept {} expired keys", swept);
+        db.flush()?;
+    }
+
+    Ok(())
+}
+
+// Remove a key that expired at `ts`
+fn expire(db: &Tree, ttl: &Tree, expiring: &Tree, key: &[u8], ts: u64) -> Result<()> {
+    transaction(db, ttl, expiring, |db, ttl, expiring| {
+        // Only remove the key if it was not set again since
+        if ttl
+            .get(key)?
+            .is_some_and(|current| decode_ts(&current) == ts)
+        {
+            db.remove(key)?;
+            ttl.remove(key)?;
+        }
+        expiring.remove(expiring_key(ts, key))?;
+        Ok(())
+    })
+}
+
+// Run a transaction across the data and expiry trees
+fn transaction<T>(
+    db: &Tree,
+    ttl: &Tree,
+    expiring: &Tree,
+    f: impl Fn(
+        &TransactionalTree,
+        &TransactionalTree,
+        &TransactionalTree,
+    ) -> ConflictableTransactionResult<T>,
+) -> Result<T> {
+    (db, ttl, expiring)
+        .transaction(|(db, ttl, expiring)| f(db, ttl, expiring))
+        .map_err(|e| match e {
+            TransactionError::Storage(e) => e.into(),
+            TransactionError::Abort(()) => Error::Generic("Transaction aborted".to_owned()),
+        })
+}
+
+// Replace the expiry time of a key
+fn set_expiry(
+    ttl: &TransactionalTree,
+    expiring: &TransactionalTree,
+    key: &[u8],
+    expires_at: Option<u64>,
+) -> ConflictableTransactionResult<()> {
+    clear_expiry(ttl, expiring, key)?;
+
+    if let Some(ts) = expires_at {
+        ttl.insert(key, &ts.to_be_bytes())?;
+        expiring.insert(expiring_key(ts, key), &[])?;
+    }
+
+    Ok(())
+}
+
+// Forget the expiry time of a key, if it has one
+fn clear_expiry(
+    ttl: &TransactionalTree,
+    expiring: &TransactionalTree,
+    key: &[u8],
+) -> ConflictableTransactionResult<()> {
+    if let Some(ts) = ttl.remove(key)? {
+        expiring.remove(expiring_key(decode_ts(&ts), key))?;
+    }
+    Ok(())
+}
+
+fn is_expired(ttl: &Tree, key: &[u8], now: u64) -> sled::Result<bool> {
+    Ok(ttl.get(key)?.is_some_and(|ts| decode_ts(&ts) <= now))
+}
+
+// Timestamps are stored big-endian, so that they sort in time order
+fn expiring_key(ts: u64, key: &[u8]) -> Vec<u8> {
+    let mut buf = ts.to_be_bytes().to_vec();
+    buf.extend_from_slice(key);
+    buf
+}
+
+fn decode_ts(buf: &[u8]) -> u64 {
+    let mut ts = [0u8; 8];
+    ts.copy_from_slice(&buf[..8]);
+    u64::from_be_bytes(ts)
+}
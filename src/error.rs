@@ -1,53 +1,223 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
 use serde::{Deserialize, Serialize};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+// Underlying error, kept around for `source()`. It cannot be sent over the
+// wire, so a remote error only has the message.
+type Source = Option<Box<dyn std::error::Error + Send + Sync>>;
+
+// Errors are sent to clients as they are, so variants are only ever added at
+// the end, and the code of a variant never changes.
 #[derive(Debug, Deserialize, Serialize)]
 pub enum Error {
     Generic(String),
-    IOError(String),
-    SerializeError(String),
-    DeserializeError(String),
-    SledError(String),
+    Io {
+        #[serde(with = "io_kind")]
+        kind: io::ErrorKind,
+        message: String,
+        #[serde(skip)]
+        source: Source,
+    },
+    Serialize {
+        message: String,
+        #[serde(skip)]
+        source: Source,
+    },
+    Deserialize {
+        message: String,
+        #[serde(skip)]
+        source: Source,
+    },
+    Sled {
+        message: String,
+        #[serde(skip)]
+        source: Source,
+    },
+    // The peer did not follow the wire protocol
+    Protocol(String),
     KeyNotFound,
+    // The log is damaged at the given byte offset, of the given file if known
+    Corruption {
+        file: Option<PathBuf>,
+        offset: u64,
+        reason: String,
+    },
+    // The store was opened read-only
+    ReadOnly,
+    // A key or value is larger than the store accepts
+    TooLarge {
+        what: String,
+        size: u64,
+        max: u64,
+    },
+    // The store is in use by another process, which holds its lock
+    // The pid is only known if that process opened the store for writing
+    Locked {
+        pid: Option<u32>,
+    },
+    // The log is written in a format version this version cannot read
+    UnsupportedVersion {
+        found: u32,
+        expected: u32,
+    },
+    // The server did not advertise the capability a request needs
+    Unsupported {
+        capability: String,
+    },
+}
+
+impl Error {
+    /// Numeric code identifying the kind of error
+    ///
+    /// Codes are stable across versions and survive being sent to a client, so
+    /// they can be relied on to tell errors apart.
+    pub fn code(&self) -> u32 {
+        match self {
+            Self::Generic(_) => 1,
+            Self::Io { .. } => 2,
+            Self::Serialize { .. } => 3,
+            Self::Deserialize { .. } => 4,
+            Self::Sled { .. } => 5,
+            Self::Protocol(_) => 6,
+            Self::KeyNotFound => 7,
+            Self::Corruption { .. } => 8,
+            Self::ReadOnly => 9,
+            Self::TooLarge { .. } => 10,
+            Self::Locked { .. } => 11,
+            Self::UnsupportedVersion { .. } => 12,
+            Self::Unsupported { .. } => 13,
+        }
+    }
+
+    /// Kind of the underlying I/O error, if this is one
+    pub fn io_kind(&self) -> Option<io::ErrorKind> {
+        match self {
+            Self::Io { kind, .. } => Some(*kind),
+            _ => None,
+        }
+    }
+
+    // Record the file a corruption was found in, unless it is already known
+    pub(crate) fn in_file(self, path: &Path) -> Self {
+        match self {
+            Self::Corruption {
+                file: None,
+                offset,
+                reason,
+            } => Self::Corruption {
+                file: Some(path.to_owned()),
+                offset,
+                reason,
+            },
+            err => err,
+        }
+    }
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io { source, .. }
+            | Self::Serialize { source, .. }
+            | Self::Deserialize { source, .. }
+            | Self::Sled { source, .. } => source
+                .as_deref()
+                .map(|e| e as &(dyn std::error::Error + 'static)),
+            _ => None,
+        }
+    }
+}
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             Self::Generic(msg) => write!(f, "{}", msg),
-            Self::IOError(msg) => write!(f, "{}", msg.to_string()),
-            Self::SerializeError(msg) => write!(f, "SerializeError: {}", msg),
-            Self::DeserializeError(msg) => write!(f, "DeserializeError: {}", msg),
-            Self::SledError(msg) => write!(f, "SledError: {}", msg),
+            Self::Io { message, .. } => write!(f, "{}", message),
+            Self::Serialize { message, .. } => write!(f, "SerializeError: {}", message),
+            Self::Deserialize { message, .. } => write!(f, "DeserializeError: {}", message),
+            Self::Sled { message, .. } => write!(f, "SledError: {}", message),
+            Self::Protocol(msg) => write!(f, "ProtocolError: {}", msg),
             Self::KeyNotFound => write!(f, "Key not found"),
+            Self::Corruption {
+                file: Some(file),
+                offset,
+                reason,
+            } => write!(
+                f,
+                "Corruption in {} at offset {}: {}",
+                file.display(),
+                offset,
+                reason
+            ),
+            Self::Corruption {
+                file: None,
+                offset,
+                reason,
+            } => write!(f, "Corruption at offset {}: {}", offset, reason),
+            Self::ReadOnly => write!(f, "Store is read-only"),
+            Self::TooLarge { what, size, max } => write!(
+                f,
+                "{} of {} bytes exceeds the maximum of {} bytes",
+                what, size, max
+            ),
+            Self::Locked { pid: Some(pid) } => write!(f, "Store is locked by pid {}", pid),
+            Self::Locked { pid: None } => {
+                write!(f, "Store is locked by another process opening it read-only")
+            }
+            Self::UnsupportedVersion { found, expected } if found < expected => write!(
+                f,
+                "Log is in format version {}, expected {}; run `kvs upgrade` to upgrade it",
+                found, expected
+            ),
+            Self::UnsupportedVersion { found, expected } => write!(
+                f,
+                "Log is in format version {}, expected {}; it was written by a newer version",
+                found, expected
+            ),
+            Self::Unsupported { capability } => {
+                write!(f, "Server does not support {}", capability)
+            }
         }
     }
 }
 
-impl From<std::io::Error> for Error {
-    fn from(err: std::io::Error) -> Self {
-        Self::IOError(err.to_string())
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Self::Io {
+            kind: err.kind(),
+            message: err.to_string(),
+            source: Some(Box::new(err)),
+        }
     }
 }
 
 impl From<rmp_serde::decode::Error> for Error {
     fn from(err: rmp_serde::decode::Error) -> Self {
-        Self::DeserializeError(err.to_string())
+        Self::Deserialize {
+            message: err.to_string(),
+            source: Some(Box::new(err)),
+        }
     }
 }
 
 impl From<std::string::FromUtf8Error> for Error {
     fn from(err: std::string::FromUtf8Error) -> Self {
-        Self::DeserializeError(err.to_string())
+        Self::Deserialize {
+            message: err.to_string(),
+            source: Some(Box::new(err)),
+        }
     }
 }
 
 impl From<rmp_serde::encode::Error> for Error {
     fn from(err: rmp_serde::encode::Error) -> Self {
-        Self::SerializeError(err.to_string())
+        Self::Serialize {
+            message: err.to_string(),
+            source: Some(Box::new(err)),
+        }
     }
 }
 
@@ -67,7 +237,114 @@ impl From<sled::Error> for Error {
     fn from(err: sled::Error) -> Self {
         match err {
             sled::Error::CollectionNotFound(_) => Self::KeyNotFound,
-            _ => Self::SledError(err.to_string()),
+            // Keep the kind of I/O errors, like we do for our own
+            sled::Error::Io(err) => err.into(),
+            _ => Self::Sled {
+                message: err.to_string(),
+                source: Some(Box::new(err)),
+            },
+        }
+    }
+}
+
+// A lock is poisoned when a thread panicked while holding it, which may have left
+// what it guards half updated. The store refuses to go on with it, so that a
+// reopen can rebuild its state from the log.
+impl<T> From<std::sync::PoisonError<T>> for Error {
+    fn from(_: std::sync::PoisonError<T>) -> Self {
+        Self::Generic("Store is unusable after a panic; reopen it".to_owned())
+    }
+}
+
+// Exports are written with serde_json and csv, which fail either to write or to
+// serialize
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        if err.is_io() {
+            return io::Error::from(err).into();
+        }
+        Self::Serialize {
+            message: err.to_string(),
+            source: Some(Box::new(err)),
+        }
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(err: csv::Error) -> Self {
+        if let csv::ErrorKind::Io(io_err) = err.kind() {
+            return Self::Io {
+                kind: io_err.kind(),
+                message: io_err.to_string(),
+                source: Some(Box::new(err)),
+            };
+        }
+        Self::Serialize {
+            message: err.to_string(),
+            source: Some(Box::new(err)),
         }
     }
 }
+
+// `io::ErrorKind` is not serializable, so it is sent by name. Kinds the other
+// side does not know about come out as `Other`.
+mod io_kind {
+    use std::io::ErrorKind;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    const KINDS: &[ErrorKind] = &[
+        ErrorKind::NotFound,
+        ErrorKind::PermissionDenied,
+        ErrorKind::ConnectionRefused,
+        ErrorKind::ConnectionReset,
+        ErrorKind::HostUnreachable,
+        ErrorKind::NetworkUnreachable,
+        ErrorKind::ConnectionAborted,
+        ErrorKind::NotConnected,
+        ErrorKind::AddrInUse,
+        ErrorKind::AddrNotAvailable,
+        ErrorKind::NetworkDown,
+        ErrorKind::BrokenPipe,
+        ErrorKind::AlreadyExists,
+        ErrorKind::WouldBlock,
+        ErrorKind::NotADirectory,
+        ErrorKind::IsADirectory,
+        ErrorKind::DirectoryNotEmpty,
+        ErrorKind::ReadOnlyFilesystem,
+        ErrorKind::StaleNetworkFileHandle,
+        ErrorKind::InvalidInput,
+        ErrorKind::InvalidData,
+        ErrorKind::TimedOut,
+        ErrorKind::WriteZero,
+        ErrorKind::StorageFull,
+        ErrorKind::NotSeekable,
+        ErrorKind::QuotaExceeded,
+        ErrorKind::FileTooLarge,
+        ErrorKind::ResourceBusy,
+        ErrorKind::ExecutableFileBusy,
+        ErrorKind::Deadlock,
+        ErrorKind::CrossesDevices,
+        ErrorKind::TooManyLinks,
+        ErrorKind::InvalidFilename,
+        ErrorKind::ArgumentListTooLong,
+        ErrorKind::Interrupted,
+        ErrorKind::Unsupported,
+        ErrorKind::UnexpectedEof,
+        ErrorKind::OutOfMemory,
+        ErrorKind::Other,
+    ];
+
+    pub(super) fn serialize<S: Serializer>(kind: &ErrorKind, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&format!("{:?}", kind))
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<ErrorKind, D::Error> {
+        let name = String::deserialize(d)?;
+        Ok(KINDS
+            .iter()
+            .copied()
+            .find(|kind| format!("{:?}", kind) == name)
+            .unwrap_or(ErrorKind::Other))
+    }
+}
@@ -1,7 +1,12 @@
 pub mod client;
 pub mod engine;
 mod error;
+pub mod protocol;
 pub mod server;
+pub mod thread_pool;
 
-pub use engine::{KvStore, KvsEngine, SledKvsEngine};
+pub use engine::{
+    ConditionFailed, ConditionalResult, Durability, ExportFormat, KvStore, KvStoreOptions,
+    KvsEngine, KvsIterator, LsmKvsEngine, LsmOptions, MemoryKvsEngine, SledKvsEngine, WriteBatch,
+};
 pub use error::{Error, Result};
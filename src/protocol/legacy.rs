@@ -0,0 +1,148 @@
+// Shapes of responses from older versions of the protocol
+//
+// Requests only ever gained variants, and strings decode as binaries, so the
+// requests of an older version decode as they are. Responses changed shape,
+// and are converted into the one the client expects before they are sent.
+
+use serde::Serialize;
+
+use super::Response;
+use crate::{Error, Result};
+
+// Responses of version 1, which sent keys and values as strings
+#[derive(Debug, Serialize)]
+pub(super) enum ResponseV1 {
+    Ok,
+    Value(String),
+    Error(ErrorV2),
+    Page {
+        entries: Vec<(String, String)>,
+        cursor: Option<String>,
+    },
+}
+
+impl ResponseV1 {
+    fn try_from_response(response: Response) -> Result<Self> {
+        Ok(match ResponseV3::<Error>::from(response) {
+            ResponseV3::Ok => Self::Ok,
+            ResponseV3::Value(value) => Self::Value(into_string(value)?),
+            ResponseV3::Error(e) => Self::Error(error_v1(e)),
+            ResponseV3::Page { entries, cursor } => Self::Page {
+                entries: entries
+                    .into_iter()
+                    .map(|(key, value)| Ok((into_string(key)?, into_string(value)?)))
+                    .collect::<Result<_>>()?,
+                cursor: cursor.map(into_string).transpose()?,
+            },
+            // Only requests of version 1 get this far, and none of them is
+            // conditional
+            ResponseV3::ConditionFailed { .. } => {
+                return Err(Error::Protocol(
+                    "Conditional writes need protocol version 2".to_owned(),
+                ))
+            }
+        })
+    }
+}
+
+impl From<Response> for ResponseV1 {
+    fn from(response: Response) -> Self {
+        Self::try_from_response(response).unwrap_or_else(|e| Self::Error(error_v1(e)))
+    }
+}
+
+fn into_string(buf: Vec<u8>) -> Result<String> {
+    String::from_utf8(buf).map_err(|_| {
+        Error::Protocol(
+            "Protocol version 1 cannot send keys or values that are not UTF-8".to_owned(),
+        )
+    })
+}
+
+// Errors added in version 2 were sent to clients of version 1 with just their
+// message
+fn error_v1(err: Error) -> ErrorV2 {
+    match err {
+        Error::ReadOnly
+        | Error::TooLarge { .. }
+        | Error::Locked { .. }
+        | Error::UnsupportedVersion { .. } => ErrorV2::Generic(err.to_string()),
+        err => err.into(),
+    }
+}
+
+// Responses of versions 2 and 3, which only said `Ok` for most requests
+//
+// A `Get` of a missing key was answered with `Ok`, and a `Remove` of one with
+// a `KeyNotFound` error.
+#[derive(Debug, Serialize)]
+pub(super) enum ResponseV3<E> {
+    Ok,
+    Value(#[serde(with = "serde_bytes")] Vec<u8>),
+    Error(E),
+    Page {
+        #[serde(with = "super::entries")]
+        entries: Vec<(Vec<u8>, Vec<u8>)>,
+        #[serde(with = "serde_bytes")]
+        cursor: Option<Vec<u8>>,
+    },
+    ConditionFailed {
+        #[serde(with = "serde_bytes")]
+        current: Option<Vec<u8>>,
+    },
+}
+
+impl<E: From<Error>> From<Response> for ResponseV3<E> {
+    fn from(response: Response) -> Self {
+        match response {
+            Response::Found(value) => Self::Value(value),
+            Response::NotFound
+            | Response::Stored
+            | Response::Deleted
+            | Response::Swapped
+            | Response::BackedUp => Self::Ok,
+            Response::Conflict { current } => Self::ConditionFailed { current },
+            Response::Page { entries, cursor } => Self::Page { entries, cursor },
+            Response::Error(e) => Self::Error(e.into()),
+        }
+    }
+}
+
+// Errors of versions 1 and 2, which only kept the message of most errors
+#[derive(Debug, Serialize)]
+pub(super) enum ErrorV2 {
+    Generic(String),
+    IOError(String),
+    SerializeError(String),
+    DeserializeError(String),
+    SledError(String),
+    Protocol(String),
+    KeyNotFound,
+    Corruption { offset: u64, reason: String },
+    ReadOnly,
+    TooLarge { what: String, size: u64, max: u64 },
+    Locked { pid: Option<u32> },
+    UnsupportedVersion { found: u32, expected: u32 },
+}
+
+impl From<Error> for ErrorV2 {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Generic(msg) => Self::Generic(msg),
+            Error::Io { message, .. } => Self::IOError(message),
+            Error::Serialize { message, .. } => Self::SerializeError(message),
+            Error::Deserialize { message, .. } => Self::DeserializeError(message),
+            Error::Sled { message, .. } => Self::SledError(message),
+            Error::Protocol(msg) => Self::Protocol(msg),
+            Error::KeyNotFound => Self::KeyNotFound,
+            Error::Corruption { offset, reason, .. } => Self::Corruption { offset, reason },
+            Error::ReadOnly => Self::ReadOnly,
+            Error::TooLarge { what, size, max } => Self::TooLarge { what, size, max },
+            Error::Locked { pid } => Self::Locked { pid },
+            Error::UnsupportedVersion { found, expected } => {
+                Self::UnsupportedVersion { found, expected }
+            }
+            err @ Error::Unsupported { .. } => Self::Generic(err.to_string()),
+        }
+    }
+}
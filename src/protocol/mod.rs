@@ -0,0 +1,352 @@
+//! Wire protocol shared by `KvsServer` and `KvsClient`
+//!
+//! Every message is sent as a frame: a 4 byte little-endian length followed by
+//! that many bytes of msgpack. A connection starts with the client sending a
+//! `Hello` and the server answering with a `HelloResponse`, after which the
+//! client sends `Request`s and gets one `Response` back for each, in order.
+
+use std::io::{Read, Write};
+use std::ops::Bound;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Result, WriteBatch};
+
+mod legacy;
+
+use legacy::{ErrorV2, ResponseV1, ResponseV3};
+
+/// Current version of the protocol
+///
+/// Version 2 sends keys and values as msgpack binaries rather than strings.
+/// Version 3 sends structured errors, which keep their kind and code.
+/// Version 4 gives each request its own responses, e.g. `Found` or `NotFound`.
+/// Version 5 adds the `Backup` request.
+pub const PROTOCOL_VERSION: u32 = 5;
+
+/// Oldest version of the protocol that is still supported
+///
+/// A client that agrees on an older version than the current one only gets to
+/// send the requests that version had, and gets responses in its shape.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest version of the protocol that `KvsClient` speaks
+///
+/// Requests and responses have had their current shape since this version.
+pub const MIN_CLIENT_VERSION: u32 = 4;
+
+/// Largest frame that will be sent or accepted (64 MB)
+pub const MAX_FRAME_SIZE: u32 = 64 * 1024 * 1024;
+
+/// Features supported by this server, along with the protocol version that
+/// introduced each of them
+///
+/// Only the features of the version agreed on are advertised during the
+/// handshake.
+pub const CAPABILITIES: &[(&str, u32)] = &[
+    ("pipelining", 1),
+    ("scan", 1),
+    ("ttl", 2),
+    ("batch", 2),
+    ("cas", 2),
+    ("backup", 5),
+];
+
+/// Largest number of entries returned in a single page of a scan
+pub const MAX_PAGE_SIZE: u32 = 10_000;
+
+/// Sent by the client to open a connection
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Hello {
+    /// Range of protocol versions the client can speak
+    pub min_version: u32,
+    pub max_version: u32,
+}
+
+/// Sent by the server in response to a `Hello`
+#[derive(Debug, Deserialize, Serialize)]
+pub enum HelloResponse {
+    Accepted {
+        /// Protocol version used for the rest of the connection
+        version: u32,
+        capabilities: Vec<String>,
+        max_frame_size: u32,
+    },
+    /// The server closes the connection after sending this
+    Rejected(String),
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum Request {
+    Set(
+        #[serde(with = "serde_bytes")] Vec<u8>,
+        #[serde(with = "serde_bytes")] Vec<u8>,
+    ),
+    Get(#[serde(with = "serde_bytes")] Vec<u8>),
+    Remove(#[serde(with = "serde_bytes")] Vec<u8>),
+    /// Fetch a page of key-value pairs, in key order (or reverse key order)
+    ///
+    /// Pass the cursor returned with the previous page to get the next one.
+    Scan {
+        range: KeyRange,
+        reverse: bool,
+        limit: u32,
+        #[serde(with = "serde_bytes")]
+        cursor: Option<Vec<u8>>,
+    },
+    /// Set a key that expires after the given number of milliseconds
+    SetWithTtl(
+        #[serde(with = "serde_bytes")] Vec<u8>,
+        #[serde(with = "serde_bytes")] Vec<u8>,
+        u64,
+    ),
+    /// Apply a group of writes atomically
+    Batch(WriteBatch),
+    /// Replace the value of a key if it currently has the expected one
+    ///
+    /// `None` stands for a missing key, on either side.
+    CompareAndSwap {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        expected: Option<Vec<u8>>,
+        #[serde(with = "serde_bytes")]
+        new: Option<Vec<u8>>,
+    },
+    /// Write a backup of the store to a directory, relative to the server's
+    /// backup directory
+    Backup(String),
+}
+
+/// Answer to a `Request`
+///
+/// Every request has its own set of outcomes, so that a client never has to
+/// guess what a response means from the request it sent.
+#[derive(Debug, Deserialize, Serialize)]
+pub enum Response {
+    /// The value of a key, for a `Get`
+    Found(#[serde(with = "serde_bytes")] Vec<u8>),
+    /// The key of a `Get` or `Remove` does not exist
+    NotFound,
+    /// A `Set`, `SetWithTtl` or `Batch` was applied
+    Stored,
+    /// The key of a `Remove` was removed
+    Deleted,
+    /// The condition of a `CompareAndSwap` held and the write was applied
+    Swapped,
+    /// The condition of a `CompareAndSwap` did not hold, so nothing was written
+    Conflict {
+        #[serde(with = "serde_bytes")]
+        current: Option<Vec<u8>>,
+    },
+    /// A `Backup` was written in full
+    BackedUp,
+    /// A page of a scan; the cursor is `None` once the scan is done
+    Page {
+        #[serde(with = "entries")]
+        entries: Vec<(Vec<u8>, Vec<u8>)>,
+        #[serde(with = "serde_bytes")]
+        cursor: Option<Vec<u8>>,
+    },
+    Error(Error),
+}
+
+/// Keys selected by a scan
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum KeyRange {
+    Range(
+        #[serde(with = "bound")] Bound<Vec<u8>>,
+        #[serde(with = "bound")] Bound<Vec<u8>>,
+    ),
+    Prefix(#[serde(with = "serde_bytes")] Vec<u8>),
+}
+
+// `serde_bytes` only handles byte strings at the top level of a field, so the
+// nested ones need some help to be sent as binaries too
+mod bound {
+    use std::ops::Bound;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use serde_bytes::{ByteBuf, Bytes};
+
+    pub(super) fn serialize<S: Serializer>(
+        bound: &Bound<Vec<u8>>,
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        match bound {
+            Bound::Included(key) => Bound::Included(Bytes::new(key)),
+            Bound::Excluded(key) => Bound::Excluded(Bytes::new(key)),
+            Bound::Unbounded => Bound::Unbounded,
+        }
+        .serialize(s)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Bound<Vec<u8>>, D::Error> {
+        Ok(match Bound::<ByteBuf>::deserialize(d)? {
+            Bound::Included(key) => Bound::Included(key.into_vec()),
+            Bound::Excluded(key) => Bound::Excluded(key.into_vec()),
+            Bound::Unbounded => Bound::Unbounded,
+        })
+    }
+}
+
+mod entries {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde_bytes::{ByteBuf, Bytes};
+
+    type Entries = Vec<(Vec<u8>, Vec<u8>)>;
+
+    pub(super) fn serialize<S: Serializer>(entries: &Entries, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_seq(entries.iter().map(|(k, v)| (Bytes::new(k), Bytes::new(v))))
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Entries, D::Error> {
+        let entries = Vec::<(ByteBuf, ByteBuf)>::deserialize(d)?;
+        Ok(entries
+            .into_iter()
+            .map(|(k, v)| (k.into_vec(), v.into_vec()))
+            .collect())
+    }
+}
+
+impl Hello {
+    pub fn new() -> Self {
+        Self {
+            min_version: MIN_CLIENT_VERSION,
+            max_version: PROTOCOL_VERSION,
+        }
+    }
+
+    /// Pick the version to use with a client, if there is one we both speak
+    pub fn negotiate(&self) -> Option<u32> {
+        let version = self.max_version.min(PROTOCOL_VERSION);
+
+        if version < self.min_version || version < MIN_PROTOCOL_VERSION {
+            None
+        } else {
+            Some(version)
+        }
+    }
+}
+
+impl Default for Hello {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Request {
+    // Oldest version of the protocol that has this request
+    fn since_version(&self) -> u32 {
+        match self {
+            Self::Set(..) | Self::Get(_) | Self::Remove(_) | Self::Scan { .. } => 1,
+            Self::SetWithTtl(..) | Self::Batch(_) | Self::CompareAndSwap { .. } => 2,
+            Self::Backup(_) => 5,
+        }
+    }
+
+    // Capability the server has to advertise for this request, if any
+    pub(crate) fn capability(&self) -> Option<&'static str> {
+        match self {
+            Self::Set(..) | Self::Get(_) | Self::Remove(_) => None,
+            Self::Scan { .. } => Some("scan"),
+            Self::SetWithTtl(..) => Some("ttl"),
+            Self::Batch(_) => Some("batch"),
+            Self::CompareAndSwap { .. } => Some("cas"),
+            Self::Backup(_) => Some("backup"),
+        }
+    }
+}
+
+/// Features to advertise to a client that speaks the given version
+pub fn capabilities(version: u32) -> Vec<String> {
+    CAPABILITIES
+        .iter()
+        .filter(|(_, since)| *since <= version)
+        .map(|(name, _)| name.to_string())
+        .collect()
+}
+
+/// Decode a request sent by a client that speaks the given version
+///
+/// Requests that were added in a later version are rejected.
+pub fn decode_request(payload: &[u8], version: u32) -> Result<Request> {
+    let request: Request = decode_message(payload)?;
+
+    if request.since_version() > version {
+        return Err(Error::Protocol(format!(
+            "Request needs protocol version {}, but version {} is in use",
+            request.since_version(),
+            version
+        )));
+    }
+
+    Ok(request)
+}
+
+/// Write a response in the shape used by the given version
+pub fn write_response(writer: &mut impl Write, response: Response, version: u32) -> Result<()> {
+    match version {
+        1 => write_message(writer, &ResponseV1::from(response)),
+        2 => write_message(writer, &ResponseV3::<ErrorV2>::from(response)),
+        3 => write_message(writer, &ResponseV3::<Error>::from(response)),
+        // Version 5 only added a request, so responses have the same shape
+        4 | 5 => write_message(writer, &response),
+        _ => Err(Error::Protocol(format!(
+            "Unsupported protocol version {}",
+            version
+        ))),
+    }
+}
+
+/// Write a single message as a frame
+pub fn write_message<T: Serialize>(writer: &mut impl Write, message: &T) -> Result<()> {
+    let payload = rmp_serde::to_vec(message)?;
+
+    if payload.len() > MAX_FRAME_SIZE as usize {
+        return Err(Error::Protocol(format!(
+            "Frame of {} bytes exceeds the maximum of {} bytes",
+            payload.len(),
+            MAX_FRAME_SIZE
+        )));
+    }
+
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(&payload)?;
+
+    Ok(())
+}
+
+/// Read the payload of a single frame
+///
+/// The size is checked before anything is allocated, so a bogus length cannot
+/// make us allocate without bound.
+pub fn read_frame(reader: &mut impl Read) -> Result<Vec<u8>> {
+    let mut size = [0u8; 4];
+    reader.read_exact(&mut size)?;
+    let size = u32::from_le_bytes(size);
+
+    if size > MAX_FRAME_SIZE {
+        return Err(Error::Protocol(format!(
+            "Frame of {} bytes exceeds the maximum of {} bytes",
+            size, MAX_FRAME_SIZE
+        )));
+    }
+
+    let mut payload = vec![0u8; size as usize];
+    reader.read_exact(&mut payload)?;
+
+    Ok(payload)
+}
+
+/// Decode a message from the payload of a frame
+pub fn decode_message<T: DeserializeOwned>(payload: &[u8]) -> Result<T> {
+    Ok(rmp_serde::from_slice(payload)?)
+}
+
+/// Read and decode a single message
+pub fn read_message<T: DeserializeOwned>(reader: &mut impl Read) -> Result<T> {
+    let payload = read_frame(reader)?;
+    decode_message(&payload)
+}
@@ -1,76 +1,314 @@
-use std::io::Write;
+use std::io::{BufRead, BufReader, BufWriter, Write};
 use std::net::{TcpListener, TcpStream};
+use std::ops::Bound;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
 
-use serde::{Deserialize, Serialize};
-
+use crate::engine::{prefix_end, ConditionFailed};
+use crate::protocol::{
+    capabilities, decode_request, read_frame, read_message, write_message, write_response, Hello,
+    HelloResponse, KeyRange, MAX_FRAME_SIZE, MAX_PAGE_SIZE,
+};
+use crate::thread_pool::ThreadPool;
 use crate::{Error, KvsEngine, Result};
 
-#[derive(Debug, Deserialize, Serialize)]
-pub enum Request {
-    Set(String, String),
-    Get(String),
-    Remove(String),
+pub use crate::protocol::{Request, Response};
+
+/// Serves requests to a storage engine, handling each connection on a thread pool
+pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
+    store: E,
+    pool: P,
+    addr: String,
+
+    // Directory that `Backup` requests write below, if backups are allowed
+    backup_dir: Option<PathBuf>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-pub enum Response {
-    Ok,
-    Value(String),
-    Error(Error),
+impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
+    pub fn new(store: E, pool: P, addr: String) -> Result<Self> {
+        let server = KvsServer {
+            store,
+            pool,
+            addr,
+            backup_dir: None,
+        };
+        Ok(server)
+    }
+
+    /// Allow clients to have backups written to subdirectories of `dir`
+    ///
+    /// Backups are refused unless this is set, and the path a client sends must
+    /// be relative, and stay below `dir`.
+    pub fn backup_dir(&mut self, dir: impl Into<PathBuf>) -> &mut Self {
+        self.backup_dir = Some(dir.into());
+        self
+    }
+
+    pub fn start(&mut self) -> Result<()> {
+        let socket = TcpListener::bind(&self.addr)?;
+
+        loop {
+            let (stream, addr) = socket.accept()?;
+
+            let store = self.store.clone();
+            let backup_dir = self.backup_dir.clone();
+            self.pool.spawn(move || {
+                if let Err(e) = serve(store, backup_dir, stream, addr.to_string()) {
+                    log::error!("Failed to serve {}: {}", addr, e);
+                }
+            });
+        }
+    }
 }
 
-pub struct KvsServer {
-    store: Box<dyn KvsEngine>,
+// Serve requests on a connection until the client closes it
+//
+// Responses are buffered while the client has more requests in flight, so
+// pipelined requests get their responses in as few writes as possible.
+fn serve<E: KvsEngine>(
+    store: E,
+    backup_dir: Option<PathBuf>,
+    stream: TcpStream,
     addr: String,
-}
+) -> Result<()> {
+    log::info!("Accepted connection from {}", addr);
 
-impl KvsServer {
-    pub fn new(store: Box<dyn KvsEngine>, addr: String) -> Result<Self> {
-        let server = KvsServer { store, addr };
-        Ok(server)
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+
+    let version = handshake(&mut reader, &mut writer, &addr, backup_dir.is_some())?;
+
+    loop {
+        // The client closed the connection
+        if reader.fill_buf()?.is_empty() {
+            break;
+        }
+
+        // A frame that is too large cannot be skipped without reading it, so
+        // the connection is dropped
+        let payload = read_frame(&mut reader)?;
+
+        // Build a response based on the result of handling the request
+        let response = match decode_request(&payload, version) {
+            Ok(request) => {
+                match handle_request(&store, request, version, backup_dir.as_deref(), &addr) {
+                    Ok(response) => response,
+                    Err(e) => Response::Error(e),
+                }
+            }
+            Err(e) => Response::Error(e),
+        };
+
+        // Write back response to the socket, in the shape the client expects
+        write_response(&mut writer, response, version)?;
+
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
     }
 
-    fn handle_request(&mut self, stream: &TcpStream, addr: String) -> Result<Option<String>> {
-        log::info!("Received request from {}", addr);
+    log::info!("Closed connection from {}", addr);
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+// Agree on a protocol version with the client, and return it
+//
+// Backups are only advertised if they are allowed.
+fn handshake(
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+    addr: &str,
+    backups: bool,
+) -> Result<u32> {
+    let hello: Hello = read_message(reader)?;
 
-        let request: Request = rmp_serde::from_read(stream)?;
+    let response = match hello.negotiate() {
+        Some(version) => {
+            log::info!("Using protocol version {} with {}", version, addr);
 
-        let value = match request {
-            Request::Get(key) => {
-                log::info!("Get: {}", key);
-                self.store.get(key)?
+            HelloResponse::Accepted {
+                version,
+                capabilities: capabilities(version)
+                    .into_iter()
+                    .filter(|capability| backups || capability != "backup")
+                    .collect(),
+                max_frame_size: MAX_FRAME_SIZE,
             }
-            Request::Set(key, value) => {
-                log::info!("Set: {} -> {}", key, value);
-                self.store.set(key.clone(), value.clone())?;
-                None
+        }
+        None => HelloResponse::Rejected(format!(
+            "Unsupported protocol versions {}-{}",
+            hello.min_version, hello.max_version
+        )),
+    };
+
+    write_message(writer, &response)?;
+    writer.flush()?;
+
+    match response {
+        HelloResponse::Accepted { version, .. } => Ok(version),
+        HelloResponse::Rejected(msg) => Err(Error::Protocol(msg)),
+    }
+}
+
+fn handle_request<E: KvsEngine>(
+    store: &E,
+    request: Request,
+    version: u32,
+    backup_dir: Option<&Path>,
+    addr: &str,
+) -> Result<Response> {
+    log::info!("Received request from {}", addr);
+
+    let response = match request {
+        Request::Get(key) => {
+            log::info!("Get: {}", String::from_utf8_lossy(&key));
+            match store.get_bytes(key)? {
+                Some(value) => Response::Found(value),
+                None => Response::NotFound,
             }
-            Request::Remove(key) => {
-                log::info!("Remove: {}", key);
-                self.store.remove(key.clone())?;
-                None
+        }
+        Request::Set(key, value) => {
+            log::info!(
+                "Set: {} ({} bytes)",
+                String::from_utf8_lossy(&key),
+                value.len()
+            );
+            store.set_bytes(key, value)?;
+            Response::Stored
+        }
+        Request::SetWithTtl(key, value, ttl) => {
+            log::info!(
+                "Set: {} ({} bytes, expires in {} ms)",
+                String::from_utf8_lossy(&key),
+                value.len(),
+                ttl
+            );
+            store.set_bytes_with_ttl(key, value, Duration::from_millis(ttl))?;
+            Response::Stored
+        }
+        Request::Batch(batch) => {
+            log::info!("Batch: {} writes", batch.len());
+            store.write_batch(batch)?;
+            Response::Stored
+        }
+        Request::CompareAndSwap { key, expected, new } => {
+            log::info!("Compare and swap: {}", String::from_utf8_lossy(&key));
+            match store.compare_and_swap_bytes(key, expected, new)? {
+                Ok(()) => Response::Swapped,
+                Err(ConditionFailed { current }) => Response::Conflict { current },
             }
-        };
+        }
+        Request::Remove(key) => {
+            log::info!("Remove: {}", String::from_utf8_lossy(&key));
+            match store.remove_bytes(key) {
+                Ok(()) => Response::Deleted,
+                // Before version 4, a missing key was reported as an error
+                Err(Error::KeyNotFound) if version >= 4 => Response::NotFound,
+                Err(e) => return Err(e),
+            }
+        }
+        Request::Scan {
+            range,
+            reverse,
+            limit,
+            cursor,
+        } => {
+            log::info!(
+                "Scan: {:?} (reverse = {}, cursor = {:?})",
+                range,
+                reverse,
+                cursor
+            );
+            scan_page(store, range, reverse, limit, cursor)?
+        }
+        Request::Backup(path) => {
+            log::info!("Backup: {}", path);
+            store.backup(backup_path(backup_dir, &path)?)?;
+            Response::BackedUp
+        }
+    };
+
+    Ok(response)
+}
 
-        Ok(value)
+// Resolve the path of a `Backup` request against the backup directory
+//
+// Only a relative path made of plain names is accepted, so that a client
+// cannot have the server write outside of the backup directory.
+fn backup_path(backup_dir: Option<&Path>, path: &str) -> Result<PathBuf> {
+    let backup_dir = backup_dir
+        .ok_or_else(|| Error::Generic("Backups are not enabled on this server".to_owned()))?;
+
+    let path = Path::new(path);
+    let mut components = path.components().peekable();
+    if components.peek().is_none() || !components.all(|c| matches!(c, Component::Normal(_))) {
+        return Err(Error::Generic(format!(
+            "Invalid backup path: {}",
+            path.display()
+        )));
     }
 
-    pub fn start(&mut self) -> Result<()> {
-        let socket = TcpListener::bind(&self.addr)?;
+    Ok(backup_dir.join(path))
+}
 
-        loop {
-            let (mut stream, addr) = socket.accept()?;
-
-            // Build a response based on the result of handling the request
-            let response = match self.handle_request(&stream, addr.to_string()) {
-                Ok(v) if v.is_some() => Response::Value(v.unwrap()),
-                Ok(_) => Response::Ok,
-                Err(e) => Response::Error(e),
-            };
-
-            // Write back response to the socket
-            let buf = rmp_serde::to_vec(&response)?;
-            stream.write_all(&buf)?;
+// Fetch a single page of a scan
+//
+// The cursor is the last key of the previous page, so the scan resumes right
+// after it (or right before it, in reverse).
+fn scan_page<E: KvsEngine>(
+    store: &E,
+    range: KeyRange,
+    reverse: bool,
+    limit: u32,
+    cursor: Option<Vec<u8>>,
+) -> Result<Response> {
+    let (mut start, mut end) = match range {
+        KeyRange::Range(start, end) => (start, end),
+        KeyRange::Prefix(prefix) => {
+            let end = prefix_end(&prefix);
+            (Bound::Included(prefix), end)
+        }
+    };
+
+    if let Some(cursor) = cursor {
+        if reverse {
+            end = Bound::Excluded(cursor);
+        } else {
+            start = Bound::Excluded(cursor);
         }
     }
+
+    let iter = store.scan_bytes((start, end))?;
+    let iter: Box<dyn Iterator<Item = _>> = if reverse { Box::new(iter.rev()) } else { iter };
+
+    // Stop well short of the frame size, as values can be large
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    let max_bytes = MAX_FRAME_SIZE as usize / 2;
+
+    let mut entries = Vec::new();
+    let mut bytes = 0;
+    let mut more = false;
+
+    for entry in iter {
+        let (key, value) = entry?;
+
+        if entries.len() == limit || (bytes + key.len() + value.len() > max_bytes && bytes > 0) {
+            more = true;
+            break;
+        }
+
+        bytes += key.len() + value.len();
+        entries.push((key, value));
+    }
+
+    let cursor = if more {
+        entries.last().map(|(key, _)| key.clone())
+    } else {
+        None
+    };
+
+    Ok(Response::Page { entries, cursor })
 }
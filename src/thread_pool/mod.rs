@@ -0,0 +1,38 @@
+use crate::error::{Error, Result};
+
+mod naive;
+mod rayon;
+mod shared_queue;
+
+pub use self::rayon::RayonThreadPool;
+pub use naive::NaiveThreadPool;
+pub use shared_queue::SharedQueueThreadPool;
+
+/// A pool of threads that runs jobs in the background
+///
+/// A job that panics must not take the pool down with it: the remaining jobs
+/// should still run.
+pub trait ThreadPool {
+    /// Create a new pool with the given number of threads
+    ///
+    /// Fails if `threads` is 0.
+    fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Run a job on one of the threads in the pool
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
+
+// A pool without threads would never run the jobs spawned on it
+fn check_threads(threads: u32) -> Result<()> {
+    if threads == 0 {
+        return Err(Error::Generic(
+            "A thread pool needs at least one thread".to_owned(),
+        ));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,22 @@
+use std::thread;
+
+use super::{check_threads, ThreadPool};
+use crate::error::Result;
+
+/// Not really a pool: every job gets a thread of its own
+pub struct NaiveThreadPool;
+
+impl ThreadPool for NaiveThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        check_threads(threads)?;
+
+        Ok(Self)
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        thread::spawn(job);
+    }
+}
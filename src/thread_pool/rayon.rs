@@ -0,0 +1,29 @@
+use super::{check_threads, ThreadPool};
+use crate::error::{Error, Result};
+
+/// Work-stealing pool backed by rayon
+pub struct RayonThreadPool {
+    pool: rayon::ThreadPool,
+}
+
+impl ThreadPool for RayonThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        check_threads(threads)?;
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            // Without a handler, rayon aborts the process when a job panics
+            .panic_handler(|_| log::error!("A job in the thread pool panicked"))
+            .build()
+            .map_err(|e| Error::Generic(e.to_string()))?;
+
+        Ok(Self { pool })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.pool.spawn(job);
+    }
+}
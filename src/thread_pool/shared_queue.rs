@@ -0,0 +1,60 @@
+use std::thread;
+
+use crossbeam_channel::{Receiver, Sender};
+
+use super::{check_threads, ThreadPool};
+use crate::error::Result;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed number of threads pulling jobs from a single shared queue
+pub struct SharedQueueThreadPool {
+    sender: Sender<Job>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        check_threads(threads)?;
+
+        let (sender, receiver) = crossbeam_channel::unbounded::<Job>();
+
+        for _ in 0..threads {
+            let receiver = JobReceiver(receiver.clone());
+            thread::Builder::new().spawn(move || run_jobs(receiver))?;
+        }
+
+        Ok(Self { sender })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // Workers are respawned on panic, so the queue always has a receiver
+        self.sender
+            .send(Box::new(job))
+            .expect("No threads left in the pool");
+    }
+}
+
+#[derive(Clone)]
+struct JobReceiver(Receiver<Job>);
+
+impl Drop for JobReceiver {
+    // A job panicked and took its worker down with it, so start a new one
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let receiver = self.clone();
+            if let Err(e) = thread::Builder::new().spawn(move || run_jobs(receiver)) {
+                log::error!("Failed to replace a thread in the pool: {}", e);
+            }
+        }
+    }
+}
+
+// Run jobs until the pool is dropped
+fn run_jobs(receiver: JobReceiver) {
+    while let Ok(job) = receiver.0.recv() {
+        job();
+    }
+}
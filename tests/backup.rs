@@ -0,0 +1,155 @@
+use std::fs::{self, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use kvs::engine::{restore, verify_backup};
+use kvs::{
+    Error, KvStore, KvStoreOptions, KvsEngine, LsmKvsEngine, LsmOptions, MemoryKvsEngine, Result,
+    SledKvsEngine,
+};
+use tempfile::TempDir;
+
+// Back up a store while another thread keeps writing to it, then restore the
+// backup and check what made it in
+//
+// Keys written before the backup must all be there. Keys written during the
+// backup are written in order, so a point-in-time copy holds some prefix of them.
+fn backup_while_writing<E: KvsEngine>(store: E, open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    for i in 0..2000 {
+        store.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    for i in 0..2000 {
+        if i % 3 == 0 {
+            store.remove(format!("key{:04}", i))?;
+        }
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let store = store.clone();
+        let done = done.clone();
+        thread::spawn(move || -> Result<u64> {
+            let mut i = 0;
+            while !done.load(Ordering::SeqCst) {
+                store.set(format!("live{:06}", i), "x".repeat(100))?;
+                i += 1;
+            }
+            Ok(i)
+        })
+    };
+
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    store.backup(backup_dir.path())?;
+    done.store(true, Ordering::SeqCst);
+    let written = writer.join().unwrap()?;
+
+    let manifest = verify_backup(backup_dir.path())?;
+    assert!(!manifest.files.is_empty());
+
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    restore(backup_dir.path(), restore_dir.path())?;
+    let restored = open(restore_dir.path())?;
+
+    for i in 0..2000 {
+        let value = restored.get(format!("key{:04}", i))?;
+        if i % 3 == 0 {
+            assert_eq!(value, None);
+        } else {
+            assert_eq!(value, Some(format!("value{}", i)));
+        }
+    }
+
+    let live: Vec<_> = restored
+        .scan_prefix("live".to_owned())?
+        .collect::<Result<_>>()?;
+    assert!(live.len() as u64 <= written);
+    for (i, (key, _)) in live.iter().enumerate() {
+        assert_eq!(key, &format!("live{:06}", i));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn backup_kvs_store() -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .max_segment_size(16 * 1024)
+        .compaction_threshold(32 * 1024)
+        .open(dir.path())?;
+
+    backup_while_writing(store, |path| KvStore::open(path))
+}
+
+#[test]
+fn backup_sled_store() -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::open_with_durability(dir.path(), kvs::Durability::None)?;
+
+    backup_while_writing(store, |path| SledKvsEngine::open(path))
+}
+
+#[test]
+fn backup_lsm_store() -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmOptions::new()
+        .memtable_size(16 * 1024)
+        .level0_limit(2)
+        .open(dir.path())?;
+
+    backup_while_writing(store, |path| LsmKvsEngine::open(path))
+}
+
+#[test]
+fn backup_memory_store() -> Result<()> {
+    backup_while_writing(MemoryKvsEngine::new(), |path| {
+        MemoryKvsEngine::with_snapshot(path.join("snapshot"))
+    })
+}
+
+// A damaged or incomplete backup should be caught before anything is restored
+#[test]
+fn reject_corrupt_backup() -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup = backup_dir.path().join("backup");
+    store.backup(&backup)?;
+
+    // Backups only go to empty directories
+    assert!(matches!(store.backup(&backup), Err(Error::Generic(_))));
+
+    // Nor can a backup be restored over a store
+    assert!(matches!(
+        restore(&backup, dir.path()),
+        Err(Error::Generic(_))
+    ));
+
+    let segment = verify_backup(&backup)?.files[0].path.clone();
+    let mut file = OpenOptions::new().write(true).open(backup.join(&segment))?;
+    file.seek(SeekFrom::Start(100))?;
+    file.write_all(b"!")?;
+    drop(file);
+
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(matches!(
+        restore(&backup, restore_dir.path()),
+        Err(Error::Corruption { .. })
+    ));
+    assert!(!KvStore::is_log_present(restore_dir.path()));
+
+    fs::remove_file(backup.join(&segment))?;
+    assert!(matches!(
+        verify_backup(&backup),
+        Err(Error::Corruption { .. })
+    ));
+
+    Ok(())
+}
@@ -1,4 +1,5 @@
 use assert_cmd::prelude::*;
+use kvs::KvsEngine;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
 use std::process::Command;
@@ -16,6 +17,7 @@ fn client_cli_no_args() {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
@@ -48,6 +50,7 @@ fn client_cli_invalid_get() {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
@@ -87,6 +90,7 @@ fn client_cli_invalid_set() {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
@@ -118,7 +122,411 @@ fn client_cli_invalid_rm() {
         .failure();
 }
 
+// A missing key should have its own exit code, for `get` and `rm` alike.
 #[test]
+fn cli_missing_key() {
+    let temp_dir = TempDir::new().unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key", "value"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    for args in [&["get", "missing"][..], &["rm", "missing"][..]] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+            .code(2)
+            .stdout(is_empty())
+            .stderr(contains("Key not found"));
+    }
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rm", "key"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+}
+
+// A TTL that is not a number of seconds should be reported, and nothing set.
+#[test]
+fn cli_invalid_ttl() {
+    let temp_dir = TempDir::new().unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key", "value", "--ttl", "soon"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Invalid TTL: soon"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2);
+}
+
+// `kvs` should refuse to touch a store that another process has open.
+#[test]
+fn cli_locked_store() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = kvs::KvStore::open(temp_dir.path()).unwrap();
+
+    for args in [&["set", "key", "value"][..], &["get", "key"][..]] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stdout(contains(format!(
+                "Store is locked by pid {}",
+                std::process::id()
+            )));
+    }
+
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key", "value"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    // The server takes the lock before it looks at the store
+    let lock = kvs::engine::StoreLock::acquire(temp_dir.path()).unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4025"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains(format!(
+            "Store is locked by pid {}",
+            std::process::id()
+        )));
+    drop(lock);
+}
+
+// `kvs migrate` should move a store over to another engine, and back
+#[test]
+fn cli_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = kvs::KvStore::open(temp_dir.path()).unwrap();
+    for i in 0..100 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+    store.remove("key0".to_owned()).unwrap();
+    drop(store);
+
+    // The engine has to match the store
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate", "--from", "sled", "--to", "lsm"])
+        .arg(temp_dir.path())
+        .assert()
+        .failure()
+        .stdout(contains(
+            "Current engine kvs does not match selected engine sled",
+        ));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate", "--from", "kvs", "--to", "sled"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("Migrated 99 keys from kvs to sled\n"));
+
+    assert!(!kvs::KvStore::is_log_present(temp_dir.path()));
+    let engine = kvs::SledKvsEngine::open(temp_dir.path()).unwrap();
+    assert_eq!(engine.get("key0".to_owned()).unwrap(), None);
+    assert_eq!(
+        engine.get("key99".to_owned()).unwrap(),
+        Some("value99".to_owned())
+    );
+    drop(engine);
+
+    // The old store is kept until it is removed by hand
+    let old_dir = temp_dir.path().join("migrate.old");
+    assert!(kvs::KvStore::is_log_present(&old_dir));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate", "--from", "sled", "--to", "kvs"])
+        .arg(temp_dir.path())
+        .assert()
+        .failure()
+        .stdout(contains("remove it before migrating again"));
+    std::fs::remove_dir_all(&old_dir).unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate", "--from", "sled", "--to", "kvs"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("Migrated 99 keys from sled to kvs\n"));
+
+    assert!(!kvs::SledKvsEngine::is_log_present(temp_dir.path()));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key42"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value42\n");
+}
+
+// `kvs-client backup` should have the server back up its store while it runs,
+// and `kvs restore` should turn the backup back into a store
+#[test]
+fn cli_backup_and_restore() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4020";
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr, "--backup-dir", "backups"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for i in 0..10 {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", &format!("key{}", i), &format!("value{}", i)])
+            .args(["--addr", addr])
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["backup", "nightly", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    let backup = temp_dir.path().join("backups/nightly");
+
+    // Backups stay inside the backup directory
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["backup", "../escaped", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("Invalid backup path"));
+    assert!(!temp_dir.path().join("escaped").exists());
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    // A store cannot be restored over another one
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("restore")
+        .arg(&backup)
+        .arg(temp_dir.path())
+        .assert()
+        .failure()
+        .stdout(contains("A store already exists"));
+
+    let restore_dir = temp_dir.path().join("restored");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("restore")
+        .arg(&backup)
+        .arg(&restore_dir)
+        .assert()
+        .success()
+        .stdout(contains("Restored kvs store from"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key7"])
+        .current_dir(&restore_dir)
+        .assert()
+        .success()
+        .stdout("value7\n");
+}
+
+// `kvs export` should write a store out in a form `kvs import` can load into
+// another one
+#[test]
+fn cli_export_import() {
+    let temp_dir = TempDir::new().unwrap();
+    let source = temp_dir.path().join("source");
+    let dest = temp_dir.path().join("dest");
+    fs::create_dir(&source).unwrap();
+    fs::create_dir(&dest).unwrap();
+
+    for (key, value) in [("a", "1"), ("b,c", "two\nlines")].iter() {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["set", key, value])
+            .current_dir(&source)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("export")
+        .current_dir(&source)
+        .assert()
+        .success()
+        .stdout("{\"key\":\"a\",\"value\":\"1\"}\n{\"key\":\"b,c\",\"value\":\"two\\nlines\"}\n");
+
+    let export = temp_dir.path().join("export.csv");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--format", "csv", "--output"])
+        .arg(&export)
+        .current_dir(&source)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "--format", "csv"])
+        .arg(&export)
+        .current_dir(&dest)
+        .assert()
+        .success()
+        .stdout("Imported 2 keys\n");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "b,c"])
+        .current_dir(&dest)
+        .assert()
+        .success()
+        .stdout("two\nlines\n");
+
+    // Input that is not in the format is refused
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("import")
+        .arg(&export)
+        .current_dir(&dest)
+        .assert()
+        .failure();
+}
+
+// `kvs-inspect` should point out damage that keeps a store from opening, and
+// salvage what is left of it
+#[test]
+fn cli_inspect() {
+    let temp_dir = TempDir::new().unwrap();
+    let log_path = temp_dir.path().join("kvs-1.log");
+    let store = kvs::KvStore::open(temp_dir.path()).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let second = fs::metadata(&log_path).unwrap().len() as usize;
+    store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    store.set("key1".to_owned(), "value3".to_owned()).unwrap();
+    drop(store);
+
+    Command::cargo_bin("kvs-inspect")
+        .unwrap()
+        .arg("verify")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("2 live keys"))
+        .stdout(contains("key1 (2 writes)"))
+        .stdout(contains("Log is intact"));
+
+    // Damage the second record
+    let mut data = fs::read(&log_path).unwrap();
+    data[second + 20] ^= 0xff;
+    fs::write(&log_path, data).unwrap();
+
+    Command::cargo_bin("kvs-inspect")
+        .unwrap()
+        .arg("dump")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("set key1 (6 bytes)"))
+        .stdout(contains("Damaged record at offset"));
+
+    Command::cargo_bin("kvs-inspect")
+        .unwrap()
+        .arg("verify")
+        .arg(temp_dir.path())
+        .assert()
+        .failure()
+        .stdout(contains("Log is damaged"));
+
+    // Skipping the damage has to be asked for, as it may bring back old values
+    let salvaged = temp_dir.path().join("salvaged");
+    Command::cargo_bin("kvs-inspect")
+        .unwrap()
+        .arg("salvage")
+        .arg(temp_dir.path())
+        .arg(&salvaged)
+        .assert()
+        .failure()
+        .stdout(contains("stale values have to be allowed"));
+
+    Command::cargo_bin("kvs-inspect")
+        .unwrap()
+        .args(["salvage", "--allow-stale"])
+        .arg(temp_dir.path())
+        .arg(&salvaged)
+        .assert()
+        .success()
+        .stdout(contains("Skipped 1 damaged regions"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&salvaged)
+        .assert()
+        .success()
+        .stdout("value3\n");
+
+    // So does cutting off the intact records past it
+    Command::cargo_bin("kvs-inspect")
+        .unwrap()
+        .arg("truncate")
+        .arg(temp_dir.path())
+        .assert()
+        .failure()
+        .stdout(contains("stale values have to be allowed"));
+
+    Command::cargo_bin("kvs-inspect")
+        .unwrap()
+        .args(["truncate", "--allow-stale"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains(format!("Truncated {}", log_path.display())));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+}
+
+#[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
@@ -131,6 +539,7 @@ fn client_cli_invalid_subcommand() {
 
 // `kvs-client -V` should print the version
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
@@ -142,6 +551,7 @@ fn client_cli_version() {
 
 // `kvs-server -V` should print the version
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
@@ -151,7 +561,21 @@ fn server_cli_version() {
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+// `kvs-server --threads 0` should be rejected before anything is served
+#[test]
+fn server_cli_no_threads() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--threads", "0", "--addr", "127.0.0.1:4022"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Invalid number of threads"));
+}
+
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
     let stderr_path = temp_dir.path().join("stderr");
@@ -164,6 +588,7 @@ fn cli_log_configuration() {
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().unwrap();
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -172,6 +597,7 @@ fn cli_log_configuration() {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn cli_wrong_engine() {
     // sled first, kvs second
     {
@@ -184,6 +610,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
@@ -203,6 +630,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
@@ -212,18 +640,84 @@ fn cli_wrong_engine() {
     }
 }
 
-fn cli_access_server(engine: &str, addr: &str) {
+// The memory engine should save its snapshot when the server is told to stop,
+// and never conflict with a store in the directory
+#[test]
+fn cli_memory_engine_snapshot() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4008";
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key", "on disk"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "memory", "--durability", "sync", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("--durability is not supported by engine memory"));
+
+    for round in 0..2 {
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", "memory", "--snapshot", "snap", "--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        if round == 0 {
+            Command::cargo_bin("kvs-client")
+                .unwrap()
+                .args(["set", "key", "in memory", "--addr", addr])
+                .assert()
+                .success();
+        }
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", "key", "--addr", addr])
+            .assert()
+            .success()
+            .stdout("in memory\n");
+
+        // Stop the server the way a service manager would
+        Command::new("kill")
+            .arg(child.id().to_string())
+            .assert()
+            .success();
+        assert!(child.wait().unwrap().success());
+    }
+
+    // The store on disk is untouched
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("on disk\n");
+}
+
+#[allow(clippy::needless_borrows_for_generic_args)]
+fn cli_access_server(engine: &str, pool: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(&["--engine", engine, "--thread-pool", pool, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -264,15 +758,17 @@ fn cli_access_server(engine: &str, addr: &str) {
         .args(&["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
-        .success()
-        .stdout(contains("Key not found"));
+        .code(2)
+        .stdout(is_empty())
+        .stderr(contains("Key not found"));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
         .args(&["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
-        .failure()
+        .code(2)
+        .stdout(is_empty())
         .stderr(contains("Key not found"));
 
     Command::cargo_bin("kvs-client")
@@ -283,6 +779,31 @@ fn cli_access_server(engine: &str, addr: &str) {
         .success()
         .stdout(is_empty());
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--prefix", "key", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key1\tvalue2\nkey2\tvalue3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--reverse", "--limit", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key2\tvalue3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--limit", "many", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(is_empty())
+        .stderr(contains("Invalid limit: many"));
+
     Command::cargo_bin("kvs-client")
         .unwrap()
         .args(&["rm", "key1", "--addr", addr])
@@ -302,13 +823,14 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(&["--engine", engine, "--thread-pool", pool, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -324,18 +846,33 @@ fn cli_access_server(engine: &str, addr: &str) {
         .args(&["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
-        .success()
-        .stdout(contains("Key not found"));
+        .code(2)
+        .stderr(contains("Key not found"));
     sender.send(()).unwrap();
     handle.join().unwrap();
 }
 
 #[test]
 fn cli_access_server_kvs_engine() {
-    cli_access_server("kvs", "127.0.0.1:4004");
+    cli_access_server("kvs", "shared-queue", "127.0.0.1:4004");
 }
 
 #[test]
 fn cli_access_server_sled_engine() {
-    cli_access_server("sled", "127.0.0.1:4005");
+    cli_access_server("sled", "shared-queue", "127.0.0.1:4005");
+}
+
+#[test]
+fn cli_access_server_lsm_engine() {
+    cli_access_server("lsm", "shared-queue", "127.0.0.1:4009");
+}
+
+#[test]
+fn cli_access_server_naive_pool() {
+    cli_access_server("kvs", "naive", "127.0.0.1:4006");
+}
+
+#[test]
+fn cli_access_server_rayon_pool() {
+    cli_access_server("kvs", "rayon", "127.0.0.1:4007");
 }
@@ -0,0 +1,250 @@
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use kvs::{Error, ExportFormat, KvsEngine, MemoryKvsEngine, Result};
+
+// Keys and values that need escaping in one format or the other
+fn tricky_store() -> Result<MemoryKvsEngine> {
+    let store = MemoryKvsEngine::new();
+    store.set("plain".to_owned(), "value".to_owned())?;
+    store.set("comma,key".to_owned(), "a \"quoted\" value".to_owned())?;
+    store.set("lines".to_owned(), "first\nsecond\r\nthird".to_owned())?;
+    store.set("unicode".to_owned(), "caf\u{e9} \u{1f980}".to_owned())?;
+    store.set("control".to_owned(), "\u{0}\t\\".to_owned())?;
+    store.set("empty".to_owned(), String::new())?;
+    store.set_bytes(b"binary".to_vec(), b"\xff\x00\x80".to_vec())?;
+    store.set_bytes(b"\xfe\xff".to_vec(), b"text".to_vec())?;
+    Ok(store)
+}
+
+fn round_trip(format: ExportFormat) -> Result<()> {
+    let source = tricky_store()?;
+    for i in 0..2500 {
+        source.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+
+    let mut buf = Vec::new();
+    assert_eq!(source.export(&mut buf, format)?, 2508);
+
+    let dest = MemoryKvsEngine::new();
+    assert_eq!(dest.import(buf.as_slice(), format)?, 2508);
+
+    let source: Vec<_> = source.scan_bytes(..)?.collect::<Result<_>>()?;
+    let dest: Vec<_> = dest.scan_bytes(..)?.collect::<Result<_>>()?;
+    assert_eq!(source, dest);
+
+    Ok(())
+}
+
+#[test]
+fn json_lines_round_trip() -> Result<()> {
+    round_trip(ExportFormat::JsonLines)
+}
+
+#[test]
+fn csv_round_trip() -> Result<()> {
+    round_trip(ExportFormat::Csv)
+}
+
+// Text stays readable, and only entries that are not UTF-8 fall back to base64,
+// key and value together
+#[test]
+fn export_formats() -> Result<()> {
+    let store = tricky_store()?;
+
+    let mut buf = Vec::new();
+    store.export(&mut buf, ExportFormat::JsonLines)?;
+    assert_eq!(
+        String::from_utf8(buf)?,
+        concat!(
+            "{\"key\":\"YmluYXJ5\",\"value\":\"/wCA\",\"encoding\":\"base64\"}\n",
+            "{\"key\":\"comma,key\",\"value\":\"a \\\"quoted\\\" value\"}\n",
+            "{\"key\":\"control\",\"value\":\"\\u0000\\t\\\\\"}\n",
+            "{\"key\":\"empty\",\"value\":\"\"}\n",
+            "{\"key\":\"lines\",\"value\":\"first\\nsecond\\r\\nthird\"}\n",
+            "{\"key\":\"plain\",\"value\":\"value\"}\n",
+            "{\"key\":\"unicode\",\"value\":\"caf\u{e9} \u{1f980}\"}\n",
+            "{\"key\":\"/v8=\",\"value\":\"dGV4dA==\",\"encoding\":\"base64\"}\n",
+        )
+    );
+
+    let mut buf = Vec::new();
+    store.export(&mut buf, ExportFormat::Csv)?;
+    assert_eq!(
+        String::from_utf8(buf)?,
+        concat!(
+            "key,value,encoding,expires_at\n",
+            "YmluYXJ5,/wCA,base64,\n",
+            "\"comma,key\",\"a \"\"quoted\"\" value\",utf8,\n",
+            "control,\u{0}\t\\,utf8,\n",
+            "empty,,utf8,\n",
+            "lines,\"first\nsecond\r\nthird\",utf8,\n",
+            "plain,value,utf8,\n",
+            "unicode,caf\u{e9} \u{1f980},utf8,\n",
+            "/v8=,dGV4dA==,base64,\n",
+        )
+    );
+
+    Ok(())
+}
+
+// Input written by hand or by other tools should be accepted, and mistakes
+// reported with the line they are on
+#[test]
+fn import_errors() -> Result<()> {
+    let store = MemoryKvsEngine::new();
+
+    let input = concat!(
+        "{ \"value\" : \"\\u00e9\\ud83e\\udd80\", \"key\" : \"a\" }\n",
+        "\n",
+        "{\"key\":\"Yg==\",\"value\":\"AA==\",\"encoding\":\"base64\"}\r\n",
+    );
+    assert_eq!(store.import(input.as_bytes(), ExportFormat::JsonLines)?, 2);
+    assert_eq!(
+        store.get("a".to_owned())?,
+        Some("\u{e9}\u{1f980}".to_owned())
+    );
+    assert_eq!(store.get_bytes(b"b".to_vec())?, Some(vec![0]));
+
+    let input = "key,value,encoding,expires_at\r\nc,\"multi\r\nline\",utf8,\r\n";
+    assert_eq!(store.import(input.as_bytes(), ExportFormat::Csv)?, 1);
+    assert_eq!(store.get("c".to_owned())?, Some("multi\r\nline".to_owned()));
+
+    let invalid = [
+        (ExportFormat::JsonLines, "{\"key\":\"a\"}\n", 1),
+        (
+            ExportFormat::JsonLines,
+            "{\"key\":\"a\",\"value\":\"b\"}\n[]\n",
+            2,
+        ),
+        (
+            ExportFormat::JsonLines,
+            "{\"key\":\"a\",\"value\":\"b\",\"ttl\":\"1\"}\n",
+            1,
+        ),
+        (
+            ExportFormat::JsonLines,
+            "{\"key\":\"a\",\"value\":\"!\",\"encoding\":\"base64\"}\n",
+            1,
+        ),
+        (ExportFormat::Csv, "key,value,encoding,expires_at\na,b\n", 2),
+        (
+            ExportFormat::Csv,
+            "key,value,encoding,expires_at\na,b,utf8,\nc,\"d\n",
+            3,
+        ),
+        (
+            ExportFormat::Csv,
+            "key,value,encoding,expires_at\na,b,utf8,soon\n",
+            2,
+        ),
+        (
+            ExportFormat::Csv,
+            "key,value,encoding,expires_at\na,b,latin1,\n",
+            2,
+        ),
+    ];
+    for (format, input, line) in invalid.iter() {
+        match store.import(input.as_bytes(), *format) {
+            Err(Error::Generic(msg)) => {
+                assert!(msg.contains(&format!("line {}:", line)), "{}", msg)
+            }
+            res => panic!("{:?} was accepted: {:?}", input, res),
+        }
+    }
+
+    // CSV needs its header
+    assert!(matches!(
+        store.import("a,b,utf8\n".as_bytes(), ExportFormat::Csv),
+        Err(Error::Generic(_))
+    ));
+
+    Ok(())
+}
+
+// Keys with a TTL are exported with the time they expire at, and expire then
+// once imported
+#[test]
+fn export_ttls() -> Result<()> {
+    let source = MemoryKvsEngine::new();
+    source.set("plain".to_owned(), "value".to_owned())?;
+    source.set_with_ttl(
+        "long".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    source.set_with_ttl(
+        "short".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(300),
+    )?;
+    let expires_at = source.expiry("long".to_owned())?.unwrap();
+
+    let mut buf = Vec::new();
+    source.export(&mut buf, ExportFormat::JsonLines)?;
+    let text = String::from_utf8(buf)?;
+    assert!(text.contains(&format!(
+        "{{\"key\":\"long\",\"value\":\"value\",\"expires_at\":{}}}\n",
+        expires_at
+    )));
+    assert!(text.contains("{\"key\":\"plain\",\"value\":\"value\"}\n"));
+
+    for format in [ExportFormat::JsonLines, ExportFormat::Csv] {
+        let mut buf = Vec::new();
+        source.export(&mut buf, format)?;
+
+        let dest = MemoryKvsEngine::new();
+        assert_eq!(dest.import(buf.as_slice(), format)?, 3);
+        assert_eq!(dest.expiry("long".to_owned())?, Some(expires_at));
+        assert_eq!(dest.expiry("plain".to_owned())?, None);
+        assert!(dest.expiry("short".to_owned())?.is_some());
+    }
+
+    // A key whose time is up by the time it is imported is already expired
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    let input = format!(
+        "{{\"key\":\"old\",\"value\":\"value\",\"expires_at\":{}}}\n",
+        now - 1000
+    );
+    let dest = MemoryKvsEngine::new();
+    dest.import(input.as_bytes(), ExportFormat::JsonLines)?;
+    thread::sleep(Duration::from_millis(10));
+    assert_eq!(dest.get("old".to_owned())?, None);
+
+    Ok(())
+}
+
+// An import that fails partway through keeps the batches before the error,
+// and says how many keys they held
+#[test]
+fn partial_import() -> Result<()> {
+    let mut input = String::new();
+    for i in 0..2500 {
+        input.push_str(&format!(
+            "{{\"key\":\"key{:04}\",\"value\":\"value\"}}\n",
+            i
+        ));
+    }
+    input.push_str("{\"key\":\"broken\"}\n");
+
+    let store = MemoryKvsEngine::new();
+    match store.import(input.as_bytes(), ExportFormat::JsonLines) {
+        Err(Error::Generic(msg)) => {
+            assert!(msg.contains("line 2501:"), "{}", msg);
+            assert!(msg.contains("2000 keys were imported"), "{}", msg);
+        }
+        res => panic!("the import did not fail: {:?}", res),
+    }
+    assert_eq!(store.scan_bytes(..)?.count(), 2000);
+
+    // Nothing is said about keys when none were imported
+    match store.import("{}\n".as_bytes(), ExportFormat::JsonLines) {
+        Err(Error::Generic(msg)) => assert!(!msg.contains("imported"), "{}", msg),
+        res => panic!("the import did not fail: {:?}", res),
+    }
+
+    Ok(())
+}
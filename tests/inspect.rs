@@ -0,0 +1,245 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use kvs::engine::kvs::{LogCommand, SegmentReader};
+use kvs::{Error, KvStore, KvsEngine, Result, WriteBatch};
+use tempfile::TempDir;
+
+// Write `count` keys to a fresh store, returning the offset of every record
+fn write_keys(dir: &Path, count: usize) -> Result<Vec<u64>> {
+    let store = KvStore::open(dir)?;
+    let log_path = dir.join("kvs-1.log");
+
+    let mut offsets = Vec::new();
+    for i in 0..count {
+        offsets.push(fs::metadata(&log_path)?.len());
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    Ok(offsets)
+}
+
+// Every record should be read back as it was written, and bytes counted as
+// live or dead the same way compaction sees them
+#[test]
+fn inspect_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("a".to_owned(), "22".to_owned())?;
+    store.set("b".to_owned(), "1".to_owned())?;
+    store.remove("b".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("c".to_owned(), "333".to_owned());
+    batch.remove("a".to_owned());
+    store.write_batch(batch)?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("kvs-1.log");
+    let mut reader = SegmentReader::open(&log_path)?;
+    assert_eq!(reader.version(), 1);
+    assert_eq!(reader.gen(), Some(1));
+
+    let mut offset = reader.header_size();
+    let mut commands = Vec::new();
+    for record in &mut reader {
+        let record = record.unwrap();
+        assert_eq!(record.offset, offset);
+        offset += record.size;
+        commands.push(record.command);
+    }
+    assert_eq!(offset, fs::metadata(&log_path)?.len());
+
+    assert_eq!(commands.len(), 5);
+    assert!(matches!(
+        &commands[1],
+        LogCommand::Set { key, value_size: 2, expires_at: None } if key == b"a"
+    ));
+    assert!(matches!(&commands[3], LogCommand::Remove { key } if key == b"b"));
+    match &commands[4] {
+        LogCommand::Batch(commands) => assert_eq!(commands.len(), 2),
+        command => panic!("expected a batch, found {:?}", command),
+    }
+
+    let report = KvStore::inspect(temp_dir.path())?;
+    assert!(!report.is_damaged());
+    assert_eq!(report.live_keys, 1);
+    assert_eq!(report.duplicate_keys.get(b"a".as_slice()), Some(&3));
+    assert_eq!(report.duplicate_keys.get(b"b".as_slice()), Some(&2));
+    assert_eq!(report.duplicate_keys.get(b"c".as_slice()), None);
+
+    let segment = &report.segments[0];
+    assert_eq!(segment.records, 5);
+    assert_eq!(
+        segment.live_bytes + segment.dead_bytes,
+        segment.size - reader.header_size()
+    );
+    assert!(segment.live_bytes > 0 && segment.dead_bytes > segment.live_bytes);
+
+    Ok(())
+}
+
+// A torn tail is told apart from damage in the middle of the log
+#[test]
+fn inspect_damage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let offsets = write_keys(temp_dir.path(), 10)?;
+    let log_path = temp_dir.path().join("kvs-1.log");
+
+    let mut file = OpenOptions::new().append(true).open(&log_path)?;
+    file.write_all(b"torn")?;
+    drop(file);
+
+    let damage = KvStore::inspect(temp_dir.path())?.segments[0]
+        .damage
+        .clone()
+        .unwrap();
+    assert!(damage.torn);
+
+    let mut data = fs::read(&log_path)?;
+    data[offsets[5] as usize + 20] ^= 0xff;
+    fs::write(&log_path, data)?;
+
+    let report = KvStore::inspect(temp_dir.path())?;
+    let segment = &report.segments[0];
+    assert_eq!(segment.records, 5);
+    let damage = segment.damage.clone().unwrap();
+    assert_eq!(damage.offset, offsets[5]);
+    assert!(!damage.torn);
+
+    // Reading carries on past the damage
+    let mut reader = SegmentReader::open(&log_path)?;
+    assert_eq!((&mut reader).filter_map(|res| res.ok()).count(), 5);
+    assert_eq!(reader.skip_damage(), Some(offsets[6]));
+    assert_eq!((&mut reader).filter_map(|res| res.ok()).count(), 4);
+
+    Ok(())
+}
+
+// Salvaging keeps every intact record, truncating drops everything from the
+// damage on, and both leave a log that opens
+#[test]
+fn repair_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let offsets = write_keys(temp_dir.path(), 100)?;
+    let log_path = temp_dir.path().join("kvs-1.log");
+
+    let mut data = fs::read(&log_path)?;
+    data[offsets[40] as usize + 20] ^= 0xff;
+    fs::write(&log_path, data)?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    // Skipping damage may bring back old values, so it has to be allowed
+    let salvaged = TempDir::new().expect("unable to create temporary working directory");
+    assert!(matches!(
+        KvStore::salvage(temp_dir.path(), salvaged.path(), false),
+        Err(Error::Generic(_))
+    ));
+    assert!(!KvStore::is_log_present(salvaged.path()));
+
+    let repairs = KvStore::salvage(temp_dir.path(), salvaged.path(), true)?;
+    assert_eq!(repairs.len(), 1);
+    assert_eq!(repairs[0].records, 99);
+    assert_eq!(repairs[0].dropped, offsets[41] - offsets[40]);
+
+    // A store cannot be salvaged over another one
+    assert!(KvStore::salvage(temp_dir.path(), salvaged.path(), true).is_err());
+
+    let store = KvStore::open(salvaged.path())?;
+    for i in 0..100 {
+        let value = store.get(format!("key{}", i))?;
+        if i == 40 {
+            assert_eq!(value, None);
+        } else {
+            assert_eq!(value, Some(format!("value{}", i)));
+        }
+    }
+    drop(store);
+
+    // A hard link to the segment, as a backup would have, is left alone
+    let linked = salvaged.path().join("linked.log");
+    fs::hard_link(&log_path, &linked)?;
+    let size = fs::metadata(&log_path)?.len();
+
+    // Cutting off intact records past the damage has to be allowed as well
+    assert!(matches!(
+        KvStore::truncate_damaged(temp_dir.path(), false),
+        Err(Error::Generic(_))
+    ));
+    assert_eq!(fs::metadata(&log_path)?.len(), size);
+
+    let repairs = KvStore::truncate_damaged(temp_dir.path(), true)?;
+    assert_eq!(repairs.len(), 1);
+    assert_eq!(fs::metadata(&log_path)?.len(), offsets[40]);
+    assert_eq!(fs::metadata(&linked)?.len(), size);
+    assert!(KvStore::truncate_damaged(temp_dir.path(), false)?.is_empty());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key39".to_owned())?, Some("value39".to_owned()));
+    assert_eq!(store.get("key41".to_owned())?, None);
+
+    Ok(())
+}
+
+// A command as the very first version of kvs wrote it to `kvs.log`
+#[derive(serde::Serialize)]
+#[allow(dead_code)]
+enum BaselineCommand {
+    Get(String),
+    Set(String, String),
+    Remove(String),
+}
+
+// A `kvs.log` from before segments is read with the framing it was written
+// with, and salvaging it frames its records the way they are now
+#[test]
+fn inspect_baseline_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("kvs.log");
+
+    let mut commands: Vec<_> = (0..10)
+        .map(|i| BaselineCommand::Set(format!("key{}", i), format!("value{}", i)))
+        .collect();
+    commands.push(BaselineCommand::Remove("key0".to_owned()));
+
+    let mut data = Vec::new();
+    let mut offsets = Vec::new();
+    for command in &commands {
+        let buf = rmp_serde::to_vec(command)?;
+        offsets.push(data.len() as u64);
+        data.extend_from_slice(&(buf.len() as u64).to_le_bytes());
+        data.extend_from_slice(&buf);
+    }
+    data.extend_from_slice(&[0; 3]);
+    fs::write(&log_path, &data)?;
+
+    let mut reader = SegmentReader::open(&log_path)?;
+    assert_eq!(reader.version(), 0);
+    for offset in &offsets {
+        assert_eq!(reader.next().unwrap().unwrap().offset, *offset);
+    }
+    let damage = reader.next().unwrap().unwrap_err();
+    assert_eq!(damage.offset, data.len() as u64 - 3);
+    assert!(damage.torn);
+
+    let report = KvStore::inspect(temp_dir.path())?;
+    assert_eq!(report.segments[0].records, 11);
+    assert_eq!(report.live_keys, 9);
+
+    // Only the torn tail is dropped, so nothing comes back
+    let salvaged = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::salvage(temp_dir.path(), salvaged.path(), false)?;
+    let store = KvStore::open(salvaged.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key9".to_owned())?, Some("value9".to_owned()));
+    drop(store);
+
+    // Losing the removal would bring the key back
+    data[offsets[10] as usize + 8] = 0xc1;
+    fs::write(&log_path, &data)?;
+    let salvaged = TempDir::new().expect("unable to create temporary working directory");
+    assert!(KvStore::salvage(temp_dir.path(), salvaged.path(), false).is_err());
+
+    Ok(())
+}
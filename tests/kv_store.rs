@@ -1,4 +1,12 @@
-use kvs::{KvStore, KvsEngine, Result};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::thread;
+use std::time::Duration;
+
+use kvs::engine::StoreLock;
+use kvs::{
+    ConditionFailed, Durability, Error, KvStore, KvStoreOptions, KvsEngine, Result, WriteBatch,
+};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -6,7 +14,7 @@ use walkdir::WalkDir;
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -16,7 +24,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -27,7 +35,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -36,7 +44,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -48,14 +56,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -64,7 +72,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -72,7 +80,7 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -84,7 +92,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -114,7 +122,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -124,3 +132,1181 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// A record cut short at the end of the log should be dropped on open
+#[test]
+fn recover_torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // Chop off the tail of the last record
+    let log_path = temp_dir.path().join("kvs-1.log");
+    let log = OpenOptions::new().write(true).open(&log_path)?;
+    let len = log.metadata()?.len();
+    log.set_len(len - 3)?;
+    drop(log);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // The log should be usable again after recovery
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+
+    // Same thing with a partially written header
+    drop(store);
+    let mut log = OpenOptions::new().append(true).open(&log_path)?;
+    log.write_all(&[1, 2, 3])?;
+    drop(log);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A damaged record in the middle of the log should be reported with its offset
+#[test]
+fn detect_corruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let log_path = temp_dir.path().join("kvs-1.log");
+
+    // The first record starts right after the segment header
+    let header_len = std::fs::metadata(&log_path)?.len() as usize;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let first_record_end = std::fs::metadata(&log_path)?.len() as usize;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // Flip the last byte of the first record
+    let mut data = std::fs::read(&log_path)?;
+    data[first_record_end - 1] ^= 0xff;
+    std::fs::write(&log_path, data)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(Error::Corruption { file, offset, .. }) => {
+            assert_eq!(file, Some(log_path));
+            assert_eq!(offset, header_len as u64);
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption was not detected"),
+    }
+
+    Ok(())
+}
+
+// Only the active segment can end in a torn write; a sealed segment that looks
+// torn is corrupt and must not be truncated
+#[test]
+fn torn_sealed_segment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .max_segment_size(1024)
+        .open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let log_path = temp_dir.path().join("kvs-1.log");
+    assert!(temp_dir.path().join("kvs-2.log").exists());
+
+    // Make sure the sealed segment is replayed rather than read from its hint
+    let _ = std::fs::remove_file(temp_dir.path().join("kvs-1.hint"));
+    let log = OpenOptions::new().write(true).open(&log_path)?;
+    let len = log.metadata()?.len();
+    log.set_len(len - 3)?;
+    drop(log);
+
+    match KvStore::open(temp_dir.path()) {
+        Err(Error::Corruption { file, .. }) => assert_eq!(file, Some(log_path.clone())),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption was not detected"),
+    }
+    assert_eq!(std::fs::metadata(&log_path)?.len(), len - 3);
+
+    Ok(())
+}
+
+// I/O errors should keep their kind and their source
+#[test]
+fn structured_errors() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    // A file where the store directory should be
+    let path = temp_dir.path().join("file");
+    std::fs::write(&path, b"")?;
+
+    let err = KvStore::open(&path)
+        .err()
+        .expect("opened a store in a file");
+    assert_eq!(err.io_kind(), Some(std::io::ErrorKind::NotADirectory));
+    assert_eq!(err.code(), 2);
+    assert!(std::error::Error::source(&err).is_some());
+
+    assert_eq!(Error::KeyNotFound.code(), 7);
+    assert!(std::error::Error::source(&Error::KeyNotFound).is_none());
+
+    Ok(())
+}
+
+// Compaction should leave segments without any stale data untouched
+#[test]
+fn compaction_skips_live_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    // Fill up the first segment with unique keys, so it is entirely live
+    let mut key_id = 0;
+    while !temp_dir.path().join("kvs-2.log").exists() {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+        key_id += 1;
+    }
+    let first_segment = std::fs::read(temp_dir.path().join("kvs-1.log"))?;
+
+    // Overwrite a single key until compaction kicks in and removes segment 2
+    let mut iter = 0;
+    while temp_dir.path().join("kvs-2.log").exists() {
+        store.set("hot".to_owned(), format!("{}", iter))?;
+        iter += 1;
+    }
+
+    assert_eq!(
+        std::fs::read(temp_dir.path().join("kvs-1.log"))?,
+        first_segment
+    );
+
+    // reopen and check content
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("hot".to_owned())?, Some(format!("{}", iter - 1)));
+    for id in (0..key_id).step_by(97) {
+        assert_eq!(store.get(format!("key{}", id))?, Some("value".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Writes issued while a background compaction is running should not be lost
+#[test]
+fn compaction_with_concurrent_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for iter in 0..200 {
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            store.set(key, format!("{}", iter))?;
+        }
+
+        // Removals race with compaction copying the removed keys
+        if iter % 50 == 25 {
+            for key_id in (0..1000).step_by(2) {
+                store.remove(format!("key{}", key_id))?;
+            }
+        }
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..1000 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some("199".to_owned()));
+        }
+        Ok(())
+    };
+
+    check(&store)?;
+
+    // reopen and check content
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+
+    Ok(())
+}
+
+// Compacted segments should be loaded from their hint file instead of being replayed
+#[test]
+fn open_from_hint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    // Overwrite the same keys until a compaction has finished
+    let mut iter = 0;
+    let hint_path = loop {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        iter += 1;
+
+        let hint = std::fs::read_dir(temp_dir.path())?
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().is_some_and(|ext| ext == "hint"));
+        if let Some(hint) = hint {
+            break hint;
+        }
+    };
+    drop(store);
+
+    // Damage a value in the middle of the compacted segment. Replaying the
+    // segment would fail, but the hint means values are not read on open.
+    let segment_path = hint_path.with_extension("log");
+    let mut data = std::fs::read(&segment_path)?;
+    let middle = data.len() / 2;
+    data[middle] ^= 0xff;
+    std::fs::write(&segment_path, data)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get("key999".to_owned())?,
+        Some(format!("{}", iter - 1))
+    );
+    drop(store);
+
+    // A damaged hint is ignored and the segment is replayed instead
+    std::fs::write(&hint_path, b"garbage")?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(Error::Corruption { .. })
+    ));
+
+    Ok(())
+}
+
+// A crash in the middle of a compaction should leave a store that opens, with
+// whatever the compaction had written so far thrown away
+#[test]
+fn interrupted_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let compacted_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = |path: &std::path::Path, threshold| {
+        KvStoreOptions::new()
+            .max_segment_size(1024)
+            .compaction_threshold(threshold)
+            .open(path)
+    };
+
+    let store = open(temp_dir.path(), usize::MAX)?;
+    for iter in 0..5 {
+        for key_id in 0..50 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    drop(store);
+
+    // Compact a copy of the store to get at what a compaction writes
+    for entry in std::fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        std::fs::copy(&path, compacted_dir.path().join(path.file_name().unwrap()))?;
+    }
+    let store = open(compacted_dir.path(), 1)?;
+    store.set("key0".to_owned(), "4".to_owned())?;
+    drop(store);
+    let hint_path = std::fs::read_dir(compacted_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().is_some_and(|ext| ext == "hint"))
+        .expect("no compaction ran");
+
+    // Put the output back half written, as if the process died writing it
+    let name = hint_path.file_name().unwrap();
+    let output = std::fs::read(hint_path.with_extension("log"))?;
+    let tmp_path = temp_dir.path().join(name).with_extension("log.tmp");
+    std::fs::write(&tmp_path, &output[..output.len() / 2])?;
+    std::fs::copy(&hint_path, temp_dir.path().join(name))?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..50 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some("4".to_owned()));
+        }
+        Ok(())
+    };
+
+    let store = open(temp_dir.path(), usize::MAX)?;
+    check(&store)?;
+    assert!(!tmp_path.exists());
+    assert!(!temp_dir.path().join(name).exists());
+
+    // The generation the output had is free to be used by the log again, and
+    // is read as it is once it is sealed
+    let gen: u64 = name.to_str().unwrap()[4..]
+        .trim_end_matches(".hint")
+        .parse()
+        .unwrap();
+    while !temp_dir
+        .path()
+        .join(format!("kvs-{}.log", gen + 1))
+        .exists()
+    {
+        store.set("filler".to_owned(), "value".repeat(20))?;
+    }
+    drop(store);
+    let store = open(temp_dir.path(), usize::MAX)?;
+    check(&store)?;
+
+    Ok(())
+}
+
+// Handles cloned across threads should all see the same store
+#[test]
+fn concurrent_set_and_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..500 {
+                    let key = format!("key{}-{}", thread_id, i);
+                    store.set(key.clone(), format!("value{}", i)).unwrap();
+                    assert_eq!(store.get(key).unwrap(), Some(format!("value{}", i)));
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for thread_id in 0..8 {
+            for i in 0..500 {
+                let key = format!("key{}-{}", thread_id, i);
+                assert_eq!(store.get(key)?, Some(format!("value{}", i)));
+            }
+        }
+        Ok(())
+    };
+
+    check(&store)?;
+
+    // reopen and check content
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+
+    Ok(())
+}
+
+// Scans should return keys in order, in either direction
+#[test]
+fn scan_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for i in 0..1000 {
+        store.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    store.remove("key0500".to_owned())?;
+
+    let keys = |iter: kvs::KvsIterator<String>| -> Result<Vec<String>> {
+        iter.map(|entry| entry.map(|(k, _)| k)).collect()
+    };
+
+    let all = keys(store.scan(..)?)?;
+    assert_eq!(all.len(), 999);
+    assert!(all.windows(2).all(|w| w[0] < w[1]));
+
+    let range = keys(store.scan("key0100".to_owned().."key0110".to_owned())?)?;
+    let expected: Vec<String> = (100..110).map(|i| format!("key{:04}", i)).collect();
+    assert_eq!(range, expected);
+
+    let reversed = keys(Box::new(store.scan(..)?.rev()))?;
+    assert_eq!(reversed, all.iter().rev().cloned().collect::<Vec<_>>());
+
+    // Iterating from both ends meets in the middle
+    let mut iter = store.scan("key0498".to_owned()..="key0502".to_owned())?;
+    assert_eq!(iter.next().unwrap()?.0, "key0498");
+    assert_eq!(iter.next_back().unwrap()?.0, "key0502");
+    assert_eq!(iter.next().unwrap()?.0, "key0499");
+    assert_eq!(iter.next_back().unwrap()?.0, "key0501");
+    assert!(iter.next().is_none());
+
+    // Inverted ranges are simply empty
+    assert_eq!(store.scan("b".to_owned().."a".to_owned())?.count(), 0);
+
+    Ok(())
+}
+
+// Prefix scans should only return keys that start with the prefix
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for key in ["a", "ab", "abc", "abd", "ac", "b"] {
+        store.set(key.to_owned(), key.to_uppercase())?;
+    }
+
+    let entries: Vec<(String, String)> =
+        store.scan_prefix("ab".to_owned())?.collect::<Result<_>>()?;
+    assert_eq!(
+        entries,
+        vec![
+            ("ab".to_owned(), "AB".to_owned()),
+            ("abc".to_owned(), "ABC".to_owned()),
+            ("abd".to_owned(), "ABD".to_owned()),
+        ]
+    );
+    assert_eq!(store.scan_prefix(String::new())?.count(), 6);
+    assert_eq!(store.scan_prefix("z".to_owned())?.count(), 0);
+
+    Ok(())
+}
+
+// Keys and values should be arbitrary bytes
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let key = vec![0xff, 0x00, 0xc3];
+    let value: Vec<u8> = (0..=255).rev().collect();
+    store.set_bytes(key.clone(), value.clone())?;
+    store.set_bytes(vec![0xff, 0xff], b"last".to_vec())?;
+    store.set("text".to_owned(), "value".to_owned())?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(key.clone())?, Some(value));
+    assert_eq!(store.get_bytes(b"text".to_vec())?, Some(b"value".to_vec()));
+    assert_eq!(store.get("text".to_owned())?, Some("value".to_owned()));
+
+    // The String API refuses to hand out values that are not UTF-8
+    store.set_bytes(b"binary".to_vec(), vec![0xff])?;
+    assert!(store.get("binary".to_owned()).is_err());
+
+    let keys: Vec<Vec<u8>> = store
+        .scan_prefix_bytes(vec![0xff])?
+        .map(|entry| entry.map(|(k, _)| k))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec![key.clone(), vec![0xff, 0xff]]);
+
+    store.remove_bytes(key.clone())?;
+    assert_eq!(store.get_bytes(key)?, None);
+
+    Ok(())
+}
+
+// Keys with a TTL should disappear once it runs out, and stay gone on reopen
+#[test]
+fn expire_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set_with_ttl(
+        "short".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(500),
+    )?;
+    store.set_with_ttl(
+        "long".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    store.set("forever".to_owned(), "value".to_owned())?;
+
+    // Overwriting a key without a TTL clears it
+    store.set_with_ttl(
+        "cleared".to_owned(),
+        "old".to_owned(),
+        Duration::from_millis(500),
+    )?;
+    store.set("cleared".to_owned(), "new".to_owned())?;
+
+    assert_eq!(store.get("short".to_owned())?, Some("value".to_owned()));
+
+    thread::sleep(Duration::from_millis(600));
+
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert_eq!(store.get("long".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("cleared".to_owned())?, Some("new".to_owned()));
+    assert!(matches!(
+        store.remove("short".to_owned()),
+        Err(Error::KeyNotFound)
+    ));
+
+    let keys: Vec<String> = store
+        .scan(..)?
+        .map(|entry| entry.map(|(k, _)| k))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec!["cleared", "forever", "long"]);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert_eq!(store.get("long".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Expired keys should be reclaimed by compaction
+#[test]
+fn compact_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let value = "x".repeat(1024);
+    for key_id in 0..2000 {
+        let key = format!("key{}", key_id);
+        store.set_with_ttl(key, value.clone(), Duration::from_millis(500))?;
+    }
+    store.set("kept".to_owned(), "value".to_owned())?;
+
+    thread::sleep(Duration::from_millis(600));
+
+    // The next write notices the expired keys and compacts them away
+    store.set("trigger".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    let size: u64 = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum();
+    assert!(
+        size < 64 * 1024,
+        "expired keys were not reclaimed: {}",
+        size
+    );
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("kept".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Every write in a batch should be applied, in order
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("old".to_owned(), "value".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("key1".to_owned(), "value1".to_owned());
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.set("key1".to_owned(), "value3".to_owned());
+    batch.remove("old".to_owned());
+    batch.remove("missing".to_owned());
+    store.write_batch(batch)?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("old".to_owned())?, None);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("old".to_owned())?, None);
+
+    Ok(())
+}
+
+// A batch cut short by a crash should not be applied at all
+#[test]
+fn torn_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key0".to_owned(), "value0".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    for i in 1..100 {
+        batch.set(format!("key{}", i), format!("value{}", i));
+    }
+    batch.remove("key0".to_owned());
+    store.write_batch(batch)?;
+    drop(store);
+
+    // Chop the log off in the middle of the batch
+    let log_path = temp_dir.path().join("kvs-1.log");
+    let log = OpenOptions::new().write(true).open(&log_path)?;
+    let len = log.metadata()?.len();
+    log.set_len(len - 100)?;
+    drop(log);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.scan(..)?.count(), 1);
+
+    Ok(())
+}
+
+// Compaction should keep the live writes of a batch, and only those
+#[test]
+fn compact_write_batches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let mut batch = WriteBatch::new();
+    for i in 0..100 {
+        batch.set(format!("key{}", i), format!("value{}", i));
+    }
+    store.write_batch(batch)?;
+
+    // Remove and overwrite part of the batch
+    for i in 0..10 {
+        store.remove(format!("key{}", i))?;
+    }
+    for i in 10..20 {
+        store.set(format!("key{}", i), "new".to_owned())?;
+    }
+
+    // Write enough garbage to compact the batch away
+    let value = "x".repeat(1024);
+    for iter in 0..3000 {
+        store.set(format!("garbage{}", iter % 10), value.clone())?;
+    }
+    drop(store);
+    assert!(!temp_dir.path().join("kvs-1.log").exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        assert_eq!(store.get(format!("key{}", i))?, None);
+    }
+    for i in 10..20 {
+        assert_eq!(store.get(format!("key{}", i))?, Some("new".to_owned()));
+    }
+    for i in 20..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+// Conditional writes should only apply if the key has the expected value
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(
+        store.set_if_absent("key".to_owned(), "v1".to_owned())?,
+        Ok(())
+    );
+    assert_eq!(
+        store.set_if_absent("key".to_owned(), "v2".to_owned())?,
+        Err(ConditionFailed {
+            current: Some("v1".to_owned())
+        })
+    );
+
+    let res = store.compare_and_swap(
+        "key".to_owned(),
+        Some("v1".to_owned()),
+        Some("v2".to_owned()),
+    )?;
+    assert_eq!(res, Ok(()));
+    let res = store.compare_and_swap(
+        "key".to_owned(),
+        Some("v1".to_owned()),
+        Some("v3".to_owned()),
+    )?;
+    assert_eq!(
+        res,
+        Err(ConditionFailed {
+            current: Some("v2".to_owned())
+        })
+    );
+    assert_eq!(store.get("key".to_owned())?, Some("v2".to_owned()));
+
+    // Swapping in `None` removes the key
+    let res = store.compare_and_swap("key".to_owned(), Some("v2".to_owned()), None)?;
+    assert_eq!(res, Ok(()));
+    assert_eq!(store.get("key".to_owned())?, None);
+    let res = store.compare_and_swap("key".to_owned(), Some("v2".to_owned()), None)?;
+    assert_eq!(res, Err(ConditionFailed { current: None }));
+
+    Ok(())
+}
+
+// Concurrent read-modify-write loops built on compare-and-swap should not lose updates
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..100 {
+                    loop {
+                        let current = store.get("counter".to_owned())?;
+                        let next = current.as_deref().map_or(0, |v| v.parse::<u32>().unwrap()) + 1;
+                        let res = store.compare_and_swap(
+                            "counter".to_owned(),
+                            current,
+                            Some(next.to_string()),
+                        )?;
+                        if res.is_ok() {
+                            break;
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    assert_eq!(store.get("counter".to_owned())?, Some("800".to_owned()));
+
+    Ok(())
+}
+
+// Durability policies should parse from and print to the same strings
+#[test]
+fn parse_durability() -> Result<()> {
+    for policy in ["none", "sync", "group-commit", "periodic:250"] {
+        assert_eq!(policy.parse::<Durability>()?.to_string(), policy);
+    }
+    assert_eq!(
+        "periodic:100".parse::<Durability>()?,
+        Durability::Periodic(Duration::from_millis(100))
+    );
+
+    for policy in ["", "always", "periodic:", "periodic:0", "periodic:soon"] {
+        assert!(policy.parse::<Durability>().is_err());
+    }
+
+    Ok(())
+}
+
+// Writes should be readable after reopening under every durability policy,
+// including from many concurrent writers
+#[test]
+fn durability_policies() -> Result<()> {
+    let policies = [
+        Durability::None,
+        Durability::Periodic(Duration::from_millis(10)),
+        Durability::GroupCommit,
+        Durability::Sync,
+    ];
+
+    for durability in policies {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with_durability(temp_dir.path(), durability)?;
+
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let store = store.clone();
+                thread::spawn(move || -> Result<()> {
+                    for i in 0..50 {
+                        store.set(format!("key{}-{}", t, i), format!("value{}", i))?;
+                    }
+                    store.remove(format!("key{}-0", t))?;
+                    Ok(())
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+
+        let mut batch = WriteBatch::new();
+        batch.set("batch".to_owned(), "value".to_owned());
+        store.write_batch(batch)?;
+
+        drop(store);
+
+        let store = KvStore::open_with_durability(temp_dir.path(), durability)?;
+        for t in 0..4 {
+            assert_eq!(store.get(format!("key{}-0", t))?, None);
+            for i in 1..50 {
+                assert_eq!(
+                    store.get(format!("key{}-{}", t, i))?,
+                    Some(format!("value{}", i))
+                );
+            }
+        }
+        assert_eq!(store.get("batch".to_owned())?, Some("value".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Stores should only be created or reused when the options allow it
+#[test]
+fn open_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    assert!(KvStoreOptions::new()
+        .create_if_missing(false)
+        .open(temp_dir.path())
+        .is_err());
+    assert!(KvStoreOptions::new()
+        .compaction_ratio(1.5)
+        .open(temp_dir.path())
+        .is_err());
+
+    let store = KvStoreOptions::new()
+        .error_if_exists(true)
+        .open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    assert!(KvStoreOptions::new()
+        .error_if_exists(true)
+        .open(temp_dir.path())
+        .is_err());
+
+    let store = KvStoreOptions::new()
+        .create_if_missing(false)
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// A read-only store should serve reads and reject every write
+#[test]
+fn read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    assert!(KvStoreOptions::new()
+        .read_only(true)
+        .open(temp_dir.path())
+        .is_err());
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    let store = KvStoreOptions::new()
+        .read_only(true)
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.scan(..)?.count(), 1);
+
+    assert!(matches!(
+        store.set("key".to_owned(), "new".to_owned()),
+        Err(Error::ReadOnly)
+    ));
+    assert!(matches!(
+        store.remove("key".to_owned()),
+        Err(Error::ReadOnly)
+    ));
+    let mut batch = WriteBatch::new();
+    batch.set("other".to_owned(), "value".to_owned());
+    assert!(matches!(store.write_batch(batch), Err(Error::ReadOnly)));
+
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Keys and values over the configured limits should be rejected
+#[test]
+fn max_key_and_value_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .max_key_size(8)
+        .max_value_size(16)
+        .open(temp_dir.path())?;
+
+    store.set("12345678".to_owned(), "0123456789abcdef".to_owned())?;
+
+    assert!(matches!(
+        store.set("123456789".to_owned(), "value".to_owned()),
+        Err(Error::TooLarge {
+            size: 9,
+            max: 8,
+            ..
+        })
+    ));
+    assert!(matches!(
+        store.set("key".to_owned(), "0123456789abcdefg".to_owned()),
+        Err(Error::TooLarge {
+            size: 17,
+            max: 16,
+            ..
+        })
+    ));
+
+    // Nothing in a batch is applied if any of it is too large
+    let mut batch = WriteBatch::new();
+    batch.set("key".to_owned(), "value".to_owned());
+    batch.set("key2".to_owned(), "0123456789abcdefg".to_owned());
+    assert!(store.write_batch(batch).is_err());
+    assert_eq!(store.get("key".to_owned())?, None);
+
+    Ok(())
+}
+
+// Compaction should wait until enough of the log is stale
+#[test]
+fn compaction_ratio() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStoreOptions::new()
+            .compaction_threshold(1)
+            .compaction_ratio(0.5)
+            .open(temp_dir.path())
+    };
+    let store = open()?;
+
+    // Compaction moves writes over to a fresh segment after the one reserved for
+    // its output, so a third segment shows it started
+    let compacted = || temp_dir.path().join("kvs-3.log").exists();
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "new".to_owned())?;
+    }
+    assert!(!compacted());
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "newer".to_owned())?;
+    }
+    assert!(compacted());
+
+    drop(store);
+    let store = open()?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("newer".to_owned())
+        );
+    }
+
+    Ok(())
+}
+
+// Only one handle should be able to write to a store at a time, while any
+// number of read-only handles can share it
+#[test]
+fn lock_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let read_only = || KvStoreOptions::new().read_only(true).open(temp_dir.path());
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+
+    let pid = std::process::id();
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(Error::Locked { pid: Some(p) }) if p == pid
+    ));
+    assert!(matches!(read_only(), Err(Error::Locked { pid: Some(p) }) if p == pid));
+
+    // Clones share the lock, which goes away with the last of them
+    let clone = store.clone();
+    drop(store);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    drop(clone);
+
+    let reader1 = read_only()?;
+    let reader2 = read_only()?;
+    assert_eq!(reader1.get("key".to_owned())?, Some("value".to_owned()));
+    assert_eq!(reader2.get("key".to_owned())?, Some("value".to_owned()));
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(Error::Locked { pid: None })
+    ));
+
+    drop(reader1);
+    drop(reader2);
+    KvStore::open(temp_dir.path())?;
+
+    // A read-only open does not create a missing lock file
+    std::fs::remove_file(temp_dir.path().join("LOCK"))?;
+    let reader = read_only()?;
+    assert_eq!(reader.get("key".to_owned())?, Some("value".to_owned()));
+    assert!(!temp_dir.path().join("LOCK").exists());
+
+    Ok(())
+}
+
+// A store lock should be shared with the stores this process opens under it
+#[test]
+fn store_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let lock = StoreLock::acquire(temp_dir.path())?;
+    let pid = std::process::id();
+
+    // The lock is handed to a single writer at a time
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(Error::Locked { pid: Some(p) }) if p == pid
+    ));
+    assert!(matches!(
+        KvStoreOptions::new().read_only(true).open(temp_dir.path()),
+        Err(Error::Locked { .. })
+    ));
+    assert!(matches!(
+        StoreLock::acquire(temp_dir.path()),
+        Err(Error::Locked { pid: Some(p) }) if p == pid
+    ));
+    drop(store);
+
+    // Or to any number of readers
+    let reader = KvStoreOptions::new()
+        .read_only(true)
+        .open(temp_dir.path())?;
+    let other = KvStoreOptions::new()
+        .read_only(true)
+        .open(temp_dir.path())?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(Error::Locked { pid: None })
+    ));
+    drop((reader, other));
+
+    let store = KvStore::open(temp_dir.path())?;
+    drop(store);
+
+    let contents = std::fs::read_to_string(temp_dir.path().join("LOCK"))?;
+    assert_eq!(contents, pid.to_string());
+
+    drop(lock);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(
+        StoreLock::acquire(temp_dir.path()),
+        Err(Error::Locked { pid: Some(p) }) if p == pid
+    ));
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Segments in a format version we do not know should be rejected
+#[test]
+fn reject_unknown_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    // The version follows the 8 byte magic
+    let log_path = temp_dir.path().join("kvs-1.log");
+    let mut data = std::fs::read(&log_path)?;
+    assert_eq!(&data[..8], b"KVSLOG\r\n");
+    data[8..12].copy_from_slice(&99u32.to_le_bytes());
+    std::fs::write(&log_path, data)?;
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(Error::UnsupportedVersion {
+            found: 99,
+            expected: 1
+        })
+    ));
+    assert!(KvStore::upgrade(temp_dir.path()).is_err());
+
+    Ok(())
+}
+
+// A command as the very first version of kvs wrote it to `kvs.log`
+#[derive(serde::Serialize)]
+#[allow(dead_code)]
+enum BaselineCommand {
+    Get(String),
+    Set(String, String),
+    Remove(String),
+}
+
+// Write a log the way the very first version of kvs did: a single `kvs.log`
+// holding each command's size as a u64 LE, followed by the command, with no
+// header and no checksums
+fn write_baseline_log(dir: &std::path::Path, commands: &[BaselineCommand]) -> Result<()> {
+    let mut data = Vec::new();
+    for command in commands {
+        let buf = rmp_serde::to_vec(command)?;
+        data.extend_from_slice(&(buf.len() as u64).to_le_bytes());
+        data.extend_from_slice(&buf);
+    }
+    std::fs::write(dir.join("kvs.log"), data)?;
+
+    Ok(())
+}
+
+// A log in the original format should be refused with a pointer to `kvs
+// upgrade`, rather than be mistaken for a corrupt one
+#[test]
+fn reject_baseline_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_baseline_log(
+        temp_dir.path(),
+        &[
+            BaselineCommand::Set("key".to_owned(), "value".to_owned()),
+            BaselineCommand::Remove("key".to_owned()),
+        ],
+    )?;
+
+    assert!(KvStore::is_log_present(temp_dir.path()));
+    match KvStore::open(temp_dir.path()) {
+        Err(e @ Error::UnsupportedVersion { found: 0, .. }) => {
+            assert!(e.to_string().contains("kvs upgrade"))
+        }
+        res => panic!("baseline log was not refused: {:?}", res.err()),
+    }
+    assert!(KvStoreOptions::new()
+        .read_only(true)
+        .open(temp_dir.path())
+        .is_err());
+
+    Ok(())
+}
+
+// A log in the original format should open once upgraded
+#[test]
+fn upgrade_baseline_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut commands: Vec<_> = (0..100)
+        .map(|i| BaselineCommand::Set(format!("key{}", i), format!("value{}", i)))
+        .collect();
+    commands.push(BaselineCommand::Set("key1".to_owned(), "new".to_owned()));
+    commands.push(BaselineCommand::Remove("key0".to_owned()));
+    write_baseline_log(temp_dir.path(), &commands)?;
+
+    // A record cut short by a crash is dropped
+    let log_path = temp_dir.path().join("kvs.log");
+    let mut log = OpenOptions::new().append(true).open(&log_path)?;
+    log.write_all(&[42, 0, 0, 0, 0, 0, 0, 0, 1, 2])?;
+    drop(log);
+    let legacy = std::fs::read(&log_path)?;
+
+    assert_eq!(KvStore::upgrade(temp_dir.path())?, 1);
+    assert!(!log_path.exists());
+    assert_eq!(KvStore::upgrade(temp_dir.path())?, 0);
+
+    // An upgrade that died before removing the original log finishes the job
+    std::fs::write(&log_path, &legacy)?;
+    assert_eq!(KvStore::upgrade(temp_dir.path())?, 0);
+    assert!(!log_path.exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+    for i in 2..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    // The upgraded records are checksummed like any other
+    drop(store);
+    let report = KvStore::inspect(temp_dir.path())?;
+    assert!(!report.is_damaged());
+    assert_eq!(report.segments[0].records, 102);
+
+    Ok(())
+}
@@ -0,0 +1,375 @@
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use kvs::{
+    ConditionFailed, Durability, Error, KvsEngine, LsmKvsEngine, LsmOptions, Result, WriteBatch,
+};
+use tempfile::TempDir;
+
+// Options small enough that a few thousand keys go through several flushes and
+// compactions
+fn small_options() -> LsmOptions {
+    let mut options = LsmOptions::new();
+    options
+        .memtable_size(16 * 1024)
+        .block_size(512)
+        .table_size(8 * 1024)
+        .level0_limit(2)
+        .base_level_size(32 * 1024);
+    options
+}
+
+// Files with the given extension in the store
+fn files(path: &Path, ext: &str) -> Vec<PathBuf> {
+    let mut files: Vec<_> = fs::read_dir(path.join("lsm"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|e| e == ext))
+        .collect();
+    files.sort();
+    files
+}
+
+// Basic reads and writes, which should survive reopening the store
+#[test]
+fn get_set_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(!LsmKvsEngine::is_log_present(temp_dir.path()));
+
+    let engine = LsmKvsEngine::open(temp_dir.path())?;
+    assert!(LsmKvsEngine::is_log_present(temp_dir.path()));
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.set("key1".to_owned(), "new".to_owned())?;
+    engine.set_bytes(b"binary\xff".to_vec(), b"\x00\x01".to_vec())?;
+
+    assert_eq!(engine.get("key1".to_owned())?, Some("new".to_owned()));
+    assert_eq!(engine.get("missing".to_owned())?, None);
+
+    engine.remove("key2".to_owned())?;
+    assert!(matches!(
+        engine.remove("key2".to_owned()),
+        Err(Error::KeyNotFound)
+    ));
+
+    drop(engine);
+    let engine = LsmKvsEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("new".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    assert_eq!(
+        engine.get_bytes(b"binary\xff".to_vec())?,
+        Some(b"\x00\x01".to_vec())
+    );
+
+    // Only one handle can have the store open at a time
+    assert!(matches!(
+        LsmKvsEngine::open(temp_dir.path()),
+        Err(Error::Locked { .. })
+    ));
+
+    Ok(())
+}
+
+// Keys should stay readable as the memtable is flushed and tables are compacted
+// down the levels, and removed keys should stay removed
+#[test]
+fn flush_and_compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = small_options().open(temp_dir.path())?;
+
+    let value = "x".repeat(100);
+    for round in 0..3 {
+        for i in 0..2000 {
+            engine.set(format!("key{:05}", i), format!("{}{}", value, round))?;
+        }
+    }
+    for i in (0..2000).step_by(3) {
+        engine.remove(format!("key{:05}", i))?;
+    }
+
+    let check = |engine: &LsmKvsEngine| -> Result<()> {
+        for i in 0..2000 {
+            let expected = if i % 3 == 0 {
+                None
+            } else {
+                Some(format!("{}2", value))
+            };
+            assert_eq!(engine.get(format!("key{:05}", i))?, expected);
+        }
+
+        let keys: Vec<_> = engine
+            .scan(..)?
+            .map(|entry| entry.map(|(key, _)| key))
+            .collect::<Result<_>>()?;
+        let expected: Vec<_> = (0..2000)
+            .filter(|i| i % 3 != 0)
+            .map(|i| format!("key{:05}", i))
+            .collect();
+        assert_eq!(keys, expected);
+        Ok(())
+    };
+
+    check(&engine)?;
+    drop(engine);
+
+    // Compaction should have dropped the overwritten values: every key was
+    // written three times, so the tables would take up over 600 KB otherwise
+    let tables = files(temp_dir.path(), "sst");
+    let size: u64 = tables
+        .iter()
+        .map(|path| fs::metadata(path).unwrap().len())
+        .sum();
+    assert!(tables.len() > 1);
+    assert!(size < 400 * 1024, "{} bytes in tables", size);
+
+    let engine = small_options().open(temp_dir.path())?;
+    check(&engine)?;
+    assert_eq!(files(temp_dir.path(), "wal").len(), 1);
+
+    Ok(())
+}
+
+// Scans should return keys in order, in either direction, merging the memtable
+// with every level
+#[test]
+fn scan_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = small_options().open(temp_dir.path())?;
+
+    for i in 0..1000 {
+        engine.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    // Some keys only live in the memtable, some removals hide keys in tables
+    engine.remove("key0500".to_owned())?;
+    engine.set("key0250".to_owned(), "new".to_owned())?;
+
+    let keys = |iter: kvs::KvsIterator<String>| -> Result<Vec<String>> {
+        iter.map(|entry| entry.map(|(k, _)| k)).collect()
+    };
+
+    let all = keys(engine.scan(..)?)?;
+    assert_eq!(all.len(), 999);
+    assert!(all.windows(2).all(|w| w[0] < w[1]));
+
+    let range = keys(engine.scan("key0100".to_owned().."key0110".to_owned())?)?;
+    let expected: Vec<String> = (100..110).map(|i| format!("key{:04}", i)).collect();
+    assert_eq!(range, expected);
+
+    let reversed = keys(Box::new(engine.scan(..)?.rev()))?;
+    assert_eq!(reversed, all.iter().rev().cloned().collect::<Vec<_>>());
+
+    let (_, value) = engine.scan("key0250".to_owned()..)?.next().unwrap()?;
+    assert_eq!(value, "new");
+
+    let prefixed = keys(engine.scan_prefix("key099".to_owned())?)?;
+    assert_eq!(prefixed.len(), 10);
+
+    // Iterating from both ends meets in the middle
+    let mut iter = engine.scan("key0498".to_owned()..="key0502".to_owned())?;
+    assert_eq!(iter.next().unwrap()?.0, "key0498");
+    assert_eq!(iter.next_back().unwrap()?.0, "key0502");
+    assert_eq!(iter.next().unwrap()?.0, "key0499");
+    assert_eq!(iter.next_back().unwrap()?.0, "key0501");
+    assert!(iter.next().is_none());
+
+    // Inverted ranges are simply empty
+    assert_eq!(engine.scan("b".to_owned().."a".to_owned())?.count(), 0);
+
+    Ok(())
+}
+
+// Keys with a TTL should disappear once it runs out, and stay gone on reopen
+#[test]
+fn expire_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = LsmKvsEngine::open(temp_dir.path())?;
+
+    engine.set_with_ttl(
+        "long".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    engine.set_with_ttl(
+        "short".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    assert_eq!(engine.get("short".to_owned())?, Some("value".to_owned()));
+
+    thread::sleep(Duration::from_millis(300));
+
+    assert_eq!(engine.get("short".to_owned())?, None);
+    assert_eq!(engine.scan(..)?.count(), 1);
+    assert!(matches!(
+        engine.remove("short".to_owned()),
+        Err(Error::KeyNotFound)
+    ));
+
+    drop(engine);
+    let engine = LsmKvsEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get("short".to_owned())?, None);
+    assert_eq!(engine.get("long".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Every write in a batch should be applied, in order
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = LsmKvsEngine::open(temp_dir.path())?;
+    engine.set("old".to_owned(), "value".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("key1".to_owned(), "value1".to_owned());
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.set("key1".to_owned(), "value3".to_owned());
+    batch.remove("old".to_owned());
+    batch.remove("missing".to_owned());
+    engine.write_batch(batch)?;
+
+    let check = |engine: &LsmKvsEngine| -> Result<()> {
+        assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(engine.get("old".to_owned())?, None);
+        assert_eq!(engine.get("missing".to_owned())?, None);
+        Ok(())
+    };
+
+    check(&engine)?;
+    drop(engine);
+    let engine = LsmKvsEngine::open(temp_dir.path())?;
+    check(&engine)?;
+
+    Ok(())
+}
+
+// A write cut short at the end of the WAL should be dropped on open
+#[test]
+fn recover_torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = LsmKvsEngine::open(temp_dir.path())?;
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    drop(engine);
+
+    // Chop off the tail of the last record
+    let wal_path = files(temp_dir.path(), "wal").pop().unwrap();
+    let wal = OpenOptions::new().write(true).open(&wal_path)?;
+    let len = wal.metadata()?.len();
+    wal.set_len(len - 3)?;
+    drop(wal);
+
+    let engine = LsmKvsEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Conditional writes should only apply if the key has the expected value
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = small_options().open(temp_dir.path())?;
+
+    assert_eq!(
+        engine.set_if_absent("key".to_owned(), "v1".to_owned())?,
+        Ok(())
+    );
+    assert_eq!(
+        engine.set_if_absent("key".to_owned(), "v2".to_owned())?,
+        Err(ConditionFailed {
+            current: Some("v1".to_owned())
+        })
+    );
+
+    // Concurrent read-modify-write loops should not lose updates, even as the
+    // counter moves from the memtable into tables
+    engine.set("counter".to_owned(), "0".to_owned())?;
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..100 {
+                    engine.set(format!("filler{}-{}", t, i), "x".repeat(200))?;
+                    loop {
+                        let current = engine.get("counter".to_owned())?.unwrap();
+                        let next = (current.parse::<u32>().unwrap() + 1).to_string();
+                        let res = engine.compare_and_swap(
+                            "counter".to_owned(),
+                            Some(current),
+                            Some(next),
+                        )?;
+                        if res.is_ok() {
+                            break;
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    assert_eq!(engine.get("counter".to_owned())?, Some("400".to_owned()));
+
+    Ok(())
+}
+
+// Writes should be readable after reopening under every durability policy,
+// including from many concurrent writers
+#[test]
+fn durability_policies() -> Result<()> {
+    let policies = [
+        Durability::None,
+        Durability::Periodic(Duration::from_millis(10)),
+        Durability::GroupCommit,
+        Durability::Sync,
+    ];
+
+    for durability in policies {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut options = small_options();
+        options.durability(durability);
+        let engine = options.open(temp_dir.path())?;
+
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let engine = engine.clone();
+                thread::spawn(move || -> Result<()> {
+                    for i in 0..50 {
+                        engine.set(format!("key{}-{}", t, i), format!("value{}", i))?;
+                    }
+                    engine.remove(format!("key{}-0", t))?;
+                    Ok(())
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+
+        drop(engine);
+
+        let engine = LsmKvsEngine::open_with_durability(temp_dir.path(), durability)?;
+        for t in 0..4 {
+            assert_eq!(engine.get(format!("key{}-0", t))?, None);
+            for i in 1..50 {
+                assert_eq!(
+                    engine.get(format!("key{}-{}", t, i))?,
+                    Some(format!("value{}", i))
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,181 @@
+use std::thread;
+use std::time::Duration;
+
+use kvs::{ConditionFailed, Error, KvsEngine, MemoryKvsEngine, Result, WriteBatch};
+use tempfile::TempDir;
+
+// Basic reads and writes, including scans in both directions
+#[test]
+fn get_set_remove_scan() -> Result<()> {
+    let engine = MemoryKvsEngine::new();
+
+    for i in 0..10 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    engine.set("key1".to_owned(), "new".to_owned())?;
+
+    assert_eq!(engine.get("key1".to_owned())?, Some("new".to_owned()));
+    assert_eq!(engine.get("missing".to_owned())?, None);
+
+    engine.remove("key0".to_owned())?;
+    assert!(matches!(
+        engine.remove("key0".to_owned()),
+        Err(Error::KeyNotFound)
+    ));
+
+    let keys: Vec<_> = engine
+        .scan("key2".to_owned().."key5".to_owned())?
+        .map(|entry| entry.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, ["key2", "key3", "key4"]);
+
+    let (key, _) = engine.scan(..)?.next_back().unwrap()?;
+    assert_eq!(key, "key9");
+
+    // Every clone shares the same store
+    let clone = engine.clone();
+    thread::spawn(move || clone.set("shared".to_owned(), "value".to_owned()))
+        .join()
+        .unwrap()?;
+    assert_eq!(engine.get("shared".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Keys with a TTL should disappear once it runs out
+#[test]
+fn expire_keys() -> Result<()> {
+    let engine = MemoryKvsEngine::new();
+
+    engine.set_with_ttl(
+        "long".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    engine.set_with_ttl(
+        "cleared".to_owned(),
+        "old".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    engine.set("cleared".to_owned(), "new".to_owned())?;
+    engine.set_with_ttl(
+        "short".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(200),
+    )?;
+
+    assert_eq!(engine.get("short".to_owned())?, Some("value".to_owned()));
+
+    thread::sleep(Duration::from_millis(300));
+
+    assert_eq!(engine.get("short".to_owned())?, None);
+    assert_eq!(engine.scan(..)?.count(), 2);
+    assert_eq!(engine.get("cleared".to_owned())?, Some("new".to_owned()));
+    assert!(matches!(
+        engine.remove("short".to_owned()),
+        Err(Error::KeyNotFound)
+    ));
+
+    Ok(())
+}
+
+// Every write in a batch should be applied, in order
+#[test]
+fn write_batch() -> Result<()> {
+    let engine = MemoryKvsEngine::new();
+    engine.set("old".to_owned(), "value".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("key1".to_owned(), "value1".to_owned());
+    batch.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_millis(200),
+    );
+    batch.set("key1".to_owned(), "value3".to_owned());
+    batch.remove("old".to_owned());
+    batch.remove("missing".to_owned());
+    engine.write_batch(batch)?;
+
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("old".to_owned())?, None);
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Conditional writes should only apply if the key has the expected value
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let engine = MemoryKvsEngine::new();
+
+    assert_eq!(
+        engine.set_if_absent("key".to_owned(), "v1".to_owned())?,
+        Ok(())
+    );
+    assert_eq!(
+        engine.set_if_absent("key".to_owned(), "v2".to_owned())?,
+        Err(ConditionFailed {
+            current: Some("v1".to_owned())
+        })
+    );
+
+    let res = engine.compare_and_swap("key".to_owned(), Some("v1".to_owned()), None)?;
+    assert_eq!(res, Ok(()));
+    assert_eq!(engine.get("key".to_owned())?, None);
+
+    // An expired key counts as missing
+    engine.set_with_ttl(
+        "session".to_owned(),
+        "old".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(
+        engine.set_if_absent("session".to_owned(), "new".to_owned())?,
+        Ok(())
+    );
+    assert_eq!(engine.get("session".to_owned())?, Some("new".to_owned()));
+
+    Ok(())
+}
+
+// A store with a snapshot file should be saved when dropped and loaded when reopened
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("snapshot");
+
+    let engine = MemoryKvsEngine::with_snapshot(&path)?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set_bytes(b"binary\xff".to_vec(), b"\x00\x01".to_vec())?;
+    engine.set_with_ttl(
+        "short".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(100),
+    )?;
+
+    // Nothing is written until asked
+    assert!(!path.exists());
+    engine.snapshot()?;
+    assert!(path.exists());
+
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    thread::sleep(Duration::from_millis(200));
+    drop(engine);
+
+    let engine = MemoryKvsEngine::with_snapshot(&path)?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(
+        engine.get_bytes(b"binary\xff".to_vec())?,
+        Some(b"\x00\x01".to_vec())
+    );
+    assert_eq!(engine.get("short".to_owned())?, None);
+    assert_eq!(engine.scan(..)?.count(), 3);
+
+    Ok(())
+}
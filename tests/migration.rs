@@ -0,0 +1,91 @@
+use std::time::Duration;
+
+use kvs::engine::{migrate, verify_migration};
+use kvs::{Error, KvStore, KvsEngine, LsmKvsEngine, MemoryKvsEngine, Result, SledKvsEngine};
+use tempfile::TempDir;
+
+// Every live key should make it through a chain of migrations across engines
+#[test]
+fn migrate_between_engines() -> Result<()> {
+    let source = MemoryKvsEngine::new();
+    for i in 0..1500 {
+        source.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    source.set_bytes(b"binary\xff".to_vec(), b"\x00\x01".to_vec())?;
+    source.remove("key0000".to_owned())?;
+    source.set_with_ttl(
+        "session".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    let expiry = source.expiry("session".to_owned())?.unwrap();
+
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = KvStore::open(kvs_dir.path())?;
+    assert_eq!(migrate(&source, &kvs)?, 1501);
+
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled = SledKvsEngine::open(sled_dir.path())?;
+    assert_eq!(migrate(&kvs, &sled)?, 1501);
+
+    let lsm_dir = TempDir::new().expect("unable to create temporary working directory");
+    let lsm = LsmKvsEngine::open(lsm_dir.path())?;
+    assert_eq!(migrate(&sled, &lsm)?, 1501);
+
+    assert_eq!(verify_migration(&source, &lsm)?, 1501);
+    assert_eq!(lsm.get("key0000".to_owned())?, None);
+    assert_eq!(lsm.get("key1499".to_owned())?, Some("value1499".to_owned()));
+    assert_eq!(
+        lsm.get_bytes(b"binary\xff".to_vec())?,
+        Some(b"\x00\x01".to_vec())
+    );
+
+    // Keys with a TTL keep it, give or take the time the migrations took
+    let migrated = lsm.expiry("session".to_owned())?.unwrap();
+    assert!(migrated.abs_diff(expiry) < 10_000);
+    assert_eq!(lsm.expiry("key1499".to_owned())?, None);
+
+    Ok(())
+}
+
+// Migrating into a store that already has keys should be refused, and stores
+// that differ should fail verification
+#[test]
+fn verify_stores() -> Result<()> {
+    let source = MemoryKvsEngine::new();
+    source.set("key1".to_owned(), "value1".to_owned())?;
+    source.set("key2".to_owned(), "value2".to_owned())?;
+
+    let dest = MemoryKvsEngine::new();
+    dest.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(migrate(&source, &dest), Err(Error::Generic(_))));
+
+    // A missing key
+    let err = verify_migration(&source, &dest).unwrap_err();
+    assert!(err.to_string().contains("\"key2\""), "{}", err);
+
+    // A different value
+    dest.set("key2".to_owned(), "other".to_owned())?;
+    let err = verify_migration(&source, &dest).unwrap_err();
+    assert!(err.to_string().contains("\"key2\""), "{}", err);
+
+    // An extra key
+    dest.set("key2".to_owned(), "value2".to_owned())?;
+    dest.set("key3".to_owned(), "value3".to_owned())?;
+    let err = verify_migration(&source, &dest).unwrap_err();
+    assert!(err.to_string().contains("\"key3\""), "{}", err);
+
+    dest.remove("key3".to_owned())?;
+    assert_eq!(verify_migration(&source, &dest)?, 2);
+
+    // A key that lost its TTL
+    source.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    let err = verify_migration(&source, &dest).unwrap_err();
+    assert!(err.to_string().contains("\"key2\""), "{}", err);
+
+    Ok(())
+}
@@ -0,0 +1,785 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use kvs::client::KvsClient;
+use kvs::engine::restore;
+use kvs::protocol::{
+    read_frame, read_message, write_message, Hello, HelloResponse, KeyRange, MAX_FRAME_SIZE,
+    PROTOCOL_VERSION,
+};
+use kvs::server::{KvsServer, Request, Response};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    ConditionFailed, ConditionalResult, Error, KvsEngine, KvsIterator, MemoryKvsEngine, Result,
+    WriteBatch,
+};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use tempfile::TempDir;
+
+// Start a server on a background thread, backed by an in-memory store
+fn start_server(addr: &str) {
+    start_server_with_backups(addr, None);
+}
+
+fn start_server_with_backups(addr: &str, backup_dir: Option<&Path>) {
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    let mut server = KvsServer::new(MemoryKvsEngine::new(), pool, addr.to_owned()).unwrap();
+    if let Some(dir) = backup_dir {
+        server.backup_dir(dir);
+    }
+
+    thread::spawn(move || server.start().unwrap());
+    thread::sleep(Duration::from_millis(500));
+}
+
+// A single connection should serve any number of requests
+#[test]
+fn persistent_connection() -> Result<()> {
+    let addr = "127.0.0.1:4010";
+    start_server(addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    for i in 0..100 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..100 {
+        assert_eq!(
+            client.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    client.remove("key0".to_owned())?;
+    assert_eq!(client.get("key0".to_owned())?, None);
+
+    Ok(())
+}
+
+// Pipelined requests should get their responses in order
+#[test]
+fn pipelined_requests() -> Result<()> {
+    let addr = "127.0.0.1:4011";
+    start_server(addr);
+
+    let mut client = KvsClient::connect(addr)?;
+
+    let mut requests = Vec::new();
+    for i in 0..1000 {
+        requests.push(Request::Set(
+            format!("key{}", i).into_bytes(),
+            format!("value{}", i).into_bytes(),
+        ));
+        requests.push(Request::Get(format!("key{}", i).into_bytes()));
+    }
+    requests.push(Request::Remove(b"missing".to_vec()));
+
+    let responses = client.pipeline(&requests)?;
+    assert_eq!(responses.len(), requests.len());
+
+    for (i, pair) in responses.chunks(2).take(1000).enumerate() {
+        assert!(matches!(pair[0], Response::Stored));
+        match &pair[1] {
+            Response::Found(v) => assert_eq!(v, format!("value{}", i).as_bytes()),
+            r => panic!("unexpected response: {:?}", r),
+        }
+    }
+    assert!(matches!(responses.last(), Some(Response::NotFound)));
+
+    // The connection is still usable afterwards
+    assert_eq!(
+        client.get("key999".to_owned())?,
+        Some("value999".to_owned())
+    );
+
+    Ok(())
+}
+
+// Every request should get a response that says what happened
+#[test]
+fn typed_responses() -> Result<()> {
+    let addr = "127.0.0.1:4020";
+    start_server(addr);
+
+    let mut client = KvsClient::connect(addr)?;
+
+    let requests = [
+        Request::Get(b"key".to_vec()),
+        Request::Set(b"key".to_vec(), b"v1".to_vec()),
+        Request::Get(b"key".to_vec()),
+        Request::CompareAndSwap {
+            key: b"key".to_vec(),
+            expected: None,
+            new: Some(b"v2".to_vec()),
+        },
+        Request::CompareAndSwap {
+            key: b"key".to_vec(),
+            expected: Some(b"v1".to_vec()),
+            new: Some(b"v2".to_vec()),
+        },
+        Request::Remove(b"key".to_vec()),
+        Request::Remove(b"key".to_vec()),
+    ];
+    let responses = client.pipeline(&requests)?;
+
+    assert!(matches!(responses[0], Response::NotFound));
+    assert!(matches!(responses[1], Response::Stored));
+    assert!(matches!(&responses[2], Response::Found(v) if v == b"v1"));
+    assert!(matches!(&responses[3], Response::Conflict { current: Some(v) } if v == b"v1"));
+    assert!(matches!(responses[4], Response::Swapped));
+    assert!(matches!(responses[5], Response::Deleted));
+    assert!(matches!(responses[6], Response::NotFound));
+
+    // The client maps them back onto the same results as the engine
+    assert_eq!(client.get("key".to_owned())?, None);
+    assert!(matches!(
+        client.remove("key".to_owned()),
+        Err(Error::KeyNotFound)
+    ));
+
+    Ok(())
+}
+
+// The handshake should agree on a version and advertise the server's capabilities
+#[test]
+fn handshake() -> Result<()> {
+    let addr = "127.0.0.1:4012";
+    start_server(addr);
+
+    let client = KvsClient::connect(addr)?;
+    assert_eq!(client.protocol_version(), PROTOCOL_VERSION);
+    assert!(client.has_capability("pipelining"));
+
+    // A client that only speaks newer versions is turned away
+    let mut stream = TcpStream::connect(addr)?;
+    let hello = Hello {
+        min_version: PROTOCOL_VERSION + 1,
+        max_version: PROTOCOL_VERSION + 2,
+    };
+    write_message(&mut stream, &hello)?;
+    assert!(matches!(
+        read_message(&mut stream)?,
+        HelloResponse::Rejected(_)
+    ));
+
+    Ok(())
+}
+
+// Malformed frames should be rejected without taking the server down
+#[test]
+fn malformed_frames() -> Result<()> {
+    let addr = "127.0.0.1:4013";
+    start_server(addr);
+
+    let mut stream = TcpStream::connect(addr)?;
+    write_message(&mut stream, &Hello::new())?;
+    let _: HelloResponse = read_message(&mut stream)?;
+
+    // A frame that does not contain a request gets an error back
+    stream.write_all(&3u32.to_le_bytes())?;
+    stream.write_all(&[0xc1, 0xc1, 0xc1])?;
+    assert!(matches!(read_message(&mut stream)?, Response::Error(_)));
+
+    // ... and the connection can still be used
+    write_message(&mut stream, &Request::Get(b"key".to_vec()))?;
+    assert!(matches!(read_message(&mut stream)?, Response::NotFound));
+
+    // An oversized frame closes the connection before anything is allocated
+    stream.write_all(&(MAX_FRAME_SIZE + 1).to_le_bytes())?;
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf)?;
+    assert!(buf.is_empty());
+
+    // Other clients are still served
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Scans should be split into pages that pick up where the last one stopped
+#[test]
+fn paginated_scan() -> Result<()> {
+    let addr = "127.0.0.1:4014";
+    start_server(addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(client.has_capability("scan"));
+
+    for i in 0..250 {
+        client.set(format!("key{:03}", i), format!("value{}", i))?;
+    }
+    client.set("other".to_owned(), "value".to_owned())?;
+
+    let range = KeyRange::Prefix(b"key".to_vec());
+    let (page, cursor) = client.scan_page(range.clone(), false, 100, None)?;
+    assert_eq!(page.len(), 100);
+    assert_eq!(page[0].0, b"key000");
+    assert_eq!(cursor.as_deref(), Some(&b"key099"[..]));
+
+    let (page, _) = client.scan_page(range.clone(), false, 100, cursor)?;
+    assert_eq!(page[0].0, b"key100");
+
+    let entries = client.scan(range.clone(), false)?;
+    assert_eq!(entries.len(), 250);
+
+    let reversed = client.scan(range, true)?;
+    assert_eq!(reversed.first().unwrap().0, b"key249");
+    assert_eq!(reversed.len(), 250);
+
+    Ok(())
+}
+
+// Keys and values that are not UTF-8 should make it through unchanged
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let addr = "127.0.0.1:4015";
+    start_server(addr);
+
+    let mut client = KvsClient::connect(addr)?;
+
+    let key = vec![0x00, 0xff, 0xfe, b'k'];
+    let value: Vec<u8> = (0..=255).collect();
+    client.set_bytes(key.clone(), value.clone())?;
+    assert_eq!(client.get_bytes(key.clone())?, Some(value.clone()));
+
+    let entries = client.scan(KeyRange::Prefix(vec![0x00, 0xff]), false)?;
+    assert_eq!(entries, vec![(key.clone(), value)]);
+
+    client.remove_bytes(key.clone())?;
+    assert_eq!(client.get_bytes(key)?, None);
+
+    Ok(())
+}
+
+// Keys set with a TTL over the wire should expire
+#[test]
+fn set_with_ttl() -> Result<()> {
+    let addr = "127.0.0.1:4016";
+    start_server(addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(client.has_capability("ttl"));
+
+    client.set_with_ttl(
+        "key".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(500),
+    )?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+
+    thread::sleep(Duration::from_millis(600));
+    assert_eq!(client.get("key".to_owned())?, None);
+
+    Ok(())
+}
+
+// A batch sent over the wire should be applied as a whole
+#[test]
+fn write_batch() -> Result<()> {
+    let addr = "127.0.0.1:4017";
+    start_server(addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(client.has_capability("batch"));
+
+    client.set("old".to_owned(), "value".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("key1".to_owned(), "value1".to_owned());
+    batch.set_bytes(vec![0xff], vec![0x00]);
+    batch.remove("old".to_owned());
+    client.write_batch(batch)?;
+
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get_bytes(vec![0xff])?, Some(vec![0x00]));
+    assert_eq!(client.get("old".to_owned())?, None);
+
+    Ok(())
+}
+
+// A failed conditional write should report the current value
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let addr = "127.0.0.1:4018";
+    start_server(addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(client.has_capability("cas"));
+
+    assert_eq!(
+        client.set_if_absent("key".to_owned(), "v1".to_owned())?,
+        Ok(())
+    );
+
+    let res = client.compare_and_swap(
+        "key".to_owned(),
+        Some("v0".to_owned()),
+        Some("v2".to_owned()),
+    )?;
+    assert_eq!(
+        res,
+        Err(ConditionFailed {
+            current: Some("v1".to_owned())
+        })
+    );
+
+    let res = client.compare_and_swap(
+        "key".to_owned(),
+        Some("v1".to_owned()),
+        Some("v2".to_owned()),
+    )?;
+    assert_eq!(res, Ok(()));
+    assert_eq!(client.get("key".to_owned())?, Some("v2".to_owned()));
+
+    Ok(())
+}
+
+// Errors should reach the client with their code and details intact
+#[test]
+fn structured_errors() -> Result<()> {
+    let addr = "127.0.0.1:4019";
+    start_server(addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.protocol_version(), PROTOCOL_VERSION);
+
+    let err = client.remove("missing".to_owned()).unwrap_err();
+    assert!(matches!(err, Error::KeyNotFound));
+    assert_eq!(err.code(), 7);
+
+    // The kind of an I/O error makes it across, but not its source
+    let err = Error::from(std::io::Error::new(
+        std::io::ErrorKind::PermissionDenied,
+        "no access",
+    ));
+    let mut buf = Vec::new();
+    write_message(&mut buf, &Response::Error(err))?;
+    match read_message(&mut buf.as_slice())? {
+        Response::Error(err) => {
+            assert_eq!(err.code(), 2);
+            assert_eq!(err.io_kind(), Some(std::io::ErrorKind::PermissionDenied));
+            assert_eq!(err.to_string(), "no access");
+            assert!(std::error::Error::source(&err).is_none());
+        }
+        r => panic!("unexpected response: {:?}", r),
+    }
+
+    Ok(())
+}
+
+// A backup request should write a backup below the server's backup directory
+// that restores to the same keys
+#[test]
+fn backup() -> Result<()> {
+    let addr = "127.0.0.1:4021";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    start_server_with_backups(addr, Some(temp_dir.path()));
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(client.has_capability("backup"));
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set_bytes(b"key\xff".to_vec(), b"\x00".to_vec())?;
+
+    client.backup("backup".to_owned())?;
+    let backup = temp_dir.path().join("backup");
+
+    // The directory is no longer empty
+    let err = client.backup("backup".to_owned()).unwrap_err();
+    assert!(matches!(err, Error::Generic(_)));
+
+    // Nothing may be written outside of the backup directory
+    let outside = temp_dir.path().join("outside");
+    for path in [outside.to_str().unwrap(), "../outside", "backup/../..", ""] {
+        let err = client.backup(path.to_owned()).unwrap_err();
+        assert!(err.to_string().contains("Invalid backup path"), "{}", err);
+    }
+    assert!(!outside.exists());
+
+    let manifest = restore(&backup, temp_dir.path().join("restored"))?;
+    assert_eq!(manifest.engine, "memory");
+
+    let store = MemoryKvsEngine::with_snapshot(temp_dir.path().join("restored/snapshot"))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(
+        store.get_bytes(b"key\xff".to_vec())?,
+        Some(b"\x00".to_vec())
+    );
+
+    // Backups are refused by a server without a backup directory
+    let addr = "127.0.0.1:4026";
+    start_server(addr);
+    let mut client = KvsClient::connect(addr)?;
+    assert!(!client.has_capability("backup"));
+    assert!(matches!(
+        client.backup("backup".to_owned()),
+        Err(Error::Unsupported { .. })
+    ));
+    drop(client);
+
+    let mut stream = TcpStream::connect(addr)?;
+    write_message(&mut stream, &Hello::new())?;
+    let _: HelloResponse = read_message(&mut stream)?;
+    write_message(&mut stream, &Request::Backup("backup".to_owned()))?;
+    match read_message(&mut stream)? {
+        Response::Error(Error::Generic(msg)) => assert!(msg.contains("not enabled"), "{}", msg),
+        r => panic!("unexpected response: {:?}", r),
+    }
+
+    Ok(())
+}
+
+// Open a connection that speaks the given protocol version
+fn connect_with_version(addr: &str, version: u32) -> Result<(TcpStream, Vec<String>)> {
+    let mut stream = TcpStream::connect(addr)?;
+    let hello = Hello {
+        min_version: version,
+        max_version: version,
+    };
+    write_message(&mut stream, &hello)?;
+
+    match read_message(&mut stream)? {
+        HelloResponse::Accepted {
+            version: agreed,
+            capabilities,
+            ..
+        } => {
+            assert_eq!(agreed, version);
+            Ok((stream, capabilities))
+        }
+        HelloResponse::Rejected(msg) => Err(Error::Protocol(msg)),
+    }
+}
+
+// Responses as versions 1 to 3 of the protocol sent them
+#[derive(Debug, Deserialize)]
+enum OldResponse<E> {
+    Ok,
+    Value(ByteBuf),
+    Error(E),
+    Page {
+        entries: Vec<(ByteBuf, ByteBuf)>,
+        cursor: Option<ByteBuf>,
+    },
+    ConditionFailed {
+        current: Option<ByteBuf>,
+    },
+}
+
+// Requests as version 1 of the protocol sent them
+#[derive(Serialize)]
+enum RequestV1 {
+    Set(String, String),
+    Get(String),
+}
+
+// Errors as versions 1 and 2 of the protocol sent them, up to the ones these
+// tests expect
+#[derive(Debug, Deserialize)]
+#[allow(dead_code)]
+enum OldError {
+    Generic(String),
+    IOError(String),
+    SerializeError(String),
+    DeserializeError(String),
+    SledError(String),
+    Protocol(String),
+    KeyNotFound,
+}
+
+// A client that agreed on an older version should only be offered, and only be
+// able to use, what that version had
+#[test]
+fn older_protocol_versions() -> Result<()> {
+    let addr = "127.0.0.1:4023";
+    start_server(addr);
+
+    let (mut stream, capabilities) = connect_with_version(addr, 4)?;
+    assert!(capabilities.contains(&"cas".to_owned()));
+    assert!(!capabilities.contains(&"backup".to_owned()));
+
+    write_message(&mut stream, &Request::Backup("backup".to_owned()))?;
+    assert!(matches!(
+        read_message(&mut stream)?,
+        Response::Error(Error::Protocol(_))
+    ));
+    write_message(&mut stream, &Request::Get(b"key".to_vec()))?;
+    assert!(matches!(read_message(&mut stream)?, Response::NotFound));
+
+    // Each connection holds on to one of the server's two threads
+    drop(stream);
+
+    // Version 3 answers most requests with a plain `Ok`
+    let (mut stream, _) = connect_with_version(addr, 3)?;
+    let requests = [
+        Request::Get(b"key".to_vec()),
+        Request::Set(b"key".to_vec(), b"v1".to_vec()),
+        Request::Get(b"key".to_vec()),
+        Request::CompareAndSwap {
+            key: b"key".to_vec(),
+            expected: None,
+            new: Some(b"v2".to_vec()),
+        },
+        Request::Scan {
+            range: KeyRange::Prefix(b"k".to_vec()),
+            reverse: false,
+            limit: 10,
+            cursor: None,
+        },
+        Request::Remove(b"key".to_vec()),
+        Request::Remove(b"key".to_vec()),
+    ];
+    for request in &requests {
+        write_message(&mut stream, request)?;
+    }
+    let mut responses = Vec::new();
+    for _ in &requests {
+        responses.push(read_message::<OldResponse<Error>>(&mut stream)?);
+    }
+
+    assert!(matches!(responses[0], OldResponse::Ok));
+    assert!(matches!(responses[1], OldResponse::Ok));
+    assert!(matches!(&responses[2], OldResponse::Value(v) if v.as_slice() == b"v1"));
+    assert!(matches!(
+        &responses[3],
+        OldResponse::ConditionFailed { current: Some(v) } if v.as_slice() == b"v1"
+    ));
+    assert!(matches!(
+        &responses[4],
+        OldResponse::Page { entries, cursor: None } if entries.len() == 1
+    ));
+    assert!(matches!(responses[5], OldResponse::Ok));
+    assert!(matches!(
+        responses[6],
+        OldResponse::Error(Error::KeyNotFound)
+    ));
+
+    drop(stream);
+
+    // Version 2 sends errors with just their message
+    let (mut stream, _) = connect_with_version(addr, 2)?;
+    stream.write_all(&3u32.to_le_bytes())?;
+    stream.write_all(&[0xc1, 0xc1, 0xc1])?;
+    assert!(matches!(
+        read_message(&mut stream)?,
+        OldResponse::<OldError>::Error(OldError::DeserializeError(_))
+    ));
+    write_message(&mut stream, &Request::Remove(b"key".to_vec()))?;
+    assert!(matches!(
+        read_message(&mut stream)?,
+        OldResponse::<OldError>::Error(OldError::KeyNotFound)
+    ));
+    drop(stream);
+
+    // Version 1 sends keys and values as strings, so binary ones cannot be sent
+    let (mut stream, capabilities) = connect_with_version(addr, 1)?;
+    assert_eq!(capabilities, vec!["pipelining", "scan"]);
+    write_message(
+        &mut stream,
+        &RequestV1::Set("key".to_owned(), "v1".to_owned()),
+    )?;
+    assert!(matches!(
+        read_message(&mut stream)?,
+        OldResponse::<OldError>::Ok
+    ));
+    write_message(&mut stream, &RequestV1::Get("key".to_owned()))?;
+    assert!(read_frame(&mut stream)?.ends_with(&[0xa2, b'v', b'1']));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set_bytes(b"binary".to_vec(), vec![0xff])?;
+    write_message(&mut stream, &RequestV1::Get("binary".to_owned()))?;
+    assert!(matches!(
+        read_message(&mut stream)?,
+        OldResponse::<OldError>::Error(OldError::Protocol(_))
+    ));
+    write_message(&mut stream, &Request::Batch(WriteBatch::new()))?;
+    assert!(matches!(
+        read_message(&mut stream)?,
+        OldResponse::<OldError>::Error(OldError::Protocol(_))
+    ));
+
+    Ok(())
+}
+
+// Accept a single connection and advertise only the given capabilities,
+// returning every byte the client sends after the handshake
+fn start_limited_server(addr: &str, capabilities: &[&str]) -> thread::JoinHandle<Vec<u8>> {
+    let listener = TcpListener::bind(addr).unwrap();
+    let capabilities = capabilities.iter().map(|c| c.to_string()).collect();
+
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let _: Hello = read_message(&mut stream).unwrap();
+        let hello = HelloResponse::Accepted {
+            version: PROTOCOL_VERSION,
+            capabilities,
+            max_frame_size: MAX_FRAME_SIZE,
+        };
+        write_message(&mut stream, &hello).unwrap();
+
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        buf
+    })
+}
+
+// The client should refuse to send requests the server does not support
+#[test]
+fn missing_capabilities() -> Result<()> {
+    let addr = "127.0.0.1:4024";
+    let server = start_limited_server(addr, &["pipelining"]);
+
+    let mut client = KvsClient::connect(addr)?;
+    let err = client
+        .scan(KeyRange::Prefix(b"key".to_vec()), false)
+        .unwrap_err();
+    assert!(matches!(&err, Error::Unsupported { capability } if capability == "scan"));
+    assert_eq!(err.code(), 13);
+
+    let err = client
+        .set_with_ttl("key".to_owned(), "value".to_owned(), Duration::from_secs(1))
+        .unwrap_err();
+    assert!(matches!(&err, Error::Unsupported { capability } if capability == "ttl"));
+
+    let err = client.write_batch(WriteBatch::new()).unwrap_err();
+    assert!(matches!(&err, Error::Unsupported { capability } if capability == "batch"));
+
+    let err = client
+        .set_if_absent("key".to_owned(), "value".to_owned())
+        .unwrap_err();
+    assert!(matches!(&err, Error::Unsupported { capability } if capability == "cas"));
+
+    // Pipelined requests are checked one by one before any is sent
+    let requests = [
+        Request::Set(b"key".to_vec(), b"value".to_vec()),
+        Request::Batch(WriteBatch::new()),
+    ];
+    let err = client.pipeline(&requests).unwrap_err();
+    assert!(matches!(&err, Error::Unsupported { capability } if capability == "batch"));
+
+    drop(client);
+    assert!(server.join().unwrap().is_empty());
+
+    Ok(())
+}
+
+// A pipeline should stop at a response it cannot read, even while the server
+// is not reading the rest of the requests
+#[test]
+fn pipeline_bad_response() -> Result<()> {
+    let addr = "127.0.0.1:4028";
+    let listener = TcpListener::bind(addr)?;
+    let (done, wait) = mpsc::channel::<()>();
+
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let _: Hello = read_message(&mut stream).unwrap();
+        let hello = HelloResponse::Accepted {
+            version: PROTOCOL_VERSION,
+            capabilities: vec!["pipelining".to_owned()],
+            max_frame_size: MAX_FRAME_SIZE,
+        };
+        write_message(&mut stream, &hello).unwrap();
+        write_message(&mut stream, &"garbage").unwrap();
+
+        // Keep the connection open without reading from it
+        let _ = wait.recv();
+    });
+
+    // More than the socket buffers can take
+    let mut client = KvsClient::connect(addr)?;
+    let requests: Vec<_> = (0..64)
+        .map(|i| Request::Set(format!("key{}", i).into_bytes(), vec![0; 1024 * 1024]))
+        .collect();
+    assert!(client.pipeline(&requests).is_err());
+
+    drop(done);
+    server.join().unwrap();
+
+    Ok(())
+}
+
+// In-memory store that panics when asked for the key `panic`
+#[derive(Clone)]
+struct PanickingEngine(MemoryKvsEngine);
+
+impl KvsEngine for PanickingEngine {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.0.set_bytes(key, value)
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.0.set_bytes_with_ttl(key, value, ttl)
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if key == b"panic" {
+            panic!("asked for the key that panics");
+        }
+        self.0.get_bytes(key)
+    }
+
+    fn expiry_bytes(&self, key: Vec<u8>) -> Result<Option<u64>> {
+        self.0.expiry_bytes(key)
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.0.remove_bytes(key)
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.0.write_batch(batch)
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<ConditionalResult> {
+        self.0.compare_and_swap_bytes(key, expected, new)
+    }
+
+    fn scan_bytes(&self, range: impl RangeBounds<Vec<u8>>) -> Result<KvsIterator> {
+        self.0.scan_bytes(range)
+    }
+
+    fn backup(&self, dest: impl Into<PathBuf>) -> Result<()> {
+        self.0.backup(dest)
+    }
+}
+
+// A handler that panics only takes down its own connection
+#[test]
+fn panicking_handler() -> Result<()> {
+    let addr = "127.0.0.1:4027";
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    let mut server = KvsServer::new(
+        PanickingEngine(MemoryKvsEngine::new()),
+        pool,
+        addr.to_owned(),
+    )?;
+    thread::spawn(move || server.start().unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    drop(client);
+
+    // More panics than there are threads in the pool
+    for _ in 0..3 {
+        let mut client = KvsClient::connect(addr)?;
+        assert!(client.get("panic".to_owned()).is_err());
+
+        let mut client = KvsClient::connect(addr)?;
+        assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+        client.set("key".to_owned(), "value".to_owned())?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,189 @@
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use kvs::{ConditionFailed, Durability, Error, KvsEngine, Result, SledKvsEngine, WriteBatch};
+use tempfile::TempDir;
+
+// Keys with a TTL should disappear once it runs out, and be swept away
+#[test]
+fn expire_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+
+    engine.set_with_ttl(
+        "long".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    engine.set_with_ttl(
+        "cleared".to_owned(),
+        "old".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    engine.set("cleared".to_owned(), "new".to_owned())?;
+    engine.set_with_ttl(
+        "short".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(1),
+    )?;
+
+    assert_eq!(engine.get("short".to_owned())?, Some("value".to_owned()));
+
+    thread::sleep(Duration::from_millis(1200));
+
+    // Expired keys are hidden whether or not the sweeper got to them yet
+    assert_eq!(engine.get("short".to_owned())?, None);
+    assert_eq!(engine.scan(..)?.count(), 2);
+    assert_eq!(engine.get("cleared".to_owned())?, Some("new".to_owned()));
+
+    // Give the sweeper time to run, then check that the key is gone for good
+    thread::sleep(Duration::from_millis(1500));
+    assert!(matches!(
+        engine.remove("short".to_owned()),
+        Err(Error::KeyNotFound)
+    ));
+
+    drop(engine);
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get("short".to_owned())?, None);
+    assert_eq!(engine.get("long".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Every write in a batch should be applied, in order
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+
+    engine.set_with_ttl(
+        "old".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("key1".to_owned(), "value1".to_owned());
+    batch.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_millis(500),
+    );
+    batch.set("key1".to_owned(), "value3".to_owned());
+    batch.remove("old".to_owned());
+    batch.remove("missing".to_owned());
+    engine.write_batch(batch)?;
+
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("old".to_owned())?, None);
+
+    thread::sleep(Duration::from_millis(600));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Conditional writes should only apply if the key has the expected value
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+
+    assert_eq!(
+        engine.set_if_absent("key".to_owned(), "v1".to_owned())?,
+        Ok(())
+    );
+    assert_eq!(
+        engine.set_if_absent("key".to_owned(), "v2".to_owned())?,
+        Err(ConditionFailed {
+            current: Some("v1".to_owned())
+        })
+    );
+
+    let res = engine.compare_and_swap(
+        "key".to_owned(),
+        Some("v1".to_owned()),
+        Some("v2".to_owned()),
+    )?;
+    assert_eq!(res, Ok(()));
+    assert_eq!(engine.get("key".to_owned())?, Some("v2".to_owned()));
+
+    // An expired key counts as missing
+    engine.set_with_ttl(
+        "session".to_owned(),
+        "old".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(
+        engine.set_if_absent("session".to_owned(), "new".to_owned())?,
+        Ok(())
+    );
+    assert_eq!(engine.get("session".to_owned())?, Some("new".to_owned()));
+
+    // A swapped value does not keep the TTL of the value it replaced
+    engine.set_with_ttl(
+        "session".to_owned(),
+        "short".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    let res = engine.compare_and_swap(
+        "session".to_owned(),
+        Some("short".to_owned()),
+        Some("long".to_owned()),
+    )?;
+    assert_eq!(res, Ok(()));
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(engine.get("session".to_owned())?, Some("long".to_owned()));
+
+    Ok(())
+}
+
+// Writes should be readable after reopening under every durability policy
+#[test]
+fn durability_policies() -> Result<()> {
+    let policies = [
+        Durability::None,
+        Durability::Periodic(Duration::from_millis(10)),
+        Durability::GroupCommit,
+        Durability::Sync,
+    ];
+
+    for durability in policies {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let engine = SledKvsEngine::open_with_durability(temp_dir.path(), durability)?;
+
+        for i in 0..50 {
+            engine.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        engine.remove("key0".to_owned())?;
+
+        drop(engine);
+
+        let engine = reopen(temp_dir.path(), durability)?;
+        assert_eq!(engine.get("key0".to_owned())?, None);
+        for i in 1..50 {
+            assert_eq!(
+                engine.get(format!("key{}", i))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Sled's background flusher can hold on to the database lock for a moment after
+// the last handle is dropped
+fn reopen(path: &Path, durability: Durability) -> Result<SledKvsEngine> {
+    for _ in 0..50 {
+        if let Ok(engine) = SledKvsEngine::open_with_durability(path, durability) {
+            return Ok(engine);
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    SledKvsEngine::open_with_durability(path, durability)
+}
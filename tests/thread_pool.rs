@@ -0,0 +1,71 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::Result;
+
+const TASK_NUM: usize = 20;
+
+// Every spawned job should run exactly once
+fn spawn_counter<P: ThreadPool>(pool: P) -> Result<()> {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let (sender, receiver) = mpsc::channel();
+
+    for _ in 0..TASK_NUM {
+        let counter = counter.clone();
+        let sender = sender.clone();
+        pool.spawn(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            sender.send(()).unwrap();
+        });
+    }
+
+    for _ in 0..TASK_NUM {
+        receiver.recv().unwrap();
+    }
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM);
+
+    Ok(())
+}
+
+// Panicking jobs should not stop the remaining jobs from running
+fn panic_task<P: ThreadPool>(pool: P) -> Result<()> {
+    for _ in 0..TASK_NUM {
+        pool.spawn(|| panic!("intentional panic in a pool job"));
+    }
+
+    spawn_counter(pool)
+}
+
+#[test]
+fn naive_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(NaiveThreadPool::new(4)?)
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(SharedQueueThreadPool::new(4)?)
+}
+
+#[test]
+fn rayon_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(RayonThreadPool::new(4)?)
+}
+
+#[test]
+fn shared_queue_thread_pool_panic_task() -> Result<()> {
+    panic_task(SharedQueueThreadPool::new(4)?)
+}
+
+#[test]
+fn rayon_thread_pool_panic_task() -> Result<()> {
+    panic_task(RayonThreadPool::new(4)?)
+}
+
+#[test]
+fn thread_pool_without_threads() {
+    assert!(NaiveThreadPool::new(0).is_err());
+    assert!(SharedQueueThreadPool::new(0).is_err());
+    assert!(RayonThreadPool::new(0).is_err());
+}